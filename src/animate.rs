@@ -0,0 +1,101 @@
+use crate::{to_image, CodelChoice, CodelCoord, Delta, Direction, PietCode, Trace};
+use image::codecs::gif::{GifEncoder, Repeat};
+use image::{Delay, DynamicImage, Frame, ImageResult, Rgb, RgbImage};
+
+/// How long each step is shown for.
+const FRAME_MS: u32 = 250;
+/// What the current codel and the pointers are drawn in.
+const HIGHLIGHT: Rgb<u8> = Rgb([0xff, 0x00, 0xff]);
+
+impl Trace {
+    /// Render this trace of `code` as an animated GIF, one frame a step,
+    /// eg for teaching how execution moves through a program.
+    /// Each frame shades the current block and outlines the current codel,
+    /// with an arrow from it showing the direction pointer. A barb on one
+    /// side of the arrowhead shows which way the codel chooser points.
+    pub fn to_gif(&self, code: &PietCode) -> ImageResult<Vec<u8>> {
+        // Sized as the HTML trace sizes its canvas, but big enough to draw an arrow in a codel.
+        let size = (640 / code.width.max(code.height).max(1) as u32).clamp(12, 32);
+        let base = to_image(code, size);
+        let mut bytes = Vec::new();
+        {
+            let mut encoder = GifEncoder::new_with_speed(&mut bytes, 10);
+            encoder.set_repeat(Repeat::Infinite)?;
+            let frames = self.steps.iter().map(|step| {
+                let mut img = base.clone();
+                draw_step(&mut img, code, size, step.pos, step.direction, step.chooser);
+                let delay = Delay::from_numer_denom_ms(FRAME_MS, 1);
+                Frame::from_parts(DynamicImage::ImageRgb8(img).into_rgba8(), 0, 0, delay)
+            });
+            encoder.encode_frames(frames)?;
+        }
+        Ok(bytes)
+    }
+}
+
+fn draw_step(
+    img: &mut RgbImage,
+    code: &PietCode,
+    size: u32,
+    pos: CodelCoord,
+    direction: Direction,
+    chooser: CodelChoice,
+) {
+    if let Some(block) = code.region_at(pos) {
+        for &codel in &block.region {
+            for (x, y) in codel_pixels(codel, size) {
+                let Rgb(rgb) = img.get_pixel(x, y);
+                img.put_pixel(x, y, Rgb(rgb.map(|c| (c as u32 * 3 / 5) as u8)));
+            }
+        }
+    }
+    for (x, y) in codel_pixels(pos, size) {
+        let (dx, dy) = (x - pos.x as u32 * size, y - pos.y as u32 * size);
+        if dx == 0 || dy == 0 || dx == size - 1 || dy == size - 1 {
+            img.put_pixel(x, y, HIGHLIGHT);
+        }
+    }
+
+    // The arrow runs from the middle of the codel to its edge.
+    let Delta { dx, dy } = direction.to_delta();
+    // The chooser's left or right, facing the way the direction pointer does.
+    let (sx, sy) = match chooser {
+        CodelChoice::Left => (dy, -dx),
+        CodelChoice::Right => (-dy, dx),
+    };
+    let half = (size / 2) as isize;
+    let center = ((pos.x as u32 * size) as isize + half, (pos.y as u32 * size) as isize + half);
+    let reach = half - 2;
+    let tip = (center.0 + dx * reach, center.1 + dy * reach);
+    let shaft = (0..=reach).map(|t| (center.0 + dx * t, center.1 + dy * t));
+    let barb = (1..=reach / 2).map(|t| (tip.0 - dx * t + sx * t, tip.1 - dy * t + sy * t));
+    for (x, y) in shaft.chain(barb) {
+        img.put_pixel(x as u32, y as u32, HIGHLIGHT);
+    }
+}
+
+fn codel_pixels(codel: CodelCoord, size: u32) -> impl Iterator<Item = (u32, u32)> {
+    let (x, y) = (codel.x as u32 * size, codel.y as u32 * size);
+    (y..y + size).flat_map(move |y| (x..x + size).map(move |x| (x, y)))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{load, Trace};
+    use image::codecs::gif::GifDecoder;
+    use image::AnimationDecoder;
+
+    #[test]
+    fn test_to_gif() {
+        let code = load("test_imgs/test_slide.png", 1).unwrap();
+        let mut trace = Trace::new();
+        code.execute().tracer(&mut trace).run();
+        let gif = trace.to_gif(&code).unwrap();
+        let frames = GifDecoder::new(gif.as_slice()).unwrap().into_frames().collect_frames().unwrap();
+        assert_eq!(frames.len(), trace.steps.len());
+        // The first frame outlines the top-left codel.
+        let first = frames[0].buffer();
+        assert_eq!(first.get_pixel(0, 0).0, [0xff, 0x00, 0xff, 0xff]);
+        assert_ne!(frames[0].buffer(), frames[1].buffer());
+    }
+}
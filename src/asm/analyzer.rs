@@ -0,0 +1,118 @@
+use crate::asm::{AsmCommand, ParseError, ParseErrorType, PietAsm};
+
+/// How many values `cmd` pops and pushes when it runs, and whether it can
+/// fail even given that many, eg dividing by zero. A failed command is
+/// skipped, leaving the stack as it was.
+pub(super) fn stack_effect(cmd: &AsmCommand) -> (usize, usize, bool) {
+    use AsmCommand::*;
+    match cmd {
+        Push(_) => (0, 1, false),
+        Pop | OutNum => (1, 0, false),
+        Not => (1, 1, false),
+        Duplicate => (1, 2, false),
+        Add | Subtract | Multiply | Greater => (2, 1, false),
+        Divide | Mod => (2, 1, true),
+        Roll => (2, 0, true),
+        InNum | InChar => (0, 1, true),
+        OutChar => (1, 0, true),
+        Label(_) | Jump(_) | JumpIf(_) | Stop | Raw(_) | Hint(_) => (0, 0, false),
+    }
+}
+
+/// The fewest values there can be on the stack as each command is reached,
+/// from the start of the program, or None where it can't be reached.
+/// Values a raw block leaves aren't counted on.
+fn min_depths(asm: &PietAsm) -> Vec<Option<usize>> {
+    use AsmCommand::*;
+    let mut labels = vec![0; asm.jump_counts.len()];
+    for (i, cmd) in asm.cmds.iter().enumerate() {
+        if let Label(id) = cmd {
+            labels[*id] = i;
+        }
+    }
+    let mut depths = vec![None; asm.cmds.len()];
+    let mut pending: Vec<(usize, usize)> = vec![(0, 0)];
+    while let Some((i, depth)) = pending.pop() {
+        if i >= asm.cmds.len() || depths[i].is_some_and(|known| known <= depth) {
+            continue;
+        }
+        depths[i] = Some(depth);
+        let cmd = &asm.cmds[i];
+        let (pops, pushes, fallible) = stack_effect(cmd);
+        let after = match depth.checked_sub(pops) {
+            // Too few values: it might fail, or there might be more than counted on.
+            None => depth.min(pushes),
+            Some(rest) if fallible => depth.min(rest + pushes),
+            Some(rest) => rest + pushes,
+        };
+        match cmd {
+            Stop => (),
+            Raw(_) => { pending.push((i + 1, 0)); }
+            Jump(id) => { pending.push((labels[*id], after)); }
+            JumpIf(id) => {
+                let after = depth.saturating_sub(1);
+                pending.extend([(i + 1, after), (labels[*id], after)]);
+            }
+            _ => { pending.push((i + 1, after)); }
+        }
+    }
+    depths
+}
+
+/// Check every reachable `JUMPIF` has a value to pop. One that doesn't
+/// would be skipped as it ran, silently falling through.
+pub(super) fn check_branches(asm: &PietAsm) -> Result<(), ParseError> {
+    let depths = min_depths(asm);
+    let empty = asm.cmds.iter().zip(&depths).enumerate()
+        .filter(|(_, (cmd, depth))| matches!(cmd, AsmCommand::JumpIf(_)) && **depth == Some(0))
+        // A command made up along the way is part of the last statement with a
+        // line, eg the `JUMPIF` it was made for. Only hand-built IR has none.
+        .find_map(|(i, _)| asm.origins[..=i].iter().rev().find_map(|origin| origin.lineno));
+    match empty {
+        None => Ok(()),
+        Some(lineno) => Err(ParseErrorType::EmptyBranch.at(lineno)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::{parser, preprocessor};
+    use assert_matches::assert_matches;
+
+    fn parse(source: &str) -> Result<PietAsm, String> {
+        let lines: Vec<String> = source.lines().map(String::from).collect();
+        let ast = preprocessor::preprocess(&lines).map_err(|e| e.to_string())?;
+        parser::to_bytecode(ast, false).map_err(|e| e.to_string())
+    }
+
+    fn check(source: &str) -> Result<(), String> {
+        check_branches(&parse(source)?).map_err(|e| e.to_string())
+    }
+
+    #[test]
+    fn test_check_branches() {
+        assert_eq!(check("PUSH 1\nJUMPIF end\n:end"), Ok(()));
+        assert_eq!(
+            check("PUSH 1\nPOP\nJUMPIF end\n:end"),
+            Err("error at 3: JUMPIF may find the stack empty, and fall through".to_string()),
+        );
+        // Each time round the loop pops what was pushed before it.
+        assert_eq!(check("PUSH 3\n:loop\nDUP\nJUMPIF loop\nSTOP"), Ok(()));
+        assert!(check("PUSH 3\n:loop\nJUMPIF loop\nSTOP").is_err());
+        // Input might not arrive, and a failed division leaves both operands.
+        assert!(check("INNUM\nJUMPIF end\n:end").is_err());
+        assert_eq!(check("PUSH 1\nPUSH 0\nDIV\nJUMPIF end\n:end"), Ok(()));
+        // Unreachable branches are never taken.
+        assert_eq!(check("JUMP end\nJUMPIF end\n:end"), Ok(()));
+        // Every way through a switch counts, including matching no case.
+        assert_eq!(check("PUSH 2\nSWITCH\nCASE 1\nPUSH 4\nDEFAULT\nPUSH 5\nENDSWITCH\nJUMPIF end\n:end"), Ok(()));
+        assert!(check("PUSH 2\nSWITCH\nCASE 1\nPUSH 4\nENDSWITCH\nJUMPIF end\n:end").is_err());
+
+        // A branch without a line of its own is reported at the statement it's part of.
+        let mut asm = parse("PUSH 1\nPOP\nJUMPIF end\n:end").unwrap();
+        let branch = asm.cmds.iter().position(|cmd| matches!(cmd, AsmCommand::JumpIf(_))).unwrap();
+        asm.origins[branch].lineno = None;
+        assert_matches!(check_branches(&asm), Err(ParseError { lineno: 3, .. }));
+    }
+}
@@ -1,16 +1,27 @@
-use crate::asm::{AsmCommand, LabelId, PietAsm};
-use crate::{Color, Command, PietCode};
+use crate::asm::{AsmCommand, CodeSink, LabelId, LayoutHint, PietAsm, PlanSink, RawBlock, sink, MAX_OPT_LEVEL};
+use crate::{CodelCoord, Color, Command, PietCode, Progress};
+use std::fmt::Write;
 use indoc::indoc;
 use log::{debug, info, error};
 use num_traits::ToPrimitive;
+use std::cmp::Reverse;
 use std::collections::{HashMap, HashSet};
-use std::iter::repeat;
+use std::fmt;
+use std::iter::repeat_n;
 use std::mem::{self, ManuallyDrop};
+use std::ops::Range;
 
 // const WIDTH: usize = 800;
 const WIDTH: usize = 100;
+const MIN_WIDTH: usize = 20;
+const MAX_WIDTH: usize = 2000;
+const WIDTH_STEP: usize = 10;
 const ROW_HEIGHT: usize = 10;
 const ROW_FILL_HEIGHT: usize = 5;
+/// Jump lanes start no further left than this.
+const LANE_MIN: usize = 4;
+/// Jump lanes are at least this far apart.
+const LANE_GAP: usize = 3;
 const CONTROL_COLOR: Color = Color::Red;
 
 macro_rules! draw {
@@ -36,7 +47,6 @@ macro_rules! draw_here {
 }
 
 struct DrawPattern {
-    off_y: usize,
     step_x: usize,
     allocation_width: usize,
     last_color: Option<Color>,
@@ -89,8 +99,9 @@ impl DrawPattern {
         //     .next().unwrap();
         let end_color = end.map(|end| {
             pixels.iter()
-                .filter_map(|&(x, y, p)| ((x + 1, y) == end).then(|| p))
-                .next().unwrap()
+                .find(|&&(x, y, _)| (x + 1, y) == end)
+                .map(|&(_, _, p)| p)
+                .unwrap()
         });
         if let Some((x, y)) = end {
             // for now, at least.
@@ -98,7 +109,6 @@ impl DrawPattern {
             assert!(pixels.iter().all(|&(px, _, _)| px < x));
         }
         DrawPattern {
-            off_y: start_y,
             step_x: width,
             last_color: end_color,
             allocation_width,
@@ -108,11 +118,28 @@ impl DrawPattern {
 }
 
 #[derive(Debug)]
-enum DrawError {
-    OutOfBounds(usize, usize),
-    ColorMismatch(Color, Color, (usize, usize)),
+pub(super) enum DrawError {
+    OutOfBounds(CodelCoord),
+    ColorMismatch(Color, Color, CodelCoord),
     AllocationError,
-    Todo,
+    RawBlockTooTall,
+    ColumnUnavailable(usize),
+    GroupTooWide,
+}
+
+impl fmt::Display for DrawError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DrawError::OutOfBounds(coord) => write!(f, "out of bounds at {coord}"),
+            DrawError::ColorMismatch(new, old, coord) => {
+                write!(f, "can't draw {new:?} over {old:?} at {coord}")
+            }
+            DrawError::AllocationError => write!(f, "couldn't allocate space"),
+            DrawError::RawBlockTooTall => write!(f, "raw block doesn't fit in a row"),
+            DrawError::ColumnUnavailable(x) => write!(f, "can't place label at column {x}"),
+            DrawError::GroupTooWide => write!(f, "group doesn't fit in a row"),
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -126,6 +153,8 @@ struct PietCodeBuffer {
     x: usize,
     y: usize,
     jump_xs: HashSet<usize>,
+    jumps_routed: usize,
+    longest_jump: usize,
 }
 
 impl PietCodeBuffer {
@@ -143,6 +172,8 @@ impl PietCodeBuffer {
             x: 0,
             y: 0,
             jump_xs: HashSet::new(),
+            jumps_routed: 0,
+            longest_jump: 0,
         }
     }
 
@@ -169,30 +200,25 @@ impl PietCodeBuffer {
         Ok(())
     }
 
-    fn allocate_here(&mut self, width: usize) -> Result<PietCodeBufferEdit, DrawError> {
+    fn allocate_here(&mut self, width: usize) -> Result<PietCodeBufferEdit<'_>, DrawError> {
         let height = ROW_HEIGHT;
         let area = Rect { x: self.x, y: self.y, width, height };
+        self.check_bounds(area)?;
         Ok(PietCodeBufferEdit::new_slice(self, area))
     }
 
     // TODO signature sucks, burn this place down
-    fn allocate(&mut self, width: usize) -> Result<(PietCodeBufferEdit, Option<Color>), DrawError> {
+    fn allocate(&mut self, width: usize) -> Result<(PietCodeBufferEdit<'_>, Option<Color>), DrawError> {
         const ATTEMPTS: i32 = 10;
         let height = ROW_HEIGHT;
         let mut attempts = 0;
         while attempts < ATTEMPTS {
-            if self.x + width >= WIDTH {
-                self.reserve(height);
-                let x = self.x;
-                let y = self.y;
-                PietCodeBufferEdit::new(self).draw_newline(x, y + 1)?;
-                self.x = 2;
-                self.y += height;
-                self.last_color = Some(Color::White);
+            if self.x + width >= self.width {
+                self.newline()?;
             }
             let idx = (0..width).rev().filter_map(|w| {
                 let x = w + self.x;
-                self.jump_xs.contains(&x).then(|| x)
+                self.jump_xs.contains(&x).then_some(x)
             }).next();
             if let Some(idx) = idx {
                 let x = self.x;
@@ -215,10 +241,38 @@ impl PietCodeBuffer {
             return Err(DrawError::AllocationError);
         }
         let area = Rect { x: self.x, y: self.y, width, height };
+        self.check_bounds(area)?;
         let last_color = self.last_color;
         Ok((PietCodeBufferEdit::new_slice(self, area), last_color))
     }
 
+    fn check_bounds(&self, area: Rect) -> Result<(), DrawError> {
+        let Rect { x, y, width, height } = area;
+        if x + width > self.width || y + height > self.height {
+            return Err(DrawError::OutOfBounds(CodelCoord::new(x + width, y + height)));
+        }
+        Ok(())
+    }
+
+    /// Whether nothing's been drawn on this row yet.
+    /// There's no room to route a newline from here.
+    fn at_row_start(&self) -> bool {
+        self.x <= 2
+    }
+
+    /// Route execution down to the start of a new row.
+    fn newline(&mut self) -> Result<(), DrawError> {
+        let height = ROW_HEIGHT;
+        self.reserve(height);
+        let x = self.x;
+        let y = self.y;
+        PietCodeBufferEdit::new(self).draw_newline(x, y + 1)?;
+        self.x = 2;
+        self.y += height;
+        self.last_color = Some(Color::White);
+        Ok(())
+    }
+
     fn advance_to(&mut self, to_x: usize) -> Result<(), DrawError> {
         info!("advance to {to_x} (from {})", self.x);
         let do_draw = self.last_color.is_some();
@@ -236,7 +290,8 @@ impl PietCodeBuffer {
         }
         let x = self.x;
         let y = self.y;
-        let dist = to_x - x;
+        // Even a new row starts past some columns.
+        let dist = to_x.checked_sub(x).ok_or(DrawError::ColumnUnavailable(to_x))?;
         if do_draw {
             PietCodeBufferEdit::new(self).draw_rect(
                 x, y + 1, dist, 1, Color::White,
@@ -247,11 +302,36 @@ impl PietCodeBuffer {
         Ok(())
     }
 
+    /// Make room for `width` codels, in which a new jump lane will start
+    /// `lane(self)` codels in. Whatever later meets the lane draws a couple of
+    /// codels either side of it, so keep it clear of the start of the row and
+    /// of the other lanes.
+    fn allocate_lane(&mut self, width: usize, lane: fn(&Self) -> usize) -> Result<(), DrawError> {
+        let y = self.y;
+        loop {
+            let (edit, _) = self.allocate(width)?;
+            mem::drop(edit);
+            let x = self.x + lane(self);
+            if x >= LANE_MIN && self.jump_xs.iter().all(|&other| other.abs_diff(x) >= LANE_GAP) {
+                return Ok(());
+            }
+            if self.y > y + ROW_HEIGHT {
+                error!("no room for a jump lane");
+                return Err(DrawError::AllocationError);
+            }
+            self.advance_to(self.x + 1)?;
+        }
+    }
+
     fn draw_jump(&mut self, x: usize, y0: usize, y1: usize) -> Result<(), DrawError> {
         info!("draw_jump: {x} {y0} {y1}");
         assert!(y0 < y1);
         let mut edit = PietCodeBufferEdit::new(self);
-        edit.draw_rect(x, y0, 1, y1 - y0, Color::White)
+        edit.draw_rect(x, y0, 1, y1 - y0, Color::White)?;
+        mem::drop(edit);
+        self.jumps_routed += 1;
+        self.longest_jump = self.longest_jump.max(y1 - y0);
+        Ok(())
     }
 
     fn draw_command(&mut self, cmd: Command) -> Result<(), DrawError> {
@@ -273,23 +353,60 @@ impl PietCodeBuffer {
         Ok(())
     }
 
+    fn draw_raw(&mut self, block: &RawBlock) -> Result<(), DrawError> {
+        let top = raw_top(block).ok_or(DrawError::RawBlockTooTall)?;
+        let has_color = self.last_color.is_some();
+        let (mut edit, _) = self.allocate(raw_width(block).ok_or(DrawError::RawBlockTooTall)? + 1)?;
+        let mut x = 0;
+        if has_color {
+            edit.draw_pixel(0, 1, Color::White)?;
+            x = 1;
+        }
+        let entry = top + block.entry;
+        if entry != 1 {
+            edit.draw_step(x, 1, entry)?;
+            edit.draw_pixel(x + 2, entry, Color::White)?;
+            x += 3;
+        }
+        for dy in 0..block.height {
+            for dx in 0..block.width {
+                edit.draw_pixel(x + dx, top + dy, block.at(dx, dy))?;
+            }
+        }
+        x += block.width;
+        let last_color = match block.exit.map(|exit| top + exit) {
+            Some(1) => Some(block.at(block.width - 1, 1 - top)),
+            Some(exit) => {
+                edit.draw_pixel(x, exit, Color::White)?;
+                edit.draw_step(x + 1, exit, 1)?;
+                x += 3;
+                Some(CONTROL_COLOR)
+            }
+            None => None,
+        };
+        mem::drop(edit);
+        self.x += x;
+        self.last_color = last_color;
+        Ok(())
+    }
+
     /// Resize the buffer to accommodate `additional_height`
     fn reserve(&mut self, additional_height: usize) {
         self.height += additional_height;
-        self.code.extend(repeat(Color::Other).take(self.width * additional_height));
+        self.code.extend(repeat_n(Color::Other, self.width * additional_height));
     }
 
     fn draw_pixel(&mut self, x: usize, y: usize, color: Color) -> Result<(), DrawError> {
         if x >= self.width || y >= self.height {
             // TODO: kind of spooky with our resizeable buffer. reconsider this.
             if matches!(color, Color::Black) { return Ok(()); }
-            else { return Err(DrawError::OutOfBounds(x, y)); }
+            else { return Err(DrawError::OutOfBounds(CodelCoord::new(x, y))); }
         }
         let idx = y * self.width + x;
         match &mut self.code[idx] {
             c @ Color::Other => { *c = color; }
             c if *c == color => (),
-            c => { return Err(DrawError::ColorMismatch(color, *c, (x, y))); }
+            c => { return Err(DrawError::ColorMismatch(color, *c, CodelCoord::new(x, y))); }
         }
         Ok(())
     }
@@ -298,7 +415,7 @@ impl PietCodeBuffer {
         if x >= self.width || y >= self.height {
             // TODO: kind of spooky with our resizeable buffer. reconsider this.
             if matches!(color, Color::Black) { return Ok(()); }
-            else { return Err(DrawError::OutOfBounds(x, y)); }
+            else { return Err(DrawError::OutOfBounds(CodelCoord::new(x, y))); }
         }
         let idx = y * self.width + x;
         self.code[idx] = color;
@@ -319,6 +436,8 @@ impl PietCodeBuffer {
             code, width, height,
             last_color: None, x: 0, y: 0,
             jump_xs: HashSet::new(),
+            jumps_routed: 0,
+            longest_jump: 0,
         }
     }
 
@@ -413,6 +532,15 @@ impl<'a> PietCodeBufferEdit<'a> {
         Ok(())
     }
 
+    /// Route execution moving right on row `from` over to row `to`: a column
+    /// of `CONTROL_COLOR` spanning both rows at `x`, with one more codel to its
+    /// right on `to`. That codel is the whole of the block's right edge,
+    /// so execution leaves from it whichever way the codel chooser points.
+    fn draw_step(&mut self, x: usize, from: usize, to: usize) -> Result<(), DrawError> {
+        self.draw_rect(x, from.min(to), 1, from.abs_diff(to) + 1, CONTROL_COLOR)?;
+        self.draw_pixel(x + 1, to, CONTROL_COLOR)
+    }
+
     fn draw_newline(&mut self, x: usize, y: usize) -> Result<(), DrawError> {
         self.draw_rect(x, y, 1, ROW_HEIGHT - 2, Color::White)?;
         self.draw_horiz(y + ROW_HEIGHT - 2)?;
@@ -439,24 +567,41 @@ impl Drop for PietCodeBufferEdit<'_> {
     }
 }
 
-impl From<PietCodeBuffer> for PietCode {
-    fn from(this: PietCodeBuffer) -> PietCode {
-        let PietCodeBuffer { width, height, code, .. } = this;
-        PietCode { width, height, code }
+impl PietCodeBuffer {
+    /// Feed `sink` each codel, in reading order.
+    fn emit<S: CodeSink>(self, sink: &mut S) {
+        let width = self.width;
+        for (idx, color) in self.code.into_iter().enumerate() {
+            sink.codel(CodelCoord::new(idx % width, idx / width), color);
+        }
     }
 }
 
-pub(super) fn generate(asm: PietAsm) -> PietCode {
-    let mut buffer = PietCodeBuffer::new(WIDTH, ROW_HEIGHT);
-
+#[derive(Clone)]
+struct Generator {
+    buffer: PietCodeBuffer,
     // TODO: can these be the same thing?
-    let mut labels: HashMap<LabelId, (usize, usize)> = HashMap::new();
-    let mut unmatched_jumps: HashMap<LabelId, (usize, usize)> = HashMap::new();
+    labels: HashMap<LabelId, CodelCoord>,
+    unmatched_jumps: HashMap<LabelId, CodelCoord>,
+    jump_counts: Vec<usize>,
+    pinned_column: Option<usize>,
+    placements: Vec<(CodelCoord, String)>,
+}
 
-    let PietAsm { cmds, mut jump_counts } = asm;
+impl Generator {
+    fn new(jump_counts: Vec<usize>, width: usize) -> Self {
+        Generator {
+            buffer: PietCodeBuffer::new(width, ROW_HEIGHT),
+            labels: HashMap::new(),
+            unmatched_jumps: HashMap::new(),
+            jump_counts,
+            pinned_column: None,
+            placements: Vec::new(),
+        }
+    }
 
-    // wow i suddenly get why Rust could use a `try` block.
-    let res = (|| -> Result<(), DrawError> {
+    fn draw_start(&mut self) -> Result<(), DrawError> {
+        let buffer = &mut self.buffer;
         let (mut edit, _) = buffer.allocate(3)?;
         edit.draw_pixel(0, 0, CONTROL_COLOR)?;
         edit.draw_pixel(0, 1, CONTROL_COLOR)?;
@@ -464,186 +609,826 @@ pub(super) fn generate(asm: PietAsm) -> PietCode {
         mem::drop(edit);
         buffer.x += 2;
         buffer.last_color = Some(CONTROL_COLOR);
+        Ok(())
+    }
 
-        for cmd in cmds {
-            info!("{cmd:?}");
-            match cmd {
-                AsmCommand::Label(label) => {
-                    if let Some(&(dest, y0)) = unmatched_jumps.get(&label) {
-                        buffer.advance_to(dest - 2)?;
-                        draw_here!(buffer, b"
-                             #
-                           >...>
-                            #..
-                              #
-                        ")?;
-                        buffer.draw_jump(dest, y0, buffer.y + 1)?;
-                        buffer.jump_xs.remove(&(buffer.x - 1));
-                        buffer.jump_xs.insert(buffer.x - 2);
-                        labels.insert(label, (dest - 1, buffer.y + 1));
-                    }
-                    else {
-                        draw!(buffer, b"
-                             #
-                           >...>
-                            #..
-                              #
-                        ")?;
-                        labels.insert(label, (buffer.x - 2, buffer.y + 1));
-                        buffer.jump_xs.insert(buffer.x - 2);
-                    }
-                    match jump_counts[label].checked_sub(1) {
-                        Some(num) => { jump_counts[label] = num; }
-                        None => {
-                            let x = buffer.x - 2;
-                            buffer.jump_xs.remove(&x);
-                        }
+    /// Draw a group of commands, all on the same row.
+    /// If they don't fit on the current row, start a new one.
+    fn draw_group(&mut self, group: Vec<AsmCommand>) -> Result<(), DrawError> {
+        let snapshot = self.clone();
+        let y = self.buffer.y;
+        for cmd in group.iter().cloned() {
+            self.draw_cmd(cmd)?;
+        }
+        if self.buffer.y == y {
+            return Ok(());
+        }
+        if snapshot.buffer.at_row_start() {
+            return Err(DrawError::GroupTooWide);
+        }
+        debug!("group didn't fit, retrying on a new row");
+        *self = snapshot;
+        self.buffer.newline()?;
+        let y = self.buffer.y;
+        for cmd in group {
+            self.draw_cmd(cmd)?;
+        }
+        if self.buffer.y != y {
+            return Err(DrawError::GroupTooWide);
+        }
+        Ok(())
+    }
+
+    fn draw_cmd(&mut self, cmd: AsmCommand) -> Result<(), DrawError> {
+        let description = cmd.describe();
+        self.lay_out(cmd)?;
+        let cursor = CodelCoord::new(self.buffer.x, self.buffer.y);
+        self.placements.push((cursor, description));
+        Ok(())
+    }
+
+    fn lay_out(&mut self, cmd: AsmCommand) -> Result<(), DrawError> {
+        let Generator { buffer, labels, unmatched_jumps, jump_counts, pinned_column, .. } = self;
+        info!("{cmd:?}");
+        let pinned = pinned_column.take();
+        match cmd {
+            AsmCommand::Label(label) => {
+                if let Some(&CodelCoord { x: dest, y: y0 }) = unmatched_jumps.get(&label) {
+                    // The jump lane's already been laid, so we go where it goes.
+                    if pinned.is_some_and(|column| column != dest - 1) {
+                        return Err(DrawError::ColumnUnavailable(pinned.unwrap()));
                     }
+                    buffer.advance_to(dest - 2)?;
+                    draw_here!(buffer, b"
+                         #
+                       >...>
+                        #..
+                          #
+                    ")?;
+                    buffer.draw_jump(dest, y0, buffer.y + 1)?;
+                    buffer.jump_xs.remove(&(buffer.x - 1));
+                    buffer.jump_xs.insert(buffer.x - 2);
+                    labels.insert(label, CodelCoord::new(dest - 1, buffer.y + 1));
                 }
-                AsmCommand::Jump(label) => {
-                    // connecting to an existing label
-                    if let Some(&(dest, y0)) = labels.get(&label) {
-                        buffer.advance_to(dest - 1)?;
-                        draw_here!(buffer, b"
-                             .
-                           >...#
-                            #..
-                              #
-                        ")?;
-                        buffer.draw_jump(dest, y0, buffer.y + 1)?;
+                else if let Some(column) = pinned {
+                    let start = column.checked_sub(1).ok_or(DrawError::ColumnUnavailable(column))?;
+                    if start < 2 || column + 3 >= buffer.width {
+                        return Err(DrawError::ColumnUnavailable(column));
                     }
-                    // connecting to an existing jump
-                    else if let Some(&(dest, y0)) = unmatched_jumps.get(&label) {
-                        buffer.advance_to(dest)?;
-                        draw_here!(buffer, b"\n>.#")?;
-                        buffer.draw_jump(dest, y0, buffer.y + 1)?;
+                    buffer.advance_to(start)?;
+                    if (start..start + 4).any(|x| buffer.jump_xs.contains(&x)) {
+                        return Err(DrawError::ColumnUnavailable(column));
                     }
-                    else {
-                        draw!(buffer, b"\n>.#")?;
+                    draw_here!(buffer, b"
+                         #
+                       >...>
+                        #..
+                          #
+                    ")?;
+                    labels.insert(label, CodelCoord::new(column, buffer.y + 1));
+                    buffer.jump_xs.insert(column);
+                }
+                else {
+                    buffer.allocate_lane(4, |_| 1)?;
+                    draw!(buffer, b"
+                         #
+                       >...>
+                        #..
+                          #
+                    ")?;
+                    labels.insert(label, CodelCoord::new(buffer.x - 2, buffer.y + 1));
+                    buffer.jump_xs.insert(buffer.x - 2);
+                }
+                match jump_counts[label].checked_sub(1) {
+                    Some(num) => { jump_counts[label] = num; }
+                    None => {
                         let x = buffer.x - 2;
-                        buffer.jump_xs.insert(x);
-                        unmatched_jumps.insert(label, (x, buffer.y + 1));
-                    }
-                    match jump_counts[label].checked_sub(1) {
-                        Some(num) => { jump_counts[label] = num; }
-                        None => {
-                            let x = buffer.x - 3;
-                            buffer.jump_xs.remove(&x);
-                        }
+                        buffer.jump_xs.remove(&x);
                     }
                 }
-                AsmCommand::JumpIf(label) => {
-                    // connecting to an existing label
-                    if let Some(&(dest, y0)) = labels.get(&label) {
-                        buffer.advance_to(dest - 1)?;
-                        let a = CONTROL_COLOR;
-                        let b = a.next_for_command(Command::Pointer);
-                        draw_here!(buffer, b"
-                             .
-                           >..ab>
-                            #...
-                               #
-                        ", a, b)?;
-                        buffer.draw_jump(dest, y0, buffer.y + 1)?;
-                    }
-                    // connecting to an existing jump
-                    else if let Some((dest, y0)) = unmatched_jumps.remove(&label) {
-                        buffer.advance_to(dest - 2)?;
-                        let a = CONTROL_COLOR;
-                        let b = a.next_for_command(Command::Pointer);
-                        draw_here!(buffer, b"
-                              .
-                           >.abb>
-                              b
-                        ", a, b)?;
-                        buffer.draw_jump(dest, y0, buffer.y + 1)?;
-                        unmatched_jumps.insert(label, (dest, buffer.y + 3));
-                    }
-                    // first of their name
-                    else {
-                        // TODO: there's gotta be a nicer api with `draw_command`
-                        let mut x = 0;
-                        let (mut edit, last_color) = buffer.allocate(4)?;
-                        let color = match last_color {
-                            Some(Color::White) | None => {
-                                edit.draw_pixel(0, 1, CONTROL_COLOR)?;
-                                x += 1;
-                                CONTROL_COLOR
-                            }
-                            Some(color) => color,
-                        };
-                        let color = color.next_for_command(Command::Pointer);
-                        edit.draw_pixel(x, 1, color)?;
-                        edit.draw_pixel(x, 2, color)?;
-                        edit.draw_pixel(x + 1, 1, color)?;
-                        mem::drop(edit);
-                        buffer.jump_xs.insert(buffer.x + x);
-                        let key = (buffer.x + x, buffer.y + 3);
-                        unmatched_jumps.insert(label, key);
-                        buffer.x += x + 2;
-                        buffer.last_color = Some(color);
-                    }
-                    match jump_counts[label].checked_sub(1) {
-                        Some(num) => { jump_counts[label] = num; }
-                        None => {
-                            let x = buffer.x - 3;
-                            buffer.jump_xs.remove(&x);
-                        }
+            }
+            AsmCommand::Jump(label) => {
+                // connecting to an existing label
+                if let Some(&CodelCoord { x: dest, y: y0 }) = labels.get(&label) {
+                    buffer.advance_to(dest - 1)?;
+                    draw_here!(buffer, b"
+                         .
+                       >...#
+                        #..
+                          #
+                    ")?;
+                    buffer.draw_jump(dest, y0, buffer.y + 1)?;
+                }
+                // connecting to an existing jump
+                else if let Some(&CodelCoord { x: dest, y: y0 }) = unmatched_jumps.get(&label) {
+                    buffer.advance_to(dest)?;
+                    draw_here!(buffer, b"\n>.#")?;
+                    buffer.draw_jump(dest, y0, buffer.y + 1)?;
+                }
+                else {
+                    buffer.allocate_lane(3, |_| 0)?;
+                    draw!(buffer, b"\n>.#")?;
+                    let x = buffer.x - 2;
+                    buffer.jump_xs.insert(x);
+                    unmatched_jumps.insert(label, CodelCoord::new(x, buffer.y + 1));
+                }
+                match jump_counts[label].checked_sub(1) {
+                    Some(num) => { jump_counts[label] = num; }
+                    None => {
+                        let x = buffer.x - 3;
+                        buffer.jump_xs.remove(&x);
                     }
                 }
-                AsmCommand::Push(num) => {
-                    // TODO: push is hard.. as a first pass we're unconditionally
-                    // ensuring a white intro, but we could try being more
-                    // clever here.
-                    let num = num.to_usize().expect("larger constants are unsupported");
-                    let sans_dangle = num - 1;
-                    let width = sans_dangle / ROW_FILL_HEIGHT;
-                    let extra = sans_dangle % ROW_FILL_HEIGHT;
-
-                    let has_color = buffer.last_color.is_some();
-                    let (mut edit, _) = buffer.allocate(width + 5)?;
+            }
+            AsmCommand::JumpIf(label) => {
+                // connecting to an existing label
+                if let Some(&CodelCoord { x: dest, y: y0 }) = labels.get(&label) {
+                    buffer.advance_to(dest - 1)?;
+                    let a = CONTROL_COLOR;
+                    let b = a.next_for_command(Command::Pointer);
+                    draw_here!(buffer, b"
+                         .
+                       >..ab>
+                        #...
+                           #
+                    ", a, b)?;
+                    buffer.draw_jump(dest, y0, buffer.y + 1)?;
+                }
+                // connecting to an existing jump
+                else if let Some(CodelCoord { x: dest, y: y0 }) = unmatched_jumps.remove(&label) {
+                    buffer.advance_to(dest - 2)?;
+                    let a = CONTROL_COLOR;
+                    let b = a.next_for_command(Command::Pointer);
+                    draw_here!(buffer, b"
+                          .
+                       >.abb>
+                          b
+                    ", a, b)?;
+                    buffer.draw_jump(dest, y0, buffer.y + 1)?;
+                    unmatched_jumps.insert(label, CodelCoord::new(dest, buffer.y + 3));
+                }
+                // first of their name
+                else {
+                    // TODO: there's gotta be a nicer api with `draw_command`
+                    buffer.allocate_lane(4, |buffer| match buffer.last_color {
+                        Some(Color::White) | None => 1,
+                        Some(_) => 0,
+                    })?;
                     let mut x = 0;
-                    if has_color {
-                        edit.draw_pixel(0, 1, Color::White)?;
-                        x = 1;
-                    }
-                    edit.draw_rect(x, 1, width, ROW_FILL_HEIGHT, CONTROL_COLOR)?;
-                    x += width;
-                    if extra > 0 {
-                        edit.draw_rect(x, 1, 1, extra, CONTROL_COLOR)?;
-                        x += 1;
-                    }
-                    edit.draw_pixel(x, 1, CONTROL_COLOR)?;
-                    let color = CONTROL_COLOR.next_for_command(Command::Push);
+                    let (mut edit, last_color) = buffer.allocate(4)?;
+                    let color = match last_color {
+                        Some(Color::White) | None => {
+                            edit.draw_pixel(0, 1, CONTROL_COLOR)?;
+                            x += 1;
+                            CONTROL_COLOR
+                        }
+                        Some(color) => color,
+                    };
+                    let color = color.next_for_command(Command::Pointer);
+                    edit.draw_pixel(x, 1, color)?;
+                    edit.draw_pixel(x, 2, color)?;
                     edit.draw_pixel(x + 1, 1, color)?;
                     mem::drop(edit);
+                    buffer.jump_xs.insert(buffer.x + x);
+                    let key = CodelCoord::new(buffer.x + x, buffer.y + 3);
+                    unmatched_jumps.insert(label, key);
                     buffer.x += x + 2;
                     buffer.last_color = Some(color);
                 }
-                AsmCommand::Pop | AsmCommand::Add | AsmCommand::Subtract | AsmCommand::Multiply |
-                AsmCommand::Divide | AsmCommand::Mod | AsmCommand::Not | AsmCommand::Greater |
-                AsmCommand::Duplicate | AsmCommand::Roll | AsmCommand::InNum | AsmCommand::InChar |
-                AsmCommand::OutNum | AsmCommand::OutChar => {
-                    let cmd: Command = cmd.try_into().unwrap();
-                    buffer.draw_command(cmd)?;
+                match jump_counts[label].checked_sub(1) {
+                    Some(num) => { jump_counts[label] = num; }
+                    None => {
+                        let x = buffer.x - 3;
+                        buffer.jump_xs.remove(&x);
+                    }
                 }
-                AsmCommand::Stop => {
-                    draw!(buffer, b"
-                          #
-                       >..a#
-                        #aa#
-                         ##
-                    ", CONTROL_COLOR)?;
+            }
+            AsmCommand::Push(num) => {
+                // TODO: push is hard.. as a first pass we're unconditionally
+                // ensuring a white intro, but we could try being more
+                // clever here.
+                let num = num.to_usize().expect("larger constants are unsupported");
+                let sans_dangle = num - 1;
+                let width = sans_dangle / ROW_FILL_HEIGHT;
+                let extra = sans_dangle % ROW_FILL_HEIGHT;
+
+                let has_color = buffer.last_color.is_some();
+                let (mut edit, _) = buffer.allocate(width + 5)?;
+                let mut x = 0;
+                if has_color {
+                    edit.draw_pixel(0, 1, Color::White)?;
+                    x = 1;
+                }
+                edit.draw_rect(x, 1, width, ROW_FILL_HEIGHT, CONTROL_COLOR)?;
+                x += width;
+                if extra > 0 {
+                    edit.draw_rect(x, 1, 1, extra, CONTROL_COLOR)?;
+                    x += 1;
                 }
+                edit.draw_pixel(x, 1, CONTROL_COLOR)?;
+                let color = CONTROL_COLOR.next_for_command(Command::Push);
+                edit.draw_pixel(x + 1, 1, color)?;
+                mem::drop(edit);
+                buffer.x += x + 2;
+                buffer.last_color = Some(color);
+            }
+            AsmCommand::Pop | AsmCommand::Add | AsmCommand::Subtract | AsmCommand::Multiply |
+            AsmCommand::Divide | AsmCommand::Mod | AsmCommand::Not | AsmCommand::Greater |
+            AsmCommand::Duplicate | AsmCommand::Roll | AsmCommand::InNum | AsmCommand::InChar |
+            AsmCommand::OutNum | AsmCommand::OutChar => {
+                let cmd: Command = cmd.try_into().unwrap();
+                buffer.draw_command(cmd)?;
+            }
+            AsmCommand::Hint(LayoutHint::Row) => {
+                if !buffer.at_row_start() {
+                    // Step over any jump lanes before turning the corner.
+                    let (edit, _) = buffer.allocate(1)?;
+                    mem::drop(edit);
+                    buffer.newline()?;
+                }
+            }
+            AsmCommand::Hint(LayoutHint::Column(column)) => {
+                *pinned_column = Some(column);
+            }
+            // Groups are handled by `draw_group`, and nesting them means nothing.
+            AsmCommand::Hint(LayoutHint::GroupStart | LayoutHint::GroupEnd) => {}
+            AsmCommand::Raw(block) => {
+                buffer.draw_raw(&block)?;
+            }
+            AsmCommand::Stop => {
+                draw!(buffer, b"
+                      #
+                   >..a#
+                    #aa#
+                     ##
+                ", CONTROL_COLOR)?;
             }
         }
         Ok(())
-    })();
-    match res {
-        Ok(_) => (),
-        Err(e) => {
-            error!("error: {e:?}");
+    }
+}
+
+/// Options controlling the shape of generated images.
+#[derive(Clone, Debug)]
+pub struct GeneratorOptions {
+    width: usize,
+    target_aspect: Option<(u32, u32)>,
+    progress: Option<Progress>,
+    pub(super) opt_level: u8,
+    pub(super) strict_mnemonics: bool,
+    pub(super) checked_branches: bool,
+    profile: Option<Vec<CodelCoord>>,
+    fewer_steps: bool,
+}
+
+impl Default for GeneratorOptions {
+    fn default() -> Self {
+        GeneratorOptions {
+            width: WIDTH,
+            target_aspect: None,
+            progress: None,
+            opt_level: MAX_OPT_LEVEL,
+            strict_mnemonics: false,
+            checked_branches: false,
+            profile: None,
+            fewer_steps: false,
+        }
+    }
+}
+
+impl GeneratorOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Lay out rows `width` codels wide.
+    pub fn width(mut self, width: usize) -> Self {
+        self.width = width;
+        self
+    }
+
+    /// Pick the row width whose image comes closest to a `width`:`height`
+    /// aspect ratio. Overrides `width`.
+    pub fn target_aspect(mut self, width: u32, height: u32) -> Self {
+        self.target_aspect = Some((width, height));
+        self
+    }
+
+    /// Report the number of commands laid out so far, after each one.
+    /// When searching for an aspect ratio the count restarts for each width tried.
+    pub fn progress(mut self, progress: Progress) -> Self {
+        self.progress = Some(progress);
+        self
+    }
+
+    /// Optimize the program at this level before laying it out,
+    /// from 0 (no optimization) up to `MAX_OPT_LEVEL`, the default.
+    pub fn opt_level(mut self, level: u8) -> Self {
+        self.opt_level = level.min(MAX_OPT_LEVEL);
+        self
+    }
+
+    /// Only accept mnemonics spelled exactly as PietASM has them, rather
+    /// than also in lowercase, in long form, or split up with dots.
+    pub fn strict_mnemonics(mut self) -> Self {
+        self.strict_mnemonics = true;
+        self
+    }
+
+    /// Refuse to build a program with a `JUMPIF` that might find the stack
+    /// empty, rather than lay out a branch that would be skipped as it ran,
+    /// falling through whatever the program meant.
+    pub fn checked_branches(mut self) -> Self {
+        self.checked_branches = true;
+        self
+    }
+
+    /// Lay out the program to run faster the way it ran before: `positions`
+    /// are where a run of it went, step by step, when built with the same
+    /// options but no profile, eg as read with `Trace::positions_from_json`.
+    /// The loops it went round most are each kept to one row where they fit,
+    /// so they don't cross a row break every time round.
+    pub fn profile(mut self, positions: Vec<CodelCoord>) -> Self {
+        self.profile = Some(positions);
+        self
+    }
+
+    /// Lay out the program to run in fewer steps, without a profile: the
+    /// most deeply nested loops are each kept to one row where they fit,
+    /// so they don't slide through a row break's white plumbing every time
+    /// round. A `profile` overrides this, as it knows which loops are hot.
+    pub fn fewer_steps(mut self) -> Self {
+        self.fewer_steps = true;
+        self
+    }
+}
+
+/// Statistics about a generated image.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct GenerateReport {
+    /// Image width, in codels.
+    pub width: usize,
+    /// Image height, in codels.
+    pub height: usize,
+    /// Commands laid out, after optimization.
+    pub commands: usize,
+    /// Rows of `ROW_HEIGHT` codels laid out.
+    pub rows: usize,
+    /// Vertical lanes drawn between jumps and their labels.
+    pub jumps: usize,
+    /// The tallest of those lanes, in codels.
+    pub longest_jump: usize,
+    /// Where the cursor was left after laying out each command.
+    pub placements: Vec<(CodelCoord, String)>,
+    /// Loops kept to one row each, as the profile (or `fewer_steps`) found them hot.
+    pub hot_loops: usize,
+    /// Where execution arrives at each label.
+    labels: HashMap<LabelId, CodelCoord>,
+}
+
+impl GenerateReport {
+    /// A textual description of the layout, to pin it in a golden file.
+    pub fn plan(&self, code: &PietCode) -> String {
+        let mut sink = PlanSink::new();
+        sink::emit_placements(self, &mut sink);
+        let mut plan = sink.finish();
+        // Last, so a changed layout is reported by the first command it moved.
+        writeln!(plan, "fingerprint {:016x}", code.fingerprint()).unwrap();
+        plan
+    }
+}
+
+/// How many codels of a row `cmd` takes up, following another command, or
+/// None if that depends on where it's laid out, as for labels and jumps,
+/// which route through lanes wherever they're needed, and new rows.
+pub(super) fn layout_width(cmd: &AsmCommand) -> Option<usize> {
+    Some(match cmd {
+        AsmCommand::Push(num) => {
+            // A white codel, the block to push, and the codel pushing it.
+            let sans_dangle = num.to_usize()?.checked_sub(1)?;
+            3 + sans_dangle.div_ceil(ROW_FILL_HEIGHT)
+        }
+        AsmCommand::Pop | AsmCommand::Add | AsmCommand::Subtract | AsmCommand::Multiply |
+        AsmCommand::Divide | AsmCommand::Mod | AsmCommand::Not | AsmCommand::Greater |
+        AsmCommand::Duplicate | AsmCommand::Roll | AsmCommand::InNum | AsmCommand::InChar |
+        AsmCommand::OutNum | AsmCommand::OutChar => 1,
+        AsmCommand::Raw(block) => raw_width(block)?,
+        AsmCommand::Stop => 4,
+        AsmCommand::Hint(LayoutHint::Column(_) | LayoutHint::GroupStart | LayoutHint::GroupEnd) => 0,
+        AsmCommand::Label(_) | AsmCommand::Jump(_) | AsmCommand::JumpIf(_) | AsmCommand::Hint(LayoutHint::Row) => {
+            return None;
+        }
+    })
+}
+
+/// The row of a layout row a raw block's top row is drawn on: with its entry
+/// row on the execution lane, if it fits there, else as near as it fits.
+/// None if the block is too tall to fit above the next row's newline plumbing.
+fn raw_top(block: &RawBlock) -> Option<usize> {
+    let max_top = (ROW_HEIGHT - 1).checked_sub(block.height)?;
+    Some(1usize.saturating_sub(block.entry).min(max_top))
+}
+
+/// How many codels of a row a raw block takes up: a white codel, then the
+/// block, with a step routing execution to its entry row and back from its
+/// exit row wherever those aren't on the execution lane.
+fn raw_width(block: &RawBlock) -> Option<usize> {
+    let top = raw_top(block)?;
+    let step = |row| if top + row == 1 { 0 } else { 3 };
+    Some(1 + step(block.entry) + block.width + block.exit.map_or(0, step))
+}
+
+/// Lay out `asm`, and hand the laid-out program to `sink`.
+pub(super) fn generate<S: CodeSink>(
+    asm: PietAsm,
+    options: &GeneratorOptions,
+    mut sink: S,
+) -> Result<(S::Output, GenerateReport), DrawError> {
+    let (buffer, report) = lay_out(asm, options)?;
+    sink::emit_placements(&report, &mut sink);
+    buffer.emit(&mut sink);
+    Ok((sink.finish(), report))
+}
+
+fn lay_out(
+    asm: PietAsm,
+    options: &GeneratorOptions,
+) -> Result<(PietCodeBuffer, GenerateReport), DrawError> {
+    let mut loops = if let Some(profile) = &options.profile {
+        let (_, report) = generate_unprofiled(asm.clone(), options)?;
+        let loops = loops(&asm).into_iter().filter_map(|(label, range)| {
+            let &CodelCoord { x, y } = report.labels.get(&label)?;
+            // Execution passes through the codels around the label every time round.
+            let hits = profile.iter()
+                .filter(|pos| pos.x.abs_diff(x) <= 2 && pos.y.abs_diff(y) <= 1)
+                .count();
+            (hits > 1).then_some((hits, range))
+        });
+        hottest(loops.collect())
+    } else if options.fewer_steps {
+        // Without a profile, guess that the most deeply nested loops run most,
+        // and the shortest of those, with the least work each time round, most of all.
+        let loops = loops(&asm);
+        let heat = |range: &Range<usize>| {
+            let depth = loops.iter()
+                .filter(|(_, outer)| outer.start <= range.start && range.end <= outer.end)
+                .count();
+            (depth, Reverse(range.len()))
+        };
+        hottest(loops.iter().map(|(_, range)| (heat(range), range.clone())).collect())
+    } else {
+        return generate_unprofiled(asm, options);
+    };
+    loop {
+        match generate_unprofiled(group_loops(&asm, &loops), options) {
+            Ok((buffer, report)) => { return Ok((buffer, GenerateReport { hot_loops: loops.len(), ..report })); }
+            // One of the loops doesn't fit on a row: give up on the coldest.
+            Err(_) if !loops.is_empty() => { loops.pop(); }
+            Err(e) => { return Err(e); }
+        }
+    }
+}
+
+/// Each loop in the program, as its label and the commands from the label up
+/// to the last jump back to it. Loops that already have layout hints of their
+/// own are left as they are.
+fn loops(asm: &PietAsm) -> Vec<(LabelId, Range<usize>)> {
+    let mut loops = Vec::new();
+    for (start, cmd) in asm.cmds.iter().enumerate() {
+        let &AsmCommand::Label(label) = cmd else { continue; };
+        let jumps_to = |cmd: &AsmCommand| matches!(cmd, AsmCommand::Jump(to) | AsmCommand::JumpIf(to) if *to == label);
+        let Some(end) = asm.cmds.iter().rposition(jumps_to).filter(|&end| end > start) else { continue; };
+        if !asm.cmds[start..end].iter().any(|cmd| matches!(cmd, AsmCommand::Hint(_))) {
+            loops.push((label, start..end));
+        }
+    }
+    loops
+}
+
+/// The loops to keep to a row each, hottest first by their `heat`,
+/// leaving out any that overlap a hotter one.
+fn hottest<H: Ord>(mut loops: Vec<(H, Range<usize>)>) -> Vec<Range<usize>> {
+    loops.sort_by(|(a, a_range), (b, b_range)| b.cmp(a).then(a_range.start.cmp(&b_range.start)));
+    let mut hottest: Vec<Range<usize>> = Vec::new();
+    for (_, range) in loops {
+        if hottest.iter().all(|hot| hot.end <= range.start || range.end <= hot.start) {
+            hottest.push(range);
+        }
+    }
+    hottest
+}
+
+/// Group each of `loops` so it's laid out on one row.
+fn group_loops(asm: &PietAsm, loops: &[Range<usize>]) -> PietAsm {
+    let mut asm = asm.clone();
+    let mut loops = loops.to_vec();
+    // From the back, so the ranges still to go stay put.
+    loops.sort_by_key(|range| Reverse(range.start));
+    for range in loops {
+        asm.insert(range.end, AsmCommand::Hint(LayoutHint::GroupEnd), "hot loops");
+        asm.insert(range.start, AsmCommand::Hint(LayoutHint::GroupStart), "hot loops");
+    }
+    asm
+}
+
+fn generate_unprofiled(
+    asm: PietAsm,
+    options: &GeneratorOptions,
+) -> Result<(PietCodeBuffer, GenerateReport), DrawError> {
+    let (target_w, target_h) = match options.target_aspect {
+        Some(aspect) => aspect,
+        None => { return generate_width(asm, options.width, options.progress.as_ref()); }
+    };
+    let target = (target_w as f64 / target_h as f64).ln();
+    let mut best: Option<(f64, (PietCodeBuffer, GenerateReport))> = None;
+    let mut last_err = None;
+    for width in (MIN_WIDTH..=MAX_WIDTH).step_by(WIDTH_STEP) {
+        let generated = match generate_width(asm.clone(), width, options.progress.as_ref()) {
+            Ok(generated) => generated,
+            // Too narrow to fit something, most likely.
+            Err(e) => {
+                last_err = Some(e);
+                continue;
+            }
+        };
+        let (code, _) = &generated;
+        let score = ((code.width as f64 / code.height as f64).ln() - target).abs();
+        let single_row = code.height <= ROW_HEIGHT;
+        if best.as_ref().is_none_or(|(best_score, _)| score < *best_score) {
+            best = Some((score, generated));
+        }
+        // Any wider just adds empty space.
+        if single_row { break; }
+    }
+    match (best, last_err) {
+        (Some((_, generated)), _) => Ok(generated),
+        (None, Some(e)) => Err(e),
+        (None, None) => unreachable!(),
+    }
+}
+
+fn generate_width(
+    asm: PietAsm,
+    width: usize,
+    progress: Option<&Progress>,
+) -> Result<(PietCodeBuffer, GenerateReport), DrawError> {
+    let PietAsm { cmds, jump_counts, .. } = asm;
+    let mut generator = Generator::new(jump_counts, width);
+    generator.draw_start()?;
+
+    let mut cmds = cmds.into_iter();
+    let mut done = 0;
+    while let Some(cmd) = cmds.next() {
+        match cmd {
+            AsmCommand::Hint(LayoutHint::GroupStart) => {
+                let mut depth = 0;
+                let group = cmds.by_ref()
+                    .take_while(|cmd| match cmd {
+                        AsmCommand::Hint(LayoutHint::GroupStart) => { depth += 1; true }
+                        AsmCommand::Hint(LayoutHint::GroupEnd) if depth == 0 => false,
+                        AsmCommand::Hint(LayoutHint::GroupEnd) => { depth -= 1; true }
+                        _ => true,
+                    })
+                    .collect::<Vec<_>>();
+                done += group.len();
+                generator.draw_group(group)?;
+            }
+            cmd => {
+                done += 1;
+                generator.draw_cmd(cmd)?;
+            }
+        }
+        if let Some(progress) = progress {
+            progress.report(done as u64);
+        }
+    }
+    let Generator { buffer, placements, labels, .. } = generator;
+    let report = GenerateReport {
+        width: buffer.width,
+        height: buffer.height,
+        commands: done,
+        rows: buffer.height / ROW_HEIGHT,
+        jumps: buffer.jumps_routed,
+        longest_jump: buffer.longest_jump,
+        placements,
+        hot_loops: 0,
+        labels,
+    };
+    Ok((buffer, report))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::PixelSink;
+    use assert_matches::assert_matches;
+    use std::fs;
+
+    fn hello_asm() -> PietAsm {
+        let cmds = (0..40)
+            .flat_map(|i| [AsmCommand::Push((65 + i % 26).into()), AsmCommand::OutChar])
+            .chain([AsmCommand::Stop])
+            .collect();
+        PietAsm::new(cmds, Vec::new())
+    }
+
+    #[test]
+    fn test_target_aspect() {
+        let options = GeneratorOptions::new().target_aspect(1, 1);
+        let square = generate(hello_asm(), &options, PixelSink::new()).unwrap().0;
+        let options = GeneratorOptions::new().target_aspect(1, 4);
+        let tall = generate(hello_asm(), &options, PixelSink::new()).unwrap().0;
+
+        assert!(tall.width < square.width);
+        let ratio = square.width as f64 / square.height as f64;
+        assert!((0.5..2.0).contains(&ratio), "{}x{}", square.width, square.height);
+    }
+
+    #[test]
+    fn test_raw_rows() {
+        // Entered below the execution lane, and left lower still, pushing 3 on the way.
+        let source = "\
+            @RAW 3 4\n\
+            \x20 x x  x\n\
+            \x20 x x  x\n\
+            > r x  x\n\
+            \x20 r r dr >\n\
+            @END\n\
+            OUTNUM\n\
+            @RAW 1 1\n\
+            > r >\n\
+            @END\n\
+            PUSH 4\n\
+            OUTNUM\n";
+        let code = crate::asm::load_str(source).unwrap();
+        assert_eq!(code.execute().capture_io("").run_collect().stdout_lossy(), "34");
+
+        let tall = "@RAW 1 10\n> r\n".to_string() + &"r\n".repeat(9) + "@END\n";
+        assert_eq!(crate::asm::load_str(&tall).unwrap_err(), "layout error: raw block doesn't fit in a row");
+    }
+
+    #[test]
+    fn test_fixed_width() {
+        let options = GeneratorOptions::new().width(200);
+        let code = generate(hello_asm(), &options, PixelSink::new()).unwrap().0;
+        assert_eq!(code.width, 200);
+    }
+
+    #[test]
+    fn test_progress() {
+        use std::sync::{Arc, Mutex};
+
+        let reports = Arc::new(Mutex::new(Vec::new()));
+        let progress = Progress::new({
+            let reports = reports.clone();
+            move |done| reports.lock().unwrap().push(done)
+        });
+        let options = GeneratorOptions::new().progress(progress);
+        generate(hello_asm(), &options, PixelSink::new()).unwrap();
+        let expected: Vec<_> = (1..=hello_asm().cmds.len() as u64).collect();
+        assert_eq!(*reports.lock().unwrap(), expected);
+    }
+
+    #[test]
+    fn test_report() {
+        let options = GeneratorOptions::new().width(200);
+        let (code, report) = generate(hello_asm(), &options, PixelSink::new()).unwrap();
+        assert_eq!(report, GenerateReport {
+            width: 200,
+            height: code.height,
+            commands: 81,
+            rows: code.height / ROW_HEIGHT,
+            jumps: 0,
+            longest_jump: 0,
+            placements: report.placements.clone(),
+            hot_loops: 0,
+            labels: HashMap::new(),
+        });
+        assert_eq!(report.placements.len(), 81);
+        assert!(report.plan(&code).starts_with("size 200x"));
+
+        // Jump back up a row
+        let mut asm = hello_asm();
+        asm.cmds.insert(0, AsmCommand::Label(0));
+        asm.cmds.pop();
+        asm.cmds.push(AsmCommand::Jump(0));
+        asm.jump_counts = vec![1];
+        let (_, report) = generate(asm, &options, PixelSink::new()).unwrap();
+        assert_eq!(report.jumps, 1);
+        assert!(report.rows > 1);
+        assert!(report.longest_jump > ROW_HEIGHT);
+    }
+
+    /// Assemble PietASM `source` as `pietasm build` does, up to layout.
+    fn assemble(source: &str) -> PietAsm {
+        let lines: Vec<_> = source.lines().map(String::from).collect();
+        crate::asm::to_asm(&lines, &GeneratorOptions::new()).unwrap()
+    }
+
+    /// How many steps `code` runs for on `input`, checking it outputs `output`.
+    fn steps(code: &PietCode, input: &str, output: &str) -> u64 {
+        let mut runner = code.execute().capture_io(input);
+        runner.run();
+        assert_eq!(runner.output(), Some(output.as_bytes()));
+        runner.steps()
+    }
+
+    /// Enough setup to push the loop across a row break.
+    fn straddling_loop() -> PietAsm {
+        let source = "PUSH 1\nPOP\n".repeat(32)
+            + "PUSH 200\n:LOOP\nPUSH 1\nSUB\nDUP\nPUSH 2\nMUL\nPOP\nDUP\nJUMPIF LOOP\nOUTNUM\n";
+        assemble(&source)
+    }
+
+    #[test]
+    fn test_column_unavailable() {
+        use AsmCommand::*;
+        for column in [0, 1, 1000] {
+            let cmds = vec![Hint(LayoutHint::Column(column)), Label(0), Push(1.into()), Jump(0)];
+            assert_matches!(
+                generate(PietAsm::new(cmds, vec![1]), &GeneratorOptions::new(), PixelSink::new()),
+                Err(DrawError::ColumnUnavailable(x)) if x == column
+            );
+        }
+    }
+
+    #[test]
+    fn test_layout_width() {
+        let cmds = vec![
+            AsmCommand::Push(1.into()), AsmCommand::Push(6.into()), AsmCommand::Push(7.into()),
+            AsmCommand::Push(23.into()), AsmCommand::Add, AsmCommand::Duplicate,
+            AsmCommand::Hint(LayoutHint::GroupStart), AsmCommand::OutNum,
+            AsmCommand::Hint(LayoutHint::GroupEnd), AsmCommand::Stop,
+        ];
+        let asm = PietAsm::new(cmds.clone(), Vec::new());
+        let (_, report) = generate(asm, &GeneratorOptions::new(), PixelSink::new()).unwrap();
+        let widths: Vec<_> = cmds.iter()
+            .filter(|cmd| !matches!(cmd, AsmCommand::Hint(_)))
+            .map(|cmd| layout_width(cmd).unwrap())
+            .collect();
+        let laid_out: Vec<_> = report.placements.windows(2)
+            .map(|pair| pair[1].0.x - pair[0].0.x)
+            .collect();
+        // The first command follows the start, not another command.
+        assert_eq!(laid_out, widths[1..]);
+        assert_eq!(layout_width(&AsmCommand::Label(0)), None);
+    }
+
+    #[test]
+    fn test_profile() {
+        let asm = straddling_loop();
+        let options = GeneratorOptions::new().width(100);
+        let (code, report) = generate(asm.clone(), &options, PixelSink::new()).unwrap();
+        assert_eq!(report.hot_loops, 0);
+        let mut trace = crate::Trace::new();
+        let unprofiled = {
+            let mut runner = code.execute().tracer(&mut trace).capture_io("");
+            runner.run();
+            runner.steps()
+        };
+        let positions = trace.steps.iter().map(|step| step.pos).collect();
+
+        let options = options.profile(positions);
+        let (code, report) = generate(asm, &options, PixelSink::new()).unwrap();
+        assert_eq!(report.hot_loops, 1);
+        let profiled = steps(&code, "", "0");
+        assert!(profiled < unprofiled, "{profiled} steps, from {unprofiled}");
+    }
+
+    #[test]
+    fn test_fewer_steps() {
+        // Before and after, on a loop across a row break, an inner loop across
+        // one, and a real program, whose inner loop is pinned by a jump into it.
+        let nested = assemble(&("PUSH 1\nPOP\n".repeat(36) + indoc::indoc!("
+            PUSH 20
+            :OUTER
+            PUSH 10
+            :INNER
+            SUB 1
+            DUP
+            JUMPIF INNER
+            POP
+            SUB 1
+            DUP
+            JUMPIF OUTER
+            OUTNUM
+        ")));
+        let aoc = fs::read_to_string("examples/piet_asm/aoc16-1.pasm").unwrap();
+        let benchmarks = [
+            (straddling_loop(), "", "0", true),
+            (nested, "", "0", true),
+            (assemble(&aoc), "A0016C880162017C3686B18A3D4780", "31", false),
+        ];
+        let options = GeneratorOptions::new().width(100);
+        for (asm, input, output, fewer_steps) in benchmarks {
+            let (code, _) = generate(asm.clone(), &options, PixelSink::new()).unwrap();
+            let (fewer, report) = generate(asm, &options.clone().fewer_steps(), PixelSink::new()).unwrap();
+            let (before, after) = (steps(&code, input, output), steps(&fewer, input, output));
+            match fewer_steps {
+                true => assert!(after < before, "{before} steps before, {after} after"),
+                false => assert_eq!(after, before),
+            }
+            assert_eq!(report.hot_loops, usize::from(fewer_steps));
         }
     }
-    buffer.into()
 }
@@ -1,13 +1,28 @@
-use crate::{Command, PietCode};
+// The assembler is a development tool, not part of loading or running programs,
+// so it keeps to asserting its own invariants.
+#![allow(clippy::panic, clippy::unwrap_used, clippy::expect_used, clippy::unreachable)]
+
+use crate::{Color, Command, InputSchema, PietCode, ProgramStats, RunLimits};
 use num_bigint::BigInt;
 use std::fmt;
 use std::fs::File;
 use std::io::{BufRead, BufReader};
+use std::ops::Range;
 
+mod analyzer;
 mod generator;
 mod optimizer;
 mod parser;
 mod preprocessor;
+mod sink;
+#[cfg(test)]
+mod verify;
+
+pub use generator::{GenerateReport, GeneratorOptions};
+pub use optimizer::MAX_OPT_LEVEL;
+pub use preprocessor::LANGUAGE_VERSION;
+pub(crate) use preprocessor::string_source;
+pub use sink::{AsciiSink, CodeSink, PixelSink, PlanSink, SvgSink};
 
 pub type LabelId = usize;
 
@@ -35,6 +50,61 @@ enum AsmCommand {
     Jump(LabelId),
     JumpIf(LabelId),
     Stop,
+    Raw(RawBlock),
+    Hint(LayoutHint),
+}
+
+/// Source-level control over where the generator places code.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+enum LayoutHint {
+    /// Start a new row.
+    Row,
+    /// Place the following label's jump lane at this column.
+    Column(usize),
+    /// Keep everything up to the matching `GroupEnd` on one row.
+    GroupStart,
+    GroupEnd,
+}
+
+/// A block of codels to be drawn verbatim, from a `@RAW` pragma.
+#[derive(Debug, PartialEq, Eq, Clone)]
+struct RawBlock {
+    width: usize,
+    height: usize,
+    /// The row execution enters on, from the left.
+    entry: usize,
+    /// The row execution leaves on, to the right, if it leaves at all.
+    exit: Option<usize>,
+    codels: Vec<Color>,
+}
+
+impl AsmCommand {
+    /// A one-line description of the command, for reports and dumps.
+    fn describe(&self) -> String {
+        match self {
+            AsmCommand::Raw(block) => format!("Raw({}x{})", block.width, block.height),
+            cmd => format!("{cmd:?}"),
+        }
+    }
+}
+
+impl RawBlock {
+    fn at(&self, x: usize, y: usize) -> Color {
+        self.codels[x + y * self.width]
+    }
+}
+
+/// A test case declared inline with a `@TEST` pragma.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct TestCase {
+    /// The line the pragma is on.
+    pub lineno: usize,
+    /// Fed to the program as input.
+    pub input: String,
+    /// The program's expected output, if checked.
+    pub expect: Option<String>,
+    /// The program's expected final stack, from bottom to top, if checked.
+    pub expect_stack: Option<Vec<BigInt>>,
 }
 
 impl TryFrom<AsmCommand> for Command {
@@ -62,10 +132,75 @@ impl TryFrom<AsmCommand> for Command {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct PietAsm {
     cmds: Vec<AsmCommand>,
     jump_counts: Vec<usize>,
+    /// Where each of `cmds` came from.
+    origins: Vec<Origin>,
+}
+
+impl PietAsm {
+    /// Commands of unknown origin.
+    #[cfg(test)]
+    fn new(cmds: Vec<AsmCommand>, jump_counts: Vec<usize>) -> Self {
+        let origins = vec![Origin::default(); cmds.len()];
+        PietAsm { cmds, jump_counts, origins }
+    }
+
+    fn push(&mut self, cmd: AsmCommand, pass: &'static str) {
+        self.cmds.push(cmd);
+        self.origins.push(Origin { lineno: None, pass: Some(pass) });
+    }
+
+    /// Insert `cmd` before the command at `idx`, attributing it to that command's line.
+    fn insert(&mut self, idx: usize, cmd: AsmCommand, pass: &'static str) {
+        let lineno = self.origins.get(idx).and_then(|origin| origin.lineno);
+        self.cmds.insert(idx, cmd);
+        self.origins.insert(idx, Origin { lineno, pass: Some(pass) });
+    }
+
+    fn remove(&mut self, idx: usize) {
+        self.cmds.remove(idx);
+        self.origins.remove(idx);
+    }
+
+    /// Move the commands in `range` from `mid` on to the start of the range,
+    /// keeping their lines, and attributing the moved commands to `pass`.
+    fn rotate(&mut self, range: Range<usize>, mid: usize, pass: &'static str) {
+        self.cmds[range.clone()].rotate_left(mid - range.start);
+        self.origins[range.clone()].rotate_left(mid - range.start);
+        for origin in &mut self.origins[range] {
+            origin.pass = Some(pass);
+        }
+    }
+
+    /// Replace the non-empty `range` of commands with `cmds`,
+    /// attributing them to the first replaced command's line and to `pass`.
+    fn splice(&mut self, range: Range<usize>, cmds: Vec<AsmCommand>, pass: &'static str) {
+        let origin = Origin { lineno: self.origins[range.start].lineno, pass: Some(pass) };
+        self.origins.splice(range.clone(), std::iter::repeat_n(origin, cmds.len()));
+        self.cmds.splice(range, cmds);
+    }
+}
+
+/// Where a command came from: the source line that produced it,
+/// and the last optimizer pass to rewrite it.
+#[derive(Debug, Default, PartialEq, Eq, Clone, Copy)]
+struct Origin {
+    lineno: Option<usize>,
+    pass: Option<&'static str>,
+}
+
+impl fmt::Display for Origin {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (self.lineno, self.pass) {
+            (Some(lineno), None) => write!(f, "line {lineno}"),
+            (Some(lineno), Some(pass)) => write!(f, "line {lineno}, via {pass}"),
+            (None, Some(pass)) => write!(f, "via {pass}"),
+            (None, None) => write!(f, "unknown"),
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -87,10 +222,21 @@ enum ParseErrorType {
     UnrecognizedCommand(String),
     WrongArgumentCount(usize, usize, Option<usize>),
     ExpectedInteger(String),
+    InvalidString(String),
     MissingLabel(String),
     DuplicateLabel(String),
+    DuplicateCase(BigInt),
+    DuplicateDefault,
+    CaseAfterDefault,
+    CaseOutsideSwitch,
+    ExpectedLabel,
     UnboundVarError(String),
     InvalidPragma(String),
+    UnsupportedVersion(u32),
+    MisplacedVersion,
+    InvalidCodel(String),
+    InvalidRawBlock(&'static str),
+    EmptyBranch,
     MissingEnd,
     ExtraEnd,
     TypeError, // TODO: any metadata.
@@ -120,10 +266,24 @@ impl fmt::Display for ParseErrorType {
                 write!(f, "expected between {min} and {max} arguments, but found {count}")
             }
             ExpectedInteger(code) => write!(f, "invalid integer literal '{code}'"),
+            InvalidString(code) => write!(f, "invalid string literal '{code}'"),
             MissingLabel(label) => write!(f, "missing label '{label}'"),
             DuplicateLabel(label) => write!(f, "duplicate label '{label}'"),
+            DuplicateCase(value) => write!(f, "duplicate case '{value}'"),
+            DuplicateDefault => write!(f, "duplicate default case"),
+            CaseAfterDefault => write!(f, "case after default case"),
+            CaseOutsideSwitch => write!(f, "case outside of switch"),
+            ExpectedLabel => write!(f, "expected a label"),
             UnboundVarError(var) => write!(f, "unbound var '{var}'"),
             InvalidPragma(line) => write!(f, "invalid pragma: '{line}'"),
+            UnsupportedVersion(version) => write!(
+                f,
+                "PietASM version {version} isn't supported; this assembler reads versions 1 to {LANGUAGE_VERSION}",
+            ),
+            MisplacedVersion => write!(f, "@VERSION must come before anything else"),
+            InvalidCodel(codel) => write!(f, "invalid codel '{codel}'"),
+            InvalidRawBlock(reason) => write!(f, "invalid raw block: {reason}"),
+            EmptyBranch => write!(f, "JUMPIF may find the stack empty, and fall through"),
             MissingEnd => write!(f, "unclosed delimiter"),
             ExtraEnd => write!(f, "unexpected closing delimiter"),
             TypeError => write!(f, "type error"),
@@ -131,19 +291,232 @@ impl fmt::Display for ParseErrorType {
     }
 }
 
-fn parse(lines: &[String]) -> Result<PietCode, ParseError> {
+#[derive(Debug)]
+enum BuildError {
+    Parse(ParseError),
+    Layout(generator::DrawError),
+}
+
+impl fmt::Display for BuildError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BuildError::Parse(e) => write!(f, "{e}"),
+            BuildError::Layout(e) => write!(f, "layout error: {e}"),
+        }
+    }
+}
+
+impl From<ParseError> for BuildError {
+    fn from(e: ParseError) -> Self {
+        BuildError::Parse(e)
+    }
+}
+
+impl From<generator::DrawError> for BuildError {
+    fn from(e: generator::DrawError) -> Self {
+        BuildError::Layout(e)
+    }
+}
+
+fn to_asm(lines: &[String], options: &GeneratorOptions) -> Result<PietAsm, ParseError> {
     let ast = preprocessor::preprocess(lines)?;
-    let asm = parser::to_bytecode(ast)?;
-    let asm = optimizer::optimize(asm);
-    let asm = optimizer::sanitize(asm);
-    let img = generator::generate(asm);
-    Ok(img)
+    let asm = parser::to_bytecode(ast, options.strict_mnemonics)?;
+    if options.checked_branches {
+        analyzer::check_branches(&asm)?;
+    }
+    let asm = optimizer::optimize(asm, options.opt_level);
+    Ok(optimizer::sanitize(asm))
+}
+
+fn parse<S: CodeSink>(
+    lines: &[String],
+    options: &GeneratorOptions,
+    sink: S,
+) -> Result<(S::Output, GenerateReport), BuildError> {
+    let asm = to_asm(lines, options)?;
+    Ok(generator::generate(asm, options, sink)?)
 }
 
 pub fn load(filename: &str) -> Result<PietCode, String> {
+    load_with_options(filename, &GeneratorOptions::default())
+}
+
+/// Like `load`, but assembles PietASM `source` rather than a file.
+pub fn load_str(source: &str) -> Result<PietCode, String> {
+    let lines: Vec<String> = source.lines().map(String::from).collect();
+    let (code, _) = parse(&lines, &GeneratorOptions::default(), PixelSink::new()).map_err(|e| e.to_string())?;
+    Ok(code)
+}
+
+pub fn load_with_options(filename: &str, options: &GeneratorOptions) -> Result<PietCode, String> {
+    let (code, _) = load_with_report(filename, options)?;
+    Ok(code)
+}
+
+/// Like `load_with_options`, but also reports on the layout of the generated image.
+pub fn load_with_report(
+    filename: &str,
+    options: &GeneratorOptions,
+) -> Result<(PietCode, GenerateReport), String> {
+    build(filename, options, PixelSink::new())
+}
+
+/// Like `load_with_report`, but hands the laid-out program to `sink`, eg
+/// to draw it as an SVG, rather than making a `PietCode` of it.
+pub fn build<S: CodeSink>(
+    filename: &str,
+    options: &GeneratorOptions,
+    sink: S,
+) -> Result<(S::Output, GenerateReport), String> {
+    let lines = read_lines(filename)?;
+    parse(&lines, options, sink).map_err(|e| e.to_string())
+}
+
+/// List the commands a PietASM file compiles to, just before layout.
+/// With `annotate`, each is followed by the source line it came from,
+/// and the optimizer pass that last rewrote it.
+pub fn dump_ir(
+    filename: &str,
+    options: &GeneratorOptions,
+    annotate: bool,
+) -> Result<String, String> {
+    let lines = read_lines(filename)?;
+    let asm = to_asm(&lines, options).map_err(|e| e.to_string())?;
+    let mut dump = String::new();
+    for (cmd, origin) in asm.cmds.iter().zip(&asm.origins) {
+        let line = match annotate {
+            true => format!("{:<24}  # {origin}\n", cmd.describe()),
+            false => format!("{}\n", cmd.describe()),
+        };
+        dump.push_str(&line);
+    }
+    Ok(dump)
+}
+
+/// The commands a PietASM file compiles to, just before layout, eg to
+/// measure with `stats`.
+pub fn load_ir(filename: &str, options: &GeneratorOptions) -> Result<PietAsm, String> {
+    let lines = read_lines(filename)?;
+    to_asm(&lines, options).map_err(|e| e.to_string())
+}
+
+/// Measure compiled commands, as `PietCode::stats` measures an image.
+/// Branches are conditional jumps, and loops are jumps back to an earlier
+/// label. There's no image to measure yet, so `region_sizes` is empty.
+pub fn stats(asm: &PietAsm) -> ProgramStats {
+    let mut stats = ProgramStats::default();
+    let labels: Vec<_> = asm.cmds.iter().enumerate()
+        .filter_map(|(idx, cmd)| match cmd {
+            AsmCommand::Label(id) => Some((*id, idx)),
+            _ => None,
+        })
+        .collect();
+    for (idx, cmd) in asm.cmds.iter().enumerate() {
+        let target = match cmd {
+            AsmCommand::Jump(id) => id,
+            AsmCommand::JumpIf(id) => {
+                stats.branches += 1;
+                id
+            }
+            cmd => {
+                if let Ok(command) = Command::try_from(cmd.clone()) {
+                    stats.add(command);
+                }
+                continue;
+            }
+        };
+        if labels.iter().any(|&(id, label)| id == *target && label < idx) {
+            stats.loops += 1;
+        }
+    }
+    stats
+}
+
+/// How many codels of a row pushing `num` takes up, once it's rewritten
+/// into pushes the generator can draw, eg to choose the cheaper of two
+/// ways to build a value. None if it's too big to draw.
+pub fn push_width(num: &BigInt) -> Option<usize> {
+    optimizer::cost(&AsmCommand::Push(num.clone()))
+}
+
+/// How many codels of a row each compiled command takes up, described as
+/// `dump_ir` describes it, as the generator lays it out following another
+/// command. Labels, jumps, and row breaks are None, since they take up
+/// however much routing to their lanes takes wherever they land.
+pub fn widths(asm: &PietAsm) -> Vec<(String, Option<usize>)> {
+    asm.cmds.iter().map(|cmd| (cmd.describe(), optimizer::cost(cmd))).collect()
+}
+
+/// A line of PietASM once its pragmas are expanded. See `preprocess_str`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ExpandedLine {
+    /// The line of the source it came from, counting from 1.
+    pub lineno: usize,
+    pub text: String,
+}
+
+/// Expand the pragmas of PietASM `source`, eg to template programs with
+/// `@EACH` without assembling them: each `@EACH` block is repeated with its
+/// metavariable bound, and comments, blank lines, and `@VERSION` are
+/// dropped. The lines read as the same program, and each says which line
+/// of `source` it came from.
+pub fn preprocess_str(source: &str) -> Result<Vec<ExpandedLine>, String> {
+    let lines: Vec<String> = source.lines().map(String::from).collect();
+    let ast = preprocessor::preprocess(&lines).map_err(|e| e.to_string())?;
+    let expanded = ast.iter()
+        .flat_map(|line| {
+            line.stmt.to_source().into_iter().map(|text| ExpandedLine { lineno: line.lineno, text })
+        })
+        .collect();
+    Ok(expanded)
+}
+
+/// Collect the test cases declared in a PietASM file.
+pub fn load_tests(filename: &str) -> Result<Vec<TestCase>, String> {
+    let lines = read_lines(filename)?;
+    let ast = preprocessor::preprocess(&lines).map_err(|e| e.to_string())?;
+    let tests = ast.into_iter()
+        .filter_map(|line| match line.stmt {
+            preprocessor::Statement::Test(case) => Some(case),
+            _ => None,
+        })
+        .collect();
+    Ok(tests)
+}
+
+/// The recommended run limits declared in a PietASM file with `@LIMIT`, if any.
+pub fn load_limits(filename: &str) -> Result<Option<RunLimits>, String> {
+    let lines = read_lines(filename)?;
+    let ast = preprocessor::preprocess(&lines).map_err(|e| e.to_string())?;
+    let mut limits = None;
+    for line in ast {
+        if let preprocessor::Statement::Limit(limit) = line.stmt {
+            if limits.replace(limit).is_some() {
+                return Err(format!("error at {}: only one @LIMIT is allowed", line.lineno));
+            }
+        }
+    }
+    Ok(limits)
+}
+
+/// The input a PietASM file declares its program reads with `@INPUT`, if any.
+pub fn load_input_schema(filename: &str) -> Result<Option<InputSchema>, String> {
+    let lines = read_lines(filename)?;
+    let ast = preprocessor::preprocess(&lines).map_err(|e| e.to_string())?;
+    let mut schema = None;
+    for line in ast {
+        if let preprocessor::Statement::Input(input) = line.stmt {
+            if schema.replace(input).is_some() {
+                return Err(format!("error at {}: only one @INPUT is allowed", line.lineno));
+            }
+        }
+    }
+    Ok(schema)
+}
+
+fn read_lines(filename: &str) -> Result<Vec<String>, String> {
     let file = File::open(filename).map_err(|e| e.to_string())?;
     let reader = BufReader::new(file);
     let lines: Result<Vec<_>, _> = reader.lines().collect();
-    let lines = lines.map_err(|e| e.to_string())?;
-    parse(&lines).map_err(|e| e.to_string())
+    lines.map_err(|e| e.to_string())
 }
@@ -1,6 +1,9 @@
-use crate::asm::{AsmCommand, PietAsm};
+use crate::asm::analyzer::stack_effect;
+use crate::asm::generator::layout_width;
+use crate::asm::{AsmCommand, LayoutHint, PietAsm};
 use num_bigint::BigInt;
-use num_traits::{ToPrimitive, One, Zero};
+use num_integer::Integer;
+use num_traits::{ToPrimitive, One, Signed, Zero};
 
 fn push(val: i32) -> AsmCommand {
     AsmCommand::Push(val.into())
@@ -8,29 +11,109 @@ fn push(val: i32) -> AsmCommand {
 
 const BIG_NUMBER: u32 = 100;
 
-pub(super) fn optimize(mut asm: PietAsm) -> PietAsm {
+/// The longest expression `schedule` will move, in commands.
+const MAX_EXPRESSION: usize = 16;
+
+/// The highest optimization level, and the default.
+/// Level 1 removes redundant jumps and labels, level 2 also rewrites
+/// commands into cheaper equivalents, and level 3 also reorders
+/// commands so more of them can be rewritten, and reuses constants.
+pub const MAX_OPT_LEVEL: u8 = 3;
+
+pub(super) fn optimize(mut asm: PietAsm, level: u8) -> PietAsm {
+    #[cfg(test)]
+    let original = asm.clone();
+
+    if level >= 1 {
+        asm = remove_dead_jumps(asm);
+    }
+    if level >= 3 {
+        asm = schedule(asm);
+    }
+    if level >= 2 {
+        asm = peephole(asm);
+    }
+    if level >= 3 {
+        asm = reuse_constants(asm);
+    }
+
+    #[cfg(test)]
+    crate::asm::verify::verify(&original, &asm);
+    asm
+}
+
+fn remove_dead_jumps(mut asm: PietAsm) -> PietAsm {
     use AsmCommand::*;
 
-    // Remove labels with no jumps
-    asm.cmds.retain(|cmd| {
-        !matches!(cmd, AsmCommand::Label(id)
-            if asm.jump_counts[*id] == 0
-        )
-    });
+    // Each removal can make more code unreachable, or more labels unused.
+    loop {
+        let len = asm.cmds.len();
+        // Drop code execution can't reach, between a jump or stop and the next
+        // label, eg a `SWITCH` case's own jump to its end after one to elsewhere.
+        // The generator can only lay out code it has a path into, and this may
+        // leave a jump just before its label, for below.
+        let mut reachable = true;
+        let mut idx = 0;
+        while let Some(cmd) = asm.cmds.get(idx) {
+            match cmd {
+                Label(_) => { reachable = true; }
+                Hint(_) => (),
+                _ if !reachable => {
+                    if let Jump(id) | JumpIf(id) = *cmd {
+                        asm.jump_counts[id] -= 1;
+                    }
+                    asm.remove(idx);
+                    continue;
+                }
+                Jump(_) | Stop => { reachable = false; }
+                _ => (),
+            }
+            idx += 1;
+        }
 
-    // Jumps immediately preceding their label
-    while let Some((idx, id)) = asm.cmds
-            .windows(2)
-            .enumerate()
-            .filter_map(|(i, w)| match w {
-                [Jump(a), Label(b)] if a == b => Some((i, *a)),
-                _ => None,
-            })
-            .next() {
-        asm.cmds.remove(idx);
-        asm.jump_counts[id] -= 1;
+        // Jumps immediately preceding their label
+        while let Some((idx, id)) = asm.cmds
+                .windows(2)
+                .enumerate()
+                .filter_map(|(i, w)| match w {
+                    [Jump(a), Label(b)] if a == b => Some((i, *a)),
+                    _ => None,
+                })
+                .next() {
+            asm.remove(idx);
+            asm.jump_counts[id] -= 1;
+        }
+
+        // Remove labels with no jumps
+        while let Some(idx) = asm.cmds
+            .iter()
+            .position(|cmd| matches!(cmd, Label(id) if asm.jump_counts[*id] == 0))
+        {
+            asm.remove(idx);
+        }
+
+        if asm.cmds.len() == len {
+            break;
+        }
     }
 
+    // Remove column pins whose label went away
+    while let Some(idx) = asm.cmds
+        .iter()
+        .enumerate()
+        .position(|(i, cmd)| {
+            matches!(cmd, Hint(LayoutHint::Column(_)))
+                && !matches!(asm.cmds.get(i + 1), Some(Label(_)))
+        })
+    {
+        asm.remove(idx);
+    }
+    asm
+}
+
+fn peephole(mut asm: PietAsm) -> PietAsm {
+    use AsmCommand::*;
+
     // TODO: [dyad, POP] => [POP, POP]
     // let constant_patterns: [(Vec<AsmCommand>, Vec<AsmCommand>); _] = [
     let constant_patterns: [(Vec<AsmCommand>, Vec<AsmCommand>); 1] = [
@@ -48,7 +131,7 @@ pub(super) fn optimize(mut asm: PietAsm) -> PietAsm {
             .windows(2)
             .rposition(|w| matches!(w[0], Push(_)) && w[0] == w[1])
         {
-            asm.cmds[idx + 1] = Duplicate;
+            asm.splice(idx + 1..idx + 2, vec![Duplicate], "duplicate pushes");
             continue 'progress;
         }
 
@@ -59,7 +142,7 @@ pub(super) fn optimize(mut asm: PietAsm) -> PietAsm {
                 .windows(len)
                 .position(|w| w == needle.as_slice())
             {
-                asm.cmds.splice(idx..idx + len, replace_with.iter().cloned());
+                asm.splice(idx..idx + len, replace_with.clone(), "constant patterns");
                 continue 'progress;
             }
         }
@@ -72,19 +155,18 @@ pub(super) fn optimize(mut asm: PietAsm) -> PietAsm {
 pub(super) fn sanitize(mut asm: PietAsm) -> PietAsm {
     use AsmCommand::*;
 
+    #[cfg(test)]
+    let original = asm.clone();
+
     // Factor out negative constants
-    while let Some((idx, num)) = {
+    while let Some((idx, replace)) = {
         asm.cmds.iter().enumerate().filter_map(|(i, e)| match e {
-            Push(n) if n <= &BigInt::zero() => Some((i, n)),
+            Push(n) => factor_nonpositive_number(n).map(|v| (i, v)),
             _ => None,
         }).next()
     }
     {
-        let replace = match num.to_u32() {
-            Some(0) => vec![push(1), Not],
-            _ => vec![push(1), Push(num + BigInt::one()), Subtract],
-        };
-        asm.cmds.splice(idx..idx + 1, replace);
+        asm.splice(idx..idx + 1, replace, "negative constants");
     }
 
     // Factor out large constants
@@ -95,21 +177,33 @@ pub(super) fn sanitize(mut asm: PietAsm) -> PietAsm {
         }).next()
     }
     {
-        asm.cmds.splice(idx..idx + 1, replace);
+        asm.splice(idx..idx + 1, replace, "large constants");
     }
 
     // End on an "STOP"
     if !matches!(asm.cmds.last(), Some(Stop | Jump(_))) {
-        asm.cmds.push(Stop);
+        asm.push(Stop, "final stop");
     }
+
+    #[cfg(test)]
+    crate::asm::verify::verify(&original, &asm);
     asm
 }
 
+fn factor_nonpositive_number(num: &BigInt) -> Option<Vec<AsmCommand>> {
+    use AsmCommand::*;
+
+    (num <= &BigInt::zero()).then(|| match num.to_u32() {
+        Some(0) => vec![push(1), Not],
+        _ => vec![push(1), Push(BigInt::one() - num), Subtract],
+    })
+}
+
 // TODO: this is hard.
 fn factor_big_number(num: &BigInt) -> Option<Vec<AsmCommand>> {
     use AsmCommand::*;
 
-    num.to_u32().map_or(true, |n| n >= BIG_NUMBER).then(|| {
+    num.to_u32().is_none_or(|n| n >= BIG_NUMBER).then(|| {
         let sqrt = num.sqrt();
         let diff = num - (&sqrt * &sqrt);
         let mut result = vec![Push(sqrt), Duplicate, Multiply];
@@ -121,10 +215,138 @@ fn factor_big_number(num: &BigInt) -> Option<Vec<AsmCommand>> {
     })
 }
 
+/// How many values `cmd` pops and pushes, if it can't fail or have any other
+/// effect given that many values, so it can be moved among others like it.
+fn movable_effect(cmd: &AsmCommand) -> Option<(usize, usize)> {
+    use AsmCommand::*;
+    match cmd {
+        // Pushes of values that aren't positive are rewritten into several commands.
+        Push(n) if !n.is_positive() => None,
+        OutNum | Label(_) | Jump(_) | JumpIf(_) | Stop | Raw(_) | Hint(_) => None,
+        cmd => match stack_effect(cmd) {
+            (pops, pushes, false) => Some((pops, pushes)),
+            (_, _, true) => None,
+        },
+    }
+}
+
+/// The start of the expression ending just before `end`: the shortest run
+/// of commands that only uses values it pushes itself to leave one value.
+fn expression_start(cmds: &[AsmCommand], end: usize) -> Option<usize> {
+    let mut needed: usize = 1;
+    for start in (end.saturating_sub(MAX_EXPRESSION)..end).rev() {
+        let (pops, pushes) = movable_effect(&cmds[start])?;
+        // Leaving more values than are needed would use one from below.
+        needed = needed.checked_sub(pushes)? + pops;
+        if needed == 0 {
+            return Some(start);
+        }
+    }
+    None
+}
+
+/// Swap the operands of commutative commands where that puts a push next to
+/// the same push, so `peephole` can share the value with a `Duplicate`.
+fn schedule(mut asm: PietAsm) -> PietAsm {
+    use AsmCommand::*;
+
+    for op in 0..asm.cmds.len() {
+        if !matches!(asm.cmds[op], Add | Multiply) {
+            continue;
+        }
+        let Some(mid) = expression_start(&asm.cmds, op) else { continue; };
+        let Some(start) = expression_start(&asm.cmds, mid) else { continue; };
+        let Some(before) = start.checked_sub(1) else { continue; };
+        let cmds = &asm.cmds;
+        if matches!(cmds[before], Push(_)) && cmds[before] == cmds[mid] && cmds[before] != cmds[start] {
+            asm.rotate(start..op, mid, "scheduling");
+        }
+    }
+    asm
+}
+
+/// How many codels of a row `cmd` takes up, as `layout_width`, once
+/// `sanitize` has rewritten it into commands the generator can draw.
+pub(super) fn cost(cmd: &AsmCommand) -> Option<usize> {
+    match cmd {
+        AsmCommand::Push(n) => match factor_nonpositive_number(n).or_else(|| factor_big_number(n)) {
+            Some(cmds) => cmds.iter().map(cost).sum(),
+            None => layout_width(cmd),
+        },
+        cmd => layout_width(cmd),
+    }
+}
+
+/// The next push of the same constant as the push at `i`, and how many values
+/// would be above a copy of the constant left under the commands between.
+/// The commands between can only use the constant and what they push
+/// themselves, and mustn't be able to fail, so the copy stays where it was.
+fn next_push(cmds: &[AsmCommand], i: usize) -> Option<(usize, usize)> {
+    use AsmCommand::*;
+
+    // The values on the stack since the push, where they're known.
+    let mut stack: Vec<Option<BigInt>> = match &cmds[i] {
+        Push(n) => vec![Some(n.clone())],
+        _ => { return None; }
+    };
+    for (j, cmd) in cmds.iter().enumerate().skip(i + 1) {
+        if *cmd == cmds[i] {
+            return Some((j, stack.len()));
+        }
+        let pops = match cmd {
+            Push(_) => 0,
+            Pop | Not | Duplicate | OutNum | OutChar => 1,
+            Add | Subtract | Multiply | Divide | Mod | Greater | Roll => 2,
+            _ => { return None; }
+        };
+        let args = stack.split_off(stack.len().checked_sub(pops)?);
+        match (cmd, args.as_slice()) {
+            (Push(n), []) => { stack.push(Some(n.clone())); }
+            (Duplicate, [value]) => { stack.extend([value.clone(), value.clone()]); }
+            (Pop | OutNum, [_]) => (),
+            (OutChar, [Some(c)]) if c.to_u32().and_then(char::from_u32).is_some() => (),
+            (Not | Add | Subtract | Multiply | Greater, _) => { stack.push(None); }
+            (Divide | Mod, [_, Some(divisor)]) if !divisor.is_zero() => { stack.push(None); }
+            (Roll, [Some(depth), Some(rolls)]) if depth.is_positive() => {
+                let rolls = rolls.mod_floor(depth).to_usize()?;
+                let start = stack.len().checked_sub(depth.to_usize()?)?;
+                stack[start..].rotate_right(rolls);
+            }
+            _ => { return None; }
+        }
+    }
+    None
+}
+
+/// Where a constant's pushed again, keep a copy of it from the push before
+/// to `Roll` back up instead, if that's narrower to lay out.
+fn reuse_constants(mut asm: PietAsm) -> PietAsm {
+    use AsmCommand::*;
+
+    'progress: while {
+        for i in 0..asm.cmds.len() {
+            let Some((j, above)) = next_push(&asm.cmds, i) else { continue; };
+            let retrieve = match above {
+                0 => Vec::new(),
+                _ => vec![Push((above + 1).into()), Push(above.into()), Roll],
+            };
+            let reused = cost(&Duplicate).zip(retrieve.iter().map(cost).sum::<Option<usize>>());
+            if reused.is_some_and(|(dup, roll)| Some(dup + roll) < cost(&asm.cmds[j])) {
+                asm.splice(j..j + 1, retrieve, "reused constants");
+                asm.insert(i + 1, Duplicate, "reused constants");
+                continue 'progress;
+            }
+        }
+        false
+    } {}
+    asm
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
     use crate::asm::AsmCommand::*;
+    use crate::asm::Origin;
 
     fn to_piet_asm(cmds: Vec<AsmCommand>) -> PietAsm {
         let count = cmds.iter().filter(|c| matches!(c, Label(_))).count();
@@ -137,41 +359,161 @@ mod tests {
                 _ => (),
             }
         }
-        PietAsm { cmds, jump_counts }
+        PietAsm::new(cmds, jump_counts)
     }
 
     #[test]
     fn test_dup_pushes() {
         let asm = to_piet_asm(vec![push(5), push(2), push(2), push(2), push(8), push(8)]);
-        let PietAsm { cmds, .. } = optimize(asm);
+        let PietAsm { cmds, .. } = optimize(asm, MAX_OPT_LEVEL);
         assert_eq!(cmds, vec![push(5), push(2), Duplicate, Duplicate, push(8), Duplicate]);
     }
 
     #[test]
     fn test_stack_bump() {
         let asm = to_piet_asm(vec![push(1), Multiply]);
-        let PietAsm { cmds, .. } = optimize(asm);
+        let PietAsm { cmds, .. } = optimize(asm, MAX_OPT_LEVEL);
         assert_eq!(cmds, vec![push(1), Multiply]);
     }
 
+    #[test]
+    fn test_schedule() {
+        // 2 * (5 + 2) => 2 * (2 + 5), sharing the 2s
+        let asm = to_piet_asm(vec![push(2), push(5), push(2), Add, Multiply]);
+        let PietAsm { cmds, .. } = optimize(asm, MAX_OPT_LEVEL);
+        assert_eq!(cmds, vec![push(2), Duplicate, push(5), Add, Multiply]);
+
+        // Whole expressions move, not just pushes.
+        let asm = to_piet_asm(vec![push(3), push(4), push(5), Multiply, push(3), Duplicate, Multiply, Add]);
+        let PietAsm { cmds, .. } = optimize(asm, MAX_OPT_LEVEL);
+        assert_eq!(cmds, vec![push(3), Duplicate, Duplicate, Multiply, push(4), push(5), Multiply, Add]);
+
+        // Subtraction doesn't commute, and an expression can't use what's below it.
+        for cmds in [
+            vec![push(2), push(5), push(2), Subtract],
+            vec![push(2), push(5), Add, push(2), Multiply],
+            vec![push(2), InNum, push(2), Add],
+        ] {
+            let asm = to_piet_asm(cmds.clone());
+            let PietAsm { cmds: scheduled, .. } = optimize(asm, MAX_OPT_LEVEL);
+            assert_eq!(scheduled, cmds);
+        }
+        let cmds = vec![push(2), push(5), push(2), Add];
+        let PietAsm { cmds: unscheduled, .. } = optimize(to_piet_asm(cmds.clone()), 2);
+        assert_eq!(unscheduled, cmds);
+    }
+
+    #[test]
+    fn test_reuse_constants() {
+        // Newlines between lines: the 10 is kept under the line to output next.
+        let asm = to_piet_asm(vec![push(10), OutChar, push(72), OutChar, push(10), OutChar]);
+        let PietAsm { cmds, .. } = optimize(asm, MAX_OPT_LEVEL);
+        assert_eq!(cmds, vec![push(10), Duplicate, OutChar, push(72), OutChar, OutChar]);
+
+        // Rolled back up from under a value, where that's narrower than pushing it again.
+        let asm = to_piet_asm(vec![push(50), OutNum, push(7), push(50), Multiply, OutNum]);
+        let PietAsm { cmds, .. } = optimize(asm, MAX_OPT_LEVEL);
+        assert_eq!(cmds, vec![
+            push(50), Duplicate, OutNum, push(7), push(2), push(1), Roll, Multiply, OutNum,
+        ]);
+
+        for cmds in [
+            // Cheaper to push again
+            vec![push(3), OutNum, push(7), push(3), Add],
+            // Reading input can fail, leaving the copy somewhere else.
+            vec![push(10), InChar, push(10)],
+            // The commands between use what's under the constant.
+            vec![push(10), Pop, Pop, push(10)],
+            vec![push(10), OutChar, Label(0), push(10), Jump(0)],
+        ] {
+            let asm = to_piet_asm(cmds.clone());
+            let PietAsm { cmds: optimized, .. } = optimize(asm, MAX_OPT_LEVEL);
+            assert_eq!(optimized, cmds);
+        }
+    }
+
+    #[test]
+    fn test_cost() {
+        assert_eq!(crate::asm::push_width(&1.into()), Some(3));
+        assert_eq!(crate::asm::push_width(&7.into()), Some(5));
+        // Pushed as 1, then negated, and as a product.
+        assert_eq!(crate::asm::push_width(&0.into()), Some(3 + 1));
+        assert!(crate::asm::push_width(&1000.into()).is_some_and(|width| width < 3 + 999_usize.div_ceil(5)));
+
+        let asm = to_piet_asm(vec![push(10), Label(0), OutChar, Jump(0)]);
+        assert_eq!(crate::asm::widths(&asm), [
+            ("Push(10)".to_string(), Some(5)),
+            ("Label(0)".to_string(), None),
+            ("OutChar".to_string(), Some(1)),
+            ("Jump(0)".to_string(), None),
+        ]);
+    }
+
     #[test]
     fn test_rm_unused_labels() {
         let asm = to_piet_asm(vec![Label(0), push(1), Label(1), push(2), Label(2), Jump(1)]);
-        let PietAsm { cmds, .. } = optimize(asm);
+        let PietAsm { cmds, .. } = optimize(asm, MAX_OPT_LEVEL);
         assert_eq!(cmds, vec![push(1), Label(1), push(2), Jump(1)]);
     }
 
+    #[test]
+    fn test_rm_unused_label_pin() {
+        let pin = || Hint(LayoutHint::Column(20));
+        let asm = to_piet_asm(vec![pin(), Label(0), push(1), pin(), Label(1), Jump(1)]);
+        let PietAsm { cmds, .. } = optimize(asm, MAX_OPT_LEVEL);
+        assert_eq!(cmds, vec![push(1), pin(), Label(1), Jump(1)]);
+    }
+
     #[test]
     fn test_rm_unnecessary_jump() {
         let asm = to_piet_asm(vec![Jump(0), Label(0), Jump(0)]);
-        let PietAsm { cmds, .. } = optimize(asm);
+        let PietAsm { cmds, .. } = optimize(asm, MAX_OPT_LEVEL);
         assert_eq!(cmds, vec![Label(0), Jump(0)]);
     }
 
+    #[test]
+    fn test_rm_unreachable() {
+        // Dropping the second jump leaves its label unused, and the code after it unreachable.
+        let asm = to_piet_asm(vec![
+            InNum, Jump(0), Jump(1), Label(1), push(3), OutNum, Stop, Label(0), Stop,
+        ]);
+        let PietAsm { cmds, .. } = optimize(asm, MAX_OPT_LEVEL);
+        assert_eq!(cmds, vec![InNum, Stop]);
+    }
+
+    #[test]
+    fn test_opt_levels() {
+        let cmds = vec![Jump(0), Label(0), push(2), push(2)];
+        let PietAsm { cmds: none, .. } = optimize(to_piet_asm(cmds.clone()), 0);
+        assert_eq!(none, cmds);
+        let PietAsm { cmds: some, .. } = optimize(to_piet_asm(cmds.clone()), 1);
+        assert_eq!(some, vec![push(2), push(2)]);
+        let PietAsm { cmds: all, .. } = optimize(to_piet_asm(cmds), 2);
+        assert_eq!(all, vec![push(2), Duplicate]);
+    }
+
+    #[test]
+    fn test_origins() {
+        let mut asm = to_piet_asm(vec![push(3), push(3), push(200)]);
+        asm.origins = (1..=3).map(|n| Origin { lineno: Some(n), pass: None }).collect();
+        let asm = sanitize(optimize(asm, MAX_OPT_LEVEL));
+        let origin = |lineno, pass| Origin { lineno, pass };
+        assert_eq!(asm.origins, vec![
+            origin(Some(1), None),
+            origin(Some(2), Some("duplicate pushes")),
+            origin(Some(3), Some("large constants")),
+            origin(Some(3), Some("large constants")),
+            origin(Some(3), Some("large constants")),
+            origin(Some(3), Some("large constants")),
+            origin(Some(3), Some("large constants")),
+            origin(None, Some("final stop")),
+        ]);
+    }
+
     #[test]
     fn test_rm_unnecessary_jump_and_label() {
         let asm = to_piet_asm(vec![Jump(0), Label(0)]);
-        let PietAsm { cmds, .. } = optimize(asm);
+        let PietAsm { cmds, .. } = optimize(asm, MAX_OPT_LEVEL);
         assert_eq!(cmds, vec![]);
     }
 }
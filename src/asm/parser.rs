@@ -1,5 +1,6 @@
 use crate::asm::preprocessor::{Line, Statement, Token};
-use crate::asm::{AsmCommand, LabelId, ParseError, ParseErrorType, PietAsm};
+use crate::asm::{AsmCommand, LabelId, LayoutHint, Origin, ParseError, ParseErrorType, PietAsm};
+use num_bigint::BigInt;
 use std::collections::HashMap;
 
 type LineNo = usize;
@@ -22,11 +23,33 @@ impl Label {
     }
 }
 
+/// An open `SWITCH` block.
+/// Each `CASE` tests for its own values and jumps past its body if they miss,
+/// so only a couple of jumps are ever waiting on their labels. Piet has no
+/// computed jumps, every jump being a path drawn to one label, so there's no
+/// dispatch table to index; and since each pending jump holds a lane through
+/// the rows between it and its label, neither is there room to binary search.
+struct Switch {
+    lineno: LineNo,
+    values: Vec<BigInt>,
+    /// Where the previous `CASE` jumps when its values miss.
+    miss: Option<LabelId>,
+    has_default: bool,
+    end: LabelId,
+    in_body: bool,
+}
+
 #[derive(Default)]
 struct ParseContext {
     cmds: Vec<AsmCommand>,
+    /// The line each of `cmds` came from.
+    linenos: Vec<LineNo>,
     global_label_id: LabelId,
     labels: HashMap<String, Label>,
+    switches: Vec<Switch>,
+    pinned_lineno: Option<LineNo>,
+    /// Only accept mnemonics exactly as PietASM spells them.
+    strict_mnemonics: bool,
 }
 
 impl ParseContext {
@@ -38,13 +61,86 @@ impl ParseContext {
                 Label::new(id)
             })
     }
+
+    /// Create a label that can't be referred to from source.
+    fn anon_label(&mut self, lineno: LineNo) -> LabelId {
+        // Numeric names never parse as labels, so these can't collide.
+        let label = self.get_label(self.global_label_id.to_string());
+        label.label_lineno = Some(lineno);
+        label.id
+    }
+
+    fn jump(&mut self, cmd: AsmCommand) {
+        if let AsmCommand::Jump(id) | AsmCommand::JumpIf(id) = cmd {
+            if let Some(label) = self.labels.values_mut().find(|l| l.id == id) {
+                label.jump_count += 1;
+            }
+        }
+        self.cmds.push(cmd);
+    }
+
+    fn switch(&mut self) -> Result<&mut Switch, ParseErrorType> {
+        self.switches.last_mut().ok_or(ParseErrorType::CaseOutsideSwitch)
+    }
+
+    /// Finish off the previous `CASE` body, if any,
+    /// and land where its values missed.
+    fn close_case(&mut self) -> Result<(), ParseErrorType> {
+        let switch = self.switch()?;
+        let Switch { in_body, end, .. } = *switch;
+        let miss = switch.miss.take();
+        if in_body {
+            self.jump(AsmCommand::Jump(end));
+        }
+        if let Some(miss) = miss {
+            self.cmds.push(AsmCommand::Label(miss));
+        }
+        Ok(())
+    }
+
+    /// Start a body to run if the switched-on value equals any of `values`.
+    fn open_case(&mut self, values: Vec<BigInt>, lineno: LineNo) -> Result<(), ParseErrorType> {
+        self.close_case()?;
+        let body = self.anon_label(lineno);
+        let miss = self.anon_label(lineno);
+        // Each comparison is against a copy of the value.
+        for (i, value) in values.iter().enumerate() {
+            self.cmds.push(AsmCommand::Duplicate);
+            self.cmds.push(AsmCommand::Push(value.clone()));
+            self.cmds.push(AsmCommand::Subtract);
+            self.cmds.push(AsmCommand::Not);
+            if i + 1 < values.len() {
+                self.jump(AsmCommand::JumpIf(body));
+            } else {
+                self.cmds.push(AsmCommand::Not);
+                self.jump(AsmCommand::JumpIf(miss));
+            }
+        }
+        if values.len() > 1 {
+            self.cmds.push(AsmCommand::Label(body));
+        }
+        // Each body starts with the switched-on value still on the stack.
+        self.cmds.push(AsmCommand::Pop);
+        let switch = self.switch()?;
+        switch.values.extend(values);
+        switch.miss = Some(miss);
+        switch.in_body = true;
+        Ok(())
+    }
 }
 
-pub(super) fn to_bytecode(ast: Vec<Line>) -> Result<PietAsm, ParseError> {
-    let mut context = ParseContext::default();
+pub(super) fn to_bytecode(ast: Vec<Line>, strict_mnemonics: bool) -> Result<PietAsm, ParseError> {
+    let mut context = ParseContext { strict_mnemonics, ..Default::default() };
     for line in ast {
         let lineno = line.lineno;
         parse_line(line, &mut context).map_err(|e| e.at(lineno))?;
+        context.linenos.resize(context.cmds.len(), lineno);
+    }
+    if let Some(lineno) = context.pinned_lineno {
+        return Err(ParseErrorType::ExpectedLabel.at(lineno));
+    }
+    if let Some(switch) = context.switches.first() {
+        return Err(ParseErrorType::MissingEnd.at(switch.lineno));
     }
 
     let mut missing_labels = context.labels.iter()
@@ -54,12 +150,42 @@ pub(super) fn to_bytecode(ast: Vec<Line>) -> Result<PietAsm, ParseError> {
         let lineno = label.jump_lineno.unwrap();
         return Err(ParseErrorType::MissingLabel(name.to_string()).at(lineno));
     }
-    let ParseContext { cmds, labels, .. } = context;
+    let ParseContext { cmds, linenos, labels, .. } = context;
     let mut jump_counts = vec![0; labels.len()];
     for label in labels.values() {
         jump_counts[label.id] = label.jump_count;
     }
-    Ok(PietAsm { cmds, jump_counts })
+    let origins = linenos.into_iter()
+        .map(|lineno| Origin { lineno: Some(lineno), pass: None })
+        .collect();
+    Ok(PietAsm { cmds, jump_counts, origins })
+}
+
+/// Long forms of mnemonics, as other assemblers spell them.
+const ALIASES: [(&str, &str); 9] = [
+    ("DUPLICATE", "DUP"),
+    ("SUBTRACT", "SUB"),
+    ("MULTIPLY", "MUL"),
+    ("DIVIDE", "DIV"),
+    ("MODULO", "MOD"),
+    ("INNUMBER", "INNUM"),
+    ("INCHARACTER", "INCHAR"),
+    ("OUTNUMBER", "OUTNUM"),
+    ("OUTCHARACTER", "OUTCHAR"),
+];
+
+/// `cmd` spelled as PietASM would spell it, from any case, a long form, or
+/// with its words separated by `.` or `_`, eg `OUTCHAR` for `out.char`.
+/// Whether that's a mnemonic at all is for `parse_line` to say.
+fn canonical_mnemonic(cmd: &str) -> String {
+    let name = cmd.chars()
+        .filter(|&c| c != '.' && c != '_')
+        .collect::<String>()
+        .to_ascii_uppercase();
+    match ALIASES.into_iter().find(|&(alias, _)| alias == name) {
+        Some((_, mnemonic)) => mnemonic.to_string(),
+        None => name,
+    }
 }
 
 fn parse_line(line: Line, c: &mut ParseContext) -> Result<(), ParseErrorType> {
@@ -67,7 +193,30 @@ fn parse_line(line: Line, c: &mut ParseContext) -> Result<(), ParseErrorType> {
 
     let lineno = line.lineno;
 
-    match line.stmt {
+    // Tests, limits, and input schemas don't affect the program.
+    if let Statement::Test(_) | Statement::Limit(_) | Statement::Input(_) = line.stmt {
+        return Ok(());
+    }
+
+    // A pinned column only makes sense on a label.
+    if c.pinned_lineno.take().is_some() && !matches!(line.stmt, Statement::Label(_)) {
+        return Err(ParseErrorType::ExpectedLabel);
+    }
+
+    // The command as written, to report if it isn't one.
+    let written = match line.stmt {
+        Cmd { cmd, .. } => cmd,
+        _ => "",
+    };
+    let canonical;
+    let stmt = match line.stmt {
+        Cmd { cmd, args } if !c.strict_mnemonics => {
+            canonical = canonical_mnemonic(cmd);
+            Cmd { cmd: canonical.as_str(), args }
+        }
+        stmt => stmt,
+    };
+    match stmt {
         Cmd { cmd: "PUSH", args } => {
             let args = validate_args(args, 1, None)?;
             for arg in args {
@@ -97,6 +246,16 @@ fn parse_line(line: Line, c: &mut ParseContext) -> Result<(), ParseErrorType> {
                 _ => unreachable!(),
             });
         }
+        Cmd { cmd: "OUTSTR", args } => {
+            validate_arg_count(args.len(), 1, Some(1))?;
+            let Some(Token::Str(text)) = args.into_iter().next() else {
+                return Err(ParseErrorType::TypeError);
+            };
+            for ch in text.chars() {
+                c.cmds.push(AsmCommand::Push(BigInt::from(u32::from(ch))));
+                c.cmds.push(AsmCommand::OutChar);
+            }
+        }
         Cmd { cmd: cmd @ ("ADD" | "SUB" | "MUL" | "DIV" | "MOD" | "GREATER" | "ROLL"), args } => {
             let args = validate_args(args, 0, Some(2))?;
             for arg in args {
@@ -130,10 +289,66 @@ fn parse_line(line: Line, c: &mut ParseContext) -> Result<(), ParseErrorType> {
                 _ => unreachable!(),
             }
         }
-        Cmd { cmd, .. } => {
-            let cmd = cmd.to_string();
-            return Err(ParseErrorType::UnrecognizedCommand(cmd));
+        Cmd { cmd: "SWITCH", args } => {
+            validate_arg_count(args.len(), 0, Some(0))?;
+            let end = c.anon_label(lineno);
+            c.switches.push(Switch {
+                lineno,
+                values: Vec::new(),
+                miss: None,
+                has_default: false,
+                end,
+                in_body: false,
+            });
+        }
+        Cmd { cmd: "CASE", args } => {
+            let values: Vec<BigInt> = validate_args(args, 1, None)?;
+            let switch = c.switch()?;
+            if switch.has_default {
+                return Err(ParseErrorType::CaseAfterDefault);
+            }
+            for value in &values {
+                if switch.values.contains(value) {
+                    return Err(ParseErrorType::DuplicateCase(value.clone()));
+                }
+            }
+            c.open_case(values, lineno)?;
+        }
+        Cmd { cmd: "DEFAULT", args } => {
+            validate_arg_count(args.len(), 0, Some(0))?;
+            if c.switch()?.has_default {
+                return Err(ParseErrorType::DuplicateDefault);
+            }
+            c.close_case()?;
+            c.cmds.push(AsmCommand::Pop);
+            let switch = c.switch()?;
+            switch.has_default = true;
+            switch.in_body = true;
+        }
+        Cmd { cmd: "ENDSWITCH", args } => {
+            validate_arg_count(args.len(), 0, Some(0))?;
+            let switch = c.switches.last().ok_or(ParseErrorType::ExtraEnd)?;
+            // Without a DEFAULT, a value no CASE matches is dropped here.
+            if !switch.has_default {
+                c.close_case()?;
+                c.cmds.push(AsmCommand::Pop);
+            }
+            let switch = c.switches.pop().ok_or(ParseErrorType::ExtraEnd)?;
+            c.cmds.push(AsmCommand::Label(switch.end));
+        }
+        Cmd { .. } => {
+            return Err(ParseErrorType::UnrecognizedCommand(written.to_string()));
+        }
+        Statement::Raw(block) => {
+            c.cmds.push(AsmCommand::Raw(block));
         }
+        Statement::Hint(hint) => {
+            if let LayoutHint::Column(_) = hint {
+                c.pinned_lineno = Some(lineno);
+            }
+            c.cmds.push(AsmCommand::Hint(hint));
+        }
+        Statement::Test(_) | Statement::Limit(_) | Statement::Input(_) => unreachable!(),
         Statement::Label(label_name) => {
             // XXX: i _believe_ we already ran `parse_identifier`,
             // but it'd sure be nice if that were enforced by the type system.
@@ -156,7 +371,7 @@ where T: TryFrom<Token, Error = ParseErrorType> {
 }
 
 fn validate_arg_count(count: usize, min: usize, max: Option<usize>) -> Result<(), ParseErrorType> {
-    if min <= count && max.map_or(true, |mx| count <= mx) {
+    if min <= count && max.is_none_or(|mx| count <= mx) {
         return Ok(());
     }
     Err(ParseErrorType::WrongArgumentCount(count, min, max))
@@ -174,12 +389,68 @@ mod tests {
         let ast = preprocessor::preprocess(&lines).unwrap();
 
         assert_matches!(
-            to_bytecode(ast),
+            to_bytecode(ast, false),
             Err(ParseError { error_type: ParseErrorType::MissingLabel(s), .. })
                 if s == "NOPE"
         )
     }
 
+    #[test]
+    fn test_mnemonic_aliases() {
+        let lines = vec![
+            "push 3".into(),
+            "Duplicate".into(),
+            "out.number".into(),
+            "SUBTRACT 1".into(),
+            "OUT_CHAR".into(),
+        ];
+        let ast = preprocessor::preprocess(&lines).unwrap();
+        let PietAsm { cmds, .. } = to_bytecode(ast, false).unwrap();
+        assert_eq!(cmds, [
+            AsmCommand::Push(3.into()), AsmCommand::Duplicate, AsmCommand::OutNum,
+            AsmCommand::Push(1.into()), AsmCommand::Subtract, AsmCommand::OutChar,
+        ]);
+
+        let ast = preprocessor::preprocess(&lines).unwrap();
+        assert_matches!(
+            to_bytecode(ast, true),
+            Err(ParseError { lineno: 1, error_type: ParseErrorType::UnrecognizedCommand(cmd) })
+                if cmd == "push"
+        );
+        let lines = vec!["OUTPUT".into()];
+        let ast = preprocessor::preprocess(&lines).unwrap();
+        assert_matches!(
+            to_bytecode(ast, false),
+            Err(ParseError { error_type: ParseErrorType::UnrecognizedCommand(cmd), .. })
+                if cmd == "OUTPUT"
+        );
+    }
+
+    #[test]
+    fn test_outstr() {
+        let lines = vec![r##"OUTSTR "a\"# b" # comment"##.into(), "OUTSTR 7".into()];
+        let ast = preprocessor::preprocess(&lines).unwrap();
+        assert_matches!(
+            to_bytecode(ast[..1].to_vec(), false),
+            Ok(PietAsm { cmds, .. }) if cmds == [
+                AsmCommand::Push(97.into()), AsmCommand::OutChar,
+                AsmCommand::Push(34.into()), AsmCommand::OutChar,
+                AsmCommand::Push(35.into()), AsmCommand::OutChar,
+                AsmCommand::Push(32.into()), AsmCommand::OutChar,
+                AsmCommand::Push(98.into()), AsmCommand::OutChar,
+            ]
+        );
+        assert_matches!(
+            to_bytecode(ast[1..].to_vec(), false),
+            Err(ParseError { error_type: ParseErrorType::TypeError, lineno: 2 })
+        );
+        let lines = vec![r#"OUTSTR "open"#.into()];
+        assert_matches!(
+            preprocessor::preprocess(&lines),
+            Err(ParseError { error_type: ParseErrorType::InvalidString(_), lineno: 1 })
+        );
+    }
+
     #[test]
     fn test_double_label() {
         let lines = vec![
@@ -189,9 +460,107 @@ mod tests {
         let ast = preprocessor::preprocess(&lines).unwrap();
 
         assert_matches!(
-            to_bytecode(ast),
+            to_bytecode(ast, false),
             Err(ParseError { error_type: ParseErrorType::DuplicateLabel(s), .. })
                 if s == "TWIN"
         )
     }
+
+    #[test]
+    fn test_switch() {
+        use AsmCommand::*;
+
+        let lines = vec![
+            "SWITCH".into(),
+            "CASE 1 3".into(),
+            "OUTNUM 5".into(),
+            "CASE 2".into(),
+            "OUTNUM 6".into(),
+            "DEFAULT".into(),
+            "OUTNUM 7".into(),
+            "ENDSWITCH".into(),
+        ];
+        let ast = preprocessor::preprocess(&lines).unwrap();
+        let PietAsm { cmds, jump_counts, origins } = to_bytecode(ast, false).unwrap();
+
+        let push = |n: i32| Push(n.into());
+        assert_eq!(cmds, vec![
+            Duplicate, push(1), Subtract, Not, JumpIf(1),
+            Duplicate, push(3), Subtract, Not, Not, JumpIf(2),
+            Label(1), Pop, push(5), OutNum, Jump(0),
+            Label(2), Duplicate, push(2), Subtract, Not, Not, JumpIf(4),
+            Pop, push(6), OutNum, Jump(0),
+            Label(4), Pop, push(7), OutNum,
+            Label(0),
+        ]);
+        assert_eq!(jump_counts, vec![2, 1, 1, 0, 1]);
+        // Each case's tests come from its CASE, and its closing jump
+        // from whatever closed it.
+        let linenos: Vec<_> = origins.iter().map(|o| o.lineno.unwrap()).collect();
+        assert_eq!(linenos, [
+            [2; 13].as_slice(), &[3, 3],
+            &[4; 9], &[5, 5],
+            &[6; 3], &[7, 7],
+            &[8],
+        ].concat());
+    }
+
+    #[test]
+    fn test_switch_many_cases() {
+        // Enough cases that waiting on every label at once wouldn't lay out.
+        let cases: String = (0..30)
+            .map(|n| format!("CASE {} {}\nOUTNUM {n}\n", 3 * n, 3 * n + 1))
+            .collect();
+        let source = format!("INNUM\nSWITCH\n{cases}ENDSWITCH\nOUTNUM 99\n");
+        let code = crate::asm::load_str(&source).unwrap();
+        for (input, expected) in [("0", "099"), ("28", "999"), ("88", "2999"), ("2", "99"), ("90", "99")] {
+            let output = code.execute().capture_io(input).run_collect();
+            assert_eq!(output.stdout_lossy(), expected, "input {input}");
+        }
+    }
+
+    #[test]
+    fn test_switch_duplicate_case() {
+        let lines = vec![
+            "SWITCH".into(),
+            "CASE 1 2".into(),
+            "CASE 2".into(),
+            "ENDSWITCH".into(),
+        ];
+        let ast = preprocessor::preprocess(&lines).unwrap();
+
+        assert_matches!(
+            to_bytecode(ast, false),
+            Err(ParseError { lineno: 3, error_type: ParseErrorType::DuplicateCase(n) })
+                if n == 2.into()
+        )
+    }
+
+    #[test]
+    fn test_column_without_label() {
+        let lines = vec![
+            "@COLUMN 20".into(),
+            "PUSH 1".into(),
+        ];
+        let ast = preprocessor::preprocess(&lines).unwrap();
+
+        assert_matches!(
+            to_bytecode(ast, false),
+            Err(ParseError { lineno: 2, error_type: ParseErrorType::ExpectedLabel })
+        )
+    }
+
+    #[test]
+    fn test_switch_unclosed() {
+        let lines = vec![
+            "SWITCH".into(),
+            "CASE 1".into(),
+        ];
+        let ast = preprocessor::preprocess(&lines).unwrap();
+
+        assert_matches!(
+            to_bytecode(ast, false),
+            Err(ParseError { lineno: 1, error_type: ParseErrorType::MissingEnd })
+        )
+    }
 }
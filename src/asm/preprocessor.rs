@@ -1,58 +1,135 @@
-use crate::asm::{ParseError, ParseErrorType};
+use crate::asm::{LayoutHint, ParseError, ParseErrorType, RawBlock, TestCase};
+use crate::{Color, Hue, InputSchema, Lightness, RunLimits};
 use num_bigint::BigInt;
+use std::cmp::Reverse;
+use std::collections::HashSet;
 
 enum PreprocToken<'a> {
     Line(Line<'a>),
     Each(&'a str, Vec<BigInt>),
+    Raw(usize, usize),
+    Here,
     End,
 }
 
+/// The newest version of PietASM this assembler understands.
+/// A file declares the version it's written in with `@VERSION`, and is
+/// taken to be version 1 if it doesn't.
+pub const LANGUAGE_VERSION: u32 = 1;
+
+/// A pragma block awaiting its `@END`.
+enum Block<'a> {
+    Each(&'a str, Vec<BigInt>, Vec<Line<'a>>),
+    Here,
+}
+
 /// Prep the pasm file for processing.
 /// This will:
 /// - Annotate lines with their line numbers
-/// - Strip comments + blank lines
+/// - Strip any byte order mark, comments, and blank lines
+/// - Check the language version
 /// - Expand macros
+/// - Collect raw codel blocks
 /// - Convert the code into an AST
-pub(super) fn preprocess(lines: &[String]) -> Result<Vec<Line>, ParseError> {
+pub(super) fn preprocess(lines: &[String]) -> Result<Vec<Line<'_>>, ParseError> {
     let lines = lines.iter().enumerate().filter_map(|(lineno, line)| {
         let lineno = lineno + 1;
-        let line = line.split('#').next().unwrap().trim();
-        (!line.is_empty()).then(|| (lineno, line))
+        // Windows editors may start the file with a byte order mark.
+        // Line endings needn't be handled here: a trailing `\r` is whitespace.
+        let line = match lineno {
+            1 => line.strip_prefix('\u{feff}').unwrap_or(line),
+            _ => line,
+        };
+        let line = strip_comment(line).trim();
+        (!line.is_empty()).then_some((lineno, line))
     });
+    let mut lines = lines.peekable();
+    if let Some(&(lineno, line)) = lines.peek() {
+        let (pragma, version) = line
+            .split_once(|c: char| c.is_whitespace())
+            .unwrap_or((line, ""));
+        if pragma == "@VERSION" {
+            // Every version so far reads the same way. Syntax changes that
+            // would change the meaning of an existing file must only apply
+            // to files declaring the version that introduced them.
+            check_version(version).map_err(|e| e.at(lineno))?;
+            lines.next();
+        }
+    }
     let mut command_stack = Vec::new();
     let mut commands = Vec::new();
+    let mut raw: Option<(usize, usize, usize, Vec<&str>)> = None;
     for (lineno, line) in lines {
+        if let Some((_, _, _, rows)) = &mut raw {
+            if line != "@END" {
+                rows.push(line);
+                continue;
+            }
+            let (raw_lineno, width, height, rows) = raw.take().unwrap();
+            let block = parse_raw(width, height, &rows).map_err(|e| e.at(raw_lineno))?;
+            commands.push(Line { lineno: raw_lineno, stmt: Statement::Raw(block) });
+            continue;
+        }
         let pp_token = preprocess_line(line, lineno).map_err(|e| e.at(lineno))?;
         match pp_token {
             PreprocToken::Line(cmd) => { commands.push(cmd); }
             PreprocToken::Each(name, terms) => {
-                command_stack.push((name, terms, commands, lineno));
+                command_stack.push((Block::Each(name, terms, commands), lineno));
                 commands = Vec::new();
             }
+            PreprocToken::Here => {
+                command_stack.push((Block::Here, lineno));
+                let stmt = Statement::Hint(LayoutHint::GroupStart);
+                commands.push(Line { lineno, stmt });
+            }
+            PreprocToken::Raw(width, height) => {
+                raw = Some((lineno, width, height, Vec::new()));
+            }
             PreprocToken::End => {
-                let (name, terms, mut restored_cmds, _) = command_stack.pop()
+                let (block, _) = command_stack.pop()
                     .ok_or_else(|| ParseErrorType::ExtraEnd.at(lineno))?;
-                for term in terms {
-                    let ccmds = commands.clone();
-                    for mut cmd in ccmds {
-                        cmd.bind(name, &term);
-                        restored_cmds.push(cmd);
+                match block {
+                    Block::Each(name, terms, mut restored_cmds) => {
+                        for term in terms {
+                            let ccmds = commands.clone();
+                            for mut cmd in ccmds {
+                                cmd.bind(name, &term);
+                                restored_cmds.push(cmd);
+                            }
+                        }
+                        commands = restored_cmds;
+                    }
+                    Block::Here => {
+                        let stmt = Statement::Hint(LayoutHint::GroupEnd);
+                        commands.push(Line { lineno, stmt });
                     }
                 }
-                commands = restored_cmds;
             }
         }
     }
-    if let Some((_, _, _, lineno)) = command_stack.pop() {
+    if let Some((lineno, _, _, _)) = raw {
+        return Err(ParseErrorType::MissingEnd.at(lineno));
+    }
+    if let Some((_, lineno)) = command_stack.pop() {
         return Err(ParseErrorType::MissingEnd.at(lineno));
     }
     Ok(commands)
 }
 
+fn check_version(version: &str) -> Result<(), ParseErrorType> {
+    let version = version.trim();
+    let number = version.parse()
+        .map_err(|_| ParseErrorType::InvalidPragma(format!("VERSION {version}")))?;
+    if !(1..=LANGUAGE_VERSION).contains(&number) {
+        return Err(ParseErrorType::UnsupportedVersion(number));
+    }
+    Ok(())
+}
+
 fn preprocess_line(line: &str, lineno: usize) -> Result<PreprocToken<'_>, ParseErrorType> {
     if let Some(line) = line.strip_prefix('@') {
         let (cmd, rest) = line
-            .split_once(|c: char| c.is_ascii_whitespace())
+            .split_once(|c: char| c.is_whitespace())
             .unwrap_or((line, ""));
         let rest = rest.trim();
         return match cmd {
@@ -66,12 +143,55 @@ fn preprocess_line(line: &str, lineno: usize) -> Result<PreprocToken<'_>, ParseE
                     .ok_or_else(|| ParseErrorType::InvalidPragma(cmd.to_string()))?
                     .trim();
                 let terms: Result<Vec<_>, _> = terms
-                    .split_ascii_whitespace()
+                    .split_whitespace()
                     .map(parse_integer)
                     .collect();
                 let terms = terms?;
                 Ok(PreprocToken::Each(name, terms))
             }
+            "RAW" => {
+                let dims: Result<Vec<usize>, _> = rest
+                    .split_whitespace()
+                    .map(|s| s.parse())
+                    .collect();
+                match dims.as_deref() {
+                    Ok(&[width, height]) if width > 0 && height > 0 => {
+                        Ok(PreprocToken::Raw(width, height))
+                    }
+                    _ => Err(ParseErrorType::InvalidPragma(line.to_string())),
+                }
+            }
+            "ROW" if rest.is_empty() => {
+                let stmt = Statement::Hint(LayoutHint::Row);
+                Ok(PreprocToken::Line(Line { stmt, lineno }))
+            }
+            "COLUMN" => {
+                let column = rest.parse().ok()
+                    .filter(|&column| column > 0)
+                    .ok_or_else(|| ParseErrorType::InvalidPragma(line.to_string()))?;
+                let stmt = Statement::Hint(LayoutHint::Column(column));
+                Ok(PreprocToken::Line(Line { stmt, lineno }))
+            }
+            "TEST" => {
+                let case = parse_test(rest, lineno)
+                    .ok_or_else(|| ParseErrorType::InvalidPragma(line.to_string()))?;
+                let stmt = Statement::Test(case);
+                Ok(PreprocToken::Line(Line { stmt, lineno }))
+            }
+            "LIMIT" => {
+                let limits = RunLimits::parse(rest)
+                    .ok_or_else(|| ParseErrorType::InvalidPragma(line.to_string()))?;
+                let stmt = Statement::Limit(limits);
+                Ok(PreprocToken::Line(Line { stmt, lineno }))
+            }
+            "INPUT" => {
+                let schema = InputSchema::parse(rest)
+                    .ok_or_else(|| ParseErrorType::InvalidPragma(line.to_string()))?;
+                let stmt = Statement::Input(schema);
+                Ok(PreprocToken::Line(Line { stmt, lineno }))
+            }
+            "VERSION" => Err(ParseErrorType::MisplacedVersion),
+            "HERE" if rest.is_empty() => Ok(PreprocToken::Here),
             "END" if rest.is_empty() => Ok(PreprocToken::End),
             "END" => Err(ParseErrorType::InvalidPragma(line.to_string())),
             cmd => {
@@ -85,13 +205,109 @@ fn preprocess_line(line: &str, lineno: usize) -> Result<PreprocToken<'_>, ParseE
         let label = parse_identifier(label)?;
         Statement::Label(label)
     } else {
-        let mut terms = line.split_ascii_whitespace();
-        let cmd = terms.next().unwrap();
-        let args: Result<Vec<_>, _> = terms.map(|t| t.try_into()).collect();
-        Statement::Cmd { cmd, args: args? }
+        let (cmd, rest) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+        Statement::Cmd { cmd, args: parse_args(rest)? }
     };
     let line = Line { stmt, lineno };
-    return Ok(PreprocToken::Line(line));
+    Ok(PreprocToken::Line(line))
+}
+
+/// Strip any `#` comment, ignoring `#`s within quoted strings.
+fn strip_comment(line: &str) -> &str {
+    let mut quoted = false;
+    let mut escaped = false;
+    for (i, c) in line.char_indices() {
+        match c {
+            _ if escaped => { escaped = false; }
+            '\\' if quoted => { escaped = true; }
+            '"' => { quoted = !quoted; }
+            '#' if !quoted => { return &line[..i]; }
+            _ => (),
+        }
+    }
+    line
+}
+
+/// Split a command's arguments, each a term or a double-quoted string.
+fn parse_args(mut rest: &str) -> Result<Vec<Token>, ParseErrorType> {
+    let mut args = Vec::new();
+    loop {
+        rest = rest.trim_start();
+        if rest.is_empty() {
+            return Ok(args);
+        }
+        if rest.starts_with('"') {
+            let (text, remainder) = parse_string(rest)
+                .ok_or_else(|| ParseErrorType::InvalidString(rest.to_string()))?;
+            args.push(Token::Str(text));
+            rest = remainder;
+        } else {
+            let (term, remainder) = rest.split_once(char::is_whitespace).unwrap_or((rest, ""));
+            args.push(term.try_into()?);
+            rest = remainder;
+        }
+    }
+}
+
+/// Parse the `key=value` pairs of a `@TEST` pragma.
+/// `input` defaults to nothing, but at least one of `expect` and
+/// `expect_stack` is required.
+fn parse_test(mut rest: &str, lineno: usize) -> Option<TestCase> {
+    let mut input = None;
+    let mut expect = None;
+    let mut expect_stack = None;
+    while !rest.is_empty() {
+        let (key, value) = rest.split_once('=')?;
+        let remainder = match key {
+            "input" | "expect" => {
+                let slot = if key == "input" { &mut input } else { &mut expect };
+                let (value, remainder) = parse_string(value)?;
+                if slot.replace(value).is_some() {
+                    return None;
+                }
+                remainder
+            }
+            "expect_stack" => {
+                let (list, remainder) = value.strip_prefix('[')?.split_once(']')?;
+                let stack = list.split_whitespace()
+                    .map(|term| parse_integer(term).ok())
+                    .collect::<Option<_>>()?;
+                if expect_stack.replace(stack).is_some() {
+                    return None;
+                }
+                remainder
+            }
+            _ => { return None; }
+        };
+        rest = remainder.trim_start();
+    }
+    if expect.is_none() && expect_stack.is_none() {
+        return None;
+    }
+    Some(TestCase { lineno, input: input.unwrap_or_default(), expect, expect_stack })
+}
+
+/// Parse a double-quoted string from the start of `s`,
+/// returning it along with whatever follows.
+/// Supports the escapes `\n`, `\t`, `\\`, and `\"`.
+fn parse_string(s: &str) -> Option<(String, &str)> {
+    let mut chars = s.strip_prefix('"')?.char_indices();
+    let mut value = String::new();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '"' => { return Some((value, &s[i + 2..])); }
+            '\\' => {
+                value.push(match chars.next()?.1 {
+                    'n' => '\n',
+                    't' => '\t',
+                    c @ ('\\' | '"') => c,
+                    _ => { return None; }
+                });
+            }
+            c => { value.push(c); }
+        }
+    }
+    None
 }
 
 fn parse_identifier(s: &str) -> Result<&str, ParseErrorType> {
@@ -106,6 +322,101 @@ fn parse_identifier(s: &str) -> Result<&str, ParseErrorType> {
     Ok(s)
 }
 
+/// Parse the rows of a `@RAW` block.
+/// Each row is a whitespace-separated list of codels.
+/// A leading `>` marks the row execution enters on, from the left, and a
+/// trailing `>` marks the row it leaves on, to the right, which needn't be
+/// the same.
+fn parse_raw(width: usize, height: usize, rows: &[&str]) -> Result<RawBlock, ParseErrorType> {
+    use ParseErrorType::InvalidRawBlock;
+
+    if rows.len() != height {
+        return Err(InvalidRawBlock("row count doesn't match height"));
+    }
+    let mut entry = None;
+    let mut exit = None;
+    let mut codels = Vec::with_capacity(width * height);
+    for (y, row) in rows.iter().enumerate() {
+        let mut terms: Vec<_> = row.split_whitespace().collect();
+        if terms.first() == Some(&">") {
+            if entry.replace(y).is_some() {
+                return Err(InvalidRawBlock("multiple entries"));
+            }
+            terms.remove(0);
+        }
+        if terms.last() == Some(&">") {
+            if exit.replace(y).is_some() {
+                return Err(InvalidRawBlock("multiple exits"));
+            }
+            terms.pop();
+        }
+        if terms.len() != width {
+            return Err(InvalidRawBlock("row length doesn't match width"));
+        }
+        for term in terms {
+            codels.push(parse_codel(term)?);
+        }
+    }
+    let entry = entry.ok_or(InvalidRawBlock("no entry"))?;
+    let block = RawBlock { width, height, entry, exit, codels };
+    if block.at(0, entry) == Color::Black {
+        return Err(InvalidRawBlock("entry codel is black"));
+    }
+    if let Some(exit) = exit {
+        match block.at(width - 1, exit) {
+            Color::Black => { return Err(InvalidRawBlock("exit codel is black")); }
+            Color::White => (),
+            _ if right_exit(&block, width - 1, exit) != (width - 1, exit) => {
+                return Err(InvalidRawBlock("exit codel's block leaves to the right from another codel"));
+            }
+            _ => (),
+        }
+    }
+    Ok(block)
+}
+
+/// The codel execution leaves the colored block around `(x, y)` from, going
+/// right with the codel chooser pointing left: the top of its rightmost column.
+fn right_exit(block: &RawBlock, x: usize, y: usize) -> (usize, usize) {
+    let color = block.at(x, y);
+    let mut seen = HashSet::from([(x, y)]);
+    let mut pending = vec![(x, y)];
+    while let Some((x, y)) = pending.pop() {
+        let neighbors = [(x.wrapping_sub(1), y), (x + 1, y), (x, y.wrapping_sub(1)), (x, y + 1)];
+        for (x, y) in neighbors {
+            if x < block.width && y < block.height && block.at(x, y) == color && seen.insert((x, y)) {
+                pending.push((x, y));
+            }
+        }
+    }
+    seen.into_iter().max_by_key(|&(x, y)| (x, Reverse(y))).unwrap()
+}
+
+/// Parse a single codel: `.` for white, `x` for black, or a hue letter
+/// (`r`, `y`, `g`, `c`, `b`, `m`) optionally prefixed by `l` for light
+/// or `d` for dark.
+fn parse_codel(s: &str) -> Result<Color, ParseErrorType> {
+    let lower = s.to_ascii_lowercase();
+    let (lightness, hue) = match lower.as_bytes() {
+        b"." => { return Ok(Color::White); }
+        b"x" => { return Ok(Color::Black); }
+        &[hue] => (Lightness::Normal, hue),
+        &[b'l', hue] => (Lightness::Light, hue),
+        &[b'd', hue] => (Lightness::Dark, hue),
+        _ => { return Err(ParseErrorType::InvalidCodel(s.to_string())); }
+    };
+    let hue = match hue {
+        b'r' => Hue::Red,
+        b'y' => Hue::Yellow,
+        b'g' => Hue::Green,
+        b'c' => Hue::Cyan,
+        b'b' => Hue::Blue,
+        b'm' => Hue::Magenta,
+        _ => { return Err(ParseErrorType::InvalidCodel(s.to_string())); }
+    };
+    Ok(Color::Color(hue, lightness))
+}
+
 fn parse_integer(s: &str) -> Result<BigInt, ParseErrorType> {
     s.parse().map_err(|_| { ParseErrorType::ExpectedInteger(s.to_string()) })
 }
@@ -121,9 +432,19 @@ pub(super) enum Token {
     Var(String),
     Num(BigInt),
     Label(String),
+    Str(String),
 }
 
 impl Token {
+    fn to_source(&self) -> String {
+        match self {
+            Token::Var(name) => format!("@{name}"),
+            Token::Num(num) => num.to_string(),
+            Token::Label(label) => label.clone(),
+            Token::Str(text) => string_source(text),
+        }
+    }
+
     fn bind(&mut self, name: &str, value: &BigInt) {
         if let Token::Var(id) = self {
             if *id == name {
@@ -154,7 +475,7 @@ impl TryFrom<Token> for BigInt {
         match token {
             Token::Var(var) => Err(ParseErrorType::UnboundVarError(var)),
             Token::Num(int) => Ok(int),
-            Token::Label(_) => Err(ParseErrorType::TypeError),
+            Token::Label(_) | Token::Str(_) => Err(ParseErrorType::TypeError),
         }
     }
 }
@@ -166,7 +487,7 @@ impl TryFrom<Token> for String {
     fn try_from(token: Token) -> Result<String, ParseErrorType> {
         match token {
             Token::Var(var) => Err(ParseErrorType::UnboundVarError(var)),
-            Token::Num(_) => Err(ParseErrorType::TypeError),
+            Token::Num(_) | Token::Str(_) => Err(ParseErrorType::TypeError),
             Token::Label(label) => Ok(label),
         }
     }
@@ -191,6 +512,11 @@ pub(super) enum Statement<'a> {
         args: Vec<Token>,
     },
     Label(&'a str),
+    Raw(RawBlock),
+    Hint(LayoutHint),
+    Test(TestCase),
+    Limit(RunLimits),
+    Input(InputSchema),
 }
 
 impl Statement<'_> {
@@ -202,3 +528,353 @@ impl Statement<'_> {
         }
     }
 }
+
+impl Statement<'_> {
+    /// The statement as PietASM source, which preprocesses back to the same
+    /// statement. A raw block takes a line for each row, between its pragmas.
+    pub(super) fn to_source(&self) -> Vec<String> {
+        match self {
+            Statement::Cmd { cmd, args } => {
+                let terms: Vec<_> = std::iter::once(cmd.to_string())
+                    .chain(args.iter().map(Token::to_source))
+                    .collect();
+                vec![terms.join(" ")]
+            }
+            Statement::Label(label) => vec![format!(":{label}")],
+            Statement::Raw(block) => {
+                let rows = (0..block.height).map(|y| {
+                    let mut terms: Vec<_> = (0..block.width).map(|x| codel_source(block.at(x, y))).collect();
+                    if y == block.entry {
+                        terms.insert(0, ">".to_string());
+                    }
+                    if block.exit == Some(y) {
+                        terms.push(">".to_string());
+                    }
+                    terms.join(" ")
+                });
+                std::iter::once(format!("@RAW {} {}", block.width, block.height))
+                    .chain(rows)
+                    .chain(["@END".to_string()])
+                    .collect()
+            }
+            Statement::Hint(LayoutHint::Row) => vec!["@ROW".to_string()],
+            Statement::Hint(LayoutHint::Column(column)) => vec![format!("@COLUMN {column}")],
+            Statement::Hint(LayoutHint::GroupStart) => vec!["@HERE".to_string()],
+            Statement::Hint(LayoutHint::GroupEnd) => vec!["@END".to_string()],
+            Statement::Test(case) => {
+                let mut settings = vec![format!("input={}", string_source(&case.input))];
+                if let Some(expect) = &case.expect {
+                    settings.push(format!("expect={}", string_source(expect)));
+                }
+                if let Some(stack) = &case.expect_stack {
+                    let terms: Vec<_> = stack.iter().map(BigInt::to_string).collect();
+                    settings.push(format!("expect_stack=[{}]", terms.join(" ")));
+                }
+                vec![format!("@TEST {}", settings.join(" "))]
+            }
+            Statement::Limit(limits) => vec![format!("@LIMIT {limits}")],
+            Statement::Input(schema) => vec![format!("@INPUT {schema}")],
+        }
+    }
+}
+
+/// A codel as `parse_codel` reads it.
+fn codel_source(color: Color) -> String {
+    let Color::Color(hue, lightness) = color else {
+        return if color == Color::White { ".".to_string() } else { "x".to_string() };
+    };
+    let hue = match hue {
+        Hue::Red => 'r',
+        Hue::Yellow => 'y',
+        Hue::Green => 'g',
+        Hue::Cyan => 'c',
+        Hue::Blue => 'b',
+        Hue::Magenta => 'm',
+    };
+    match lightness {
+        Lightness::Light => format!("l{hue}"),
+        Lightness::Normal => hue.to_string(),
+        Lightness::Dark => format!("d{hue}"),
+    }
+}
+
+/// A string as `parse_string` reads it, quoted and escaped.
+pub(crate) fn string_source(s: &str) -> String {
+    let mut quoted = String::from('"');
+    for c in s.chars() {
+        match c {
+            '\n' => quoted.push_str("\\n"),
+            '\t' => quoted.push_str("\\t"),
+            '\\' | '"' => { quoted.push('\\'); quoted.push(c); }
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::InputItem;
+    use assert_matches::assert_matches;
+
+    #[test]
+    fn test_raw() {
+        let lines = vec![
+            "@RAW 3 2".into(),
+            "  lr  .  x".into(),
+            "> r   dr m >  # comment".into(),
+            "@END".into(),
+        ];
+        let ast = preprocess(&lines).unwrap();
+        assert_matches!(
+            ast.as_slice(),
+            [Line { lineno: 1, stmt: Statement::Raw(block) }]
+                if *block == RawBlock {
+                    width: 3,
+                    height: 2,
+                    entry: 1,
+                    exit: Some(1),
+                    codels: vec![
+                        Color::LightRed, Color::White, Color::Black,
+                        Color::Red, Color::DarkRed, Color::Magenta,
+                    ],
+                }
+        );
+    }
+
+    #[test]
+    fn test_test_pragma() {
+        let lines = vec![
+            r#"@TEST input="3\n" expect="9"  # squares"#.into(),
+            r##"@TEST expect="a \"#\" b\\""##.into(),
+            "@TEST expect_stack=[1 -2 3]".into(),
+        ];
+        let ast = preprocess(&lines).unwrap();
+        assert_matches!(
+            ast.as_slice(),
+            [
+                Line { stmt: Statement::Test(a), .. },
+                Line { stmt: Statement::Test(b), .. },
+                Line { stmt: Statement::Test(c), .. },
+            ] if *a == TestCase {
+                lineno: 1,
+                input: "3\n".into(),
+                expect: Some("9".into()),
+                expect_stack: None,
+            } && *b == TestCase {
+                lineno: 2,
+                input: "".into(),
+                expect: Some("a \"#\" b\\".into()),
+                expect_stack: None,
+            } && *c == TestCase {
+                lineno: 3,
+                input: "".into(),
+                expect: None,
+                expect_stack: Some(vec![1.into(), (-2).into(), 3.into()]),
+            }
+        );
+    }
+
+    #[test]
+    fn test_text_encoding() {
+        let lines = vec![
+            "\u{feff}:start\r".into(),
+            "PUSH\u{a0}1 \r".into(),
+            "\u{3000}@TEST\u{2003}expect=\"a\r\"  # comment\r".into(),
+            "\r".into(),
+        ];
+        let ast = preprocess(&lines).unwrap();
+        assert_matches!(
+            ast.as_slice(),
+            [
+                Line { lineno: 1, stmt: Statement::Label("start") },
+                Line { lineno: 2, stmt: Statement::Cmd { cmd: "PUSH", args } },
+                Line { lineno: 3, stmt: Statement::Test(case) },
+            ] if args.len() == 1 && case.expect.as_deref() == Some("a\r")
+        );
+    }
+
+    #[test]
+    fn test_test_pragma_invalid() {
+        let lines = [
+            r#"@TEST input="3""#,
+            r#"@TEST expect="9"#,
+            r#"@TEST output="9""#,
+            "@TEST expect_stack=[1 x]",
+            "@TEST expect_stack=[1",
+        ];
+        for line in lines {
+            assert_matches!(
+                preprocess(&[line.into()]),
+                Err(ParseError { lineno: 1, error_type: ParseErrorType::InvalidPragma(_) })
+            );
+        }
+    }
+
+    #[test]
+    fn test_version_pragma() {
+        let lines = vec!["# comment".into(), "@VERSION 1".into(), "PUSH 1".into()];
+        assert_matches!(preprocess(&lines).unwrap().as_slice(), [Line { lineno: 3, .. }]);
+        for version in [0, 2] {
+            assert_matches!(
+                preprocess(&[format!("@VERSION {version}")]),
+                Err(ParseError { lineno: 1, error_type: ParseErrorType::UnsupportedVersion(v) })
+                    if v == version
+            );
+        }
+        for line in ["@VERSION", "@VERSION one", "@VERSION 1 2"] {
+            assert_matches!(
+                preprocess(&[line.into()]),
+                Err(ParseError { lineno: 1, error_type: ParseErrorType::InvalidPragma(_) })
+            );
+        }
+        assert_matches!(
+            preprocess(&["PUSH 1".into(), "@VERSION 1".into()]),
+            Err(ParseError { lineno: 2, error_type: ParseErrorType::MisplacedVersion })
+        );
+    }
+
+    #[test]
+    fn test_column_pragma() {
+        assert_matches!(
+            preprocess(&["@COLUMN 20".into()]).unwrap().as_slice(),
+            [Line { stmt: Statement::Hint(LayoutHint::Column(20)), .. }]
+        );
+        for line in ["@COLUMN", "@COLUMN 0", "@COLUMN -1", "@COLUMN x"] {
+            assert_matches!(
+                preprocess(&[line.into()]),
+                Err(ParseError { lineno: 1, error_type: ParseErrorType::InvalidPragma(_) })
+            );
+        }
+    }
+
+    #[test]
+    fn test_limit_pragma() {
+        let lines = vec!["@LIMIT steps=100000".into(), "@LIMIT steps=5 seconds=0.5".into()];
+        let ast = preprocess(&lines).unwrap();
+        assert_matches!(
+            ast.as_slice(),
+            [
+                Line { stmt: Statement::Limit(a), .. },
+                Line { stmt: Statement::Limit(b), .. },
+            ] if a.steps == Some(100000) && a.time.is_none()
+                && b.steps == Some(5) && b.time == Some(std::time::Duration::from_millis(500))
+        );
+        for line in ["@LIMIT", "@LIMIT steps=many", "@LIMIT steps=1 fuel=2"] {
+            assert_matches!(
+                preprocess(&[line.into()]),
+                Err(ParseError { lineno: 1, error_type: ParseErrorType::InvalidPragma(_) })
+            );
+        }
+    }
+
+    #[test]
+    fn test_input_pragma() {
+        let lines = vec!["@INPUT num num char*".into()];
+        let ast = preprocess(&lines).unwrap();
+        assert_matches!(
+            ast.as_slice(),
+            [Line { stmt: Statement::Input(schema), .. }]
+                if schema.items == [InputItem::Num, InputItem::Num, InputItem::Char] && schema.repeats
+        );
+        for line in ["@INPUT", "@INPUT number", "@INPUT num* char", "@INPUT num**"] {
+            assert_matches!(
+                preprocess(&[line.into()]),
+                Err(ParseError { lineno: 1, error_type: ParseErrorType::InvalidPragma(_) })
+            );
+        }
+    }
+
+    #[test]
+    fn test_preprocess_str() {
+        let source = "\
+            @VERSION 1\n\
+            @EACH N=[1 2]  # twice\n\
+            PUSH @N\n\
+            @END\n\
+            \n\
+            :loop\n\
+            @HERE\n\
+            JUMPIF loop\n\
+            @END\n\
+            @RAW 2 1\n\
+            > lr dm >\n\
+            @END\n\
+            @TEST input=\"a\\\"#\\n\" expect_stack=[1 -2]\n\
+            @LIMIT steps=100\n\
+            @INPUT num char*\n";
+        let expanded = crate::asm::preprocess_str(source).unwrap();
+        let lines: Vec<_> = expanded.iter().map(|line| (line.lineno, line.text.as_str())).collect();
+        assert_eq!(lines, [
+            (3, "PUSH 1"),
+            (3, "PUSH 2"),
+            (6, ":loop"),
+            (7, "@HERE"),
+            (8, "JUMPIF loop"),
+            (9, "@END"),
+            (10, "@RAW 2 1"),
+            (10, "> lr dm >"),
+            (10, "@END"),
+            (13, "@TEST input=\"a\\\"#\\n\" expect_stack=[1 -2]"),
+            (14, "@LIMIT steps=100"),
+            (15, "@INPUT num char*"),
+        ]);
+
+        // The expanded lines read as the same program.
+        let text: Vec<_> = expanded.into_iter().map(|line| line.text).collect();
+        let again = crate::asm::preprocess_str(&text.join("\n")).unwrap();
+        assert_eq!(again.iter().map(|line| line.text.as_str()).collect::<Vec<_>>(), text);
+
+        assert_eq!(crate::asm::preprocess_str("@EACH N=[1]\nPUSH @N"), Err("error at 1: unclosed delimiter".to_string()));
+    }
+
+    #[test]
+    fn test_raw_wrong_width() {
+        let lines = vec![
+            "@RAW 3 1".into(),
+            "> r r".into(),
+            "@END".into(),
+        ];
+        assert_matches!(
+            preprocess(&lines),
+            Err(ParseError { lineno: 1, error_type: ParseErrorType::InvalidRawBlock(_) })
+        );
+    }
+
+    #[test]
+    fn test_raw_exit() {
+        let raw = |rows: &[&str]| {
+            let lines: Vec<String> = [format!("@RAW 2 {}", rows.len())].into_iter()
+                .chain(rows.iter().map(|row| row.to_string()))
+                .chain(["@END".to_string()])
+                .collect();
+            preprocess(&lines).map(|ast| match &ast[0].stmt {
+                Statement::Raw(block) => (block.entry, block.exit),
+                _ => unreachable!(),
+            })
+        };
+        assert_eq!(raw(&["> r x", "  r g >"]).unwrap(), (0, Some(1)));
+        assert_eq!(raw(&["  g g >", "> r g"]).unwrap(), (1, Some(0)));
+        assert_eq!(raw(&["> r .", "  b . >"]).unwrap(), (0, Some(1)));
+        // Execution would leave the green block from its top right codel.
+        assert_matches!(
+            raw(&["> r g", "  r g >"]),
+            Err(ParseError { lineno: 1, error_type: ParseErrorType::InvalidRawBlock(reason) })
+                if reason.contains("another codel")
+        );
+    }
+
+    #[test]
+    fn test_raw_unclosed() {
+        let lines = vec![
+            "@RAW 1 1".into(),
+            "> r".into(),
+        ];
+        assert_matches!(
+            preprocess(&lines),
+            Err(ParseError { lineno: 1, error_type: ParseErrorType::MissingEnd })
+        );
+    }
+}
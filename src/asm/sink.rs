@@ -0,0 +1,236 @@
+use crate::{palette, CodelCoord, Color, Hue, Lightness, PietCode};
+use super::GenerateReport;
+use std::fmt::Write;
+
+/// Somewhere to put a program once it's laid out, eg to draw it in another
+/// format. Given the image's size first, then where each command was laid
+/// out, then each codel in reading order. See `asm::build`.
+pub trait CodeSink {
+    type Output;
+
+    fn size(&mut self, _width: usize, _height: usize) {}
+
+    /// Where the cursor was left after laying out a command, described as
+    /// `dump_ir` describes it.
+    fn placement(&mut self, _cursor: CodelCoord, _command: &str) {}
+
+    fn codel(&mut self, _coord: CodelCoord, _color: Color) {}
+
+    fn finish(self) -> Self::Output;
+}
+
+/// Feed `sink` the image's size, then where each command was laid out, as
+/// `report` lists them. The generator feeds it the codels after.
+pub(super) fn emit_placements<S: CodeSink>(report: &GenerateReport, sink: &mut S) {
+    sink.size(report.width, report.height);
+    for (cursor, command) in &report.placements {
+        sink.placement(*cursor, command);
+    }
+}
+
+/// The program itself, to run or save as an image.
+#[derive(Debug, Default)]
+pub struct PixelSink {
+    width: usize,
+    height: usize,
+    code: Vec<Color>,
+}
+
+impl PixelSink {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl CodeSink for PixelSink {
+    type Output = PietCode;
+
+    fn size(&mut self, width: usize, height: usize) {
+        self.width = width;
+        self.height = height;
+        self.code.reserve(width * height);
+    }
+
+    fn codel(&mut self, _coord: CodelCoord, color: Color) {
+        self.code.push(color);
+    }
+
+    fn finish(self) -> PietCode {
+        PietCode::new(self.width, self.height, self.code)
+    }
+}
+
+/// An SVG image, each codel `codel_size` units square, eg to scale the
+/// program up without blurring. Codels outside the palette are left out.
+#[derive(Debug)]
+pub struct SvgSink {
+    codel_size: u32,
+    svg: String,
+    /// The run of same-colored codels being drawn: its start, length, and color.
+    run: Option<(CodelCoord, usize, Color)>,
+}
+
+impl SvgSink {
+    pub fn new(codel_size: u32) -> Self {
+        SvgSink { codel_size, svg: String::new(), run: None }
+    }
+
+    fn draw_run(&mut self) {
+        let Some((CodelCoord { x, y }, len, color)) = self.run.take() else { return; };
+        let Some([r, g, b]) = palette::rgb(color) else { return; };
+        let size = self.codel_size as usize;
+        writeln!(
+            self.svg,
+            r##"<rect x="{}" y="{}" width="{}" height="{size}" fill="#{r:02x}{g:02x}{b:02x}"/>"##,
+            x * size, y * size, len * size,
+        ).unwrap();
+    }
+}
+
+impl CodeSink for SvgSink {
+    type Output = String;
+
+    fn size(&mut self, width: usize, height: usize) {
+        let (width, height) = (width * self.codel_size as usize, height * self.codel_size as usize);
+        writeln!(
+            self.svg,
+            r#"<svg xmlns="http://www.w3.org/2000/svg" width="{width}" height="{height}" viewBox="0 0 {width} {height}" shape-rendering="crispEdges">"#,
+        ).unwrap();
+    }
+
+    fn codel(&mut self, coord: CodelCoord, color: Color) {
+        // Each row of same-colored codels is drawn as one rectangle.
+        if let Some((start, len, run_color)) = &mut self.run {
+            if start.y == coord.y && start.x + *len == coord.x && *run_color == color {
+                *len += 1;
+                return;
+            }
+        }
+        self.draw_run();
+        self.run = Some((coord, 1, color));
+    }
+
+    fn finish(mut self) -> String {
+        self.draw_run();
+        self.svg.push_str("</svg>\n");
+        self.svg
+    }
+}
+
+/// The program as text, one character a codel, eg to paste into a bug report.
+/// White is `.` and black `#`. Light hues are `r`, `y`, `g`, `c`, `b`, and `m`,
+/// normal hues the same in uppercase, and dark hues `1` to `6` in the same order.
+/// Codels outside the palette are `?`.
+#[derive(Debug, Default)]
+pub struct AsciiSink {
+    width: usize,
+    text: String,
+}
+
+impl AsciiSink {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl CodeSink for AsciiSink {
+    type Output = String;
+
+    fn size(&mut self, width: usize, _height: usize) {
+        self.width = width;
+    }
+
+    fn codel(&mut self, coord: CodelCoord, color: Color) {
+        let chr = match color {
+            Color::White => '.',
+            Color::Black => '#',
+            Color::Other => '?',
+            Color::Color(hue, lightness) => {
+                let letter = match hue {
+                    Hue::Red => 'r',
+                    Hue::Yellow => 'y',
+                    Hue::Green => 'g',
+                    Hue::Cyan => 'c',
+                    Hue::Blue => 'b',
+                    Hue::Magenta => 'm',
+                };
+                match lightness {
+                    Lightness::Light => letter,
+                    Lightness::Normal => letter.to_ascii_uppercase(),
+                    Lightness::Dark => char::from(b'1' + hue as u8),
+                }
+            }
+        };
+        self.text.push(chr);
+        if coord.x + 1 == self.width {
+            self.text.push('\n');
+        }
+    }
+
+    fn finish(self) -> String {
+        self.text
+    }
+}
+
+/// Only where each command was laid out, as `GenerateReport::plan` lists
+/// them, without the image's fingerprint, eg to check a layout without
+/// drawing anything.
+#[derive(Debug, Default)]
+pub struct PlanSink {
+    plan: String,
+}
+
+impl PlanSink {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl CodeSink for PlanSink {
+    type Output = String;
+
+    fn size(&mut self, width: usize, height: usize) {
+        writeln!(self.plan, "size {width}x{height}").unwrap();
+    }
+
+    fn placement(&mut self, cursor: CodelCoord, command: &str) {
+        writeln!(self.plan, "{cursor} {command}").unwrap();
+    }
+
+    fn finish(self) -> String {
+        self.plan
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::{generator, to_asm, GeneratorOptions};
+
+    #[test]
+    fn test_sinks() {
+        fn generate<S: CodeSink>(sink: S) -> (S::Output, GenerateReport) {
+            let lines = ["PUSH 3", "OUTNUM"].map(String::from);
+            let options = GeneratorOptions::new();
+            generator::generate(to_asm(&lines, &options).unwrap(), &options, sink).unwrap()
+        }
+        let (code, report) = generate(PixelSink::new());
+        assert_eq!((code.width, code.height), (report.width, report.height));
+
+        let ascii = generate(AsciiSink::new()).0;
+        assert_eq!(ascii.lines().count(), code.height);
+        assert!(ascii.lines().all(|line| line.len() == code.width));
+        let at = |x: usize, y: usize| ascii.lines().nth(y).unwrap().chars().nth(x).unwrap();
+        assert_eq!(at(0, 1), 'R');
+        assert!(ascii.contains('#'));
+
+        let svg = generate(SvgSink::new(2)).0;
+        assert!(svg.starts_with(&format!(r#"<svg xmlns="http://www.w3.org/2000/svg" width="{}""#, code.width * 2)));
+        assert!(svg.contains(r##"<rect x="0" y="2" width="4" height="2" fill="#ff0000"/>"##));
+        assert!(svg.ends_with("</svg>\n"));
+
+        let plan = generate(PlanSink::new()).0;
+        assert_eq!(plan.lines().count(), 1 + report.placements.len());
+        assert!(report.plan(&code).starts_with(&plan));
+    }
+}
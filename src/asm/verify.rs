@@ -0,0 +1,179 @@
+//! Checks that the optimizer preserves a program's behavior, by running it
+//! before and after optimization on the same randomized stacks and inputs.
+//! Only built for tests, where every optimization is checked this way.
+
+use crate::asm::{AsmCommand, PietAsm};
+use crate::{Command, Input, Output, PietVM, Value};
+use num_traits::{One, Zero};
+use std::collections::HashMap;
+
+const TRIALS: u64 = 32;
+/// Runs taking longer than this are assumed not to halt, and aren't compared.
+const MAX_STEPS: usize = 10_000;
+
+/// A small xorshift PRNG, so runs are reproducible.
+pub(super) struct Rng(u64);
+
+impl Rng {
+    pub(super) fn new(seed: u64) -> Self {
+        Rng(seed.wrapping_mul(0x9e3779b97f4a7c15) | 1)
+    }
+
+    pub(super) fn below(&mut self, n: u64) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0 % n
+    }
+}
+
+/// What a program leaves behind: its final stack and its output.
+type Observed = (Vec<Value>, Vec<u8>);
+
+/// Interpret `asm` directly, with the VM's semantics for each command.
+/// Returns None if the behavior can't be known: the program contains raw
+/// codels, runs too long, or branches on something other than 0 or 1.
+fn run(asm: &PietAsm, stack: Vec<Value>, input: &[u8]) -> Option<Observed> {
+    let labels: HashMap<_, _> = asm.cmds.iter()
+        .enumerate()
+        .filter_map(|(i, cmd)| match cmd {
+            AsmCommand::Label(id) => Some((*id, i)),
+            _ => None,
+        })
+        .collect();
+    let mut vm = PietVM {
+        stack,
+        input: Input::Buffer(input.iter().copied().collect()),
+        output: Output::Buffer(Vec::new()),
+        ..PietVM::default()
+    };
+    let mut pc = 0;
+    let mut steps = 0;
+    while let Some(cmd) = asm.cmds.get(pc) {
+        steps += 1;
+        if steps > MAX_STEPS {
+            return None;
+        }
+        pc += 1;
+        match cmd {
+            AsmCommand::Label(_) | AsmCommand::Hint(_) => (),
+            AsmCommand::Stop => { break; }
+            AsmCommand::Raw(_) => { return None; }
+            AsmCommand::Jump(id) => { pc = labels[id]; }
+            AsmCommand::JumpIf(id) => match vm.pop1() {
+                Err(_) => (),
+                Ok(n) if n.is_one() => { pc = labels[id]; }
+                Ok(n) if n.is_zero() => (),
+                Ok(_) => { return None; }
+            },
+            cmd => {
+                let value = match cmd {
+                    AsmCommand::Push(n) => Value::from(n.clone()),
+                    _ => Value::zero(),
+                };
+                let command = Command::try_from(cmd.clone()).unwrap();
+                // Failed commands are skipped, as in the VM.
+                let _ = vm.run_command(command, value);
+            }
+        }
+    }
+    let Output::Buffer(output) = vm.output else { unreachable!() };
+    Some((vm.stack, output))
+}
+
+/// Panic if `after` behaves differently from `before`.
+pub(super) fn verify(before: &PietAsm, after: &PietAsm) {
+    const INPUT_BYTES: &[u8] = b"0123456789 -+ab\n";
+
+    for trial in 0..TRIALS {
+        let mut rng = Rng::new(trial);
+        let stack: Vec<Value> = (0..rng.below(6))
+            .map(|_| Value::from(rng.below(11) as i64 - 5))
+            .collect();
+        let input: Vec<u8> = (0..rng.below(8))
+            .map(|_| INPUT_BYTES[rng.below(INPUT_BYTES.len() as u64) as usize])
+            .collect();
+        let (Some(expected), Some(actual)) = (
+            run(before, stack.clone(), &input),
+            run(after, stack.clone(), &input),
+        ) else {
+            continue;
+        };
+        assert_eq!(
+            expected, actual,
+            "optimization changed behavior, with stack {stack:?} and input {:?}:\n\
+                before: {:?}\nafter: {:?}",
+            String::from_utf8_lossy(&input), before.cmds, after.cmds,
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::optimizer::{optimize, sanitize};
+    use crate::asm::MAX_OPT_LEVEL;
+    use AsmCommand::*;
+
+    fn push(n: i64) -> AsmCommand {
+        Push(n.into())
+    }
+
+    #[test]
+    #[should_panic(expected = "optimization changed behavior")]
+    fn test_catches_unsound_rewrite() {
+        // Only sound if there's something on the stack to multiply.
+        let before = PietAsm::new(vec![push(1), Multiply], Vec::new());
+        let after = PietAsm::new(Vec::new(), Vec::new());
+        verify(&before, &after);
+    }
+
+    #[test]
+    fn test_jumps() {
+        let asm = PietAsm::new(
+            vec![JumpIf(0), push(5), Label(0), push(6), Jump(1), OutNum, Label(1)],
+            vec![1, 1],
+        );
+        assert_eq!(run(&asm, vec![1.into()], b""), Some((vec![6.into()], Vec::new())));
+        assert_eq!(run(&asm, vec![0.into()], b""), Some((vec![5.into(), 6.into()], Vec::new())));
+        assert_eq!(run(&asm, vec![2.into()], b""), None);
+    }
+
+    /// Optimize random programs built to tempt the optimizer.
+    /// `optimize` and `sanitize` verify themselves, so this only has to run them.
+    #[test]
+    fn test_random_programs() {
+        let pool = [
+            push(1), push(2), push(0), push(-3), push(150), Pop, Add, Subtract,
+            Multiply, Divide, Mod, Not, Greater, Duplicate, Roll,
+            InNum, InChar, OutNum, OutChar,
+        ];
+        for seed in 0..200 {
+            let mut rng = Rng::new(seed);
+            let mut cmds = Vec::new();
+            let mut jump_counts = Vec::new();
+            for _ in 0..rng.below(12) {
+                match rng.below(8) {
+                    // a label, sometimes jumped to from just before it
+                    0 => {
+                        let id = jump_counts.len();
+                        let jumped = rng.below(2) == 0;
+                        if jumped {
+                            cmds.push(Jump(id));
+                        }
+                        cmds.push(Label(id));
+                        jump_counts.push(jumped as usize);
+                    }
+                    1 => {
+                        let n = rng.below(pool.len() as u64) as usize;
+                        cmds.extend([pool[n].clone(), pool[n].clone()]);
+                    }
+                    2 => { cmds.extend([Not, Not, Not]); }
+                    _ => { cmds.push(pool[rng.below(pool.len() as u64) as usize].clone()); }
+                }
+            }
+            let asm = PietAsm::new(cmds, jump_counts);
+            sanitize(optimize(asm, MAX_OPT_LEVEL));
+        }
+    }
+}
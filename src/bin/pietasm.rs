@@ -1,5 +1,65 @@
-use piet_tools::PietCode;
+use piet_tools::asm::{GenerateReport, GeneratorOptions, MAX_OPT_LEVEL};
+use num_bigint::BigInt;
+use piet_tools::{ExitReason, LogTracer, PietCode, Progress, SaveOptions, Trace, Value};
 use std::env;
+use std::fs;
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+const PROGRESS_EVERY: u64 = 100_000;
+/// How long a `@TEST` case may run before it's considered stuck.
+const TEST_FUEL: u64 = 10_000_000;
+/// How many steps of a run `--npiet-trace` describes.
+const NPIET_TRACE_STEPS: u64 = 10_000;
+/// Image hosts tend to shrink or recompress images past about this size,
+/// often as lossy JPEG, which ruins a Piet program.
+const DEFAULT_MAX_DIMENSION: u64 = 5_000;
+const DEFAULT_MAX_BYTES: u64 = 5_000_000;
+
+/// How big a built image can be before it's worth warning about.
+struct HostingLimits {
+    max_dimension: u64,
+    max_bytes: u64,
+}
+
+impl Default for HostingLimits {
+    fn default() -> Self {
+        HostingLimits { max_dimension: DEFAULT_MAX_DIMENSION, max_bytes: DEFAULT_MAX_BYTES }
+    }
+}
+
+impl HostingLimits {
+    /// Warn about each way the saved image at `out_filename` is over the limits.
+    fn check(&self, out_filename: &str, piet: &PietCode, codel_size: u32, compact: bool) -> Result<(), String> {
+        let codel_size = u64::from(codel_size);
+        let (width, height) = (piet.width() as u64 * codel_size, piet.height() as u64 * codel_size);
+        if width.max(height) > self.max_dimension {
+            eprintln!(
+                "warning: {out_filename} is {width}x{height} pixels, over the limit of {}, \
+                    past which image hosts may shrink it; try a smaller codel size, or simplify the program's largest constants",
+                self.max_dimension,
+            );
+        }
+        let bytes = fs::metadata(out_filename).map_err(|e| e.to_string())?.len();
+        if bytes > self.max_bytes {
+            let suggestion = if compact {
+                "try a smaller codel size"
+            } else {
+                "try --compact"
+            };
+            eprintln!(
+                "warning: {out_filename} is {bytes} bytes, over the limit of {}, \
+                    past which image hosts may recompress it; {suggestion}",
+                self.max_bytes,
+            );
+        }
+        Ok(())
+    }
+}
+
+fn parse_limit(arg: &str, name: &str) -> Result<u64, String> {
+    arg.parse().map_err(|_| format!("{name} must be a non-negative integer"))
+}
 
 fn parse_codel_size(arg: &str) -> Result<u32, String> {
     let codel_size = arg.parse()
@@ -10,38 +70,366 @@ fn parse_codel_size(arg: &str) -> Result<u32, String> {
     Ok(codel_size)
 }
 
+fn spinner(unit: &'static str) -> Progress {
+    const FRAMES: [char; 4] = ['|', '/', '-', '\\'];
+    let frame = AtomicUsize::new(0);
+    Progress::new(move |done| {
+        let frame = frame.fetch_add(1, Ordering::Relaxed);
+        eprint!("\r{} {done} {unit}", FRAMES[frame % FRAMES.len()]);
+    })
+}
+
 fn parse_run_args(args: &[&str]) -> Result<(), String> {
-    let (filename, codel_size) = match args {
-        [f, c] => (f, c),
-        _ => { return Err("usage: pietasm run filename codel-size".to_string()); }
+    let usage = || "usage: pietasm run filename codel-size [--progress] [--strict] [--strict-mnemonics] [--stack-full]".to_string();
+    let (filename, codel_size, mut rest) = match args {
+        [f, c, rest @ ..] => (f, c, rest),
+        _ => { return Err(usage()); }
     };
+    let mut progress = false;
+    let mut strict = false;
+    let mut stack_full = false;
+    let mut options = GeneratorOptions::new();
+    loop {
+        rest = match rest {
+            [] => break,
+            ["--progress", rest @ ..] => {
+                progress = true;
+                rest
+            }
+            ["--strict", rest @ ..] => {
+                strict = true;
+                rest
+            }
+            ["--strict-mnemonics", rest @ ..] => {
+                options = options.strict_mnemonics();
+                rest
+            }
+            ["--stack-full", rest @ ..] => {
+                stack_full = true;
+                rest
+            }
+            _ => { return Err(usage()); }
+        };
+    }
 
     let codel_size = parse_codel_size(codel_size)?;
-    let (piet, _) = build(filename, codel_size)?;
-    piet.execute().run();
+    let (piet, _) = compile(filename, options, progress)?;
+    if strict {
+        piet.check_standard()?;
+    }
+    save(&piet, filename, SaveOptions::new(codel_size))?;
+    let log = if stack_full { LogTracer::new().full_stack() } else { LogTracer::new() };
+    let mut runner = piet.execute().tracer(log);
+    if progress {
+        runner = runner.progress(PROGRESS_EVERY, spinner("steps"));
+    }
+    if strict {
+        runner = runner.strict();
+    }
+    let reason = runner.run();
+    if progress {
+        eprintln!();
+    }
     println!();
+    if let ExitReason::Error(err) = &reason {
+        eprintln!("error: {err}");
+    }
+    process::exit(reason.exit_code());
+}
+
+fn parse_test_args(args: &[&str]) -> Result<(), String> {
+    let (filename, options) = match args {
+        [filename] => (filename, GeneratorOptions::new()),
+        [filename, "--strict-mnemonics"] => (filename, GeneratorOptions::new().strict_mnemonics()),
+        _ => { return Err("usage: pietasm test filename [--strict-mnemonics]".to_string()); }
+    };
+
+    let (piet, _) = compile(filename, options, false)?;
+    let tests = piet_tools::asm::load_tests(filename)?;
+    let mut failed = 0;
+    for case in &tests {
+        let result = piet.execute()
+            .fuel(TEST_FUEL)
+            .capture_io(case.input.as_str())
+            .run_collect();
+        let output = result.stdout_lossy();
+        let stack: Vec<BigInt> = result.stack.iter().map(Value::to_bigint).collect();
+        let failure = match result.reason {
+            ExitReason::Halted => match (&case.expect, &case.expect_stack) {
+                (Some(expect), _) if *expect != output => {
+                    Some(format!("expected output {expect:?}, got {output:?}"))
+                }
+                (_, Some(expect_stack)) if *expect_stack != stack => {
+                    Some(format!(
+                        "expected stack {}, got {}",
+                        format_stack(expect_stack), format_stack(&stack),
+                    ))
+                }
+                _ => None,
+            },
+            ExitReason::OutOfFuel => Some(format!("didn't halt within {TEST_FUEL} steps")),
+            reason => Some(format!("stopped unexpectedly: {reason:?}")),
+        };
+        match failure {
+            None => println!("line {}: ok", case.lineno),
+            Some(failure) => {
+                println!("line {}: FAILED: {failure}", case.lineno);
+                failed += 1;
+            }
+        }
+    }
+    println!("{} passed, {failed} failed", tests.len() - failed);
+    if failed > 0 {
+        return Err(format!("{failed} of {} tests failed", tests.len()));
+    }
     Ok(())
 }
 
+fn parse_ir_args(args: &[&str]) -> Result<(), String> {
+    let (filename, annotate) = match args {
+        [f] => (f, false),
+        [f, "--annotate"] => (f, true),
+        _ => { return Err("usage: pietasm ir filename [--annotate]".to_string()); }
+    };
+
+    let ir = piet_tools::asm::dump_ir(filename, &GeneratorOptions::new(), annotate)?;
+    print!("{ir}");
+    Ok(())
+}
+
+fn parse_size_args(args: &[&str]) -> Result<(), String> {
+    let [filename] = args else {
+        return Err("usage: pietasm size filename".to_string());
+    };
+
+    println!("{:>5} {:>8} {:>6} {:>6} {:>8}", "level", "commands", "width", "height", "codels");
+    for level in 0..=MAX_OPT_LEVEL {
+        let options = GeneratorOptions::new().opt_level(level);
+        let (_, report) = piet_tools::asm::load_with_report(filename, &options)?;
+        println!(
+            "{level:>5} {:>8} {:>6} {:>6} {:>8}",
+            report.commands, report.width, report.height, report.width * report.height,
+        );
+    }
+    Ok(())
+}
+
+fn parse_transpile_args(args: &[&str]) -> Result<(), String> {
+    let usage = || "usage: pietasm transpile filename --target (rust | c | wasm) [-o out-filename]".to_string();
+    let (filename, mut rest) = match args {
+        [f, rest @ ..] => (f, rest),
+        _ => { return Err(usage()); }
+    };
+    let mut target = None;
+    let mut out_filename = None;
+    loop {
+        rest = match rest {
+            [] => break,
+            ["--target", t, rest @ ..] => {
+                target = Some(*t);
+                rest
+            }
+            ["-o", o, rest @ ..] => {
+                out_filename = Some(o.to_string());
+                rest
+            }
+            _ => { return Err(usage()); }
+        };
+    }
+    let (transpile, extension): (fn(&PietCode) -> Vec<u8>, _) = match target {
+        Some("rust") => (|piet| piet_tools::transpile::to_rust(piet).into_bytes(), "rs"),
+        Some("c") => (|piet| piet_tools::transpile::to_c(piet).into_bytes(), "c"),
+        Some("wasm") => (piet_tools::transpile::to_wasm, "wasm"),
+        _ => { return Err(usage()); }
+    };
+
+    let (piet, _) = compile(filename, GeneratorOptions::new(), false)?;
+    let out_filename = out_filename.unwrap_or_else(|| format!("{filename}.{extension}"));
+    fs::write(&out_filename, transpile(&piet)).map_err(|e| e.to_string())?;
+    println!("File saved to {out_filename}");
+    Ok(())
+}
+
+fn format_stack(stack: &[BigInt]) -> String {
+    let terms: Vec<_> = stack.iter().map(|n| n.to_string()).collect();
+    format!("[{}]", terms.join(" "))
+}
+
 fn parse_build_args(args: &[&str]) -> Result<(), String> {
-    let (filename, codel_size) = match args {
-        [f, c] => (f, c),
-        _ => { return Err("usage: pietasm build filename codel-size".to_string()); }
+    let usage = || {
+        "usage: pietasm build filename codel-size \
+            [--previews size,...] [--progress] [--plan | --check-plan] [--npiet-trace] [--compact] \
+            [--max-dimension pixels] [--max-bytes bytes] [--strict-mnemonics] [--checked-branches] [--strict] \
+            [--profile-data trace.json | --fewer-steps]".to_string()
+    };
+    let (filename, codel_size, mut rest) = match args {
+        [f, c, rest @ ..] => (f, c, rest),
+        _ => { return Err(usage()); }
     };
+    let mut previews = None;
+    let mut progress = false;
+    let mut plan = None;
+    let mut npiet_trace = false;
+    let mut compact = false;
+    let mut strict = false;
+    let mut hosting = HostingLimits::default();
+    let mut options = GeneratorOptions::new();
+    loop {
+        rest = match rest {
+            [] => break,
+            ["--previews", p, rest @ ..] => {
+                previews = Some(p);
+                rest
+            }
+            ["--progress", rest @ ..] => {
+                progress = true;
+                rest
+            }
+            ["--npiet-trace", rest @ ..] => {
+                npiet_trace = true;
+                rest
+            }
+            ["--compact", rest @ ..] => {
+                compact = true;
+                rest
+            }
+            ["--max-dimension", n, rest @ ..] => {
+                hosting.max_dimension = parse_limit(n, "--max-dimension")?;
+                rest
+            }
+            ["--strict-mnemonics", rest @ ..] => {
+                options = options.strict_mnemonics();
+                rest
+            }
+            ["--checked-branches", rest @ ..] => {
+                options = options.checked_branches();
+                rest
+            }
+            ["--strict", rest @ ..] => {
+                strict = true;
+                rest
+            }
+            ["--max-bytes", n, rest @ ..] => {
+                hosting.max_bytes = parse_limit(n, "--max-bytes")?;
+                rest
+            }
+            ["--profile-data", p, rest @ ..] => {
+                let json = fs::read_to_string(p).map_err(|e| format!("{p}: {e}"))?;
+                options = options.profile(Trace::positions_from_json(&json).map_err(|e| format!("{p}: {e}"))?);
+                rest
+            }
+            ["--fewer-steps", rest @ ..] => {
+                options = options.fewer_steps();
+                rest
+            }
+            [flag @ ("--plan" | "--check-plan"), rest @ ..] if plan.is_none() => {
+                plan = Some(*flag);
+                rest
+            }
+            _ => { return Err(usage()); }
+        };
+    }
 
     let codel_size = parse_codel_size(codel_size)?;
-    let (_, out_filename) = build(filename, codel_size)?;
-
+    let previews = previews.map_or(Ok(Vec::new()), |p| {
+        p.split(',').map(parse_codel_size).collect()
+    })?;
+    let (piet, report) = compile(filename, options, progress)?;
+    if strict {
+        piet.check_standard()?;
+    }
+    println!(
+        "Laid out {} commands on {} rows ({}x{} codels), routing {} jumps (longest {} codels)",
+        report.commands, report.rows, report.width, report.height,
+        report.jumps, report.longest_jump,
+    );
+    if report.hot_loops > 0 {
+        println!("Kept {} hot loops to one row each", report.hot_loops);
+    }
+    let plan_filename = format!("{filename}.plan");
+    match plan {
+        Some("--plan") => {
+            fs::write(&plan_filename, report.plan(&piet)).map_err(|e| e.to_string())?;
+            println!("Plan saved to {plan_filename}");
+        }
+        Some(_) => check_plan(&plan_filename, &piet, &report)?,
+        None => (),
+    }
+    let save_options = |codel_size| {
+        let options = SaveOptions::new(codel_size);
+        if compact { options.compact() } else { options }
+    };
+    let out_filename = save(&piet, filename, save_options(codel_size))?;
     println!("File saved to {out_filename}");
+    hosting.check(&out_filename, &piet, codel_size, compact)?;
+    if npiet_trace {
+        // Trace a run on the first test case's input, if there is one.
+        let tests = piet_tools::asm::load_tests(filename)?;
+        let input = tests.first().map_or("", |case| case.input.as_str());
+        let trace_filename = format!("{filename}.npiet");
+        fs::write(&trace_filename, piet.npiet_trace(input.as_bytes(), NPIET_TRACE_STEPS))
+            .map_err(|e| e.to_string())?;
+        println!("npiet trace saved to {trace_filename}");
+    }
+
+    for preview_size in previews {
+        let preview_filename = format!("{filename}.{preview_size}x.png");
+        save_as(&piet, filename, &preview_filename, save_options(preview_size))?;
+        println!("Preview saved to {preview_filename}");
+        hosting.check(&preview_filename, &piet, preview_size, compact)?;
+    }
     Ok(())
 }
 
-fn build(filename: &str, codel_size: u32) -> Result<(PietCode, String), String> {
-    let piet = piet_tools::asm::load(filename)?;
+/// Fail if the layout differs from the one pinned in `plan_filename`.
+fn check_plan(plan_filename: &str, piet: &PietCode, report: &GenerateReport) -> Result<(), String> {
+    let expected = fs::read_to_string(plan_filename)
+        .map_err(|e| format!("couldn't read {plan_filename}: {e}"))?;
+    let actual = report.plan(piet);
+    let mut expected_lines = expected.lines();
+    let mut actual_lines = actual.lines();
+    for lineno in 1.. {
+        match (expected_lines.next(), actual_lines.next()) {
+            (None, None) => { return Ok(()); }
+            (a, b) if a == b => (),
+            (expected, actual) => {
+                return Err(format!(
+                    "layout differs from {plan_filename} at line {lineno}: expected {:?}, got {:?} \
+                        (rebuild with --plan to accept the new layout)",
+                    expected.unwrap_or("end of file"), actual.unwrap_or("end of file"),
+                ));
+            }
+        }
+    }
+    unreachable!()
+}
+
+fn compile(
+    filename: &str,
+    mut options: GeneratorOptions,
+    progress: bool,
+) -> Result<(PietCode, GenerateReport), String> {
+    if progress {
+        options = options.progress(spinner("commands laid out"));
+    }
+    let compiled = piet_tools::asm::load_with_report(filename, &options)?;
+    if progress {
+        eprintln!();
+    }
+    Ok(compiled)
+}
+
+/// Save the program built from `filename`, carrying any `@LIMIT` it declares.
+fn save(piet: &PietCode, filename: &str, options: SaveOptions) -> Result<String, String> {
     let out_filename = format!("{filename}.png");
-    piet_tools::save(&piet, &out_filename, codel_size)
-        .map_err(|e| e.to_string())?;
-    Ok((piet, out_filename))
+    save_as(piet, filename, &out_filename, options)?;
+    Ok(out_filename)
+}
+
+fn save_as(piet: &PietCode, filename: &str, out_filename: &str, options: SaveOptions) -> Result<(), String> {
+    let limits = piet_tools::asm::load_limits(filename)?.unwrap_or_default();
+    let input = piet_tools::asm::load_input_schema(filename)?;
+    piet_tools::save_with_options(piet, out_filename, &options.limits(limits).input(input))
 }
 
 fn main() -> Result<(), String> {
@@ -51,6 +439,10 @@ fn main() -> Result<(), String> {
     match args.as_slice() {
         [_, "build", rest @ ..] => parse_build_args(rest),
         [_, "run", rest @ ..] => parse_run_args(rest),
-        _ => Err("usage: pietasm [build | run] [args]".to_string()),
+        [_, "test", rest @ ..] => parse_test_args(rest),
+        [_, "size", rest @ ..] => parse_size_args(rest),
+        [_, "ir", rest @ ..] => parse_ir_args(rest),
+        [_, "transpile", rest @ ..] => parse_transpile_args(rest),
+        _ => Err("usage: pietasm [build | run | test | size | ir | transpile] [args]".to_string()),
     }
 }
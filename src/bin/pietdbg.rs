@@ -0,0 +1,151 @@
+use piet_tools::{Breakpoint, CodelCoord, ExitReason, PietRunner, StepResult};
+use std::env;
+use std::fs::File;
+use std::io::{self, BufRead, Read, Write};
+
+/// How many steps back `back` can go.
+const HISTORY: usize = 10_000;
+
+const USAGE: &str = "usage: pietdbg filename codel-size [--input filename]";
+
+const HELP: &str = "\
+commands:
+  step [n]      take n steps (default 1), ignoring breakpoints
+  back [n]      undo the last n steps (default 1)
+  continue      run until the program stops or hits a breakpoint
+  stack         show the stack, from bottom to top
+  break x y     pause when execution moves into codel (x, y)
+  delete x y    remove the breakpoint at codel (x, y)
+  where         show the step count, position, DP and CC, and top of the stack
+  help          show this message
+  quit          exit the debugger";
+
+fn parse_coord(x: &str, y: &str) -> Result<CodelCoord, String> {
+    let err = |_| "coordinates must be non-negative integers".to_string();
+    Ok(CodelCoord::new(x.parse().map_err(err)?, y.parse().map_err(err)?))
+}
+
+fn describe(result: &StepResult) -> String {
+    match result {
+        StepResult::Continue(executed) => {
+            format!("{:?} {} -> {}", executed.command, executed.from, executed.to)
+        }
+        StepResult::Error(executed, err) => {
+            format!("{:?} {} -> {}, skipped: {err}", executed.command, executed.from, executed.to)
+        }
+        StepResult::Halted => "halted".to_string(),
+        StepResult::TurnedPage(page) => format!("turned to page {page}"),
+    }
+}
+
+/// Run one debugger command, returning false once the session should end.
+fn run_command(runner: &mut PietRunner, halted: &mut bool, args: &[&str]) -> Result<bool, String> {
+    match args {
+        [] => (),
+        ["step" | "s"] | ["step" | "s", _] => {
+            let count = match args {
+                [_, n] => n.parse().map_err(|_| "step count must be an integer".to_string())?,
+                _ => 1,
+            };
+            for _ in 0..count {
+                if *halted {
+                    println!("the program has halted");
+                    break;
+                }
+                let result = runner.step();
+                *halted = matches!(result, StepResult::Halted);
+                println!("step {}: {}", runner.steps(), describe(&result));
+            }
+        }
+        ["back" | "bk"] | ["back" | "bk", _] => {
+            let count = match args {
+                [_, n] => n.parse().map_err(|_| "step count must be an integer".to_string())?,
+                _ => 1,
+            };
+            let undone = runner.step_back(count);
+            if undone > 0 {
+                *halted = false;
+            }
+            if undone < count {
+                println!("can only go back {undone} steps");
+            }
+            println!("{runner}");
+        }
+        ["continue" | "c"] => {
+            if *halted {
+                println!("the program has halted");
+                return Ok(true);
+            }
+            let reason = runner.run();
+            println!();
+            match reason {
+                ExitReason::Halted => {
+                    *halted = true;
+                    println!("halted after {} steps", runner.steps());
+                }
+                ExitReason::Breakpoint(Breakpoint::At(coord)) => {
+                    println!("breakpoint at {coord}, step {}", runner.steps());
+                }
+                reason => println!("stopped: {reason:?}"),
+            }
+        }
+        ["stack"] => {
+            let stack: Vec<_> = runner.stack().iter().map(|n| n.to_string()).collect();
+            println!("[{}]", stack.join(" "));
+        }
+        ["break" | "b", x, y] => {
+            runner.add_breakpoint(Breakpoint::At(parse_coord(x, y)?));
+        }
+        ["delete" | "d", x, y] => {
+            if !runner.remove_breakpoint(&Breakpoint::At(parse_coord(x, y)?)) {
+                println!("no breakpoint at ({x}, {y})");
+            }
+        }
+        ["where" | "w"] => println!("{runner}"),
+        ["help" | "h"] => println!("{HELP}"),
+        ["quit" | "q"] => { return Ok(false); }
+        _ => { return Err("unknown command; try `help`".to_string()); }
+    }
+    Ok(true)
+}
+
+fn main() -> Result<(), String> {
+    let owned_args: Vec<_> = env::args().collect();
+    let args: Vec<_> = owned_args.iter().map(|x| x.as_str()).collect();
+    let (filename, codel_size, input) = match args.as_slice() {
+        [_, f, c] => (f, c, None),
+        [_, f, c, "--input", i] => (f, c, Some(i)),
+        _ => { return Err(USAGE.to_string()); }
+    };
+    let codel_size = codel_size.parse()
+        .map_err(|_| "codel-size must be an integer".to_string())?;
+    if codel_size == 0 {
+        return Err("codel-size must be non-zero".to_string())
+    }
+    // The debugger's commands come from stdin, so the program's input can't.
+    let input: Box<dyn Read + Send> = match input {
+        Some(input) => Box::new(File::open(input).map_err(|e| e.to_string())?),
+        None => Box::new(io::empty()),
+    };
+
+    let piet = piet_tools::load(filename, codel_size)?;
+    let mut runner = piet.execute().io(input, io::stdout()).history(HISTORY);
+    let mut halted = false;
+    let mut lines = io::stdin().lock().lines();
+    loop {
+        print!("(pietdbg) ");
+        io::stdout().flush().map_err(|e| e.to_string())?;
+        let Some(line) = lines.next() else {
+            println!();
+            break;
+        };
+        let line = line.map_err(|e| e.to_string())?;
+        let args: Vec<_> = line.split_whitespace().collect();
+        match run_command(&mut runner, &mut halted, &args) {
+            Ok(true) => (),
+            Ok(false) => break,
+            Err(err) => println!("error: {err}"),
+        }
+    }
+    Ok(())
+}
@@ -1,20 +1,475 @@
+use image::ImageFormat;
+use piet_tools::{
+    Dialect, EofBehavior, ExitReason, FlushPolicy, InputMode, IntModel, IntWidth, LoadOptions, LogTracer, OtherColorPolicy, OverflowPolicy, Progress, RunLimits, Trace, ValueCap,
+    Visualizer,
+};
 use std::env;
+use std::fs;
+use std::io::{self, IsTerminal, Read};
+use std::os::fd::AsFd;
+use std::process;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, OnceLock};
+use std::time::Duration;
+
+const USAGE: &str = "usage: pieti filename codel-size \
+    [--offset x,y | --detect-margin] [--mask-alpha | --mask filename] \
+    [--frame index | --pages] [--fuel steps] [--timeout seconds] [--no-limit] [--no-input-check] [--strict] [--detect-loops] [--progress] [--stats] \
+    [--input-mode line|raw] [--eof error|-1|ignore] [--flush auto|always|manual] [--dialect npiet|pietdev] [--other error|white|black] [--max-bits bits] [--on-overflow error|saturate|wrap] [--int-model bignum|i32|i64] [--max-stack depth] [--stack-full] \
+    [--trace-html out-filename] [--trace-json out-filename] [--replay trace-filename] [--trace-gif out-filename] [--heatmap out-filename] [--visual [--speed steps-per-second]]
+usage: pieti explain filename codel-size \
+    [--offset x,y | --detect-margin] [--mask-alpha | --mask filename] [--frame index]";
+
+const PROGRESS_EVERY: u64 = 100_000;
+/// How many steps a second `--visual` shows, unless `--speed` says otherwise.
+const VISUAL_SPEED: f64 = 10.0;
+/// How often to check whether the program's stuck in a loop, in steps.
+const LOOP_CHECK_EVERY: u64 = 256;
+/// Warn about at most this many codels that look like mistaken Piet colors.
+const MAX_DIAGNOSTICS: usize = 10;
+
+fn parse_input_mode(arg: &str) -> Result<InputMode, String> {
+    match arg {
+        "line" => Ok(InputMode::Line),
+        "raw" => Ok(InputMode::Raw),
+        _ => Err("input mode must be line or raw".to_string()),
+    }
+}
+
+fn parse_eof_behavior(arg: &str) -> Result<EofBehavior, String> {
+    match arg {
+        "error" => Ok(EofBehavior::Error),
+        "-1" => Ok(EofBehavior::PushNegativeOne),
+        "ignore" => Ok(EofBehavior::Ignore),
+        _ => Err("eof must be error, -1, or ignore".to_string()),
+    }
+}
+
+fn parse_flush_policy(arg: &str) -> Result<FlushPolicy, String> {
+    match arg {
+        "auto" => Ok(FlushPolicy::Auto),
+        "always" => Ok(FlushPolicy::Always),
+        "manual" => Ok(FlushPolicy::Manual),
+        _ => Err("flush must be auto, always, or manual".to_string()),
+    }
+}
+
+fn parse_dialect(arg: &str) -> Result<Dialect, String> {
+    match arg {
+        "npiet" => Ok(Dialect::Npiet),
+        "pietdev" => Ok(Dialect::PietDev),
+        _ => Err("dialect must be npiet or pietdev".to_string()),
+    }
+}
+
+fn parse_other_color_policy(arg: &str) -> Result<OtherColorPolicy, String> {
+    match arg {
+        "error" => Ok(OtherColorPolicy::Error),
+        "white" => Ok(OtherColorPolicy::White),
+        "black" => Ok(OtherColorPolicy::Black),
+        _ => Err("other must be error, white, or black".to_string()),
+    }
+}
+
+fn parse_overflow_policy(arg: &str) -> Result<OverflowPolicy, String> {
+    match arg {
+        "error" => Ok(OverflowPolicy::Error),
+        "saturate" => Ok(OverflowPolicy::Saturate),
+        "wrap" => Ok(OverflowPolicy::Wrap),
+        _ => Err("on-overflow must be error, saturate, or wrap".to_string()),
+    }
+}
+
+fn parse_int_model(arg: &str) -> Result<IntModel, String> {
+    match arg {
+        "bignum" => Ok(IntModel::Unbounded),
+        "i32" => Ok(IntModel::Wrapping(IntWidth::I32)),
+        "i64" => Ok(IntModel::Wrapping(IntWidth::I64)),
+        _ => Err("int-model must be bignum, i32, or i64".to_string()),
+    }
+}
+
+fn parse_offset(arg: &str) -> Result<(u32, u32), String> {
+    let err = || "offset must be a pair of integers, eg 3,4".to_string();
+    let (x, y) = arg.split_once(',').ok_or_else(err)?;
+    let x = x.parse().map_err(|_| err())?;
+    let y = y.parse().map_err(|_| err())?;
+    Ok((x, y))
+}
+
+static INTERRUPTED: OnceLock<Arc<AtomicBool>> = OnceLock::new();
+
+extern "C" fn on_sigint(_: libc::c_int) {
+    let Some(flag) = INTERRUPTED.get() else { return; };
+    if flag.swap(true, Ordering::Relaxed) {
+        // Second Ctrl-C: the program isn't stepping (eg, it's waiting on input),
+        // so give up on stopping gracefully.
+        unsafe { libc::_exit(130); }
+    }
+}
+
+/// Raise the returned flag on Ctrl-C, so the run can stop and report where it got to.
+fn catch_sigint() -> Arc<AtomicBool> {
+    let flag = INTERRUPTED.get_or_init(|| Arc::new(AtomicBool::new(false)));
+    let handler: extern "C" fn(libc::c_int) = on_sigint;
+    unsafe { libc::signal(libc::SIGINT, handler as libc::sighandler_t); }
+    flag.clone()
+}
+
+/// Whether stdin is redirected from a file, so all of it can be read up front.
+fn stdin_is_file() -> bool {
+    io::stdin().as_fd().try_clone_to_owned()
+        .and_then(|fd| fs::File::from(fd).metadata())
+        .is_ok_and(|metadata| metadata.is_file())
+}
+
+fn spinner() -> Progress {
+    const FRAMES: [char; 4] = ['|', '/', '-', '\\'];
+    let frame = AtomicUsize::new(0);
+    Progress::new(move |steps| {
+        let frame = frame.fetch_add(1, Ordering::Relaxed);
+        eprint!("\r{} {steps} steps", FRAMES[frame % FRAMES.len()]);
+    })
+}
 
 fn main() -> Result<(), String> {
     env_logger::init();
-    let args = env::args().collect::<Vec<_>>();
-    let (filename, codel_size) = match args.as_slice() {
-        [_, f, c] => (f, c),
-        _ => { return Err("usage: pieti filename codel-size".to_string()); },
+    let owned_args: Vec<_> = env::args().collect();
+    let args: Vec<_> = owned_args.iter().map(|x| x.as_str()).collect();
+    let (explain, filename, codel_size, mut rest) = match args.as_slice() {
+        [_, "explain", f, c, rest @ ..] => (true, f, c, rest),
+        [_, f, c, rest @ ..] => (false, f, c, rest),
+        _ => { return Err(USAGE.to_string()); },
     };
     let codel_size = codel_size.parse()
         .map_err(|_| "codel-size must be an integer".to_string())?;
     if codel_size == 0 {
         return Err("codel-size must be non-zero".to_string())
     }
+    let mut options = LoadOptions::new(codel_size);
+    let mut fuel = None;
+    let mut timeout = None;
+    let mut strict = false;
+    let mut use_limits = true;
+    let mut check_input = true;
+    let mut detect_loops = false;
+    let mut progress = false;
+    let mut stats = false;
+    let mut input_mode = InputMode::Line;
+    let mut eof = EofBehavior::Error;
+    let mut flush = FlushPolicy::Auto;
+    let mut dialect = Dialect::Npiet;
+    let mut other = OtherColorPolicy::Error;
+    let mut max_bits = None;
+    let mut on_overflow = OverflowPolicy::Error;
+    let mut int_model = None;
+    let mut max_stack = None;
+    let mut stack_full = false;
+    let mut trace_html = None;
+    let mut trace_json = None;
+    let mut replay = None;
+    let mut trace_gif = None;
+    let mut heatmap = None;
+    let mut visual = false;
+    let mut speed = None;
+    let mut pages = false;
+    loop {
+        rest = match rest {
+            [] => break,
+            ["--offset", offset, rest @ ..] => {
+                let (x, y) = parse_offset(offset)?;
+                options = options.offset(x, y);
+                rest
+            }
+            ["--detect-margin", rest @ ..] => {
+                options = options.detect_margin();
+                rest
+            }
+            ["--mask-alpha", rest @ ..] => {
+                options = options.mask_alpha();
+                rest
+            }
+            ["--mask", mask, rest @ ..] => {
+                options = options.mask_image(mask);
+                rest
+            }
+            ["--frame", frame, rest @ ..] => {
+                options = options.frame(frame.parse().map_err(|_| "frame must be an integer".to_string())?);
+                rest
+            }
+            ["--pages", rest @ ..] if !explain => {
+                pages = true;
+                rest
+            }
+            ["--fuel", steps, rest @ ..] if !explain => {
+                fuel = Some(steps.parse().map_err(|_| "fuel must be an integer".to_string())?);
+                rest
+            }
+            ["--timeout", seconds, rest @ ..] if !explain => {
+                let seconds = seconds.parse().ok()
+                    .and_then(|seconds| Duration::try_from_secs_f64(seconds).ok())
+                    .ok_or_else(|| "timeout must be a non-negative number of seconds".to_string())?;
+                timeout = Some(seconds);
+                rest
+            }
+            ["--no-limit", rest @ ..] if !explain => {
+                use_limits = false;
+                rest
+            }
+            ["--no-input-check", rest @ ..] if !explain => {
+                check_input = false;
+                rest
+            }
+            ["--strict", rest @ ..] if !explain => {
+                strict = true;
+                rest
+            }
+            ["--detect-loops", rest @ ..] if !explain => {
+                detect_loops = true;
+                rest
+            }
+            ["--progress", rest @ ..] if !explain => {
+                progress = true;
+                rest
+            }
+            ["--stats", rest @ ..] if !explain => {
+                stats = true;
+                rest
+            }
+            ["--input-mode", mode, rest @ ..] if !explain => {
+                input_mode = parse_input_mode(mode)?;
+                rest
+            }
+            ["--eof", behavior, rest @ ..] if !explain => {
+                eof = parse_eof_behavior(behavior)?;
+                rest
+            }
+            ["--flush", policy, rest @ ..] if !explain => {
+                flush = parse_flush_policy(policy)?;
+                rest
+            }
+            ["--dialect", name, rest @ ..] if !explain => {
+                dialect = parse_dialect(name)?;
+                rest
+            }
+            ["--other", policy, rest @ ..] if !explain => {
+                other = parse_other_color_policy(policy)?;
+                rest
+            }
+            ["--max-bits", bits, rest @ ..] if !explain => {
+                let bits = bits.parse().ok().filter(|&bits| bits > 0)
+                    .ok_or_else(|| "max-bits must be a positive integer".to_string())?;
+                max_bits = Some(bits);
+                rest
+            }
+            ["--on-overflow", policy, rest @ ..] if !explain => {
+                on_overflow = parse_overflow_policy(policy)?;
+                rest
+            }
+            ["--int-model", model, rest @ ..] if !explain => {
+                int_model = Some(parse_int_model(model)?);
+                rest
+            }
+            ["--stack-full", rest @ ..] if !explain => {
+                stack_full = true;
+                rest
+            }
+            ["--max-stack", depth, rest @ ..] if !explain => {
+                max_stack = Some(depth.parse().map_err(|_| "max-stack must be an integer".to_string())?);
+                rest
+            }
+            ["--trace-html", out, rest @ ..] if !explain => {
+                trace_html = Some(out);
+                rest
+            }
+            ["--trace-json", out, rest @ ..] if !explain => {
+                trace_json = Some(out);
+                rest
+            }
+            ["--replay", trace, rest @ ..] if !explain => {
+                replay = Some(trace);
+                rest
+            }
+            ["--trace-gif", out, rest @ ..] if !explain => {
+                trace_gif = Some(out);
+                rest
+            }
+            ["--heatmap", out, rest @ ..] if !explain => {
+                heatmap = Some(out);
+                rest
+            }
+            ["--visual", rest @ ..] if !explain => {
+                visual = true;
+                rest
+            }
+            ["--speed", steps, rest @ ..] if !explain => {
+                let steps = steps.parse().ok().filter(|&steps: &f64| steps > 0.0 && steps.is_finite())
+                    .ok_or_else(|| "speed must be a positive number of steps a second".to_string())?;
+                speed = Some(steps);
+                rest
+            }
+            _ => { return Err(USAGE.to_string()); }
+        };
+    }
 
-    let piet = piet_tools::load(filename, codel_size)?;
-    piet.execute().run();
+    let (piet, mut diagnostics) = piet_tools::load_with_diagnostics(filename, &options)?;
+    diagnostics.retain(|diagnostic| diagnostic.is_likely_mistake());
+    for diagnostic in diagnostics.iter().take(MAX_DIAGNOSTICS) {
+        eprintln!("warning: {diagnostic}");
+    }
+    if diagnostics.len() > MAX_DIAGNOSTICS {
+        let more = diagnostics.len() - MAX_DIAGNOSTICS;
+        eprintln!("warning: ...and {more} more codels that are nearly Piet colors");
+    }
+    if explain {
+        for line in piet.explain() {
+            println!("{line}");
+        }
+        return Ok(());
+    }
+    if strict && other != OtherColorPolicy::Error {
+        return Err("--other can't be used with --strict, which refuses codels that aren't Piet colors".to_string());
+    }
+    if max_bits.is_some() && int_model.is_some() {
+        return Err("--max-bits and --int-model can't be used together".to_string());
+    }
+    if pages && trace_html.is_some() {
+        return Err("--trace-html can't show more than one page".to_string());
+    }
+    if pages && trace_gif.is_some() {
+        return Err("--trace-gif can't show more than one page".to_string());
+    }
+    if pages && heatmap.is_some() {
+        return Err("--heatmap can't show more than one page".to_string());
+    }
+    if pages && visual {
+        return Err("--visual can't show more than one page".to_string());
+    }
+    if speed.is_some() && !visual {
+        return Err("--speed only applies to --visual".to_string());
+    }
+    // Honor the budget the program's author recommends, unless overridden.
+    let limits = match ImageFormat::from_path(filename) {
+        Ok(ImageFormat::Png) if use_limits => piet_tools::read_limits(filename)?.unwrap_or_default(),
+        _ => RunLimits::default(),
+    };
+    let input_schema = match ImageFormat::from_path(filename) {
+        Ok(ImageFormat::Png) => piet_tools::read_input_schema(filename)?,
+        _ => None,
+    };
+    let fuel_limited = fuel.is_none() && limits.steps.is_some();
+    let fuel = fuel.or(limits.steps);
+    let timeout = timeout.or(limits.time);
+    let pages = if pages { piet_tools::load_frames(filename, &options)? } else { vec![piet] };
+    if strict {
+        for (i, page) in pages.iter().enumerate() {
+            page.check_standard().map_err(|e| match pages.len() {
+                1 => e,
+                _ => format!("page {i}: {e}"),
+            })?;
+        }
+    }
+    let mut trace = Trace::new();
+    let tracing = trace_html.is_some() || trace_json.is_some() || trace_gif.is_some() || heatmap.is_some();
+    let mut visualizer = visual.then(|| {
+        let delay = Duration::from_secs_f64(1.0 / speed.unwrap_or(VISUAL_SPEED));
+        // Start from a clear screen, as each frame only clears what it draws over.
+        eprint!("\x1b[2J");
+        Visualizer::new(&pages[0], io::stderr(), delay)
+    });
+    let mut runner = piet_tools::execute_pages(&pages)
+        .interrupt_on(catch_sigint())
+        .input_mode(input_mode)
+        .eof_behavior(eof)
+        .flush_policy(flush)
+        .dialect(dialect)
+        .other_colors(other);
+    if let Some(schema) = input_schema.filter(|_| check_input) {
+        if io::stdin().is_terminal() {
+            eprintln!("This program reads input: {schema}");
+        } else if stdin_is_file() {
+            // Check redirected input up front, rather than leave the program stuck partway.
+            // A pipe is left alone, as it may still be being written to as the program reads it.
+            let mut input = Vec::new();
+            io::stdin().read_to_end(&mut input).map_err(|e| e.to_string())?;
+            schema.validate(&input)
+                .map_err(|e| format!("input doesn't match what the program reads ({schema}): {e}"))?;
+            runner = runner.io(io::Cursor::new(input), io::BufWriter::new(io::stdout()));
+        }
+    }
+    let log = if stack_full { LogTracer::new().full_stack() } else { LogTracer::new() };
+    runner = match (&mut visualizer, tracing) {
+        (Some(visualizer), true) => runner.tracer((log, (&mut trace, visualizer))),
+        (Some(visualizer), false) => runner.tracer((log, visualizer)),
+        (None, true) => runner.tracer((log, &mut trace)),
+        (None, false) => runner.tracer(log),
+    };
+    if let Some(fuel) = fuel {
+        runner = runner.fuel(fuel);
+    }
+    if strict {
+        runner = runner.strict();
+    }
+    if detect_loops {
+        runner = runner.detect_loops(LOOP_CHECK_EVERY);
+    }
+    if let Some(bits) = max_bits {
+        runner = runner.value_cap(ValueCap::new(bits, on_overflow));
+    }
+    if let Some(model) = int_model {
+        runner = runner.int_model(model);
+    }
+    if let Some(depth) = max_stack {
+        runner = runner.max_stack(depth);
+    }
+    if progress {
+        runner = runner.progress(PROGRESS_EVERY, spinner());
+    }
+    if stats {
+        runner = runner.collect_stats();
+    }
+    if let Some(filename) = replay {
+        let json = fs::read_to_string(filename).map_err(|e| e.to_string())?;
+        let expected = Trace::from_json(&json).map_err(|e| format!("{filename}: {e}"))?;
+        runner.replay(&expected).map_err(|divergence| format!("the run doesn't match {filename}: {divergence}"))?;
+    }
+    let reason = match timeout {
+        Some(timeout) => runner.run_with_limits(u64::MAX, timeout),
+        None => runner.run(),
+    };
+    if progress {
+        eprintln!();
+    }
     println!();
-    Ok(())
+    match &reason {
+        ExitReason::Halted => (),
+        ExitReason::Error(err) => eprintln!("error: {err}"),
+        ExitReason::OutOfFuel if fuel_limited => eprintln!(
+            "out of fuel after {} steps, the limit the program recommends (--no-limit to run on)",
+            runner.steps(),
+        ),
+        ExitReason::OutOfFuel => eprintln!("out of fuel after {} steps", runner.steps()),
+        ExitReason::LoopDetected => eprintln!("infinite loop detected"),
+        ExitReason::TimedOut => eprintln!("timed out after {} steps", runner.steps()),
+        ExitReason::Breakpoint(breakpoint) => eprintln!("paused at {breakpoint:?}"),
+        ExitReason::Interrupted => eprintln!("interrupted at {runner}"),
+    }
+    if let Some(stats) = runner.stats() {
+        eprintln!("{stats}");
+    }
+    drop(runner);
+    if let Some(out) = trace_html {
+        fs::write(out, trace.to_html(&pages[0])).map_err(|e| e.to_string())?;
+    }
+    if let Some(out) = trace_json {
+        fs::write(out, trace.to_json()).map_err(|e| e.to_string())?;
+    }
+    if let Some(out) = trace_gif {
+        let gif = trace.to_gif(&pages[0]).map_err(|e| e.to_string())?;
+        fs::write(out, gif).map_err(|e| e.to_string())?;
+    }
+    if let Some(out) = heatmap {
+        trace.to_heatmap(&pages[0], codel_size).save(out).map_err(|e| e.to_string())?;
+    }
+    process::exit(reason.exit_code());
 }
@@ -0,0 +1,425 @@
+use image::ImageFormat;
+use piet_tools::ExitReason;
+use std::env;
+use std::fs;
+use std::io::{Read, Write};
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+fn parse_codel_size(arg: &str) -> Result<u32, String> {
+    let codel_size = arg.parse()
+        .map_err(|_| "codel-size must be an integer".to_string())?;
+    if codel_size == 0 {
+        return Err("codel-size must be non-zero".to_string())
+    }
+    Ok(codel_size)
+}
+
+fn parse_rescale_args(args: &[&str]) -> Result<(), String> {
+    let (filename, from, to, out_filename) = match args {
+        [f, "--from", from, "--to", to] => (f, from, to, None),
+        [f, "--from", from, "--to", to, "-o", o] => (f, from, to, Some(o.to_string())),
+        _ => {
+            return Err(
+                "usage: pietimg rescale filename --from codel-size --to codel-size [-o out-filename]"
+                    .to_string()
+            );
+        }
+    };
+    let from = parse_codel_size(from)?;
+    let to = parse_codel_size(to)?;
+    let out_filename = out_filename.unwrap_or_else(|| {
+        Path::new(filename)
+            .with_extension(format!("{to}x.png"))
+            .to_string_lossy()
+            .into_owned()
+    });
+
+    let img = image::open(filename).map_err(|e| e.to_string())?;
+    let img = piet_tools::rescale(&img, from, to)?;
+    img.save(&out_filename).map_err(|e| e.to_string())?;
+    println!("File saved to {out_filename}");
+    Ok(())
+}
+
+fn parse_clean_args(args: &[&str]) -> Result<(), String> {
+    let (filename, out_filename, codel_size) = match args {
+        [f, "-o", o, "--codel-size", c] | [f, "--codel-size", c, "-o", o] => (f, o, c),
+        _ => {
+            return Err(
+                "usage: pietimg clean filename -o out-filename --codel-size codel-size"
+                    .to_string()
+            );
+        }
+    };
+    let codel_size = parse_codel_size(codel_size)?;
+
+    let img = image::open(filename).map_err(|e| e.to_string())?;
+    let cleaned = piet_tools::clean(&img, codel_size)?;
+    for coord in &cleaned.uncertain {
+        eprintln!("warning: codel {coord} is ambiguous; double check it");
+    }
+    cleaned.image.save(out_filename).map_err(|e| e.to_string())?;
+    println!("File saved to {out_filename}");
+    Ok(())
+}
+
+fn parse_fingerprint_args(args: &[&str]) -> Result<(), String> {
+    let [filename, codel_size] = args else {
+        return Err("usage: pietimg fingerprint filename codel-size".to_string());
+    };
+    let code = piet_tools::load(filename, parse_codel_size(codel_size)?)?;
+    println!("{:016x}", code.fingerprint());
+    Ok(())
+}
+
+fn parse_compare_args(args: &[&str]) -> Result<(), String> {
+    let [a, a_codel_size, b, b_codel_size] = args else {
+        return Err(
+            "usage: pietimg compare filename codel-size other-filename other-codel-size"
+                .to_string()
+        );
+    };
+    let a = piet_tools::load(a, parse_codel_size(a_codel_size)?)?;
+    let b = piet_tools::load(b, parse_codel_size(b_codel_size)?)?;
+    if a.fingerprint() != b.fingerprint() {
+        return Err("programs differ".to_string());
+    }
+    println!("programs match");
+    Ok(())
+}
+
+fn parse_decompile_args(args: &[&str]) -> Result<(), String> {
+    let (filename, codel_size, out_filename) = match args {
+        [f, c] => (f, c, None),
+        [f, c, "-o", o] => (f, c, Some(o)),
+        _ => {
+            return Err("usage: pietimg decompile filename codel-size [-o out-filename]".to_string());
+        }
+    };
+    let code = piet_tools::load(filename, parse_codel_size(codel_size)?)?;
+    let pasm = piet_tools::transpile::to_pasm(&code);
+    match out_filename {
+        Some(out_filename) => {
+            fs::write(out_filename, pasm).map_err(|e| e.to_string())?;
+            println!("File saved to {out_filename}");
+        }
+        None => { print!("{pasm}"); }
+    }
+    Ok(())
+}
+
+fn parse_link_args(args: &[&str]) -> Result<(), String> {
+    let (a, a_codel_size, b, b_codel_size, out_filename, codel_size) = match args {
+        [a, ac, b, bc, "-o", o] => (a, ac, b, bc, o, None),
+        [a, ac, b, bc, "-o", o, "--codel-size", c] => (a, ac, b, bc, o, Some(c)),
+        _ => {
+            return Err(
+                "usage: pietimg link filename codel-size other-filename other-codel-size -o out-filename [--codel-size codel-size]"
+                    .to_string()
+            );
+        }
+    };
+    let codel_size = codel_size.map_or(Ok(1), |c| parse_codel_size(c))?;
+    let a = piet_tools::load(a, parse_codel_size(a_codel_size)?)?;
+    let b = piet_tools::load(b, parse_codel_size(b_codel_size)?)?;
+    let linked = a.link(&b)?;
+    for warning in &linked.warnings {
+        eprintln!("warning: {warning}");
+    }
+    piet_tools::save(&linked.code, out_filename, codel_size).map_err(|e| e.to_string())?;
+    println!("File saved to {out_filename}");
+    Ok(())
+}
+
+/// How many steps of each program `conformance` runs here.
+const CONFORMANCE_FUEL: u64 = 10_000_000;
+/// How long `conformance` lets the other interpreter run each program.
+const CONFORMANCE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Run `command` with `input` on stdin, returning its stdout, or None if it
+/// doesn't finish within `CONFORMANCE_TIMEOUT`.
+fn run_external(command: &[String], input: &[u8]) -> Result<Option<Vec<u8>>, String> {
+    let mut child = Command::new(&command[0])
+        .args(&command[1..])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .map_err(|e| format!("couldn't run {}: {e}", command[0]))?;
+    let mut stdin = child.stdin.take().unwrap();
+    let input = input.to_vec();
+    // Feed input and collect output on other threads, so neither pipe can fill up and stall.
+    let writer = thread::spawn(move || stdin.write_all(&input));
+    let mut stdout = child.stdout.take().unwrap();
+    let reader = thread::spawn(move || {
+        let mut output = Vec::new();
+        stdout.read_to_end(&mut output).map(|_| output)
+    });
+    let deadline = Instant::now() + CONFORMANCE_TIMEOUT;
+    while child.try_wait().map_err(|e| e.to_string())?.is_none() {
+        if Instant::now() >= deadline {
+            child.kill().map_err(|e| e.to_string())?;
+            child.wait().map_err(|e| e.to_string())?;
+            return Ok(None);
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    // The program needn't have read all its input.
+    let _ = writer.join();
+    let output = reader.join().unwrap().map_err(|e| e.to_string())?;
+    Ok(Some(output))
+}
+
+fn parse_conformance_args(args: &[&str]) -> Result<(), String> {
+    let usage = || {
+        "usage: pietimg conformance directory --interpreter command [--codel-size codel-size] [--input filename] [-o out-filename]"
+            .to_string()
+    };
+    let (dir, mut rest) = match args {
+        [d, rest @ ..] => (d, rest),
+        _ => { return Err(usage()); }
+    };
+    let mut interpreter = None;
+    let mut codel_size = None;
+    let mut input = Vec::new();
+    let mut out_filename = None;
+    loop {
+        rest = match rest {
+            [] => break,
+            ["--interpreter", i, rest @ ..] => {
+                interpreter = Some(*i);
+                rest
+            }
+            ["--codel-size", c, rest @ ..] => {
+                codel_size = Some(parse_codel_size(c)?);
+                rest
+            }
+            ["--input", i, rest @ ..] => {
+                input = fs::read(i).map_err(|e| e.to_string())?;
+                rest
+            }
+            ["-o", o, rest @ ..] => {
+                out_filename = Some(*o);
+                rest
+            }
+            _ => { return Err(usage()); }
+        };
+    }
+    let interpreter = interpreter.ok_or_else(usage)?;
+    if interpreter.split_whitespace().next().is_none() {
+        return Err("the interpreter command is empty".to_string());
+    }
+
+    let mut paths: Vec<_> = fs::read_dir(dir)
+        .map_err(|e| e.to_string())?
+        .map(|entry| entry.map(|e| e.path()))
+        .collect::<Result<_, _>>()
+        .map_err(|e| e.to_string())?;
+    paths.retain(|path| path.is_file() && ImageFormat::from_path(path).is_ok());
+    paths.sort();
+
+    let mut report = String::new();
+    let (mut passed, mut failed, mut skipped) = (0, 0, 0);
+    for path in &paths {
+        let filename = path.to_string_lossy();
+        let img = match image::open(path) {
+            Ok(img) => img,
+            Err(e) => {
+                skipped += 1;
+                report.push_str(&format!("SKIP {filename}: {e}\n"));
+                continue;
+            }
+        };
+        let codel_size = codel_size.unwrap_or_else(|| piet_tools::guess_codel_size(&img));
+        let ours = match piet_tools::load(&filename, codel_size) {
+            Ok(code) => {
+                let result = code.execute().fuel(CONFORMANCE_FUEL).capture_io(input.as_slice()).run_collect();
+                match result.reason {
+                    ExitReason::OutOfFuel => None,
+                    _ => Some(result.stdout),
+                }
+            }
+            Err(e) => {
+                skipped += 1;
+                report.push_str(&format!("SKIP {filename}: {e}\n"));
+                continue;
+            }
+        };
+        // `{file}` and `{codel_size}` are filled in; without `{file}`, it goes last.
+        let mut command: Vec<_> = interpreter.split_whitespace()
+            .map(|arg| arg.replace("{file}", &filename).replace("{codel_size}", &codel_size.to_string()))
+            .collect();
+        if !interpreter.contains("{file}") {
+            command.push(filename.to_string());
+        }
+        let theirs = run_external(&command, &input)?;
+        let describe = |output: &Option<Vec<u8>>| match output {
+            Some(output) => format!("{:?}", String::from_utf8_lossy(output)),
+            None => "no output: didn't halt in time".to_string(),
+        };
+        if ours == theirs {
+            passed += 1;
+            report.push_str(&format!("PASS {filename}\n"));
+        } else {
+            failed += 1;
+            report.push_str(&format!(
+                "FAIL {filename}\n  ours:   {}\n  theirs: {}\n",
+                describe(&ours), describe(&theirs),
+            ));
+        }
+    }
+    report.push_str(&format!("{passed} agreed, {failed} differed, {skipped} skipped\n"));
+    match out_filename {
+        Some(out) => {
+            fs::write(out, &report).map_err(|e| e.to_string())?;
+            println!("Report saved to {out}");
+        }
+        None => print!("{report}"),
+    }
+    if failed > 0 {
+        return Err(format!("{failed} of {} programs differed", paths.len()));
+    }
+    Ok(())
+}
+
+fn json_string(s: &str) -> String {
+    let mut out = String::from('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            c if c.is_control() => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// Describe a single image as a JSON object.
+fn gallery_entry(
+    path: &Path,
+    codel_size: Option<u32>,
+    previews: Option<(&Path, u32)>,
+) -> Result<String, String> {
+    let filename = path.to_string_lossy();
+    let mut fields = vec![("file", json_string(&filename))];
+    let img = match image::open(path) {
+        Ok(img) => img,
+        Err(e) => {
+            fields.push(("parses", "false".to_string()));
+            fields.push(("error", json_string(&e.to_string())));
+            return Ok(format_entry(fields));
+        }
+    };
+    let codel_size = codel_size.unwrap_or_else(|| piet_tools::guess_codel_size(&img));
+    fields.push(("image_width", img.width().to_string()));
+    fields.push(("image_height", img.height().to_string()));
+    fields.push(("codel_size", codel_size.to_string()));
+    match piet_tools::load(&filename, codel_size) {
+        Ok(code) => {
+            fields.push(("parses", "true".to_string()));
+            fields.push(("width", code.width().to_string()));
+            fields.push(("height", code.height().to_string()));
+            fields.push(("fingerprint", json_string(&format!("{:016x}", code.fingerprint()))));
+            if let Some((dir, preview_size)) = previews {
+                let name = path.file_name().unwrap().to_string_lossy();
+                let preview = dir.join(format!("{name}.{preview_size}x.png"));
+                piet_tools::save(&code, &preview.to_string_lossy(), preview_size)
+                    .map_err(|e| e.to_string())?;
+                fields.push(("preview", json_string(&preview.to_string_lossy())));
+            }
+        }
+        Err(e) => {
+            fields.push(("parses", "false".to_string()));
+            fields.push(("error", json_string(&e)));
+        }
+    }
+    if matches!(ImageFormat::from_path(path), Ok(ImageFormat::Png)) {
+        let text = piet_tools::read_png_text(&filename)?;
+        let text: Vec<_> = text.iter()
+            .map(|(k, v)| format!("{}: {}", json_string(k), json_string(v)))
+            .collect();
+        fields.push(("metadata", format!("{{{}}}", text.join(", "))));
+    }
+    Ok(format_entry(fields))
+}
+
+fn format_entry(fields: Vec<(&str, String)>) -> String {
+    let fields: Vec<_> = fields.into_iter()
+        .map(|(k, v)| format!("    {}: {v}", json_string(k)))
+        .collect();
+    format!("  {{\n{}\n  }}", fields.join(",\n"))
+}
+
+fn parse_gallery_args(args: &[&str]) -> Result<(), String> {
+    let usage = || {
+        "usage: pietimg gallery directory -o out-filename [--codel-size codel-size] [--previews codel-size]"
+            .to_string()
+    };
+    let (dir, out_filename, mut rest) = match args {
+        [d, "-o", o, rest @ ..] => (d, o, rest),
+        _ => { return Err(usage()); }
+    };
+    let mut codel_size = None;
+    let mut preview_size = None;
+    loop {
+        rest = match rest {
+            [] => break,
+            ["--codel-size", c, rest @ ..] => {
+                codel_size = Some(parse_codel_size(c)?);
+                rest
+            }
+            ["--previews", p, rest @ ..] => {
+                preview_size = Some(parse_codel_size(p)?);
+                rest
+            }
+            _ => { return Err(usage()); }
+        };
+    }
+
+    let preview_dir = Path::new(out_filename).with_file_name("previews");
+    let previews = match preview_size {
+        Some(size) => {
+            fs::create_dir_all(&preview_dir).map_err(|e| e.to_string())?;
+            Some((preview_dir.as_path(), size))
+        }
+        None => None,
+    };
+    let mut paths: Vec<_> = fs::read_dir(dir)
+        .map_err(|e| e.to_string())?
+        .map(|entry| entry.map(|e| e.path()))
+        .collect::<Result<_, _>>()
+        .map_err(|e| e.to_string())?;
+    paths.retain(|path| path.is_file() && ImageFormat::from_path(path).is_ok());
+    paths.sort();
+
+    let entries = paths.iter()
+        .map(|path| gallery_entry(path, codel_size, previews))
+        .collect::<Result<Vec<_>, _>>()?;
+    let json = format!("[\n{}\n]\n", entries.join(",\n"));
+    fs::write(out_filename, json).map_err(|e| e.to_string())?;
+    println!("Gallery of {} images saved to {out_filename}", entries.len());
+    Ok(())
+}
+
+fn main() -> Result<(), String> {
+    env_logger::init();
+    let owned_args: Vec<_> = env::args().collect();
+    let args: Vec<_> = owned_args.iter().map(|x| x.as_str()).collect();
+    match args.as_slice() {
+        [_, "rescale", rest @ ..] => parse_rescale_args(rest),
+        [_, "clean", rest @ ..] => parse_clean_args(rest),
+        [_, "fingerprint", rest @ ..] => parse_fingerprint_args(rest),
+        [_, "compare", rest @ ..] => parse_compare_args(rest),
+        [_, "gallery", rest @ ..] => parse_gallery_args(rest),
+        [_, "link", rest @ ..] => parse_link_args(rest),
+        [_, "decompile", rest @ ..] => parse_decompile_args(rest),
+        [_, "conformance", rest @ ..] => parse_conformance_args(rest),
+        _ => Err("usage: pietimg [rescale|clean|fingerprint|compare|gallery|link|decompile|conformance] [args]".to_string()),
+    }
+}
@@ -0,0 +1,95 @@
+use std::fmt;
+
+/// A position on the codel grid.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug, Default)]
+pub struct CodelCoord {
+    pub x: usize,
+    pub y: usize,
+}
+
+/// A position within an image, in pixels.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug, Default)]
+pub struct PixelCoord {
+    pub x: u32,
+    pub y: u32,
+}
+
+/// A signed offset between two codel coordinates.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub struct Delta {
+    pub dx: isize,
+    pub dy: isize,
+}
+
+impl CodelCoord {
+    pub fn new(x: usize, y: usize) -> Self {
+        CodelCoord { x, y }
+    }
+
+    /// Offset by `delta`, or None if that would leave the top or left of the grid.
+    /// (The bottom and right depend on the grid, so are left to it.)
+    pub fn checked_add(self, delta: Delta) -> Option<CodelCoord> {
+        Some(CodelCoord {
+            x: self.x.checked_add_signed(delta.dx)?,
+            y: self.y.checked_add_signed(delta.dy)?,
+        })
+    }
+
+    /// The top-left pixel of this codel.
+    pub fn to_pixel(self, codel_size: u32, origin: PixelCoord) -> PixelCoord {
+        PixelCoord {
+            x: origin.x + self.x as u32 * codel_size,
+            y: origin.y + self.y as u32 * codel_size,
+        }
+    }
+}
+
+impl PixelCoord {
+    pub fn new(x: u32, y: u32) -> Self {
+        PixelCoord { x, y }
+    }
+}
+
+impl Delta {
+    pub const RIGHT: Delta = Delta { dx: 1, dy: 0 };
+    pub const DOWN: Delta = Delta { dx: 0, dy: 1 };
+    pub const LEFT: Delta = Delta { dx: -1, dy: 0 };
+    pub const UP: Delta = Delta { dx: 0, dy: -1 };
+
+    pub fn new(dx: isize, dy: isize) -> Self {
+        Delta { dx, dy }
+    }
+}
+
+impl fmt::Display for CodelCoord {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "({}, {})", self.x, self.y)
+    }
+}
+
+impl fmt::Display for PixelCoord {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "({}, {})", self.x, self.y)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_checked_add() {
+        let origin = CodelCoord::new(0, 0);
+        assert_eq!(origin.checked_add(Delta::RIGHT), Some(CodelCoord::new(1, 0)));
+        assert_eq!(origin.checked_add(Delta::DOWN), Some(CodelCoord::new(0, 1)));
+        assert_eq!(origin.checked_add(Delta::LEFT), None);
+        assert_eq!(origin.checked_add(Delta::UP), None);
+        assert_eq!(CodelCoord::new(3, 2).checked_add(Delta::new(-3, -2)), Some(origin));
+    }
+
+    #[test]
+    fn test_to_pixel() {
+        let origin = PixelCoord::new(4, 5);
+        assert_eq!(CodelCoord::new(2, 3).to_pixel(10, origin), PixelCoord::new(24, 35));
+    }
+}
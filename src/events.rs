@@ -0,0 +1,105 @@
+use crate::{Executed, ExecutionError, ExitReason, PietRunner};
+use std::ops::ControlFlow;
+
+/// Something a step of a run did. See `PietRunner::events`.
+#[derive(Debug)]
+pub enum ExecEvent {
+    /// Moved from one block into the next, running a command.
+    Command(Executed),
+    /// Crossed white, running no command (so reports `Noop`).
+    Slide(Executed),
+    /// The command failed, so was skipped.
+    Error(Executed, ExecutionError),
+    /// The program halted on one page of a multi-page program,
+    /// so moved to the start of the next: this one.
+    TurnedPage(usize),
+    /// The run stopped, eg because the program halted. Always the last event.
+    Stopped(ExitReason),
+}
+
+/// The events of a run, a step at a time, subject to the runner's limits.
+/// Ends once the run stops. See `PietRunner::events`.
+pub struct ExecEvents<'r, 'a> {
+    runner: &'r mut PietRunner<'a>,
+    /// Why the run stopped, to report after the event of the step it stopped on.
+    stopped: Option<ExitReason>,
+    done: bool,
+}
+
+impl<'a> PietRunner<'a> {
+    /// Run the program as an iterator of what each step does, eg to
+    /// `filter` out slides or `take` the first few commands. A run stopped
+    /// at a breakpoint can be carried on with another call.
+    pub fn events(&mut self) -> ExecEvents<'_, 'a> {
+        ExecEvents { runner: self, stopped: None, done: false }
+    }
+}
+
+impl Iterator for ExecEvents<'_, '_> {
+    type Item = ExecEvent;
+
+    fn next(&mut self) -> Option<ExecEvent> {
+        if self.done {
+            return None;
+        }
+        if let Some(reason) = self.stopped.take() {
+            self.done = true;
+            return Some(ExecEvent::Stopped(reason));
+        }
+        match self.runner.tick_event() {
+            (event, ControlFlow::Continue(())) => event,
+            (Some(event), ControlFlow::Break(reason)) => {
+                self.stopped = Some(reason);
+                Some(event)
+            }
+            (None, ControlFlow::Break(reason)) => {
+                self.done = true;
+                Some(ExecEvent::Stopped(reason))
+            }
+        }
+    }
+}
+
+impl std::iter::FusedIterator for ExecEvents<'_, '_> {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{load, Breakpoint, Color, Command, PietCode};
+
+    #[test]
+    fn test_events() {
+        let code = load("test_imgs/test_slide.png", 1).unwrap();
+        let mut runner = code.execute();
+        let events: Vec<_> = runner.events().collect();
+        assert_eq!(events.len() as u64, runner.steps());
+        assert!(matches!(events.last(), Some(ExecEvent::Stopped(ExitReason::Halted))));
+        let slides = events.iter().filter(|event| matches!(event, ExecEvent::Slide(_))).count();
+        assert!(slides > 0);
+        let pushes = events.iter()
+            .filter(|event| matches!(event, ExecEvent::Command(executed) if executed.command == Command::Push))
+            .count();
+        assert!(pushes > 0);
+        assert_eq!(runner.events().count(), 1);
+
+        let mut runner = code.execute();
+        let first: Vec<_> = runner.events().take(2).collect();
+        assert_eq!(first.len(), 2);
+        assert_eq!(runner.steps(), 2);
+
+        // Pop an empty stack, then loop forever: push, pop, push, pop, ...
+        let code = PietCode::new(3, 1, vec![Color::LightRed, Color::DarkRed, Color::Black]);
+        let mut runner = code.execute().fuel(3).breakpoint(Breakpoint::Step(2));
+        let events: Vec<_> = runner.events().collect();
+        assert!(matches!(
+            &events[..],
+            [
+                ExecEvent::Error(pop, ExecutionError::NotEnoughStack(1, 0)),
+                ExecEvent::Command(push),
+                ExecEvent::Stopped(ExitReason::Breakpoint(_)),
+            ] if pop.command == Command::Pop && push.command == Command::Push
+        ));
+        let events: Vec<_> = runner.events().collect();
+        assert!(matches!(&events[..], [ExecEvent::Command(_), ExecEvent::Stopped(ExitReason::OutOfFuel)]));
+    }
+}
@@ -0,0 +1,339 @@
+use crate::{CodelCoord, Color, Command, InstructionPointer, PietCode, PietVM};
+use num_bigint::BigInt;
+use num_integer::Integer;
+use num_traits::{One, ToPrimitive, Zero};
+use std::collections::HashMap;
+
+/// Something that happens along the program's main path.
+enum Event {
+    /// A command, run from a block of this size.
+    Command(Command, BigInt),
+    /// A branch on an unknown value. The path beyond it can't be followed.
+    Branch(Command),
+    /// The path rejoins itself at this event. It's only sure to go round
+    /// forever if no turn along the way was steered by the stack: true if
+    /// one was, as a later time round might turn the other way.
+    Loop(usize, bool),
+    Halt,
+    /// The path runs into a codel that isn't a Piet color.
+    Other(CodelCoord),
+}
+
+/// Follow the path the program takes, without running it.
+/// Execution only turns on `Pointer` and `Switch`, so the path is known
+/// up until one of those acts on a value that depends on the stack.
+fn walk(code: &PietCode) -> Vec<Event> {
+    let mut vm = PietVM::new();
+    let mut seen: HashMap<(CodelCoord, InstructionPointer), usize> = HashMap::new();
+    let mut stack = Stack::default();
+    let mut events = Vec::new();
+    // The events at which the stack steered a turn.
+    let mut steered = Vec::new();
+    loop {
+        let state = (vm.pos, vm.instruction_pointer);
+        if let Some(&idx) = seen.get(&state) {
+            events.push(Event::Loop(idx, steered.iter().any(|&turn| turn >= idx)));
+            return events;
+        }
+        seen.insert(state, events.len());
+        match code.at(vm.pos) {
+            None | Some(Color::Black) => (),
+            Some(Color::Other) => { events.push(Event::Other(vm.pos)); }
+            Some(Color::White) => {
+                if let Some((coord, _)) = vm.walk_white(code) {
+                    vm.pos = coord;
+                    continue;
+                }
+            }
+            Some(Color::Color(..)) => {
+                if let Some((region, coord, next_color)) = vm.walk_color(code) {
+                    let command = region.color.step_to(next_color);
+                    let value = region.value().to_bigint();
+                    if matches!(command, Command::Pointer | Command::Switch) {
+                        let Some(Some(turn)) = stack.peek() else {
+                            events.push(Event::Branch(command));
+                            return events;
+                        };
+                        match command {
+                            Command::Pointer => {
+                                for _ in 0..turn.mod_floor(&4.into()).to_u8().unwrap_or_default() {
+                                    vm.instruction_pointer.rotate();
+                                }
+                            }
+                            _ if turn.is_odd() => { vm.instruction_pointer.flip(); }
+                            _ => (),
+                        }
+                        steered.push(events.len());
+                    }
+                    stack.apply(command, &value);
+                    events.push(Event::Command(command, value));
+                    vm.pos = coord;
+                    continue;
+                }
+            }
+        }
+        if !matches!(events.last(), Some(Event::Other(_))) {
+            events.push(Event::Halt);
+        }
+        return events;
+    }
+}
+
+/// The stack, as far as it can be known without running the program.
+/// None for values that depend on input, or on the stack the path started with.
+#[derive(Default)]
+struct Stack(Vec<Option<BigInt>>);
+
+impl Stack {
+    fn peek(&self) -> Option<Option<&BigInt>> {
+        self.0.last().map(Option::as_ref)
+    }
+
+    fn pop(&mut self) -> Option<BigInt> {
+        self.0.pop().flatten()
+    }
+
+    /// Whether `command` only computes from values that are already known.
+    /// Popping, or branching on, a known value also counts: it's as if
+    /// the value had never been pushed.
+    fn is_constant(&self, command: Command) -> bool {
+        let known = |n: usize| {
+            self.0.len() >= n && self.0[self.0.len() - n..].iter().all(Option::is_some)
+        };
+        match command {
+            Command::Push => true,
+            Command::Not | Command::Duplicate
+                | Command::Pop | Command::Pointer | Command::Switch => known(1),
+            Command::Add | Command::Subtract | Command::Multiply
+                | Command::Divide | Command::Mod | Command::Greater => known(2),
+            _ => false,
+        }
+    }
+
+    fn apply(&mut self, command: Command, value: &BigInt) {
+        fn bool_value(b: bool) -> Option<BigInt> {
+            Some(if b { BigInt::one() } else { BigInt::zero() })
+        }
+        let binary = |stack: &mut Self, f: fn(BigInt, BigInt) -> Option<BigInt>| {
+            let b = stack.pop();
+            let a = stack.pop();
+            let result = a.zip(b).and_then(|(a, b)| f(a, b));
+            stack.0.push(result);
+        };
+        match command {
+            Command::Noop => (),
+            Command::Push => { self.0.push(Some(value.clone())); }
+            Command::Pop | Command::Pointer | Command::Switch
+                | Command::OutNum | Command::OutChar => { self.pop(); }
+            Command::Add => binary(self, |a, b| Some(a + b)),
+            Command::Subtract => binary(self, |a, b| Some(a - b)),
+            Command::Multiply => binary(self, |a, b| Some(a * b)),
+            Command::Divide => binary(self, |a, b| (!b.is_zero()).then(|| a.div_floor(&b))),
+            Command::Mod => binary(self, |a, b| (!b.is_zero()).then(|| a.mod_floor(&b))),
+            Command::Greater => binary(self, |a, b| bool_value(a > b)),
+            Command::Not => {
+                let a = self.pop();
+                self.0.push(a.and_then(|a| bool_value(a.is_zero())));
+            }
+            Command::Duplicate => {
+                let top = self.0.last().cloned().flatten();
+                self.0.push(top);
+            }
+            Command::InNum | Command::InChar => { self.0.push(None); }
+            // Which values move where depends on the values themselves.
+            Command::Roll => { self.0.clear(); }
+        }
+    }
+}
+
+fn describe(command: Command, value: &BigInt) -> String {
+    match command {
+        Command::Noop => "do nothing",
+        Command::Push => { return format!("push {value}"); }
+        Command::Pop => "pop",
+        Command::Add => "add",
+        Command::Subtract => "subtract",
+        Command::Multiply => "multiply",
+        Command::Divide => "divide",
+        Command::Mod => "mod",
+        Command::Not => "not",
+        Command::Greater => "greater",
+        Command::Pointer => "rotate the direction pointer",
+        Command::Switch => "toggle the codel chooser",
+        Command::Duplicate => "duplicate",
+        Command::Roll => "roll",
+        Command::InNum => "read a number",
+        Command::InChar => "read a character",
+        Command::OutNum => "output as number",
+        Command::OutChar => "output as character",
+    }.to_string()
+}
+
+/// Builds up the narrative, collapsing runs of constant output into `print`s.
+#[derive(Default)]
+struct Narrative {
+    lines: Vec<String>,
+    indent: &'static str,
+    /// Commands computing constants, not yet known to be worth listing.
+    pending: Vec<String>,
+    /// The stack depth before the pending commands.
+    pending_depth: usize,
+    printing: String,
+}
+
+impl Narrative {
+    fn line(&mut self, line: String) {
+        self.lines.push(format!("{}{line}", self.indent));
+    }
+
+    fn start_loop(&mut self) {
+        self.flush();
+        self.line("loop:".to_string());
+        self.indent = "  ";
+    }
+
+    fn end_loop(&mut self, steered: bool) {
+        self.flush();
+        let repeat = match steered {
+            // Going round again depends on the values the loop's turns act on.
+            true => "repeat from loop, unless the stack turns the path elsewhere",
+            false => "repeat from loop",
+        };
+        self.line(repeat.to_string());
+    }
+
+    fn flush(&mut self) {
+        if !self.printing.is_empty() {
+            let text = std::mem::take(&mut self.printing);
+            self.line(format!("print {text:?}"));
+        }
+        for line in std::mem::take(&mut self.pending) {
+            self.line(line);
+        }
+    }
+}
+
+impl PietCode {
+    /// A human-readable walkthrough of the program's main path,
+    /// eg "push 7", "push 2", "add", "output as number".
+    /// Constant output is collapsed into `print "..."`, and the walkthrough
+    /// ends at the program's first loop, or the first branch that depends on the stack.
+    pub fn explain(&self) -> Vec<String> {
+        let events = walk(self);
+        let loop_start = events.iter().find_map(|event| match event {
+            Event::Loop(idx, _) => Some(*idx),
+            _ => None,
+        });
+        let mut narrative = Narrative::default();
+        let mut stack = Stack::default();
+        for (idx, event) in events.iter().enumerate() {
+            if loop_start == Some(idx) {
+                narrative.start_loop();
+                // Known values may change from one time around the loop to the next.
+                stack = Stack::default();
+            }
+            let (command, value) = match event {
+                Event::Command(command, value) => (*command, value),
+                Event::Branch(command) => {
+                    narrative.flush();
+                    narrative.line(format!(
+                        "{} by the top of the stack: the path beyond depends on it",
+                        describe(*command, &BigInt::zero()),
+                    ));
+                    break;
+                }
+                Event::Loop(_, steered) => {
+                    narrative.end_loop(*steered);
+                    break;
+                }
+                Event::Halt => {
+                    narrative.flush();
+                    narrative.indent = "";
+                    narrative.line("halt".to_string());
+                    break;
+                }
+                Event::Other(coord) => {
+                    narrative.flush();
+                    narrative.line(format!("reach the non-Piet colored codel at {coord}"));
+                    break;
+                }
+            };
+            if command == Command::Noop {
+                continue;
+            }
+            if stack.is_constant(command) {
+                if narrative.pending.is_empty() {
+                    narrative.pending_depth = stack.0.len();
+                }
+                narrative.pending.push(describe(command, value));
+                stack.apply(command, value);
+                continue;
+            }
+            let output = match (command, stack.peek()) {
+                (Command::OutNum, Some(Some(n))) => Some(n.to_string()),
+                (Command::OutChar, Some(Some(n))) => {
+                    n.to_u32().and_then(char::from_u32).map(String::from)
+                }
+                _ => None,
+            };
+            match output {
+                Some(text) if stack.0.len() == narrative.pending_depth + 1 => {
+                    narrative.pending.clear();
+                    narrative.printing.push_str(&text);
+                }
+                _ => {
+                    narrative.flush();
+                    narrative.line(describe(command, value));
+                }
+            }
+            stack.apply(command, value);
+            narrative.pending_depth = stack.0.len();
+        }
+        narrative.lines
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::asm;
+
+    fn explain_asm(source: &str) -> Vec<String> {
+        asm::load_str(source).unwrap().explain()
+    }
+
+    #[test]
+    fn test_explain_arithmetic() {
+        let lines = explain_asm("INNUM\nPUSH 7\nADD\nOUTNUM\n");
+        assert_eq!(lines, ["read a number", "push 7", "add", "output as number", "halt"]);
+    }
+
+    #[test]
+    fn test_explain_print() {
+        let lines = explain_asm("PUSH 72\nOUTCHAR\nPUSH 105\nOUTCHAR\nPUSH 3 4\nADD\nOUTNUM\n");
+        assert_eq!(lines, ["print \"Hi7\"", "halt"]);
+    }
+
+    #[test]
+    fn test_explain_loop() {
+        let lines = explain_asm("PUSH 5\n:TOP\nINCHAR\nOUTCHAR\nJUMP TOP\n");
+        assert_eq!(lines, [
+            "push 5",
+            "loop:",
+            "  read a character",
+            "  output as character",
+            "  repeat from loop",
+        ]);
+    }
+
+    #[test]
+    fn test_explain_steered_loop() {
+        let lines = explain_asm("PUSH 5\n:L\nDUP\nOUTNUM\nSUB 1\nDUP\nJUMPIF L\n");
+        assert_eq!(lines.last().unwrap(), "  repeat from loop, unless the stack turns the path elsewhere");
+    }
+
+    #[test]
+    fn test_explain_branch() {
+        let lines = explain_asm("INNUM\nJUMPIF END\nPUSH 1\n:END\n");
+        assert_eq!(lines.last().unwrap(), "rotate the direction pointer by the top of the stack: the path beyond depends on it");
+    }
+}
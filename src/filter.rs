@@ -0,0 +1,132 @@
+use crate::{ExecutionError, ExitReason, Input, InputMode, Output, PietRunner};
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+
+/// A program run as a streaming text filter, eg to transform text inside a
+/// larger application: bytes written to it are the program's input, and its
+/// output can be read back as it's written. The program runs as far as it
+/// can with each write, pausing when it needs input that hasn't arrived yet.
+///
+/// Reading while the program is waiting for input fails with `WouldBlock`,
+/// and reads nothing once the program has stopped and its output's all been
+/// read. Once there's no more input to write, `close` the filter, so reading
+/// past what was written acts as the runner's `EofBehavior` says.
+pub struct PietFilter<'a> {
+    runner: PietRunner<'a>,
+    /// Why the run stopped, once it has.
+    stopped: Option<ExitReason>,
+}
+
+impl<'a> PietFilter<'a> {
+    /// Run `runner`'s program as a filter, as far as it goes before reading
+    /// any input. Its input and output are replaced, input is taken a byte at
+    /// a time as it's written, and its limits still apply.
+    pub fn new(runner: PietRunner<'a>) -> Self {
+        let mut runner = match &runner.history {
+            // A step waiting for input is undone, to take again once it's arrived.
+            Some(history) if history.depth > 0 => runner,
+            _ => runner.history(1),
+        };
+        runner.vm.input = Input::Pending(VecDeque::new());
+        runner.vm.input_mode = InputMode::Raw;
+        runner.vm.output = Output::Buffer(Vec::new());
+        let mut filter = PietFilter { runner, stopped: None };
+        filter.resume();
+        filter
+    }
+
+    /// There's no more input: run the program on to the end of it.
+    pub fn close(&mut self) {
+        if let Input::Pending(input) = &mut self.runner.vm.input {
+            self.runner.vm.input = Input::Buffer(std::mem::take(input));
+        }
+        self.resume();
+    }
+
+    /// Why the program stopped, if it has.
+    pub fn exit_reason(&self) -> Option<&ExitReason> {
+        self.stopped.as_ref()
+    }
+
+    /// The runner, eg to see the stack.
+    pub fn runner(&self) -> &PietRunner<'a> {
+        &self.runner
+    }
+
+    fn resume(&mut self) {
+        if self.stopped.is_some() {
+            return;
+        }
+        match self.runner.run() {
+            ExitReason::Error(ExecutionError::IoError(err)) if err.kind() == io::ErrorKind::WouldBlock => (),
+            reason => { self.stopped = Some(reason); }
+        }
+    }
+}
+
+impl Write for PietFilter<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match &mut self.runner.vm.input {
+            Input::Pending(input) if self.stopped.is_none() => input.extend(buf),
+            _ => { return Err(io::ErrorKind::BrokenPipe.into()); }
+        }
+        self.resume();
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Read for PietFilter<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let Output::Buffer(output) = &mut self.runner.vm.output else { return Ok(0); };
+        if output.is_empty() && self.stopped.is_none() {
+            return Err(io::ErrorKind::WouldBlock.into());
+        }
+        let len = buf.len().min(output.len());
+        buf[..len].copy_from_slice(&output[..len]);
+        output.drain(..len);
+        Ok(len)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{asm, EofBehavior};
+
+    fn read_all(filter: &mut PietFilter) -> String {
+        let mut out = String::new();
+        match filter.read_to_string(&mut out) {
+            Ok(_) => (),
+            Err(err) => assert_eq!(err.kind(), io::ErrorKind::WouldBlock),
+        }
+        out
+    }
+
+    #[test]
+    fn test_filter() {
+        // Upper-cases letters, until it reads a -1 at the end of input.
+        let code = asm::load_str("\
+            :loop\nINCHAR\nDUP\nPUSH 1\nADD\nNOT\nJUMPIF end\n\
+            PUSH 32\nSUB\nOUTCHAR\nJUMP loop\n:end\nPOP\nPUSH 33\nOUTCHAR\n",
+        ).unwrap();
+        let mut filter = PietFilter::new(code.execute().eof_behavior(EofBehavior::PushNegativeOne));
+        assert_eq!(read_all(&mut filter), "");
+
+        filter.write_all(b"abc").unwrap();
+        assert_eq!(read_all(&mut filter), "ABC");
+        let steps = filter.runner().steps();
+        filter.write_all(b"de").unwrap();
+        assert!(filter.runner().steps() > steps);
+        assert!(filter.exit_reason().is_none());
+        assert_eq!(read_all(&mut filter), "DE");
+
+        filter.close();
+        assert!(matches!(filter.exit_reason(), Some(ExitReason::Halted)));
+        assert_eq!(read_all(&mut filter), "!");
+        assert_eq!(filter.write(b"f").unwrap_err().kind(), io::ErrorKind::BrokenPipe);
+    }
+}
@@ -0,0 +1,177 @@
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::time::Duration;
+
+/// How long a paused run waits at a time, before checking whether it's been
+/// interrupted or run out of time meanwhile.
+const PAUSED_CHECK: Duration = Duration::from_millis(20);
+
+/// Controls a run from another thread, eg an editor's pause and step
+/// buttons. Clones control the same run. See `PietRunner::handle`.
+#[derive(Clone, Debug, Default)]
+pub struct RunnerHandle {
+    shared: Arc<(Mutex<Control>, Condvar)>,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+enum Control {
+    #[default]
+    Running,
+    Paused,
+    /// Paused, but with this many steps to take first.
+    Stepping(u64),
+    Stopped,
+}
+
+/// What a run should do before its next step.
+pub(crate) enum Permit {
+    Step,
+    Stop,
+    /// Still paused: check the run's other limits, then wait again.
+    Paused,
+}
+
+impl RunnerHandle {
+    /// A handle to a run that isn't paused.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Pause the run before its next step, until it's resumed, stepped, or stopped.
+    pub fn pause(&self) {
+        self.update(|control| match control {
+            Control::Stopped => Control::Stopped,
+            _ => Control::Paused,
+        });
+    }
+
+    /// Carry on running.
+    pub fn resume(&self) {
+        self.update(|control| match control {
+            Control::Stopped => Control::Stopped,
+            _ => Control::Running,
+        });
+    }
+
+    /// Take `steps` more steps, then pause.
+    pub fn step(&self, steps: u64) {
+        self.update(|control| match control {
+            Control::Stopped => Control::Stopped,
+            Control::Stepping(pending) => Control::Stepping(pending.saturating_add(steps)),
+            Control::Running | Control::Paused => Control::Stepping(steps),
+        });
+    }
+
+    /// End the run, which stops with `ExitReason::Interrupted`. It can't be resumed.
+    pub fn stop(&self) {
+        self.update(|_| Control::Stopped);
+    }
+
+    /// Whether the run is paused, or will pause once it's taken the steps it was given.
+    pub fn is_paused(&self) -> bool {
+        matches!(*self.lock(), Control::Paused | Control::Stepping(_))
+    }
+
+    /// Whether the run has been stopped.
+    pub fn is_stopped(&self) -> bool {
+        *self.lock() == Control::Stopped
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Control> {
+        // The lock is never held while anything could panic.
+        self.shared.0.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    fn update(&self, change: impl FnOnce(Control) -> Control) {
+        let mut control = self.lock();
+        *control = change(*control);
+        self.shared.1.notify_all();
+    }
+
+    /// Wait until the run may take a step, or should stop, or a while has
+    /// passed paused.
+    pub(crate) fn wait(&self) -> Permit {
+        let mut control = self.lock();
+        loop {
+            match *control {
+                Control::Running => return Permit::Step,
+                Control::Stopped => return Permit::Stop,
+                Control::Stepping(pending) => {
+                    *control = match pending {
+                        0 | 1 => Control::Paused,
+                        _ => Control::Stepping(pending - 1),
+                    };
+                    if pending > 0 {
+                        return Permit::Step;
+                    }
+                }
+                Control::Paused => {
+                    let (guard, waited) = self.shared.1.wait_timeout(control, PAUSED_CHECK)
+                        .unwrap_or_else(|poisoned| poisoned.into_inner());
+                    control = guard;
+                    if waited.timed_out() {
+                        return Permit::Paused;
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{Color, ExitReason, PietCode, RunnerHandle};
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn test_handle() {
+        // Loops forever: push, pop, push, pop, ...
+        let code = PietCode::new(3, 1, vec![Color::LightRed, Color::DarkRed, Color::Black]);
+        let handle = RunnerHandle::new();
+        let mut runner = code.execute().handle(handle.clone());
+        handle.step(2);
+        handle.step(1);
+        assert!(handle.is_paused());
+        assert!(matches!(runner.run_with_limits(3, Duration::MAX), ExitReason::OutOfFuel));
+        assert_eq!(runner.steps(), 3);
+        handle.stop();
+        handle.resume();
+        assert!(handle.is_stopped());
+        assert!(matches!(runner.run(), ExitReason::Interrupted));
+        assert_eq!(runner.steps(), 3);
+
+        // Paused once it's taken its steps, until stopped from another thread.
+        let handle = RunnerHandle::new();
+        handle.step(2);
+        let mut runner = code.execute().handle(handle.clone());
+        thread::scope(|scope| {
+            let run = scope.spawn(move || (runner.run(), runner.steps()));
+            thread::sleep(Duration::from_millis(50));
+            assert!(handle.is_paused());
+            handle.stop();
+            let (reason, steps) = run.join().unwrap();
+            assert!(matches!(reason, ExitReason::Interrupted));
+            assert_eq!(steps, 2);
+        });
+
+        // Resumed from another thread.
+        let handle = RunnerHandle::new();
+        handle.pause();
+        let mut runner = code.execute().fuel(10).handle(handle.clone());
+        thread::scope(|scope| {
+            let run = scope.spawn(move || (runner.run(), runner.steps()));
+            thread::sleep(Duration::from_millis(10));
+            handle.resume();
+            let (reason, steps) = run.join().unwrap();
+            assert!(matches!(reason, ExitReason::OutOfFuel));
+            assert_eq!(steps, 10);
+        });
+
+        // Paused runs still time out.
+        let handle = RunnerHandle::new();
+        handle.pause();
+        let mut runner = code.execute().handle(handle.clone());
+        assert!(matches!(runner.run_with_limits(u64::MAX, Duration::from_millis(50)), ExitReason::TimedOut));
+        assert_eq!(runner.steps(), 0);
+    }
+}
@@ -0,0 +1,72 @@
+use crate::{CodelCoord, PietCode, PixelCoord, Trace};
+use image::{Rgb, RgbImage};
+
+/// How bright a codel entered just once is drawn, so it stands out from
+/// codels never entered, which are black.
+const COLDEST: f64 = 64.0;
+
+impl Trace {
+    /// How many steps of this trace of `code` started on each codel, in reading order.
+    pub fn visits(&self, code: &PietCode) -> Vec<u64> {
+        let mut visits = vec![0; code.width * code.height];
+        for step in &self.steps {
+            let CodelCoord { x, y } = step.pos;
+            if x < code.width && y < code.height {
+                visits[x + y * code.width] += 1;
+            }
+        }
+        visits
+    }
+
+    /// Render this trace of `code` as a grayscale heatmap, eg to find hot
+    /// loops and dead regions in hand-painted programs: each codel is as
+    /// bright as the number of times execution entered it, on a log scale,
+    /// and black if it never did.
+    pub fn to_heatmap(&self, code: &PietCode, codel_size: u32) -> RgbImage {
+        let visits = self.visits(code);
+        let hottest = visits.iter().copied().max().unwrap_or_default();
+        let mut img = RgbImage::new(code.width as u32 * codel_size, code.height as u32 * codel_size);
+        for ((coord, _), &count) in code.codels().zip(&visits) {
+            let brightness = match count {
+                0 => 0,
+                _ if hottest == 1 => 0xff,
+                _ => (COLDEST + (255.0 - COLDEST) * (count as f64).ln() / (hottest as f64).ln()) as u8,
+            };
+            let PixelCoord { x, y } = coord.to_pixel(codel_size, PixelCoord::default());
+            for dx in 0..codel_size {
+                for dy in 0..codel_size {
+                    img.put_pixel(x + dx, y + dy, Rgb([brightness; 3]));
+                }
+            }
+        }
+        img
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{asm, CodelCoord, Trace};
+
+    #[test]
+    fn test_heatmap() {
+        let code = asm::load_str("PUSH 3\n:L\nSUB 1\nDUP\nJUMPIF L\nPOP\n").unwrap();
+        let mut trace = Trace::new();
+        code.execute().tracer(&mut trace).run();
+
+        let visits = trace.visits(&code);
+        assert_eq!(visits.iter().sum::<u64>(), trace.steps.len() as u64);
+        assert_eq!(visits[0], 1);
+        let hottest = visits.iter().copied().max().unwrap();
+        assert!(hottest >= 3);
+
+        let img = trace.to_heatmap(&code, 2);
+        assert_eq!(img.dimensions(), (code.width as u32 * 2, code.height as u32 * 2));
+        let at = |pos: CodelCoord| img.get_pixel(pos.x as u32 * 2 + 1, pos.y as u32 * 2 + 1).0[0];
+        let hot = visits.iter().position(|&count| count == hottest).unwrap();
+        let cold = visits.iter().position(|&count| count == 0).unwrap();
+        let coord = |i: usize| CodelCoord::new(i % code.width, i / code.width);
+        assert_eq!(at(coord(hot)), 0xff);
+        assert_eq!(at(coord(0)), 64);
+        assert_eq!(at(coord(cold)), 0);
+    }
+}
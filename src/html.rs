@@ -0,0 +1,149 @@
+use crate::{PietCode, Trace, OTHER_COLOR};
+use image::Rgb;
+
+/// A standalone page for stepping through a trace: no server, no dependencies.
+/// The trace is embedded as JSON in place of `TRACE_JSON`.
+const TEMPLATE: &str = r##"<!DOCTYPE html>
+<html>
+<head>
+<meta charset="utf-8">
+<title>Piet trace</title>
+<style>
+  body { font-family: monospace; margin: 1em; }
+  canvas { image-rendering: pixelated; border: 1px solid #888; }
+  #controls { margin: 0.5em 0; }
+  #scrubber { width: 40em; max-width: 100%; }
+  #stack { white-space: pre-wrap; }
+</style>
+</head>
+<body>
+<canvas id="program"></canvas>
+<div id="controls">
+  <button id="prev">&lt;</button>
+  <input id="scrubber" type="range" min="0" value="0">
+  <button id="next">&gt;</button>
+</div>
+<div id="status"></div>
+<div id="stack"></div>
+<script>
+const TRACE = TRACE_JSON;
+const canvas = document.getElementById("program");
+const scrubber = document.getElementById("scrubber");
+const size = Math.max(4, Math.min(32, Math.floor(640 / Math.max(TRACE.width, TRACE.height, 1))));
+canvas.width = TRACE.width * size;
+canvas.height = TRACE.height * size;
+const ctx = canvas.getContext("2d");
+scrubber.max = Math.max(TRACE.steps.length - 1, 0);
+
+function colorAt(x, y) {
+  return TRACE.colors[x + y * TRACE.width];
+}
+
+// The block containing (x, y): the codels of the same color connected to it.
+function regionAt(x, y) {
+  const color = colorAt(x, y);
+  const seen = new Set([x + y * TRACE.width]);
+  const queue = [[x, y]];
+  while (queue.length) {
+    const [cx, cy] = queue.pop();
+    for (const [nx, ny] of [[cx + 1, cy], [cx - 1, cy], [cx, cy + 1], [cx, cy - 1]]) {
+      const idx = nx + ny * TRACE.width;
+      if (nx < 0 || ny < 0 || nx >= TRACE.width || ny >= TRACE.height) continue;
+      if (seen.has(idx) || colorAt(nx, ny) !== color) continue;
+      seen.add(idx);
+      queue.push([nx, ny]);
+    }
+  }
+  return seen;
+}
+
+function draw(idx) {
+  for (let y = 0; y < TRACE.height; y++) {
+    for (let x = 0; x < TRACE.width; x++) {
+      ctx.fillStyle = colorAt(x, y);
+      ctx.fillRect(x * size, y * size, size, size);
+    }
+  }
+  const step = TRACE.steps[idx];
+  if (!step) {
+    document.getElementById("status").textContent = "no steps";
+    return;
+  }
+  ctx.fillStyle = "rgba(0, 0, 0, 0.4)";
+  for (const codel of regionAt(step.x, step.y)) {
+    const x = codel % TRACE.width;
+    const y = Math.floor(codel / TRACE.width);
+    ctx.fillRect(x * size, y * size, size, size);
+  }
+  ctx.strokeStyle = "#f0f";
+  ctx.lineWidth = 2;
+  ctx.strokeRect(step.x * size + 1, step.y * size + 1, size - 2, size - 2);
+  document.getElementById("status").textContent =
+    `step ${step.step} at (${step.x}, ${step.y}): ${step.command || "no command"}`;
+  document.getElementById("stack").textContent =
+    `stack (${step.stack.length} items): ${step.stack.join(" ")}`;
+}
+
+function show(idx) {
+  scrubber.value = Math.max(0, Math.min(scrubber.max, idx));
+  draw(Number(scrubber.value));
+}
+
+scrubber.addEventListener("input", () => draw(Number(scrubber.value)));
+document.getElementById("prev").addEventListener("click", () => show(Number(scrubber.value) - 1));
+document.getElementById("next").addEventListener("click", () => show(Number(scrubber.value) + 1));
+document.addEventListener("keydown", (e) => {
+  if (e.key === "ArrowLeft") show(Number(scrubber.value) - 1);
+  if (e.key === "ArrowRight") show(Number(scrubber.value) + 1);
+});
+show(0);
+</script>
+</body>
+</html>
+"##;
+
+impl Trace {
+    /// Render this trace of `code` as a standalone HTML page, with a scrubber
+    /// over the steps showing the current block and the stack at each.
+    pub fn to_html(&self, code: &PietCode) -> String {
+        let mut json = format!(r#"{{"width":{},"height":{},"colors":["#, code.width, code.height);
+        for (i, &color) in code.code.iter().enumerate() {
+            let Rgb([r, g, b]) = color.try_into().unwrap_or(OTHER_COLOR);
+            let sep = if i == 0 { "" } else { "," };
+            json.push_str(&format!(r##"{sep}"#{r:02x}{g:02x}{b:02x}""##));
+        }
+        json.push_str(r#"],"steps":["#);
+        for (i, step) in self.steps.iter().enumerate() {
+            let sep = if i == 0 { "" } else { "," };
+            let command = match &step.command {
+                Some(traced) => format!(r#""{:?}""#, traced.command),
+                None => "null".to_string(),
+            };
+            let stack: Vec<_> = step.stack.iter().map(|n| format!(r#""{n}""#)).collect();
+            json.push_str(&format!(
+                r#"{sep}{{"step":{},"x":{},"y":{},"command":{command},"stack":[{}]}}"#,
+                step.step, step.pos.x, step.pos.y, stack.join(","),
+            ));
+        }
+        json.push_str("]}");
+        TEMPLATE.replace("TRACE_JSON", &json)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{load, Trace};
+
+    #[test]
+    fn test_to_html() {
+        let code = load("test_imgs/test_slide.png", 1).unwrap();
+        let mut trace = Trace::new();
+        code.execute().tracer(&mut trace).run();
+        let html = trace.to_html(&code);
+        assert!(!html.contains("TRACE_JSON"));
+        assert!(html.contains(r#""step":1,"x":0,"y":0,"command":"#));
+        // Stack values are strings, since they needn't fit in a float.
+        assert!(html.contains(r#""stack":["8"]"#));
+        assert_eq!(html.matches(r#""step":"#).count(), trace.steps.len());
+    }
+}
@@ -1,6 +1,19 @@
-use image::{self, DynamicImage, GenericImageView, ImageResult, Rgb, Rgba, RgbImage};
+// Loading and running a program returns errors rather than panicking,
+// so the library can be trusted with untrusted images, eg in a service.
+#![cfg_attr(not(test), deny(
+    clippy::panic,
+    clippy::unwrap_used,
+    clippy::expect_used,
+    clippy::unreachable,
+    clippy::todo,
+    clippy::unimplemented,
+))]
+
+use image::codecs::gif::GifDecoder;
+use image::codecs::png::PngDecoder;
+use image::io::Reader as ImageReader;
+use image::{self, AnimationDecoder, DynamicImage, ImageFormat, GenericImageView, GrayImage, ImageResult, Luma, Rgb, Rgba, RgbImage};
 use itertools::iproduct;
-use log::info;
 use num_bigint::BigInt;
 use num_derive::FromPrimitive;
 use num_integer::Integer;
@@ -8,9 +21,45 @@ use num_traits::{FromPrimitive, One, ToPrimitive, Zero};
 use std::cmp::Reverse;
 use std::collections::{HashSet, VecDeque};
 use std::fmt;
-use std::hash::Hash;
+use std::hash::{Hash, Hasher};
+use std::fs::File;
+use std::io::{BufRead, BufReader, IsTerminal, Read, Write};
+use std::ops::ControlFlow;
+use std::sync::{Arc, OnceLock};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
 
+mod animate;
 pub mod asm;
+mod coord;
+mod events;
+mod explain;
+mod filter;
+mod handle;
+mod heatmap;
+mod html;
+mod link;
+mod npiet;
+pub mod palette;
+#[cfg(feature = "raw-terminal")]
+mod raw;
+mod region_graph;
+mod stats;
+mod trace;
+pub mod transpile;
+mod value;
+mod visual;
+
+pub use coord::{CodelCoord, Delta, PixelCoord};
+pub use events::{ExecEvent, ExecEvents};
+pub use filter::PietFilter;
+pub use handle::RunnerHandle;
+pub use link::Linked;
+pub use region_graph::{RegionGraph, RegionNode};
+pub use stats::ProgramStats;
+pub use trace::{Divergence, LogTracer, NoTracer, RuntimeWarning, Trace, TraceStep, TracedCommand, Tracer};
+pub use value::{IntModel, IntWidth, OverflowPolicy, Value, ValueCap};
+pub use visual::Visualizer;
 
 pub trait GetAllEqualIterator<T>: Iterator<Item = T> {
     fn get_all_equal(&mut self) -> Option<T>
@@ -19,17 +68,15 @@ pub trait GetAllEqualIterator<T>: Iterator<Item = T> {
         Self::Item: PartialEq,
     {
         let a = self.next()?;
-        self.all(|x| a == x).then(|| a)
+        self.all(|x| a == x).then_some(a)
     }
 }
 
 impl<T, I: Iterator<Item = T>> GetAllEqualIterator<T> for I {}
 
-type Coord = (usize, usize);
-
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
 #[derive(FromPrimitive)]
-enum Hue {
+pub enum Hue {
     Red = 0,
     Yellow = 1,
     Green = 2,
@@ -40,14 +87,15 @@ enum Hue {
 
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
 #[derive(FromPrimitive)]
-enum Lightness {
+pub enum Lightness {
     Light = 0,
     Normal = 1,
     Dark = 2,
 }
 
 #[derive(Clone, Copy, PartialEq, Eq)]
-enum Color {
+#[allow(clippy::enum_variant_names)]
+pub enum Color {
     Color(Hue, Lightness),
     Black,
     White,
@@ -76,44 +124,46 @@ impl Color {
     const DarkCyan: Color = Color::Color(Hue::Cyan, Lightness::Dark);
     const DarkBlue: Color = Color::Color(Hue::Blue, Lightness::Dark);
     const DarkMagenta: Color = Color::Color(Hue::Magenta, Lightness::Dark);
+
+    const ALL: [Color; 20] = [
+        Color::LightRed, Color::LightYellow, Color::LightGreen,
+        Color::LightCyan, Color::LightBlue, Color::LightMagenta,
+        Color::Red, Color::Yellow, Color::Green,
+        Color::Cyan, Color::Blue, Color::Magenta,
+        Color::DarkRed, Color::DarkYellow, Color::DarkGreen,
+        Color::DarkCyan, Color::DarkBlue, Color::DarkMagenta,
+        Color::White, Color::Black,
+    ];
 }
 
 impl Color {
+    /// The command run by moving from this color into `next`.
+    /// Execution only ever moves between Piet colors and white,
+    /// and moving to or from white runs no command.
     fn step_to(self, next: Color) -> Command {
-        let (hue, lightness) = match self {
-            Color::Color(h, l) => (h, l),
-            Color::White => { return Command::Noop; }
-            Color::Black => { panic!(); }
-            Color::Other => { panic!(); }
-        };
-        let (next_hue, next_lightness) = match next {
-            Color::Color(h, l) => (h, l),
-            Color::White => { return Command::Noop; }
-            Color::Black => { panic!(); }
-            Color::Other => { panic!(); }
+        let (Color::Color(hue, lightness), Color::Color(next_hue, next_lightness)) = (self, next) else {
+            return Command::Noop;
         };
         let hue_step = (next_hue as i32 - hue as i32).rem_euclid(6);
         let light_step = (next_lightness as i32 - lightness as i32).rem_euclid(3);
-        FromPrimitive::from_i32(light_step + hue_step * 3).unwrap()
+        palette::COMMANDS[hue_step as usize][light_step as usize]
     }
 
-    /// Reverse of `step_to`.
+    /// Reverse of `step_to`. Only Piet colors have a next color:
+    /// any other is returned as is.
     fn next_for_command(self, command: Command) -> Color {
-        let (hue, lightness) = match self {
-            Color::Color(h, l) => (h as i32, l as i32),
-            _ => { panic!(); }
-        };
+        let Color::Color(hue, lightness) = self else { return self; };
         let command = command as i32;
         let dlight = command % 3;
         let dhue = command / 3;
-        let hue = FromPrimitive::from_i32((hue + dhue) % 6).unwrap();
-        let lightness = FromPrimitive::from_i32((lightness + dlight) % 3).unwrap();
+        let hue = FromPrimitive::from_i32((hue as i32 + dhue) % 6).unwrap_or(hue);
+        let lightness = FromPrimitive::from_i32((lightness as i32 + dlight) % 3).unwrap_or(lightness);
         Color::Color(hue, lightness)
     }
 }
 
-#[derive(FromPrimitive, Debug)]
-enum Command {
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, FromPrimitive)]
+pub enum Command {
     Noop = 0,
     Push = 1,
     Pop = 2,
@@ -164,59 +214,33 @@ impl fmt::Debug for Color {
 
 impl From<Rgb<u8>> for Color {
     fn from(pixel: Rgb<u8>) -> Color {
-        match pixel {
-            Rgb([0xFF, 0xFF, 0xFF]) => Color::White,
-            Rgb([0x00, 0x00, 0x00]) => Color::Black,
-            Rgb([0xFF, 0xC0, 0xC0]) => Color::LightRed,
-            Rgb([0xFF, 0x00, 0x00]) => Color::Red,
-            Rgb([0xC0, 0x00, 0x00]) => Color::DarkRed,
-            Rgb([0xFF, 0xFF, 0xC0]) => Color::LightYellow,
-            Rgb([0xFF, 0xFF, 0x00]) => Color::Yellow,
-            Rgb([0xC0, 0xC0, 0x00]) => Color::DarkYellow,
-            Rgb([0xC0, 0xFF, 0xC0]) => Color::LightGreen,
-            Rgb([0x00, 0xFF, 0x00]) => Color::Green,
-            Rgb([0x00, 0xC0, 0x00]) => Color::DarkGreen,
-            Rgb([0xC0, 0xFF, 0xFF]) => Color::LightCyan,
-            Rgb([0x00, 0xFF, 0xFF]) => Color::Cyan,
-            Rgb([0x00, 0xC0, 0xC0]) => Color::DarkCyan,
-            Rgb([0xC0, 0xC0, 0xFF]) => Color::LightBlue,
-            Rgb([0x00, 0x00, 0xFF]) => Color::Blue,
-            Rgb([0x00, 0x00, 0xC0]) => Color::DarkBlue,
-            Rgb([0xFF, 0xC0, 0xFF]) => Color::LightMagenta,
-            Rgb([0xFF, 0x00, 0xFF]) => Color::Magenta,
-            Rgb([0xC0, 0x00, 0xC0]) => Color::DarkMagenta,
-            _ => Color::Other,
-        }
+        palette::COLORS.into_iter()
+            .find(|&(_, rgb)| rgb == pixel.0)
+            .map_or(Color::Other, |(color, _)| color)
     }
 }
 
 impl TryFrom<Color> for Rgb<u8> {
     type Error = ();
 
-    fn try_from(pixel: Color) -> Result<Rgb<u8>, ()> {
-        Ok(match pixel {
-            Color::White => Rgb([0xFF, 0xFF, 0xFF]),
-            Color::Black => Rgb([0x00, 0x00, 0x00]),
-            Color::LightRed => Rgb([0xFF, 0xC0, 0xC0]),
-            Color::Red => Rgb([0xFF, 0x00, 0x00]),
-            Color::DarkRed => Rgb([0xC0, 0x00, 0x00]),
-            Color::LightYellow => Rgb([0xFF, 0xFF, 0xC0]),
-            Color::Yellow => Rgb([0xFF, 0xFF, 0x00]),
-            Color::DarkYellow => Rgb([0xC0, 0xC0, 0x00]),
-            Color::LightGreen => Rgb([0xC0, 0xFF, 0xC0]),
-            Color::Green => Rgb([0x00, 0xFF, 0x00]),
-            Color::DarkGreen => Rgb([0x00, 0xC0, 0x00]),
-            Color::LightCyan => Rgb([0xC0, 0xFF, 0xFF]),
-            Color::Cyan => Rgb([0x00, 0xFF, 0xFF]),
-            Color::DarkCyan => Rgb([0x00, 0xC0, 0xC0]),
-            Color::LightBlue => Rgb([0xC0, 0xC0, 0xFF]),
-            Color::Blue => Rgb([0x00, 0x00, 0xFF]),
-            Color::DarkBlue => Rgb([0x00, 0x00, 0xC0]),
-            Color::LightMagenta => Rgb([0xFF, 0xC0, 0xFF]),
-            Color::Magenta => Rgb([0xFF, 0x00, 0xFF]),
-            Color::DarkMagenta => Rgb([0xC0, 0x00, 0xC0]),
-            Color::Other => { return Err(()); }
-        })
+    fn try_from(color: Color) -> Result<Rgb<u8>, ()> {
+        palette::rgb(color).map(Rgb).ok_or(())
+    }
+}
+
+impl Color {
+    /// The standard color closest to `pixel`, with the squared distance to it.
+    fn nearest(pixel: Rgb<u8>) -> (Color, u32) {
+        Color::ALL.into_iter()
+            .filter_map(|color| {
+                let rgb = palette::rgb(color)?;
+                let dist = rgb.iter().zip(pixel.0)
+                    .map(|(&a, b)| (a as i32 - b as i32).pow(2) as u32)
+                    .sum();
+                Some((color, dist))
+            })
+            .min_by_key(|&(_, dist)| dist)
+            .unwrap_or((Color::Other, u32::MAX))
     }
 }
 
@@ -230,52 +254,274 @@ impl From<Rgba<u8>> for Color {
     }
 }
 
+/// A loaded Piet program.
+///
+/// Execution never mutates the code, so one program can be shared between
+/// threads (eg, behind an `Arc`), each running its own `PietRunner`.
+///
+/// An editor can change the program with `set` and `fill` between runs.
+/// Anything derived from the codels, like its blocks, is rebuilt after each
+/// change, so a run or analysis started afterwards always sees the edited program.
 #[derive(Debug)]
 pub struct PietCode {
     width: usize,
     height: usize,
     code: Vec<Color>,
+    /// Found the first time a block is needed, and cleared by any edit.
+    regions: OnceLock<Regions>,
 }
 
+/// A program's blocks, which one each codel belongs to, and how execution
+/// moves between them: everything running the program needs but white slides.
+#[derive(Debug)]
+struct Regions {
+    /// An index into `blocks` for each codel, in the same order as the codels.
+    ids: Vec<usize>,
+    blocks: Vec<CodelRegion>,
+    /// For each block, by `InstructionPointer::index`, where execution arriving
+    /// with that instruction pointer leaves to, and the instruction pointer
+    /// it has once it's found a way out. None if there's no way out.
+    moves: Vec<[Option<(InstructionPointer, CodelCoord)>; 8]>,
+}
+
+// Keep it that way: any caches added to PietCode must be thread-safe.
+const _: () = {
+    const fn assert_send_sync<T: Send + Sync>() {}
+    const fn assert_send<T: Send>() {}
+    assert_send_sync::<PietCode>();
+    assert_send::<PietRunner<'static>>();
+};
+
 impl PietCode {
-    fn codels(&self) -> impl Iterator<Item = (usize, usize, Color)> + '_ {
+    fn new(width: usize, height: usize, code: Vec<Color>) -> Self {
+        PietCode { width, height, code, regions: OnceLock::new() }
+    }
+
+    /// A copy of the program with `replacement` in place of each non-Piet color.
+    fn replace_other(&self, replacement: Color) -> PietCode {
+        let code = self.code.iter()
+            .map(|&color| if color == Color::Other { replacement } else { color })
+            .collect();
+        PietCode::new(self.width, self.height, code)
+    }
+
+    fn codels(&self) -> impl Iterator<Item = (CodelCoord, Color)> + '_ {
         self.code.iter().enumerate().map(|(i, c)| {
             let x = i % self.width;
             let y = i / self.width;
-            (x, y, *c)
+            (CodelCoord::new(x, y), *c)
         })
     }
 
-    fn at(&self, x: usize, y: usize) -> Option<Color> {
+    fn at(&self, coord: CodelCoord) -> Option<Color> {
+        let CodelCoord { x, y } = coord;
         if x >= self.width || y >= self.height { return None; }
         Some(self.code[x + y * self.width])
     }
 
-    fn region_at(&self, x: usize, y: usize) -> Option<CodelRegion> {
-        let color = self.at(x, y)?;
+    /// The block containing `coord`.
+    fn region_at(&self, coord: CodelCoord) -> Option<&CodelRegion> {
+        let regions = self.regions();
+        regions.blocks.get(self.block_id(coord)?)
+    }
+
+    fn block_id(&self, coord: CodelCoord) -> Option<usize> {
+        let CodelCoord { x, y } = coord;
+        if x >= self.width || y >= self.height { return None; }
+        self.regions().ids.get(x + y * self.width).copied()
+    }
+
+    /// The block containing `coord`, and where execution leaving it with `ip`
+    /// goes, along with the instruction pointer it goes with.
+    fn move_from(
+        &self,
+        coord: CodelCoord,
+        ip: InstructionPointer,
+    ) -> Option<(&CodelRegion, Option<(InstructionPointer, CodelCoord)>)> {
+        let id = self.block_id(coord)?;
+        let regions = self.regions();
+        Some((regions.blocks.get(id)?, regions.moves.get(id)?[ip.index()]))
+    }
+
+    /// Work out the program's blocks and every move between them now,
+    /// rather than on its first step.
+    /// Running a program walks this graph of moves instead of searching
+    /// the image for the way out of each block it visits, which is what
+    /// makes a long-running loop fast. It's found the first time it's
+    /// needed, so compiling is only worth it to get that cost out of the way
+    /// ahead of time, eg before timing a run, or before sharing the program
+    /// between threads.
+    pub fn compile(&self) {
+        self.regions();
+    }
+
+    fn regions(&self) -> &Regions {
+        self.regions.get_or_init(|| self.find_regions())
+    }
+
+    fn find_regions(&self) -> Regions {
+        let mut ids = vec![usize::MAX; self.code.len()];
+        let mut blocks = Vec::new();
+        for (coord, _) in self.codels() {
+            if ids[coord.x + coord.y * self.width] != usize::MAX { continue; }
+            let Some(block) = self.flood(coord) else { continue; };
+            for codel in &block.region {
+                ids[codel.x + codel.y * self.width] = blocks.len();
+            }
+            blocks.push(block);
+        }
+        let moves = blocks.iter()
+            .map(|block| InstructionPointer::ALL.map(|ip| self.find_move(block, ip)))
+            .collect();
+        Regions { ids, blocks, moves }
+    }
+
+    /// The first way out of `block` for execution arriving with `ip`, and the
+    /// instruction pointer it leaves with. Each blocked way out flips the codel
+    /// chooser, or, every second time, rotates the direction pointer instead.
+    fn find_move(&self, block: &CodelRegion, mut ip: InstructionPointer) -> Option<(InstructionPointer, CodelCoord)> {
+        let open = |ip| block.exit_to(ip)
+            .filter(|&to| !matches!(self.at(to), None | Some(Color::Black)));
+        for _ in 0..4 {
+            if let Some(to) = open(ip) {
+                return Some((ip, to));
+            }
+            ip.flip();
+            if let Some(to) = open(ip) {
+                return Some((ip, to));
+            }
+            ip.rotate();
+        }
+        None
+    }
+
+    /// Find the block containing `coord`, codel by codel.
+    fn flood(&self, coord: CodelCoord) -> Option<CodelRegion> {
+        let color = self.at(coord)?;
         let mut seen = HashSet::new();
-        seen.insert((x, y));
+        seen.insert(coord);
         let mut queue = VecDeque::new();
-        queue.push_back((x, y));
-        while let Some((x, y)) = queue.pop_front() {
-            for (dx, dy) in [(0, 1), (1, 0), (0, usize::MAX), (usize::MAX, 0)] {
-                let nx = x.wrapping_add(dx);
-                let ny = y.wrapping_add(dy);
-                if self.at(nx, ny).map_or(true, |n| n != color) { continue; }
-                if !seen.insert((nx, ny)) { continue; }
-                queue.push_back((nx, ny));
+        queue.push_back(coord);
+        while let Some(coord) = queue.pop_front() {
+            for delta in [Delta::DOWN, Delta::RIGHT, Delta::UP, Delta::LEFT] {
+                let Some(next) = coord.checked_add(delta) else { continue; };
+                if self.at(next).is_none_or(|n| n != color) { continue; }
+                if !seen.insert(next) { continue; }
+                queue.push_back(next);
             }
         }
         Some(CodelRegion::new(seen, color))
     }
 
+    fn crop(&self, x: usize, y: usize, width: usize, height: usize) -> PietCode {
+        let code = iproduct!(y..y + height, x..x + width)
+            .map(|(y, x)| self.code[x + y * self.width])
+            .collect();
+        PietCode::new(width, height, code)
+    }
+
+    /// Trim away any uniform white, black, or non-Piet colored border,
+    /// returning the trimmed code and where it was within the original.
+    /// Programs always start from a hue, so if the top-left codel isn't one
+    /// it's assumed to be border.
+    fn trim_border(self) -> (PietCode, CodelCoord) {
+        let border = self.code[0];
+        if matches!(border, Color::Color(..)) {
+            return (self, CodelCoord::default());
+        }
+        let is_border = |c: Color| c == border;
+        let is_border_at = |x, y| self.at(CodelCoord::new(x, y)).is_none_or(is_border);
+        let row_is_border = |y| (0..self.width).all(|x| is_border_at(x, y));
+        let col_is_border = |x| (0..self.height).all(|y| is_border_at(x, y));
+        let top = (0..self.height).find(|&y| !row_is_border(y));
+        let bottom = (0..self.height).rfind(|&y| !row_is_border(y));
+        let left = (0..self.width).find(|&x| !col_is_border(x));
+        let right = (0..self.width).rfind(|&x| !col_is_border(x));
+        let (Some(top), Some(bottom), Some(left), Some(right)) = (top, bottom, left, right) else {
+            // It's all border! Nothing to trim down to.
+            return (self, CodelCoord::default());
+        };
+        (self.crop(left, top, right - left + 1, bottom - top + 1), CodelCoord::new(left, top))
+    }
+
+    /// Change one codel's color.
+    /// Returns false, changing nothing, if `coord` is outside the program.
+    pub fn set(&mut self, coord: CodelCoord, color: Color) -> bool {
+        let CodelCoord { x, y } = coord;
+        if x >= self.width || y >= self.height { return false; }
+        self.code[x + y * self.width] = color;
+        self.regions.take();
+        true
+    }
+
+    /// Recolor the whole block containing `coord`, like a paint bucket,
+    /// returning how many codels were changed.
+    pub fn fill(&mut self, coord: CodelCoord, color: Color) -> usize {
+        let Some(region) = self.region_at(coord) else { return 0; };
+        if region.color == color { return 0; }
+        let codels: Vec<_> = region.region.iter().copied().collect();
+        for &codel in &codels {
+            self.set(codel, color);
+        }
+        codels.len()
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    /// Check every codel is one of Piet's 20 colors, eg to be sure an image is
+    /// standard Piet, which any interpreter runs the same. The error says
+    /// where the first codel that isn't is, and how many others there are.
+    pub fn check_standard(&self) -> Result<(), String> {
+        let mut others = self.codels().filter(|&(_, color)| color == Color::Other);
+        let Some((first, _)) = others.next() else { return Ok(()); };
+        match others.count() {
+            0 => Err(format!("codel {first} isn't a Piet color")),
+            1 => Err(format!("codel {first} isn't a Piet color, nor is 1 more")),
+            more => Err(format!("codel {first} isn't a Piet color, nor are {more} more")),
+        }
+    }
+
+    /// A hash of the codel grid, for telling whether two images hold
+    /// the same program regardless of their codel size.
+    /// Stable across platforms and releases, so it's safe to store.
+    pub fn fingerprint(&self) -> u64 {
+        // FNV-1a
+        const PRIME: u64 = 0x100000001b3;
+        let mut hash: u64 = 0xcbf29ce484222325;
+        let dimensions = [self.width as u64, self.height as u64].map(u64::to_le_bytes);
+        let codels = self.code.iter().map(|color| {
+            Color::ALL.iter().position(|c| c == color).unwrap_or(Color::ALL.len()) as u8
+        });
+        for byte in dimensions.into_iter().flatten().chain(codels) {
+            hash = (hash ^ byte as u64).wrapping_mul(PRIME);
+        }
+        hash
+    }
+
     pub fn execute(&self) -> PietRunner<'_> {
-        PietRunner::new(self)
+        PietRunner::new(std::slice::from_ref(self))
     }
 }
 
+/// Run a multi-page program, eg the frames of an animated image from `load_frames`.
+///
+/// This is an experimental dialect: when the program halts on any page but the last,
+/// it turns to the next, starting again from its top-left codel with the DP and CC reset.
+/// The stack, input, and output carry over.
+/// With no pages at all, the program halts immediately.
+pub fn execute_pages(pages: &[PietCode]) -> PietRunner<'_> {
+    PietRunner::new(pages)
+}
+
+/// Which way the direction pointer (DP) points.
 #[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
-enum Direction {
+pub enum Direction {
     Right,
     Down,
     Left,
@@ -283,76 +529,83 @@ enum Direction {
 }
 
 impl Direction {
-    fn to_delta(self) -> Coord {
+    fn to_delta(self) -> Delta {
         match self {
-            Direction::Right => (1, 0),
-            Direction::Down => (0, 1),
-            Direction::Left => (usize::MAX, 0),
-            Direction::Up => (0, usize::MAX),
+            Direction::Right => Delta::RIGHT,
+            Direction::Down => Delta::DOWN,
+            Direction::Left => Delta::LEFT,
+            Direction::Up => Delta::UP,
         }
     }
 }
 
+/// Which way the codel chooser (CC) points, relative to the direction pointer.
 #[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
-enum CodelChoice { Left, Right }
+pub enum CodelChoice { Left, Right }
 
+#[derive(Debug)]
 pub struct CodelRegion {
     pub(crate) color: Color,
-    pub(crate) region: HashSet<Coord>,
+    pub(crate) region: HashSet<CodelCoord>,
+    /// Where each instruction pointer leaves to, by `InstructionPointer::index`.
+    exits: [Option<CodelCoord>; 8],
 }
 
 impl CodelRegion {
-    fn new(region: HashSet<Coord>, color: Color) -> Self {
-        CodelRegion { color, region }
+    fn new(region: HashSet<CodelCoord>, color: Color) -> Self {
+        let exits = InstructionPointer::ALL.map(|ip| find_exit(&region, ip));
+        CodelRegion { color, region, exits }
     }
 
-    fn value(&self) -> BigInt {
-        BigInt::from(self.region.len())
+    fn value(&self) -> Value {
+        Value::from(self.region.len())
     }
 
-    fn exit_to(&self, ip: InstructionPointer) -> Coord {
-        let InstructionPointer(dp, cc) = ip;
-        match (dp, cc) {
-            (Direction::Right, CodelChoice::Left) => {
-                let (x, y) = *self.region.iter().max_by_key(|(x, y)| (x, Reverse(y))).unwrap();
-                (x + 1, y)
-            }
-            (Direction::Right, CodelChoice::Right) => {
-                let (x, y) = *self.region.iter().max_by_key(|(x, y)| (x, y)).unwrap();
-                (x + 1, y)
-            }
-            (Direction::Down, CodelChoice::Left) => {
-                let (x, y) = *self.region.iter().max_by_key(|(x, y)| (y, x)).unwrap();
-                (x, y + 1)
-            }
-            (Direction::Down, CodelChoice::Right) => {
-                let (x, y) = *self.region.iter().max_by_key(|(x, y)| (y, Reverse(x))).unwrap();
-                (x, y + 1)
-            }
-            (Direction::Left, CodelChoice::Left) => {
-                let (x, y) = *self.region.iter().min_by_key(|(x, y)| (x, Reverse(y))).unwrap();
-                (x.wrapping_sub(1), y)
-            }
-            (Direction::Left, CodelChoice::Right) => {
-                let (x, y) = *self.region.iter().min_by_key(|(x, y)| (x, y)).unwrap();
-                (x.wrapping_sub(1), y)
-            }
-            (Direction::Up, CodelChoice::Left) => {
-                let (x, y) = *self.region.iter().min_by_key(|(x, y)| (y, x)).unwrap();
-                (x, y.wrapping_sub(1))
-            }
-            (Direction::Up, CodelChoice::Right) => {
-                let (x, y) = *self.region.iter().min_by_key(|(x, y)| (y, Reverse(x))).unwrap();
-                (x, y.wrapping_sub(1))
-            }
-        }
+    /// The codel execution would move to when leaving this region,
+    /// or None if that's off the top or left of the grid.
+    fn exit_to(&self, ip: InstructionPointer) -> Option<CodelCoord> {
+        self.exits[ip.index()]
     }
 }
 
+/// The codel leaving a set of codels with `ip` would move to.
+fn find_exit(region: &HashSet<CodelCoord>, ip: InstructionPointer) -> Option<CodelCoord> {
+    let InstructionPointer(dp, cc) = ip;
+    let codels = region.iter();
+    let edge = match (dp, cc) {
+        (Direction::Right, CodelChoice::Left) => codels.max_by_key(|c| (c.x, Reverse(c.y))),
+        (Direction::Right, CodelChoice::Right) => codels.max_by_key(|c| (c.x, c.y)),
+        (Direction::Down, CodelChoice::Left) => codels.max_by_key(|c| (c.y, c.x)),
+        (Direction::Down, CodelChoice::Right) => codels.max_by_key(|c| (c.y, Reverse(c.x))),
+        (Direction::Left, CodelChoice::Left) => codels.min_by_key(|c| (c.x, Reverse(c.y))),
+        (Direction::Left, CodelChoice::Right) => codels.min_by_key(|c| (c.x, c.y)),
+        (Direction::Up, CodelChoice::Left) => codels.min_by_key(|c| (c.y, c.x)),
+        (Direction::Up, CodelChoice::Right) => codels.min_by_key(|c| (c.y, Reverse(c.x))),
+    };
+    edge?.checked_add(dp.to_delta())
+}
+
 #[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
 struct InstructionPointer(Direction, CodelChoice);
 
 impl InstructionPointer {
+    /// Every instruction pointer, in order of `index`.
+    const ALL: [InstructionPointer; 8] = [
+        InstructionPointer(Direction::Right, CodelChoice::Left),
+        InstructionPointer(Direction::Right, CodelChoice::Right),
+        InstructionPointer(Direction::Down, CodelChoice::Left),
+        InstructionPointer(Direction::Down, CodelChoice::Right),
+        InstructionPointer(Direction::Left, CodelChoice::Left),
+        InstructionPointer(Direction::Left, CodelChoice::Right),
+        InstructionPointer(Direction::Up, CodelChoice::Left),
+        InstructionPointer(Direction::Up, CodelChoice::Right),
+    ];
+
+    /// A distinct index for each of the eight instruction pointers, from 0 to 7.
+    fn index(self) -> usize {
+        self.0 as usize * 2 + self.1 as usize
+    }
+
     fn flip(&mut self) {
         self.1 = match self.1 {
             CodelChoice::Left => CodelChoice::Right,
@@ -377,13 +630,20 @@ impl Default for InstructionPointer {
 }
 
 #[derive(Debug)]
-enum ExecutionError {
+pub enum ExecutionError {
     NotEnoughStack(usize, usize),
     NegativeRoll(BigInt),
     IntegerOverflow,
+    /// An arithmetic result was wider than the run's `ValueCap` allows, in bits.
+    ValueTooWide(u64),
+    /// The stack was already as deep as the run allows.
+    StackOverflow(usize),
     DivisionByZero,
     IoError(std::io::Error),
     EncodeError(BigInt),
+    ExpectedNumber,
+    /// Execution reached a codel of a non-Piet color, which it can't run through.
+    InvalidColor(CodelCoord),
 }
 
 impl fmt::Display for ExecutionError {
@@ -396,18 +656,199 @@ impl fmt::Display for ExecutionError {
             }
             NegativeRoll(num) => write!(f, "expected positive roll depth, not {num}"),
             IntegerOverflow => write!(f, "integer overflow"),
+            ValueTooWide(bits) => write!(f, "result doesn't fit in the {bits}-bit limit on values"),
+            StackOverflow(depth) => write!(f, "stack overflow: can't grow past the limit of {depth}"),
             IoError(e) => write!(f, "IO error: {e}"),
             DivisionByZero => write!(f, "division by zero"),
-            EncodeError(num) => write!(f, "can't encode integer '{num}' as character"),
+            EncodeError(num) => write!(f, "can't encode integer '{num}' as character: it isn't a Unicode code point"),
+            ExpectedNumber => write!(f, "expected a number on input"),
+            InvalidColor(coord) => write!(f, "can't move into codel {coord}, which isn't a Piet color"),
         }
     }
 }
 
+/// Where a program's input comes from.
+#[derive(Default)]
+enum Input {
+    #[default]
+    Stdin,
+    /// Stdin, with the terminal (if any) set up to send each key as it's pressed
+    /// until the input is dropped.
+    #[cfg(feature = "raw-terminal")]
+    RawStdin { _terminal: raw::RawTerminal },
+    Buffer(VecDeque<u8>),
+    /// Input arriving bit by bit, with more to come: reading past what's
+    /// arrived so far fails with `WouldBlock`. See `PietFilter`.
+    Pending(VecDeque<u8>),
+    Stream(Box<dyn BufRead + Send>),
+}
+
+fn peek_stdin() -> Result<Option<u8>, ExecutionError> {
+    let mut stdin = std::io::stdin().lock();
+    let buf = stdin.fill_buf().map_err(ExecutionError::IoError)?;
+    Ok(buf.first().copied())
+}
+
+/// How input is taken from where it comes from. Either way, `InChar` and
+/// `InNum` read the same input, one after the other.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum InputMode {
+    /// A line at a time: nothing is read until the whole line has arrived,
+    /// up to its newline or the end of input. At a terminal, as it usually
+    /// has it, that's once Enter is pressed, so typing can be corrected first.
+    #[default]
+    Line,
+    /// A byte at a time, as soon as each arrives, eg for interactive programs.
+    /// With the `raw-terminal` feature, a terminal is set up to send each key
+    /// as soon as it's pressed, and Enter is read as a newline.
+    Raw,
+}
+
+/// What reading input does once there's none left.
+/// Interpreters disagree, so programs written for one may expect any of these.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum EofBehavior {
+    /// The command fails with an `UnexpectedEof` IO error, so is skipped.
+    #[default]
+    Error,
+    /// The command pushes -1.
+    PushNegativeOne,
+    /// The command does nothing.
+    Ignore,
+}
+
+/// How execution moves across white, where interpreters disagree, so that
+/// programs written for one run here as they do there. Leaving a colored
+/// block they agree on: when blocked, toggle the CC, then when blocked
+/// again rotate the DP, and so on until every way out has been tried.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Dialect {
+    /// As npiet and the spec, since it was clarified, have it: slide straight
+    /// across white in the direction of the DP, and at each restriction toggle
+    /// the CC and rotate the DP together, as toggling the CC alone can't
+    /// change where a slide goes.
+    #[default]
+    Npiet,
+    /// As PietDev and the spec before it was clarified have it: leave a white
+    /// block as a colored one is left, from the edge chosen by the DP and CC,
+    /// running no command. A program can turn corners in white it would
+    /// slide straight past in npiet.
+    PietDev,
+}
+
+/// How `InNum` reads a number. Interpreters disagree on the details:
+/// the default reads as C's `scanf("%d")` does, like npiet.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct NumInputMode {
+    /// Skip whitespace, including newlines, before the number.
+    /// Otherwise any whitespace first means there's no number.
+    pub skip_whitespace: bool,
+    /// Accept a leading `+`, as well as `-`.
+    pub plus_sign: bool,
+    /// Read the rest of the line after the number, which must be blank,
+    /// as interpreters that prompt for a line of input do.
+    /// Otherwise reading stops after the last digit.
+    pub whole_line: bool,
+}
+
+impl Default for NumInputMode {
+    fn default() -> Self {
+        NumInputMode { skip_whitespace: true, plus_sign: true, whole_line: false }
+    }
+}
+
+/// Where a program's output goes.
+enum Output {
+    /// Buffered, to write out as the VM's `FlushPolicy` says.
+    Stdout { out: std::io::BufWriter<std::io::Stdout>, terminal: bool },
+    Buffer(Vec<u8>),
+    Stream(Box<dyn Write + Send>),
+}
+
+impl Default for Output {
+    fn default() -> Self {
+        let stdout = std::io::stdout();
+        Output::Stdout { terminal: stdout.is_terminal(), out: std::io::BufWriter::new(stdout) }
+    }
+}
+
+/// When a program's output to stdout is written out, rather than kept in a
+/// buffer, which is much faster for programs that print a lot.
+/// Whatever the policy, output is written out once the buffer fills, and
+/// once a run stops.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum FlushPolicy {
+    /// Before reading input, so prompts are seen, and at the end of each
+    /// line when stdout is a terminal.
+    #[default]
+    Auto,
+    /// As soon as it's written, eg to interleave it with other output.
+    Always,
+    /// Only when asked to, with `PietRunner::flush`.
+    Manual,
+}
+
+/// What a single step did.
+#[derive(Debug)]
+pub enum StepResult {
+    /// The program moved on.
+    Continue(Executed),
+    /// The program has halted. Further steps do nothing.
+    Halted,
+    /// The command failed, so was skipped. The program still moved on,
+    /// except from an `InvalidColor` error: then it's stuck where it was,
+    /// and a run stops whatever its runtime policy.
+    Error(Executed, ExecutionError),
+    /// The program halted on one page of a multi-page program,
+    /// so moved to the start of the next: this one.
+    TurnedPage(usize),
+}
+
+/// A move from one codel to the next, and the command it ran.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Executed {
+    pub command: Command,
+    pub from: CodelCoord,
+    pub to: CodelCoord,
+    /// The size of the block moved out of, ie the value `Push` would push.
+    /// Zero when sliding across white, which runs no command (so reports `Noop`).
+    pub value: Value,
+}
+
 #[derive(Default)]
 pub struct PietVM {
     instruction_pointer: InstructionPointer,
-    pos: Coord,
-    stack: Vec<BigInt>,
+    pos: CodelCoord,
+    stack: Vec<Value>,
+    input: Input,
+    output: Output,
+    eof: EofBehavior,
+    num_input: NumInputMode,
+    input_mode: InputMode,
+    /// The line being read, in `InputMode::Line`: whole once it ends in a newline.
+    line: VecDeque<u8>,
+    cap: Option<ValueCap>,
+    max_stack: Option<usize>,
+    dialect: Dialect,
+    /// Input given back by stepping back, to be read again before any more,
+    /// with the next byte last.
+    unread: Vec<u8>,
+    /// The input consumed since this was set, if keeping track.
+    read: Option<Vec<u8>>,
+    flush: FlushPolicy,
+}
+
+type CommandHook<'a> = Box<dyn FnMut(Command, &[Value]) + Send + 'a>;
+type OutputHook<'a> = Box<dyn FnMut(&str) + Send + 'a>;
+type ErrorHook<'a> = Box<dyn FnMut(Command, &ExecutionError) + Send + 'a>;
+
+/// Callbacks registered on a runner. See `PietRunner::before_command`.
+#[derive(Default)]
+struct Hooks<'a> {
+    before_command: Vec<CommandHook<'a>>,
+    after_command: Vec<CommandHook<'a>>,
+    output: Vec<OutputHook<'a>>,
+    error: Vec<ErrorHook<'a>>,
 }
 
 impl PietVM {
@@ -415,49 +856,52 @@ impl PietVM {
         Self::default()
     }
 
-    // Fetch the next position to move to.
-    fn walk_color(&mut self, code: &PietCode) -> Option<(CodelRegion, Coord, Color)> {
-        let (x, y) = self.pos;
-        let region = code.region_at(x, y).unwrap();
+    /// The stack, from bottom to top.
+    pub fn stack(&self) -> &[Value] {
+        &self.stack
+    }
 
-        for _ in 0..4 {
-            let coord @ (x, y) = region.exit_to(self.instruction_pointer);
-            match code.at(x, y) {
-                None | Some(Color::Black) => (),
-                Some(Color::Other) => { panic!("invalid color while walking [{x}, {y}]"); }
-                Some(color) => { return Some((region, coord, color)); }
-            }
-            self.instruction_pointer.flip();
+    /// The stack, for changing mid-run, eg from a debugger.
+    pub fn stack_mut(&mut self) -> &mut Vec<Value> {
+        &mut self.stack
+    }
 
-            let coord @ (x, y) = region.exit_to(self.instruction_pointer);
-            match code.at(x, y) {
-                None | Some(Color::Black) => (),
-                Some(Color::Other) => { panic!(); }
-                Some(color) => { return Some((region, coord, color)); }
-            }
-            self.instruction_pointer.rotate();
-        }
-        None
+    /// The codel execution is at.
+    pub fn position(&self) -> CodelCoord {
+        self.pos
+    }
+
+    /// The direction pointer.
+    pub fn direction(&self) -> Direction {
+        self.instruction_pointer.0
+    }
+
+    /// The codel chooser.
+    pub fn codel_chooser(&self) -> CodelChoice {
+        self.instruction_pointer.1
     }
 
-    fn walk_white(&mut self, code: &PietCode) -> Option<(Coord, Color)> {
+    // Fetch the next position to move to. This may be a non-Piet color,
+    // which execution can't move into.
+    fn walk_color<'a>(&mut self, code: &'a PietCode) -> Option<(&'a CodelRegion, CodelCoord, Color)> {
+        let (region, next) = code.move_from(self.pos, self.instruction_pointer)?;
+        let (ip, to) = next?;
+        self.instruction_pointer = ip;
+        Some((region, to, code.at(to)?))
+    }
+
+    fn walk_white(&mut self, code: &PietCode) -> Option<(CodelCoord, Color)> {
         let mut seen = HashSet::new();
-        let mut nx;
-        let mut ny;
         while seen.insert((self.pos, self.instruction_pointer)) {
             let InstructionPointer(dir, _) = self.instruction_pointer;
-            let (dx, dy) = dir.to_delta();
-            while let Some(color) = {
-                let (x, y) = self.pos;
-                nx = x.wrapping_add(dx);
-                ny = y.wrapping_add(dy);
-                code.at(nx, ny)
-            } {
+            let delta = dir.to_delta();
+            while let Some((next, color)) = self.pos.checked_add(delta)
+                .and_then(|next| Some((next, code.at(next)?)))
+            {
                 match color {
                     Color::Black => { break; }
-                    Color::Other => { panic!("invalid color while sliding"); }
-                    Color::White => { self.pos = (nx, ny); }
-                    color => { return Some(((nx, ny), color)); }
+                    Color::White => { self.pos = next; }
+                    color => { return Some((next, color)); }
                 }
             }
             self.instruction_pointer.flip();
@@ -466,86 +910,279 @@ impl PietVM {
         None
     }
 
-    fn pop1(&mut self) -> Result<BigInt, ExecutionError> {
+    fn pop1(&mut self) -> Result<Value, ExecutionError> {
         self.stack.pop()
-            .ok_or_else(|| ExecutionError::NotEnoughStack(1, 0))
+            .ok_or(ExecutionError::NotEnoughStack(1, 0))
+    }
+
+    fn pop2(&mut self) -> Result<(Value, Value), ExecutionError> {
+        let len = self.stack.len();
+        if len < 2 {
+            return Err(ExecutionError::NotEnoughStack(2, len));
+        }
+        let mut popped = self.stack.drain(len - 2..);
+        match (popped.next(), popped.next()) {
+            (Some(a), Some(b)) => Ok((a, b)),
+            _ => Err(ExecutionError::NotEnoughStack(2, len)),
+        }
     }
 
-    fn pop2(&mut self) -> Result<(BigInt, BigInt), ExecutionError> {
-        if self.stack.len() < 2 {
-            return Err(ExecutionError::NotEnoughStack(2, self.stack.len()));
+    /// Replace the top two values with `op` of them, if the result is within
+    /// the value cap (or the cap's policy brings it within).
+    fn arithmetic(&mut self, op: fn(Value, Value) -> Value) -> Result<(), ExecutionError> {
+        let (a, b) = self.pop2()?;
+        let Some(cap) = self.cap else {
+            self.stack.push(op(a, b));
+            return Ok(());
+        };
+        match cap.apply(op(a.clone(), b.clone())) {
+            Some(result) => self.stack.push(result),
+            None => {
+                self.stack.extend([a, b]);
+                return Err(ExecutionError::ValueTooWide(cap.bits));
+            }
         }
-        let b = self.stack.pop().unwrap();
-        let a = self.stack.pop().unwrap();
-        Ok((a, b))
+        Ok(())
     }
 
-    fn last1(&self) -> Result<&BigInt, ExecutionError> {
+    fn last1(&self) -> Result<&Value, ExecutionError> {
         self.stack.last()
-            .ok_or_else(|| ExecutionError::NotEnoughStack(1, 0))
+            .ok_or(ExecutionError::NotEnoughStack(1, 0))
     }
 
-    fn last2(&self) -> Result<(&BigInt, &BigInt), ExecutionError> {
-        let len = self.stack.len();
-        if len < 2 { return Err(ExecutionError::NotEnoughStack(2, self.stack.len())); }
-        if let [d, r] = &self.stack[len - 2..] { Ok((d, r)) }
-            else { unreachable!(); }  // rust you dingus
+    fn last2(&self) -> Result<(&Value, &Value), ExecutionError> {
+        match self.stack.as_slice() {
+            [.., d, r] => Ok((d, r)),
+            stack => Err(ExecutionError::NotEnoughStack(2, stack.len())),
+        }
+    }
+
+    /// The next byte of input, without consuming it, or None at the end of input.
+    fn peek_byte(&mut self) -> Result<Option<u8>, ExecutionError> {
+        if let Some(&byte) = self.unread.last() {
+            return Ok(Some(byte));
+        }
+        if self.input_mode == InputMode::Raw {
+            self.flush_before_input()?;
+            return self.peek_input();
+        }
+        if self.line.back() != Some(&b'\n') {
+            self.flush_before_input()?;
+        }
+        while self.line.back() != Some(&b'\n') {
+            let Some(byte) = self.peek_input()? else { break; };
+            self.consume_input();
+            self.line.push_back(byte);
+        }
+        Ok(self.line.front().copied())
+    }
+
+    /// Consume the byte returned by `peek_byte`.
+    fn consume_byte(&mut self) {
+        if self.read.is_some() {
+            if let Ok(Some(byte)) = self.peek_byte() {
+                self.read.get_or_insert_default().push(byte);
+            }
+        }
+        if self.unread.pop().is_some() || self.line.pop_front().is_some() {
+            return;
+        }
+        self.consume_input();
+    }
+
+    /// Write out what's been output so far, as `FlushPolicy::Auto` has it,
+    /// before waiting on input that might be waiting on it.
+    fn flush_before_input(&mut self) -> Result<(), ExecutionError> {
+        if self.flush == FlushPolicy::Auto && !matches!(self.input, Input::Buffer(_) | Input::Pending(_)) {
+            self.flush_output().map_err(ExecutionError::IoError)?;
+        }
+        Ok(())
+    }
+
+    /// The next byte from the input itself, without consuming it.
+    fn peek_input(&mut self) -> Result<Option<u8>, ExecutionError> {
+        match &mut self.input {
+            Input::Stdin => peek_stdin(),
+            // A raw terminal sends Enter as a carriage return, where it'd usually send a newline.
+            #[cfg(feature = "raw-terminal")]
+            Input::RawStdin { .. } => Ok(peek_stdin()?.map(|byte| if byte == b'\r' { b'\n' } else { byte })),
+            Input::Buffer(input) => Ok(input.front().copied()),
+            Input::Pending(input) => match input.front() {
+                Some(&byte) => Ok(Some(byte)),
+                None => Err(ExecutionError::IoError(std::io::ErrorKind::WouldBlock.into())),
+            },
+            Input::Stream(input) => {
+                let buf = input.fill_buf().map_err(ExecutionError::IoError)?;
+                Ok(buf.first().copied())
+            }
+        }
+    }
+
+    fn consume_input(&mut self) {
+        match &mut self.input {
+            Input::Stdin => std::io::stdin().lock().consume(1),
+            #[cfg(feature = "raw-terminal")]
+            Input::RawStdin { .. } => std::io::stdin().lock().consume(1),
+            Input::Buffer(input) | Input::Pending(input) => { input.pop_front(); }
+            Input::Stream(input) => input.consume(1),
+        }
+    }
+
+    fn read_byte(&mut self) -> Result<u8, ExecutionError> {
+        let byte = self.peek_byte()?.ok_or_else(|| {
+            ExecutionError::IoError(std::io::ErrorKind::UnexpectedEof.into())
+        })?;
+        self.consume_byte();
+        Ok(byte)
+    }
+
+    /// Read an integer, as the VM's `NumInputMode` has it: by default skip
+    /// leading whitespace, then read an optional sign and digits.
+    /// Whatever follows the digits is left unread.
+    fn read_num(&mut self) -> Result<Value, ExecutionError> {
+        let mode = self.num_input;
+        if mode.skip_whitespace {
+            while self.peek_byte()?.is_some_and(|b| b.is_ascii_whitespace()) {
+                self.consume_byte();
+            }
+        }
+        let mut digits = String::new();
+        if let Some(sign @ (b'-' | b'+')) = self.peek_byte()? {
+            if sign == b'-' || mode.plus_sign {
+                digits.push(sign as char);
+                self.consume_byte();
+            }
+        }
+        while let Some(digit) = self.peek_byte()?.filter(u8::is_ascii_digit) {
+            digits.push(digit as char);
+            self.consume_byte();
+        }
+        let ended = self.peek_byte()?.is_none();
+        let mut rest_of_line_blank = true;
+        if mode.whole_line {
+            while let Some(byte) = self.peek_byte()? {
+                self.consume_byte();
+                if byte == b'\n' { break; }
+                rest_of_line_blank &= byte.is_ascii_whitespace();
+            }
+        }
+        match digits.parse::<BigInt>() {
+            Ok(num) if rest_of_line_blank => Ok(Value::from(num)),
+            Err(_) if ended => {
+                Err(ExecutionError::IoError(std::io::ErrorKind::UnexpectedEof.into()))
+            }
+            _ => Err(ExecutionError::ExpectedNumber),
+        }
+    }
+
+    /// Push what was read, or handle having run out of input.
+    fn push_input(&mut self, read: Result<Value, ExecutionError>) -> Result<(), ExecutionError> {
+        let num = match read {
+            Ok(num) => num,
+            Err(ExecutionError::IoError(err)) if err.kind() == std::io::ErrorKind::UnexpectedEof => {
+                match self.eof {
+                    EofBehavior::Error => { return Err(ExecutionError::IoError(err)); }
+                    EofBehavior::PushNegativeOne => -Value::one(),
+                    EofBehavior::Ignore => { return Ok(()); }
+                }
+            }
+            Err(err) => { return Err(err); }
+        };
+        self.stack.push(num);
+        Ok(())
+    }
+
+    fn write(&mut self, text: &str, tracer: &mut dyn Tracer, hooks: &mut Hooks) -> Result<(), ExecutionError> {
+        let end_of_line = match &mut self.output {
+            Output::Stdout { out, terminal } => {
+                out.write_all(text.as_bytes()).map_err(ExecutionError::IoError)?;
+                *terminal && text.contains('\n')
+            }
+            Output::Buffer(output) => {
+                output.extend_from_slice(text.as_bytes());
+                false
+            }
+            Output::Stream(output) => {
+                output.write_all(text.as_bytes()).map_err(ExecutionError::IoError)?;
+                false
+            }
+        };
+        if self.flush == FlushPolicy::Always || (self.flush == FlushPolicy::Auto && end_of_line) {
+            self.flush_output().map_err(ExecutionError::IoError)?;
+        }
+        tracer.on_output(text);
+        for hook in &mut hooks.output {
+            hook(text);
+        }
+        Ok(())
+    }
+
+    /// Write out any buffered output.
+    fn flush_output(&mut self) -> std::io::Result<()> {
+        match &mut self.output {
+            Output::Stdout { out, .. } => out.flush(),
+            Output::Stream(output) => output.flush(),
+            Output::Buffer(_) => Ok(()),
+        }
+    }
+
+    fn run_command(&mut self, command: Command, value: Value) -> Result<(), ExecutionError> {
+        self.run_traced(command, value, &mut NoTracer, &mut Hooks::default())
     }
 
-    fn run_command(&mut self, command: Command, value: BigInt) -> Result<(), ExecutionError> {
+    /// Run `command`, reporting what it outputs to `tracer` and `hooks`.
+    fn run_traced(
+        &mut self,
+        command: Command,
+        value: Value,
+        tracer: &mut dyn Tracer,
+        hooks: &mut Hooks,
+    ) -> Result<(), ExecutionError> {
+        let grows = matches!(command, Command::Push | Command::Duplicate | Command::InNum | Command::InChar);
+        if let Some(max) = self.max_stack.filter(|&max| grows && self.stack.len() >= max) {
+            return Err(ExecutionError::StackOverflow(max));
+        }
         match command {
             Command::Noop => {}
             Command::Push => {
                 self.stack.push(value);
             }
             Command::Pop => { self.pop1()?; }
-            Command::Add => {
-                let (a, b) = self.pop2()?;
-                self.stack.push(a + b);
-            }
-            Command::Subtract => {
-                let (a, b) = self.pop2()?;
-                self.stack.push(a - b);
-            }
-            Command::Multiply => {
-                let (a, b) = self.pop2()?;
-                self.stack.push(a * b);
-            }
+            Command::Add => self.arithmetic(|a, b| a + b)?,
+            Command::Subtract => self.arithmetic(|a, b| a - b)?,
+            Command::Multiply => self.arithmetic(|a, b| a * b)?,
             Command::Divide => {
                 let (_, b) = self.last2()?;
-                if b == &BigInt::zero() {
+                if b.is_zero() {
                     return Err(ExecutionError::DivisionByZero);
                 }
-                let (a, b) = self.pop2()?;
-                self.stack.push(a.div_floor(&b));
+                self.arithmetic(|a, b| a.div_floor(&b))?;
             }
             Command::Mod => {
                 let (_, b) = self.last2()?;
-                if b == &BigInt::zero() {
+                if b.is_zero() {
                     return Err(ExecutionError::DivisionByZero);
                 }
-                let (a, b) = self.pop2()?;
-                self.stack.push(a.mod_floor(&b));
+                self.arithmetic(|a, b| a.mod_floor(&b))?;
             }
             Command::Not => {
                 let num = self.pop1()?;
-                let zero = BigInt::zero();
-                self.stack.push(if num == zero { BigInt::one() } else { zero });
+                self.stack.push(if num.is_zero() { Value::one() } else { Value::zero() });
             }
             Command::Greater => {
                 let (a, b) = self.pop2()?;
-                self.stack.push(if a > b { BigInt::one() } else { BigInt::zero() });
+                self.stack.push(if a > b { Value::one() } else { Value::zero() });
             }
             Command::Pointer => {
                 let spin = self.pop1()?;
-                let spin = spin.mod_floor(&(4.into())).to_u8().unwrap();
+                let spin = spin.mod_floor(&Value::from(4)).to_u8().unwrap_or_default();
                 for _ in 0..spin {
                     self.instruction_pointer.rotate();
                 }
             }
             Command::Switch => {
                 let swap = self.pop1()?;
-                if swap % 2 != BigInt::zero() {
+                if swap.is_odd() {
                     self.instruction_pointer.flip();
                 }
             }
@@ -555,211 +1192,2805 @@ impl PietVM {
             }
             Command::Roll => {
                 let (dive, roll) = self.last2()?;
-                if dive <= &BigInt::zero() {
-                    return Err(ExecutionError::NegativeRoll(dive.clone()));
+                if dive.is_negative() || dive.is_zero() {
+                    return Err(ExecutionError::NegativeRoll(dive.to_bigint()));
                 }
-                let roll = roll.mod_floor(&dive).to_usize()
+                let roll = roll.mod_floor(dive).to_usize()
                     .ok_or(ExecutionError::IntegerOverflow)?;
                 let dive = dive.to_usize()
                     .ok_or(ExecutionError::IntegerOverflow)?;
                 let len = self.stack.len() - 2;
                 let start = len.checked_sub(dive)
-                    .ok_or_else(|| ExecutionError::NotEnoughStack(len, dive))?;
+                    .ok_or(ExecutionError::NotEnoughStack(len, dive))?;
                 self.pop2()?;
                 self.stack[start..].rotate_right(roll);
             }
-            Command::InNum => { todo!(); }
+            Command::InNum => {
+                let num = self.read_num();
+                self.push_input(num)?;
+            }
             Command::InChar => {
-                // TODO: don't make this so stdin specific
-                use std::io::{self, Read};
-
-                let stdin = io::stdin();
-                let buf: &mut [u8] = &mut [0];
-                stdin.lock().read_exact(buf).map_err(|e| ExecutionError::IoError(e))?;
-                self.stack.push(BigInt::from(buf[0]));
+                let byte = self.read_byte().map(Value::from);
+                self.push_input(byte)?;
             }
             Command::OutNum => {
                 let num = self.pop1()?;
-                print!("{num}");
+                self.write(&num.to_string(), tracer, hooks)?;
             }
             Command::OutChar => {
                 let num = self.pop1()?;
-                let chr = num.to_u8() // TODO: non-ascii? 👀
-                    .ok_or_else(|| ExecutionError::EncodeError(num))?
-                    as char;
-                print!("{chr}");
+                let chr = num.to_u32()
+                    .and_then(char::from_u32)
+                    .ok_or_else(|| ExecutionError::EncodeError(num.into()))?;
+                self.write(chr.encode_utf8(&mut [0; 4]), tracer, hooks)?;
             }
         }
         Ok(())
     }
 
-    // TODO: bool sucks
-    pub fn step(&mut self, code: &PietCode) -> bool {
-        let (x, y) = self.pos;
-        let color = code.at(x, y).unwrap();
-        info!("{:?}", self.stack);
+    /// Take one step, reporting it to `tracer`.
+    /// Pass the same tracer to each step, since it may describe a step by
+    /// how it differs from the last.
+    pub fn step(&mut self, code: &PietCode, tracer: &mut dyn Tracer) -> StepResult {
+        self.advance(code, tracer, &mut Hooks::default())
+    }
+
+    /// Take one step.
+    /// As the spec has it, the program halts once it's tried every way out of a block:
+    /// so a 1x1 or single color image halts on its first step, without running anything.
+    fn advance(
+        &mut self,
+        code: &PietCode,
+        tracer: &mut dyn Tracer,
+        hooks: &mut Hooks,
+    ) -> StepResult {
+        let from = self.pos;
+        // An empty image halts immediately.
+        let Some(color) = code.at(from) else { return StepResult::Halted; };
+        // Non-Piet colors can't be moved through, so a step that would stops short.
+        let stuck = |to| {
+            let executed = Executed { command: Command::Noop, from, to, value: Value::zero() };
+            StepResult::Error(executed, ExecutionError::InvalidColor(to))
+        };
         match color {
-            Color::White => match self.walk_white(code) {
-                Some((coord, color)) => {
-                    info!("(White -> {color:?}) [{coord:?}]");
-                    self.pos = coord;
-                    true
+            Color::White if self.dialect == Dialect::PietDev => {
+                let start = self.instruction_pointer;
+                let Some((_, to, next_color)) = self.walk_color(code) else {
+                    return StepResult::Halted;
+                };
+                if next_color == Color::Other {
+                    self.instruction_pointer = start;
+                    return stuck(to);
                 }
-                None => false,
-            },
+                self.pos = to;
+                let value = Value::zero();
+                StepResult::Continue(Executed { command: Command::Noop, from, to, value })
+            }
+            Color::White => {
+                let start = self.instruction_pointer;
+                let Some((to, next_color)) = self.walk_white(code) else { return StepResult::Halted; };
+                if next_color == Color::Other {
+                    self.pos = from;
+                    self.instruction_pointer = start;
+                    return stuck(to);
+                }
+                self.pos = to;
+                let value = Value::zero();
+                StepResult::Continue(Executed { command: Command::Noop, from, to, value })
+            }
             Color::Color(..) => {
-                let (region, coord, next_color) = if let Some(v) = self.walk_color(code) { v }
-                    else { return false; };
+                let start = self.instruction_pointer;
+                let Some((region, to, next_color)) = self.walk_color(code) else {
+                    return StepResult::Halted;
+                };
+                if next_color == Color::Other {
+                    self.instruction_pointer = start;
+                    return stuck(to);
+                }
                 let command = region.color.step_to(next_color);
                 let value = region.value();
-                info!(
-                    "({:?} ({}) -> {:?}) [{coord:?}] = {command:?}",
-                    region.color, value, next_color,
-                );
-                if let Err(err) = self.run_command(command, value) {
-                    info!("Skipping command: {err}");
+                tracer.on_command(region.color, next_color, to, command, &value);
+                for hook in &mut hooks.before_command {
+                    hook(command, &self.stack);
+                }
+                let result = self.run_traced(command, value.clone(), tracer, hooks);
+                self.pos = to;
+                let executed = Executed { command, from, to, value };
+                match result {
+                    Ok(()) => {
+                        for hook in &mut hooks.after_command {
+                            hook(command, &self.stack);
+                        }
+                        StepResult::Continue(executed)
+                    }
+                    Err(err) => {
+                        tracer.on_error(command, &err);
+                        for hook in &mut hooks.error {
+                            hook(command, &err);
+                        }
+                        StepResult::Error(executed, err)
+                    }
                 }
-                self.pos = coord;
-                true
             }
-            Color::Other => { panic!(); }  // TODO
-            Color::Black => { panic!(); }
+            // The program can only be on a non-Piet color if it started on one.
+            Color::Other => stuck(from),
+            // Execution never moves onto black, so the program must have started on it,
+            // eg an all-black image. There's no way out.
+            Color::Black => StepResult::Halted,
         }
     }
 }
 
-pub struct PietRunner<'a> {
-    code: &'a PietCode,
-    vm: PietVM,
-}
+impl fmt::Display for PietRunner<'_> {
+    /// A summary of where the run is up to.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        const SHOWN: usize = 8;
 
-impl<'a> PietRunner<'a> {
-    fn new(code: &'a PietCode) -> Self {
-        PietRunner {
-            vm: PietVM::new(),
-            code,
+        let InstructionPointer(dp, cc) = self.vm.instruction_pointer;
+        write!(f, "step {}, ", self.steps)?;
+        if self.pages.len() > 1 {
+            write!(f, "page {}, ", self.page)?;
+        }
+        writeln!(f, "at codel {}, DP {dp:?}, CC {cc:?}", self.vm.pos)?;
+        let stack = &self.vm.stack;
+        write!(f, "stack ({} items):", stack.len())?;
+        if stack.len() > SHOWN {
+            write!(f, " ...")?;
         }
+        for value in &stack[stack.len().saturating_sub(SHOWN)..] {
+            write!(f, " {value}")?;
+        }
+        Ok(())
     }
+}
+
+/// A callback reporting how far along a long operation is,
+/// eg how many steps have been run.
+#[derive(Clone)]
+pub struct Progress(Arc<dyn Fn(u64) + Send + Sync>);
 
-    pub fn step(&mut self) -> bool {
-        self.vm.step(self.code)
+impl Progress {
+    pub fn new(callback: impl Fn(u64) + Send + Sync + 'static) -> Self {
+        Progress(Arc::new(callback))
     }
 
-    pub fn run(&mut self) {
-        while self.step() {}
+    fn report(&self, done: u64) {
+        (self.0)(done)
     }
 }
 
-pub fn load(filename: &str, codel_size: u32) -> Result<PietCode, String> {
-    let img = image::open(filename).map_err(|e| e.to_string())?;
-    to_codels(img, codel_size)
+impl fmt::Debug for Progress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Progress(..)")
+    }
 }
 
-pub fn save(code: &PietCode, filename: &str, codel_size: u32) -> ImageResult<()> {
-    let img = to_image(code, codel_size);
-    img.save(filename)
+/// Why a run stopped.
+#[derive(Debug)]
+pub enum ExitReason {
+    /// The program ended normally.
+    Halted,
+    /// The program used up its fuel before halting.
+    OutOfFuel,
+    /// The program revisited an earlier state, so would never halt.
+    LoopDetected,
+    /// A command failed while running in strict mode.
+    Error(ExecutionError),
+    /// The interrupt flag was raised, or the run was stopped through its handle.
+    Interrupted,
+    /// The run went on past its time limit.
+    TimedOut,
+    /// The run paused at a breakpoint. Running again resumes it.
+    Breakpoint(Breakpoint),
 }
 
-fn to_codels(img: DynamicImage, codel_size: u32) -> Result<PietCode, String> {
-    let (w, h) = img.dimensions();
-    if w % codel_size != 0 || h % codel_size != 0 {
-        return Err("invalid dimensions".to_string());
+impl ExitReason {
+    /// The process exit code the command line tools stop with for this reason.
+    /// 1 is left for failing before the run, eg on bad arguments.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            ExitReason::Halted => 0,
+            ExitReason::Error(_) => 2,
+            ExitReason::OutOfFuel => 3,
+            ExitReason::LoopDetected => 4,
+            ExitReason::TimedOut => 5,
+            ExitReason::Breakpoint(_) => 6,
+            ExitReason::Interrupted => 130,
+        }
     }
-    let width = w / codel_size;
-    let height = h / codel_size;
-    let img = img.into_rgb8();
-    let code = iproduct!(0..height, 0..width)
-        .map(|(y, x)| {
-            img.view(x * codel_size, y * codel_size, codel_size, codel_size)
-                .pixels()
-                .map(|(_, _, px)| px)
-                .get_all_equal()
-                // TODO: options to:
-                // - error on None
-                // - error on Other
-                // - black on Other
-                .map_or(Color::Other, |px| px.into())
-        })
-        .collect();
-    Ok(PietCode {
-        width: width as usize,
-        height: height as usize,
-        code,
-    })
 }
 
-fn to_image(code: &PietCode, codel_size: u32) -> RgbImage {
-    // TODO: options to handle Other pixels.
-    // Currently hardcoded to a nice purple
-    const OTHER_COLOR: Rgb<u8> = Rgb([0x73, 0x26, 0xb1]);
-    let PietCode { width, height, .. } = code;
-    let mut img = RgbImage::new(
-        *width as u32 * codel_size,
-        *height as u32 * codel_size,
-    );
-    for (x, y, codel) in code.codels() {
-        let img_x = x as u32 * codel_size;
-        let img_y = y as u32 * codel_size;
-        let color = codel.try_into().unwrap_or(OTHER_COLOR);
+/// Somewhere a run should pause, eg to inspect the stack.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Breakpoint {
+    /// Once execution moves into this codel.
+    At(CodelCoord),
+    /// Once this command has run. Sliding across white runs `Noop`.
+    Command(Command),
+    /// Once this many steps have been taken.
+    Step(u64),
+}
 
-        for dx in 0..codel_size {
+impl Breakpoint {
+    fn is_hit(&self, steps: u64, executed: &Executed) -> bool {
+        match self {
+            Breakpoint::At(coord) => executed.to == *coord,
+            Breakpoint::Command(command) => executed.command == *command,
+            Breakpoint::Step(step) => steps == *step,
+        }
+    }
+}
+
+/// Counts of what a run did, collected if asked for with `PietRunner::collect_stats`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct RunStats {
+    pub steps: u64,
+    /// How many times each command ran, indexed by `Command as usize`.
+    commands: [u64; 18],
+    /// The most values the stack held at once.
+    pub max_stack_depth: usize,
+    /// Steps that slid across white, running no command.
+    pub white_slides: u64,
+    /// Time spent taking steps.
+    pub elapsed: Duration,
+}
+
+impl RunStats {
+    /// How many times `command` ran, including any times it failed.
+    pub fn count(&self, command: Command) -> u64 {
+        self.commands[command as usize]
+    }
+
+    /// Each command that ran, and how many times, in the order of `Command`.
+    pub fn commands(&self) -> impl Iterator<Item = (Command, u64)> + '_ {
+        self.commands.iter().enumerate()
+            .filter(|(_, &count)| count > 0)
+            .filter_map(|(i, &count)| Some((FromPrimitive::from_usize(i)?, count)))
+    }
+}
+
+impl fmt::Display for RunStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{} steps in {:.3}s", self.steps, self.elapsed.as_secs_f64())?;
+        writeln!(f, "max stack depth: {}", self.max_stack_depth)?;
+        write!(f, "white slides: {}", self.white_slides)?;
+        for (command, count) in self.commands() {
+            write!(f, "\n{command:?}: {count}")?;
+        }
+        Ok(())
+    }
+}
+
+/// The PNG text keyword that run limits are saved under.
+const LIMITS_KEYWORD: &str = "piet-limits";
+
+/// A recommended budget for running a program, eg so that sharing a program
+/// that loops forever doesn't hang whoever runs it.
+/// Set in PietASM with `@LIMIT`, and carried in the image's metadata.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct RunLimits {
+    pub steps: Option<u64>,
+    pub time: Option<Duration>,
+}
+
+impl RunLimits {
+    /// Parse space-separated `steps=N` and `seconds=S` settings, either or both.
+    pub fn parse(text: &str) -> Option<Self> {
+        let mut limits = RunLimits::default();
+        for setting in text.split_ascii_whitespace() {
+            match setting.split_once('=')? {
+                ("steps", steps) if limits.steps.is_none() => {
+                    limits.steps = Some(steps.parse().ok()?);
+                }
+                ("seconds", seconds) if limits.time.is_none() => {
+                    limits.time = Some(Duration::try_from_secs_f64(seconds.parse().ok()?).ok()?);
+                }
+                _ => { return None; }
+            }
+        }
+        (limits != RunLimits::default()).then_some(limits)
+    }
+}
+
+impl fmt::Display for RunLimits {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut settings = Vec::new();
+        if let Some(steps) = self.steps {
+            settings.push(format!("steps={steps}"));
+        }
+        if let Some(time) = self.time {
+            settings.push(format!("seconds={}", time.as_secs_f64()));
+        }
+        write!(f, "{}", settings.join(" "))
+    }
+}
+
+/// The PNG text keyword that an input schema is saved under.
+const INPUT_KEYWORD: &str = "piet-input";
+
+/// The input a program reads, in order, eg so whoever runs it knows to give
+/// it some, rather than wondering why it's hanging.
+/// Set in PietASM with `@INPUT`, and carried in the image's metadata.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct InputSchema {
+    pub items: Vec<InputItem>,
+    /// Whether the last item repeats for the rest of the input.
+    pub repeats: bool,
+}
+
+/// Something a program reads with one input command.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum InputItem {
+    /// A number, as `InNum` reads it by default.
+    Num,
+    /// A byte, as `InChar` reads it.
+    Char,
+}
+
+impl InputSchema {
+    /// Parse space-separated `num` and `char` items, the last of which may
+    /// be followed by `*` to repeat it, eg `num num char*`.
+    pub fn parse(text: &str) -> Option<Self> {
+        let mut schema = InputSchema { items: Vec::new(), repeats: false };
+        for item in text.split_ascii_whitespace() {
+            if schema.repeats {
+                return None;
+            }
+            let (item, repeats) = match item.strip_suffix('*') {
+                Some(item) => (item, true),
+                None => (item, false),
+            };
+            schema.items.push(match item {
+                "num" => InputItem::Num,
+                "char" => InputItem::Char,
+                _ => { return None; }
+            });
+            schema.repeats = repeats;
+        }
+        (!schema.items.is_empty()).then_some(schema)
+    }
+
+    /// Check `input` holds what the program reads, read as the runner reads it
+    /// by default, explaining where it doesn't. Anything left over is fine.
+    pub fn validate(&self, input: &[u8]) -> Result<(), String> {
+        // Read a byte at a time, so what's left in the buffer is what's not been read.
+        let input_mode = InputMode::Raw;
+        let mut vm = PietVM { input: Input::Buffer(input.iter().copied().collect()), input_mode, ..PietVM::new() };
+        let offset = |vm: &PietVM| match &vm.input {
+            Input::Buffer(rest) => input.len() - rest.len(),
+            _ => input.len(),
+        };
+        for (idx, &item) in self.items.iter().enumerate() {
+            let repeats = self.repeats && idx + 1 == self.items.len();
+            loop {
+                let start = offset(&vm);
+                if repeats && input[start..].iter().all(|&b| item == InputItem::Char || b.is_ascii_whitespace()) {
+                    break;
+                }
+                let (read, expected) = match item {
+                    InputItem::Num => (vm.read_num().is_ok(), "a number"),
+                    InputItem::Char => (vm.read_byte().is_ok(), "a character"),
+                };
+                if !read {
+                    return Err(match start {
+                        _ if start == input.len() => format!("expected {expected}, at the end of input"),
+                        _ => format!("expected {expected} at byte {start}"),
+                    });
+                }
+                if !repeats {
+                    break;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for InputSchema {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let items: Vec<_> = self.items.iter()
+            .map(|item| match item {
+                InputItem::Num => "num",
+                InputItem::Char => "char",
+            })
+            .collect();
+        write!(f, "{}{}", items.join(" "), if self.repeats { "*" } else { "" })
+    }
+}
+
+/// The result of `PietRunner::run_collect`.
+#[derive(Debug)]
+pub struct RunOutput {
+    /// Everything the program output.
+    pub stdout: Vec<u8>,
+    pub reason: ExitReason,
+    pub steps: u64,
+    /// The final stack, from bottom to top.
+    pub stack: Vec<Value>,
+}
+
+impl RunOutput {
+    /// The output as text, with any invalid UTF-8 replaced.
+    pub fn stdout_lossy(&self) -> std::borrow::Cow<'_, str> {
+        String::from_utf8_lossy(&self.stdout)
+    }
+}
+
+/// What execution makes of codels that aren't Piet colors, eg stray pixels
+/// left by an editor's antialiasing.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum OtherColorPolicy {
+    /// Moving into one fails with `InvalidColor`, stopping the run.
+    #[default]
+    Error,
+    /// Treat them as white, to slide across.
+    White,
+    /// Treat them as black, to turn away from.
+    Black,
+}
+
+/// What a run does when a command fails, eg popping an empty stack.
+/// Interpreters differ: some skip the command, others halt.
+#[derive(Default)]
+pub enum RuntimePolicy<'a> {
+    /// Skip the command and carry on. The error is still reported to the
+    /// runner's tracer as a `RuntimeWarning`, so `LogTracer` logs it.
+    #[default]
+    SkipAndLog,
+    /// Stop the run, returning the error from `run`.
+    Abort,
+    /// Ask the callback, eg to collect the errors: `Continue` skips the command,
+    /// and `Break` stops the run as `Abort` would.
+    Callback(ErrorHandler<'a>),
+}
+
+/// Decides whether a run carries on past a failed command. See `RuntimePolicy::Callback`.
+pub type ErrorHandler<'a> = Box<dyn FnMut(&Executed, &ExecutionError) -> ControlFlow<()> + Send + 'a>;
+
+pub struct PietRunner<'a> {
+    pages: &'a [PietCode],
+    /// The pages with their non-Piet colors replaced, per `other_colors`.
+    replaced: Option<Vec<PietCode>>,
+    page: usize,
+    vm: PietVM,
+    steps: u64,
+    fuel: Option<u64>,
+    deadline: Option<Instant>,
+    policy: RuntimePolicy<'a>,
+    interrupt: Option<Arc<AtomicBool>>,
+    handle: Option<RunnerHandle>,
+    progress: Option<(u64, Progress)>,
+    /// Where to report each step, unless nothing would be.
+    tracer: Option<Box<dyn Tracer + Send + 'a>>,
+    hooks: Hooks<'a>,
+    breakpoints: Vec<Breakpoint>,
+    stats: Option<RunStats>,
+    loops: Option<LoopDetector>,
+    history: Option<History>,
+}
+
+/// The states the machine was in before its last so many steps, to step back to.
+struct History {
+    depth: usize,
+    /// Oldest first.
+    snapshots: VecDeque<Snapshot>,
+}
+
+/// The machine's state before a step, and the input the step read.
+struct Snapshot {
+    page: usize,
+    steps: u64,
+    pos: CodelCoord,
+    instruction_pointer: InstructionPointer,
+    stack: Vec<Value>,
+    /// How much output had been captured, if capturing it.
+    output_len: Option<usize>,
+    read: Vec<u8>,
+}
+
+/// Spots a run going round in circles: the machine coming back to a state
+/// it's been in before, with no input or output in between, so it always will.
+/// Only checks every so many steps, as hashing the stack isn't free.
+/// Any loop is still caught, just some laps late.
+struct LoopDetector {
+    every: u64,
+    /// Hashes of the states seen since the last input or output.
+    seen: HashSet<u64>,
+}
+
+impl<'a> PietRunner<'a> {
+    fn new(pages: &'a [PietCode]) -> Self {
+        PietRunner {
+            vm: PietVM::new(),
+            pages,
+            replaced: None,
+            page: 0,
+            steps: 0,
+            fuel: None,
+            deadline: None,
+            policy: RuntimePolicy::SkipAndLog,
+            interrupt: None,
+            handle: None,
+            progress: None,
+            tracer: None,
+            hooks: Hooks::default(),
+            breakpoints: Vec::new(),
+            stats: None,
+            loops: None,
+            history: None,
+        }
+    }
+
+    /// Stop after this many steps, if the program hasn't halted by then.
+    pub fn fuel(mut self, steps: u64) -> Self {
+        self.fuel = Some(steps);
+        self
+    }
+
+    /// Abort the run when a command fails, instead of skipping the command.
+    pub fn strict(self) -> Self {
+        self.policy(RuntimePolicy::Abort)
+    }
+
+    /// Choose what happens when a command fails.
+    pub fn policy(mut self, policy: RuntimePolicy<'a>) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    /// Stop the run once `flag` is set, eg from a signal handler.
+    pub fn interrupt_on(mut self, flag: Arc<AtomicBool>) -> Self {
+        self.interrupt = Some(flag);
+        self
+    }
+
+    /// Let `handle` pause, step, resume, and stop the run, eg from a GUI's thread.
+    /// While paused, the run waits before its next step, though its time limit
+    /// and `interrupt_on` flag can still stop it.
+    pub fn handle(mut self, handle: RunnerHandle) -> Self {
+        self.handle = Some(handle);
+        self
+    }
+
+    /// Report the number of steps taken, every `every` steps.
+    pub fn progress(mut self, every: u64, progress: Progress) -> Self {
+        self.progress = Some((every.max(1), progress));
+        self
+    }
+
+    /// Report each step, command, and error to `tracer`.
+    /// Nothing is reported if it isn't `enabled` as it's attached.
+    pub fn tracer(mut self, tracer: impl Tracer + Send + 'a) -> Self {
+        self.tracer = match tracer.enabled() {
+            true => Some(Box::new(tracer)),
+            false => None,
+        };
+        self
+    }
+
+    /// Call `hook` with each command about to run, and the stack it's run on,
+    /// eg to count commands without a `Tracer`. Sliding across white runs no command.
+    pub fn before_command(mut self, hook: impl FnMut(Command, &[Value]) + Send + 'a) -> Self {
+        self.hooks.before_command.push(Box::new(hook));
+        self
+    }
+
+    /// Call `hook` with each command that's run without failing, and the stack it left.
+    pub fn after_command(mut self, hook: impl FnMut(Command, &[Value]) + Send + 'a) -> Self {
+        self.hooks.after_command.push(Box::new(hook));
+        self
+    }
+
+    /// Call `hook` with each piece of output the program writes, as it's written.
+    pub fn on_output(mut self, hook: impl FnMut(&str) + Send + 'a) -> Self {
+        self.hooks.output.push(Box::new(hook));
+        self
+    }
+
+    /// Call `hook` with each command that fails, and why.
+    pub fn on_error(mut self, hook: impl FnMut(Command, &ExecutionError) + Send + 'a) -> Self {
+        self.hooks.error.push(Box::new(hook));
+        self
+    }
+
+    /// Count the steps, commands, and so on that the run takes. See `stats`.
+    pub fn collect_stats(mut self) -> Self {
+        self.stats = Some(RunStats::default());
+        self
+    }
+
+    /// Stop the run with `LoopDetected` if it gets stuck in a loop that never
+    /// reads or writes anything, checking every `every` steps.
+    /// Loops that keep growing the stack aren't caught.
+    pub fn detect_loops(mut self, every: u64) -> Self {
+        self.loops = Some(LoopDetector { every: every.max(1), seen: HashSet::new() });
+        self
+    }
+
+    /// Remember the machine's state before each of the last `depth` steps,
+    /// so the run can `step_back` through them.
+    pub fn history(mut self, depth: usize) -> Self {
+        self.history = Some(History { depth, snapshots: VecDeque::with_capacity(depth) });
+        self
+    }
+
+    /// Undo up to `steps` steps, as far back as the runner's history goes,
+    /// returning how many were undone, eg to see where a wrong `Roll` came from.
+    /// Input the undone steps read is read again. Only the machine steps back:
+    /// tracers, breakpoints, and stats aren't rewound, and output only is if captured.
+    pub fn step_back(&mut self, steps: usize) -> usize {
+        let Some(history) = &mut self.history else { return 0; };
+        let mut undone = 0;
+        while undone < steps {
+            let Some(snapshot) = history.snapshots.pop_back() else { break; };
+            self.page = snapshot.page;
+            self.steps = snapshot.steps;
+            self.vm.pos = snapshot.pos;
+            self.vm.instruction_pointer = snapshot.instruction_pointer;
+            self.vm.stack = snapshot.stack;
+            if let (Output::Buffer(output), Some(len)) = (&mut self.vm.output, snapshot.output_len) {
+                output.truncate(len);
+            }
+            self.vm.unread.extend(snapshot.read.iter().rev());
+            undone += 1;
+        }
+        undone
+    }
+
+    /// Choose what execution makes of codels that aren't Piet colors.
+    pub fn other_colors(mut self, policy: OtherColorPolicy) -> Self {
+        let replacement = match policy {
+            OtherColorPolicy::Error => None,
+            OtherColorPolicy::White => Some(Color::White),
+            OtherColorPolicy::Black => Some(Color::Black),
+        };
+        self.replaced = replacement.map(|replacement| {
+            self.pages.iter().map(|code| code.replace_other(replacement)).collect()
+        });
+        self
+    }
+
+    /// Pause the run at `breakpoint`.
+    pub fn breakpoint(mut self, breakpoint: Breakpoint) -> Self {
+        self.breakpoints.push(breakpoint);
+        self
+    }
+
+    /// Pause the run at `breakpoint`, eg when already paused at another.
+    pub fn add_breakpoint(&mut self, breakpoint: Breakpoint) {
+        if !self.breakpoints.contains(&breakpoint) {
+            self.breakpoints.push(breakpoint);
+        }
+    }
+
+    /// Stop pausing at `breakpoint`, returning whether it was set.
+    pub fn remove_breakpoint(&mut self, breakpoint: &Breakpoint) -> bool {
+        let len = self.breakpoints.len();
+        self.breakpoints.retain(|b| b != breakpoint);
+        self.breakpoints.len() != len
+    }
+
+    /// The breakpoints set, in the order they were added.
+    pub fn breakpoints(&self) -> &[Breakpoint] {
+        &self.breakpoints
+    }
+
+    /// Feed the program `input`, and capture its output instead of printing it.
+    pub fn capture_io(mut self, input: impl Into<Vec<u8>>) -> Self {
+        self.vm.input = Input::Buffer(input.into().into());
+        self.vm.output = Output::Buffer(Vec::new());
+        self
+    }
+
+    /// Choose whether input is taken a line or a byte at a time. See `InputMode`.
+    /// A terminal set up to send each key as it's pressed is restored once
+    /// the runner is dropped.
+    pub fn input_mode(mut self, mode: InputMode) -> Self {
+        self.vm.input_mode = mode;
+        #[cfg(feature = "raw-terminal")]
+        match (mode, &self.vm.input) {
+            (InputMode::Raw, Input::Stdin) => {
+                self.vm.input = Input::RawStdin { _terminal: raw::RawTerminal::enable() };
+            }
+            (InputMode::Line, Input::RawStdin { .. }) => { self.vm.input = Input::Stdin; }
+            _ => (),
+        }
+        self
+    }
+
+    /// Choose when output to stdout is written out. See `FlushPolicy`.
+    pub fn flush_policy(mut self, policy: FlushPolicy) -> Self {
+        self.vm.flush = policy;
+        self
+    }
+
+    /// Choose what reading input does once it's all been read.
+    pub fn eof_behavior(mut self, eof: EofBehavior) -> Self {
+        self.vm.eof = eof;
+        self
+    }
+
+    /// Move across white as `dialect`'s interpreters do, eg to run a program
+    /// written for PietDev.
+    pub fn dialect(mut self, dialect: Dialect) -> Self {
+        self.vm.dialect = dialect;
+        self
+    }
+
+    /// Choose how `InNum` reads a number.
+    pub fn num_input_mode(mut self, mode: NumInputMode) -> Self {
+        self.vm.num_input = mode;
+        self
+    }
+
+    /// Limit how wide the results of arithmetic can grow, eg to run untrusted
+    /// programs without letting them use up memory. Unlimited by default.
+    pub fn value_cap(mut self, cap: ValueCap) -> Self {
+        self.vm.cap = Some(cap);
+        self
+    }
+
+    /// Fail commands that would make the stack deeper than `depth`, eg so a
+    /// program duplicating values in a loop can't use up memory. As with any
+    /// failed command, the runtime policy decides whether the run carries on.
+    pub fn max_stack(mut self, depth: usize) -> Self {
+        self.vm.max_stack = Some(depth);
+        self
+    }
+
+    /// Do arithmetic as `model` has it, eg wrapping around as another
+    /// interpreter's machine integers do. Replaces any value cap.
+    pub fn int_model(mut self, model: IntModel) -> Self {
+        self.vm.cap = model.cap();
+        self
+    }
+
+    /// Read the program's input from `input`, and write its output to `output`,
+    /// instead of using stdin and stdout.
+    pub fn io(
+        mut self,
+        input: impl Read + Send + 'static,
+        output: impl Write + Send + 'static,
+    ) -> Self {
+        self.vm.input = Input::Stream(Box::new(BufReader::new(input)));
+        self.vm.output = Output::Stream(Box::new(output));
+        self
+    }
+
+    /// The output captured so far, if capturing.
+    pub fn output(&self) -> Option<&[u8]> {
+        match &self.vm.output {
+            Output::Buffer(output) => Some(output),
+            Output::Stdout { .. } | Output::Stream(_) => None,
+        }
+    }
+
+    /// Write out any output buffered so far.
+    pub fn flush(&mut self) -> std::io::Result<()> {
+        self.vm.flush_output()
+    }
+
+    /// The program's stack, from bottom to top.
+    pub fn stack(&self) -> &[Value] {
+        &self.vm.stack
+    }
+
+    /// The VM running the program, eg to see its position and direction.
+    pub fn vm(&self) -> &PietVM {
+        &self.vm
+    }
+
+    /// The VM running the program, eg to change its stack.
+    pub fn vm_mut(&mut self) -> &mut PietVM {
+        &mut self.vm
+    }
+
+    /// Statistics on the run so far, if collecting them.
+    pub fn stats(&self) -> Option<&RunStats> {
+        self.stats.as_ref()
+    }
+
+    /// The page being run, counting from 0. See `execute_pages`.
+    pub fn page(&self) -> usize {
+        self.page
+    }
+
+    /// The number of steps taken so far.
+    pub fn steps(&self) -> u64 {
+        self.steps
+    }
+
+    /// Take one step, ignoring the runner's limits.
+    pub fn step(&mut self) -> StepResult {
+        self.steps += 1;
+        self.trace_step();
+        self.advance()
+    }
+
+    pub fn run(&mut self) -> ExitReason {
+        loop {
+            if let ControlFlow::Break(reason) = self.tick() {
+                return reason;
+            }
+        }
+    }
+
+    /// Run the program, collecting its output instead of writing it anywhere.
+    /// Input is read as usual.
+    pub fn run_collect(&mut self) -> RunOutput {
+        if !matches!(self.vm.output, Output::Buffer(_)) {
+            self.vm.output = Output::Buffer(Vec::new());
+        }
+        let reason = self.run();
+        let stdout = match &mut self.vm.output {
+            Output::Buffer(stdout) => std::mem::take(stdout),
+            _ => Vec::new(),
+        };
+        RunOutput {
+            stdout,
+            reason,
+            steps: self.steps,
+            stack: self.vm.stack.clone(),
+        }
+    }
+
+    /// Run until the program stops, takes `max_steps` more steps, or runs for
+    /// `max_duration`, whichever comes first. The runner's own fuel still applies.
+    pub fn run_with_limits(&mut self, max_steps: u64, max_duration: Duration) -> ExitReason {
+        let fuel = self.fuel;
+        let limit = self.steps.saturating_add(max_steps);
+        self.fuel = Some(fuel.map_or(limit, |fuel| fuel.min(limit)));
+        self.deadline = Instant::now().checked_add(max_duration);
+        let reason = self.run();
+        self.fuel = fuel;
+        self.deadline = None;
+        reason
+    }
+
+    /// Run at most `steps` steps, eg to interleave execution with other work.
+    /// Breaks early if the run stops.
+    pub fn run_for(&mut self, steps: u64) -> ControlFlow<ExitReason> {
+        for _ in 0..steps {
+            self.tick()?;
+        }
+        ControlFlow::Continue(())
+    }
+
+    /// Take a step for each step of `trace`, checking each starts from the
+    /// same position and stack and runs the same command as it did then,
+    /// eg to check a rebuilt program still runs as a known-good one did.
+    /// Stops at the first step that doesn't match. The runner's limits don't apply.
+    pub fn replay(&mut self, trace: &Trace) -> Result<(), Box<Divergence>> {
+        for expected in &trace.steps {
+            let InstructionPointer(direction, chooser) = self.vm.instruction_pointer;
+            let mut actual = TraceStep {
+                step: self.steps + 1,
+                pos: self.vm.pos,
+                direction,
+                chooser,
+                stack: self.vm.stack.clone(),
+                command: None,
+            };
+            let color = self.code().and_then(|code| code.at(actual.pos));
+            if let StepResult::Continue(executed) | StepResult::Error(executed, _) = self.step() {
+                let to = self.code().and_then(|code| code.at(executed.to));
+                if let (Some(from @ Color::Color(..)), Some(to)) = (color, to) {
+                    actual.command = Some(TracedCommand {
+                        command: executed.command,
+                        from,
+                        to,
+                        pos: executed.to,
+                        value: executed.value,
+                    });
+                }
+            }
+            if actual != *expected {
+                return Err(Box::new(Divergence { expected: expected.clone(), actual }));
+            }
+        }
+        Ok(())
+    }
+
+    /// Take a step, turning the page if the program halts on any but the last.
+    fn advance(&mut self) -> StepResult {
+        if let Some(history) = &mut self.history {
+            if history.snapshots.len() >= history.depth {
+                history.snapshots.pop_front();
+            }
+            if history.depth > 0 {
+                history.snapshots.push_back(Snapshot {
+                    page: self.page,
+                    steps: self.steps - 1,
+                    pos: self.vm.pos,
+                    instruction_pointer: self.vm.instruction_pointer,
+                    stack: self.vm.stack.clone(),
+                    output_len: match &self.vm.output {
+                        Output::Buffer(output) => Some(output.len()),
+                        Output::Stdout { .. } | Output::Stream(_) => None,
+                    },
+                    read: Vec::new(),
+                });
+                self.vm.read = Some(Vec::new());
+            }
+        }
+        let result = self.advance_vm();
+        if let (Some(history), Some(read)) = (&mut self.history, self.vm.read.take()) {
+            if let Some(snapshot) = history.snapshots.back_mut() {
+                snapshot.read = read;
+            }
+        }
+        result
+    }
+
+    /// The page being run, as execution sees it.
+    fn code(&self) -> Option<&PietCode> {
+        self.replaced.as_deref().unwrap_or(self.pages).get(self.page)
+    }
+
+    /// Report the step about to be taken to the tracer.
+    fn trace_step(&mut self) {
+        if let Some(tracer) = &mut self.tracer {
+            let InstructionPointer(direction, chooser) = self.vm.instruction_pointer;
+            tracer.on_step(self.steps, self.vm.pos, direction, chooser, &self.vm.stack);
+        }
+    }
+
+    fn advance_vm(&mut self) -> StepResult {
+        let pages = self.replaced.as_deref().unwrap_or(self.pages);
+        let Some(code) = pages.get(self.page) else { return StepResult::Halted; };
+        let tracer: &mut dyn Tracer = match &mut self.tracer {
+            Some(tracer) => tracer.as_mut(),
+            None => &mut NoTracer,
+        };
+        let result = match &mut self.stats {
+            None => self.vm.advance(code, tracer, &mut self.hooks),
+            Some(stats) => {
+                let start = Instant::now();
+                let sliding = code.at(self.vm.pos) == Some(Color::White);
+                let result = self.vm.advance(code, tracer, &mut self.hooks);
+                stats.elapsed += start.elapsed();
+                stats.steps += 1;
+                match &result {
+                    StepResult::Continue(_) if sliding => stats.white_slides += 1,
+                    StepResult::Continue(executed) | StepResult::Error(executed, _) => {
+                        stats.commands[executed.command as usize] += 1;
+                    }
+                    StepResult::Halted | StepResult::TurnedPage(_) => (),
+                }
+                stats.max_stack_depth = stats.max_stack_depth.max(self.vm.stack.len());
+                result
+            }
+        };
+        if matches!(result, StepResult::Halted) && self.page + 1 < self.pages.len() {
+            self.page += 1;
+            self.vm.pos = CodelCoord::default();
+            self.vm.instruction_pointer = InstructionPointer::default();
+            return StepResult::TurnedPage(self.page);
+        }
+        result
+    }
+
+    /// Whether the run should stop because of `err`, per the runner's policy.
+    fn aborts(&mut self, executed: &Executed, err: &ExecutionError) -> bool {
+        match &mut self.policy {
+            RuntimePolicy::SkipAndLog => false,
+            RuntimePolicy::Abort => true,
+            RuntimePolicy::Callback(callback) => callback(executed, err).is_break(),
+        }
+    }
+
+    /// Whether the machine, having just run `command`, is back in a state it's
+    /// been in since its last input or output, if loops are being detected.
+    fn is_looping(&mut self, command: Command) -> bool {
+        let Some(loops) = &mut self.loops else { return false; };
+        if matches!(command, Command::InNum | Command::InChar | Command::OutNum | Command::OutChar) {
+            loops.seen.clear();
+        }
+        if !self.steps.is_multiple_of(loops.every) {
+            return false;
+        }
+        let mut hasher = std::hash::DefaultHasher::new();
+        (self.page, self.vm.pos, self.vm.instruction_pointer, &self.vm.stack).hash(&mut hasher);
+        !loops.seen.insert(hasher.finish())
+    }
+
+    /// Take a step, subject to the runner's limits.
+    fn tick(&mut self) -> ControlFlow<ExitReason> {
+        self.tick_event().1
+    }
+
+    /// Take a step, subject to the runner's limits: what it did, if it took
+    /// one the run carries on past, and whether the run stops there.
+    /// Output is flushed once the run stops.
+    fn tick_event(&mut self) -> (Option<ExecEvent>, ControlFlow<ExitReason>) {
+        let (event, flow) = self.take_step();
+        if flow.is_break() {
+            // The run's already stopped, so there's nothing to report a failed write to.
+            let _ = self.vm.flush_output();
+        }
+        (event, flow)
+    }
+
+    /// Why the run should stop before its next step, if it's been
+    /// interrupted or reached a limit.
+    fn limit_reached(&self) -> Option<ExitReason> {
+        if self.interrupt.as_ref().is_some_and(|flag| flag.load(Ordering::Relaxed)) {
+            return Some(ExitReason::Interrupted);
+        }
+        if self.fuel.is_some_and(|fuel| self.steps >= fuel) {
+            return Some(ExitReason::OutOfFuel);
+        }
+        if self.deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+            return Some(ExitReason::TimedOut);
+        }
+        None
+    }
+
+    fn take_step(&mut self) -> (Option<ExecEvent>, ControlFlow<ExitReason>) {
+        let stop = |reason| (None, ControlFlow::Break(reason));
+        if let Some(reason) = self.limit_reached() {
+            return stop(reason);
+        }
+        if let Some(handle) = &self.handle {
+            loop {
+                match handle.wait() {
+                    handle::Permit::Step => break,
+                    handle::Permit::Stop => return stop(ExitReason::Interrupted),
+                    handle::Permit::Paused => {
+                        if let Some(reason) = self.limit_reached() {
+                            return stop(reason);
+                        }
+                    }
+                }
+            }
+        }
+        self.steps += 1;
+        if let Some((every, progress)) = &self.progress {
+            if self.steps.is_multiple_of(*every) {
+                progress.report(self.steps);
+            }
+        }
+        self.trace_step();
+        let event = match self.advance() {
+            StepResult::Halted => return stop(ExitReason::Halted),
+            StepResult::TurnedPage(page) => ExecEvent::TurnedPage(page),
+            StepResult::Error(_, err @ ExecutionError::InvalidColor(_)) => {
+                return stop(ExitReason::Error(err));
+            }
+            // Input that hasn't arrived yet: undo the step, to take again once it has.
+            StepResult::Error(_, ExecutionError::IoError(err))
+                if err.kind() == std::io::ErrorKind::WouldBlock && self.step_back(1) == 1 =>
+            {
+                return stop(ExitReason::Error(ExecutionError::IoError(err)));
+            }
+            StepResult::Error(executed, err) if self.aborts(&executed, &err) => {
+                return stop(ExitReason::Error(err));
+            }
+            StepResult::Error(executed, err) => {
+                if let Some(tracer) = &mut self.tracer {
+                    let warning = RuntimeWarning { step: self.steps, pos: executed.from, command: executed.command, error: &err };
+                    tracer.on_warning(&warning);
+                }
+                ExecEvent::Error(executed, err)
+            }
+            // Only crossing white runs no command.
+            StepResult::Continue(executed) if executed.command == Command::Noop => ExecEvent::Slide(executed),
+            StepResult::Continue(executed) => ExecEvent::Command(executed),
+        };
+        let flow = match &event {
+            ExecEvent::Command(executed) | ExecEvent::Slide(executed) | ExecEvent::Error(executed, _) => {
+                self.carry_on(executed)
+            }
+            ExecEvent::TurnedPage(_) | ExecEvent::Stopped(_) => ControlFlow::Continue(()),
+        };
+        (Some(event), flow)
+    }
+
+    /// Whether the run stops after `executed`, on a loop or breakpoint.
+    fn carry_on(&mut self, executed: &Executed) -> ControlFlow<ExitReason> {
+        if self.is_looping(executed.command) {
+            return ControlFlow::Break(ExitReason::LoopDetected);
+        }
+        match self.breakpoints.iter().find(|b| b.is_hit(self.steps, executed)) {
+            Some(breakpoint) => ControlFlow::Break(ExitReason::Breakpoint(breakpoint.clone())),
+            None => ControlFlow::Continue(()),
+        }
+    }
+}
+
+/// Where the codel grid sits within an image.
+#[derive(Clone, Copy, Debug)]
+enum Margin {
+    /// The grid fills the whole image.
+    None,
+    /// The grid starts at this pixel. Partial codels at the right and bottom are ignored.
+    Offset(PixelCoord),
+    /// Find the grid offset, and trim away any uniform border.
+    Detect,
+}
+
+/// Which pixels lie outside the program. Codels containing any are walls,
+/// as if black, so irregularly shaped programs needn't be padded out by hand.
+#[derive(Clone, Debug)]
+enum Mask {
+    /// Transparent pixels.
+    Alpha,
+    /// Pixels that are dark in a separate image the same size as the program.
+    Image(String),
+}
+
+impl Mask {
+    /// Mark pixels outside the program as 0, and those inside as 255.
+    fn to_luma(&self, img: &DynamicImage) -> Result<GrayImage, String> {
+        let (w, h) = img.dimensions();
+        let mask = match self {
+            Mask::Alpha => img.to_luma_alpha8(),
+            Mask::Image(filename) => {
+                let mask = image::open(filename).map_err(|e| e.to_string())?;
+                if mask.dimensions() != (w, h) {
+                    return Err(format!("mask {filename} isn't the same size as the image"));
+                }
+                mask.to_luma_alpha8()
+            }
+        };
+        let channel = match self {
+            Mask::Alpha => 1,
+            Mask::Image(_) => 0,
+        };
+        Ok(GrayImage::from_fn(w, h, |x, y| {
+            let value = mask.get_pixel(x, y).0[channel];
+            Luma([if value < 128 { 0 } else { 255 }])
+        }))
+    }
+}
+
+/// Options for interpreting an image as Piet code.
+#[derive(Clone, Debug)]
+pub struct LoadOptions {
+    codel_size: u32,
+    margin: Margin,
+    mask: Option<Mask>,
+    frame: Option<usize>,
+}
+
+impl LoadOptions {
+    pub fn new(codel_size: u32) -> Self {
+        LoadOptions {
+            codel_size,
+            margin: Margin::None,
+            mask: None,
+            frame: None,
+        }
+    }
+
+    /// Load this frame of an animated GIF or PNG, counting from 0,
+    /// instead of the first.
+    pub fn frame(mut self, frame: usize) -> Self {
+        self.frame = Some(frame);
+        self
+    }
+
+    /// Treat codels with any transparent pixels as black.
+    pub fn mask_alpha(mut self) -> Self {
+        self.mask = Some(Mask::Alpha);
+        self
+    }
+
+    /// Treat codels with any pixels that are dark in the image at `filename`
+    /// as black. The mask must be the same size as the program's image.
+    pub fn mask_image(mut self, filename: &str) -> Self {
+        self.mask = Some(Mask::Image(filename.to_string()));
+        self
+    }
+
+    /// Start the codel grid at pixel (`x`, `y`) instead of the top-left corner.
+    pub fn offset(mut self, x: u32, y: u32) -> Self {
+        self.margin = Margin::Offset(PixelCoord::new(x, y));
+        self
+    }
+
+    /// Detect the codel grid's offset, and trim any white, black,
+    /// or non-Piet colored border around the program.
+    pub fn detect_margin(mut self) -> Self {
+        self.margin = Margin::Detect;
+        self
+    }
+}
+
+pub fn load(filename: &str, codel_size: u32) -> Result<PietCode, String> {
+    load_with_options(filename, &LoadOptions::new(codel_size))
+}
+
+pub fn load_with_options(filename: &str, options: &LoadOptions) -> Result<PietCode, String> {
+    let img = open_image(filename, options)?;
+    to_codels_with_options(img, options)
+}
+
+/// Load each frame of an animated GIF or PNG as its own program,
+/// eg to run them as pages with `execute_pages`. Other images have a single frame.
+/// Every frame is loaded with the same `options`, except `frame`, which is ignored.
+pub fn load_frames(filename: &str, options: &LoadOptions) -> Result<Vec<PietCode>, String> {
+    open_frames(filename)?.into_iter()
+        .map(|img| to_codels_with_options(img, options))
+        .collect()
+}
+
+/// The frames of an animated image, or just the image if it isn't animated.
+fn open_frames(filename: &str) -> Result<Vec<DynamicImage>, String> {
+    let still = || Ok(vec![image::open(filename).map_err(|e| e.to_string())?]);
+    let format = ImageReader::open(filename)
+        .and_then(|reader| reader.with_guessed_format())
+        .map_err(|e| e.to_string())?
+        .format();
+    let file = BufReader::new(File::open(filename).map_err(|e| e.to_string())?);
+    let frames = match format {
+        Some(ImageFormat::Gif) => {
+            GifDecoder::new(file).map_err(|e| e.to_string())?.into_frames()
+        }
+        Some(ImageFormat::Png) => {
+            let decoder = PngDecoder::new(file).map_err(|e| e.to_string())?;
+            if !decoder.is_apng() {
+                return still();
+            }
+            decoder.apng().into_frames()
+        }
+        _ => { return still(); }
+    };
+    let frames = frames.collect_frames().map_err(|e| e.to_string())?;
+    Ok(frames.into_iter().map(|frame| DynamicImage::ImageRgba8(frame.into_buffer())).collect())
+}
+
+fn open_image(filename: &str, options: &LoadOptions) -> Result<DynamicImage, String> {
+    let Some(frame) = options.frame else {
+        return image::open(filename).map_err(|e| e.to_string());
+    };
+    let frames = open_frames(filename)?;
+    let count = frames.len();
+    frames.into_iter()
+        .nth(frame)
+        .ok_or_else(|| format!("no frame {frame}: the image has {count}"))
+}
+
+/// Load a program, explaining each codel that isn't a Piet color.
+pub fn load_with_diagnostics(
+    filename: &str,
+    options: &LoadOptions,
+) -> Result<(PietCode, Vec<ColorDiagnostic>), String> {
+    let img = open_image(filename, options)?;
+    to_codels_with_diagnostics(img, options)
+}
+
+/// Why a codel loaded as `Color::Other`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ColorDiagnostic {
+    /// The codel is a color that isn't one of the 20 Piet colors.
+    OffPalette {
+        codel: CodelCoord,
+        pixel: PixelCoord,
+        rgb: [u8; 3],
+        /// The closest Piet color, eg the one an editor was off by one from.
+        nearest: Color,
+        /// The squared distance between the pixel's color and `nearest`.
+        distance_sq: u32,
+    },
+    /// The codel's pixels aren't all the same color, eg because the codel size is wrong.
+    /// `pixel` is the first that differs from the codel's top-left pixel.
+    Mixed { codel: CodelCoord, pixel: PixelCoord },
+}
+
+impl ColorDiagnostic {
+    /// Whether the codel looks like it was meant to be a Piet color:
+    /// its pixels are mixed, or within 16 per channel of a Piet color.
+    /// Colors further off are more likely deliberate filler.
+    pub fn is_likely_mistake(&self) -> bool {
+        const NEAR_MISS: u32 = 3 * 16 * 16;
+        match self {
+            ColorDiagnostic::OffPalette { distance_sq, .. } => *distance_sq <= NEAR_MISS,
+            ColorDiagnostic::Mixed { .. } => true,
+        }
+    }
+}
+
+impl fmt::Display for ColorDiagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ColorDiagnostic::OffPalette { codel, pixel, rgb, nearest, distance_sq } => {
+                let nearest_rgb = Rgb(palette::rgb(*nearest).unwrap_or_default());
+                write!(
+                    f,
+                    "codel {codel}: pixel {pixel} is {}, not a Piet color; \
+                        nearest is {nearest:?} ({}), at distance {:.1}",
+                    hex(Rgb(*rgb)), hex(nearest_rgb), (*distance_sq as f64).sqrt(),
+                )
+            }
+            ColorDiagnostic::Mixed { codel, pixel } => {
+                write!(f, "codel {codel}: pixel {pixel} differs from the rest of the codel")
+            }
+        }
+    }
+}
+
+pub fn save(code: &PietCode, filename: &str, codel_size: u32) -> ImageResult<()> {
+    let img = to_image(code, codel_size);
+    img.save(filename)
+}
+
+/// Options for saving Piet code as a PNG.
+#[derive(Clone, Debug)]
+pub struct SaveOptions {
+    codel_size: u32,
+    limits: RunLimits,
+    input: Option<InputSchema>,
+    compact: bool,
+}
+
+impl SaveOptions {
+    pub fn new(codel_size: u32) -> Self {
+        SaveOptions {
+            codel_size,
+            limits: RunLimits::default(),
+            input: None,
+            compact: false,
+        }
+    }
+
+    /// Record `limits` in the image's text metadata, for `read_limits`.
+    pub fn limits(mut self, limits: RunLimits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    /// Record `input` in the image's text metadata, for `read_input_schema`.
+    pub fn input(mut self, input: Option<InputSchema>) -> Self {
+        self.input = input;
+        self
+    }
+
+    /// Save an indexed-color PNG with a palette of just the colors the program
+    /// uses, packed as few bits to a pixel as they fit in, and compressed as
+    /// hard as the encoder can. Typically several times smaller, eg for sharing.
+    pub fn compact(mut self) -> Self {
+        self.compact = true;
+        self
+    }
+}
+
+/// Save `code` as a PNG, recording `limits` in its text metadata for `read_limits`.
+pub fn save_with_limits(
+    code: &PietCode,
+    filename: &str,
+    codel_size: u32,
+    limits: &RunLimits,
+) -> Result<(), String> {
+    save_with_options(code, filename, &SaveOptions::new(codel_size).limits(*limits))
+}
+
+pub fn save_with_options(code: &PietCode, filename: &str, options: &SaveOptions) -> Result<(), String> {
+    let codel_size = options.codel_size;
+    let (width, height) = (code.width as u32 * codel_size, code.height as u32 * codel_size);
+    let file = File::create(filename).map_err(|e| e.to_string())?;
+    let mut encoder = png::Encoder::new(std::io::BufWriter::new(file), width, height);
+    let data = if options.compact {
+        let (palette, depth, data) = to_indexed(code, codel_size);
+        encoder.set_color(png::ColorType::Indexed);
+        encoder.set_depth(depth);
+        encoder.set_palette(palette);
+        encoder.set_compression(png::Compression::Best);
+        // Filters predict smooth gradients; they only get in the way of
+        // flat blocks of palette indices.
+        encoder.set_filter(png::FilterType::NoFilter);
+        data
+    } else {
+        encoder.set_color(png::ColorType::Rgb);
+        encoder.set_depth(png::BitDepth::Eight);
+        to_image(code, codel_size).into_raw()
+    };
+    if options.limits != RunLimits::default() {
+        encoder.add_text_chunk(LIMITS_KEYWORD.to_string(), options.limits.to_string())
+            .map_err(|e| e.to_string())?;
+    }
+    if let Some(input) = &options.input {
+        encoder.add_text_chunk(INPUT_KEYWORD.to_string(), input.to_string())
+            .map_err(|e| e.to_string())?;
+    }
+    let mut writer = encoder.write_header().map_err(|e| e.to_string())?;
+    writer.write_image_data(&data).map_err(|e| e.to_string())
+}
+
+/// The limits recorded in a PNG by `save_with_limits`, if any.
+pub fn read_limits(filename: &str) -> Result<Option<RunLimits>, String> {
+    let text = read_png_text(filename)?;
+    let Some((_, value)) = text.iter().find(|(key, _)| key == LIMITS_KEYWORD) else {
+        return Ok(None);
+    };
+    RunLimits::parse(value)
+        .map(Some)
+        .ok_or_else(|| format!("invalid {LIMITS_KEYWORD} metadata: {value:?}"))
+}
+
+/// The input schema recorded in a PNG by `save_with_options`, if any.
+pub fn read_input_schema(filename: &str) -> Result<Option<InputSchema>, String> {
+    let text = read_png_text(filename)?;
+    let Some((_, value)) = text.iter().find(|(key, _)| key == INPUT_KEYWORD) else {
+        return Ok(None);
+    };
+    InputSchema::parse(value)
+        .map(Some)
+        .ok_or_else(|| format!("invalid {INPUT_KEYWORD} metadata: {value:?}"))
+}
+
+/// Rescale an image from one codel size to another.
+/// Errors if any codel of the source image isn't a single uniform color.
+pub fn rescale(img: &DynamicImage, from: u32, to: u32) -> Result<RgbImage, String> {
+    let (w, h) = img.dimensions();
+    if from == 0 {
+        return Err("codel size must be non-zero".to_string());
+    }
+    if w % from != 0 || h % from != 0 {
+        return Err("invalid dimensions".to_string());
+    }
+    let width = w / from;
+    let height = h / from;
+    let img = img.to_rgb8();
+    let mut out = RgbImage::new(width * to, height * to);
+    for (y, x) in iproduct!(0..height, 0..width) {
+        let view = img.view(x * from, y * from, from, from);
+        let color = view.get_pixel(0, 0);
+        if let Some((dx, dy, px)) = view.pixels().find(|&(_, _, px)| px != color) {
+            return Err(format!(
+                "codel ({x}, {y}) isn't uniform: pixel ({}, {}) is {} but pixel ({}, {}) is {}",
+                x * from, y * from, hex(color),
+                x * from + dx, y * from + dy, hex(px),
+            ));
+        }
+        for (dx, dy) in iproduct!(0..to, 0..to) {
+            out.put_pixel(x * to + dx, y * to + dy, color);
+        }
+    }
+    Ok(out)
+}
+
+/// The largest codel size that splits the image into uniformly colored codels.
+pub fn guess_codel_size(img: &DynamicImage) -> u32 {
+    let (w, h) = img.dimensions();
+    (1..=w.gcd(&h)).rev()
+        .filter(|size| w % size == 0 && h % size == 0)
+        .find(|&size| rescale(img, size, 1).is_ok())
+        .unwrap_or(1)
+}
+
+/// Read the text metadata (eg, "Title" or "Author") embedded in a PNG.
+pub fn read_png_text(filename: &str) -> Result<Vec<(String, String)>, String> {
+    let file = std::fs::File::open(filename).map_err(|e| e.to_string())?;
+    let reader = png::Decoder::new(file).read_info().map_err(|e| e.to_string())?;
+    let info = reader.info();
+    let mut text = Vec::new();
+    for chunk in &info.uncompressed_latin1_text {
+        text.push((chunk.keyword.clone(), chunk.text.clone()));
+    }
+    for chunk in &info.compressed_latin1_text {
+        text.push((chunk.keyword.clone(), chunk.get_text().map_err(|e| e.to_string())?));
+    }
+    for chunk in &info.utf8_text {
+        text.push((chunk.keyword.clone(), chunk.get_text().map_err(|e| e.to_string())?));
+    }
+    Ok(text)
+}
+
+/// The result of cleaning up an image with `clean`.
+pub struct Cleaned {
+    pub image: RgbImage,
+    /// Codels where fewer than half the pixels agreed on the chosen color.
+    pub uncertain: Vec<CodelCoord>,
+}
+
+/// Snap a noisy image (eg, one that's been scaled or saved as a JPEG)
+/// to a clean grid of standard Piet colors.
+///
+/// Each codel takes the median color of its interior, ignoring the edges
+/// where neighboring codels bleed in, and snaps it to the nearest standard
+/// color. The image's size needn't be an exact multiple of `codel_size`.
+pub fn clean(img: &DynamicImage, codel_size: u32) -> Result<Cleaned, String> {
+    if codel_size == 0 {
+        return Err("codel size must be non-zero".to_string());
+    }
+    let img = img.to_rgb8();
+    let (w, h) = img.dimensions();
+    let width = (w as f64 / codel_size as f64).round() as u32;
+    let height = (h as f64 / codel_size as f64).round() as u32;
+    if width == 0 || height == 0 {
+        return Err("no codels in image".to_string());
+    }
+    // Actual cell boundaries, in case the image was scaled by a fractional amount.
+    let bound = |i: u32, size: u32, count: u32| (i as u64 * size as u64 / count as u64) as u32;
+
+    let mut out = RgbImage::new(width * codel_size, height * codel_size);
+    let mut uncertain = Vec::new();
+    for (y, x) in iproduct!(0..height, 0..width) {
+        let (x0, x1) = (bound(x, w, width), bound(x + 1, w, width));
+        let (y0, y1) = (bound(y, h, height), bound(y + 1, h, height));
+        let (inset_x, inset_y) = ((x1 - x0) / 4, (y1 - y0) / 4);
+        let pixels: Vec<_> = iproduct!(y0 + inset_y..y1 - inset_y, x0 + inset_x..x1 - inset_x)
+            .map(|(py, px)| *img.get_pixel(px, py))
+            .collect();
+        let median = Rgb([0, 1, 2].map(|channel| {
+            let mut values: Vec<_> = pixels.iter().map(|px| px.0[channel]).collect();
+            values.sort_unstable();
+            values[values.len() / 2]
+        }));
+        let (color, _) = Color::nearest(median);
+        let votes = pixels.iter().filter(|&&px| Color::nearest(px).0 == color).count();
+        if votes * 2 < pixels.len() {
+            uncertain.push(CodelCoord::new(x as usize, y as usize));
+        }
+        let rgb = Rgb(palette::rgb(color).unwrap_or_default());
+        for (dx, dy) in iproduct!(0..codel_size, 0..codel_size) {
+            out.put_pixel(x * codel_size + dx, y * codel_size + dy, rgb);
+        }
+    }
+    Ok(Cleaned { image: out, uncertain })
+}
+
+fn hex(Rgb([r, g, b]): Rgb<u8>) -> String {
+    format!("#{r:02x}{g:02x}{b:02x}")
+}
+
+fn to_codels_with_options(img: DynamicImage, options: &LoadOptions) -> Result<PietCode, String> {
+    to_codels_with_diagnostics(img, options).map(|(code, _)| code)
+}
+
+fn to_codels_with_diagnostics(
+    img: DynamicImage,
+    options: &LoadOptions,
+) -> Result<(PietCode, Vec<ColorDiagnostic>), String> {
+    let codel_size = options.codel_size;
+    if codel_size == 0 {
+        return Err("codel size must be non-zero".to_string());
+    }
+    let mask = options.mask.as_ref().map(|mask| mask.to_luma(&img)).transpose()?;
+    let img = img.into_rgb8();
+    let origin = match options.margin {
+        Margin::None => PixelCoord::default(),
+        Margin::Offset(origin) => origin,
+        Margin::Detect => detect_offset(&img, codel_size),
+    };
+    let (w, h) = img.dimensions();
+    if matches!(options.margin, Margin::None) && (w % codel_size != 0 || h % codel_size != 0) {
+        return Err("invalid dimensions".to_string());
+    }
+    let width = w.saturating_sub(origin.x) / codel_size;
+    let height = h.saturating_sub(origin.y) / codel_size;
+    if width == 0 || height == 0 {
+        return Err("no codels in image".to_string());
+    }
+    let mut code = to_codels(&img, origin, width, height, codel_size);
+    if let Some(mask) = mask {
+        for (i, codel) in code.code.iter_mut().enumerate() {
+            let coord = CodelCoord::new(i % code.width, i / code.width);
+            let PixelCoord { x, y } = coord.to_pixel(codel_size, origin);
+            let outside = mask.view(x, y, codel_size, codel_size)
+                .pixels()
+                .any(|(_, _, Luma([value]))| value == 0);
+            if outside {
+                *codel = Color::Black;
+            }
+        }
+    }
+    let (code, trimmed) = match options.margin {
+        Margin::Detect => code.trim_border(),
+        _ => (code, CodelCoord::default()),
+    };
+    let diagnostics = code.codels()
+        .filter(|&(_, color)| color == Color::Other)
+        .map(|(codel, _)| {
+            let untrimmed = CodelCoord::new(codel.x + trimmed.x, codel.y + trimmed.y);
+            let PixelCoord { x, y } = untrimmed.to_pixel(codel_size, origin);
+            let first = *img.get_pixel(x, y);
+            let mixed = img.view(x, y, codel_size, codel_size)
+                .pixels()
+                .find(|&(_, _, px)| px != first);
+            match mixed {
+                Some((dx, dy, _)) => ColorDiagnostic::Mixed {
+                    codel,
+                    pixel: PixelCoord::new(x + dx, y + dy),
+                },
+                None => {
+                    let (nearest, distance_sq) = Color::nearest(first);
+                    ColorDiagnostic::OffPalette {
+                        codel,
+                        pixel: PixelCoord::new(x, y),
+                        rgb: first.0,
+                        nearest,
+                        distance_sq,
+                    }
+                }
+            }
+        })
+        .collect();
+    Ok((code, diagnostics))
+}
+
+/// Find the offset of the codel grid which splits the fewest codels across colors.
+fn detect_offset(img: &RgbImage, codel_size: u32) -> PixelCoord {
+    let (w, h) = img.dimensions();
+    iproduct!(0..codel_size, 0..codel_size)
+        .map(|(y, x)| PixelCoord::new(x, y))
+        .min_by_key(|&origin| {
+            let width = w.saturating_sub(origin.x) / codel_size;
+            let height = h.saturating_sub(origin.y) / codel_size;
+            iproduct!(0..height as usize, 0..width as usize)
+                .filter(|&(y, x)| {
+                    let PixelCoord { x, y } = CodelCoord::new(x, y).to_pixel(codel_size, origin);
+                    img.view(x, y, codel_size, codel_size)
+                        .pixels()
+                        .map(|(_, _, px)| px)
+                        .get_all_equal()
+                        .is_none()
+                })
+                .count()
+        })
+        .unwrap_or_default()
+}
+
+fn to_codels(img: &RgbImage, origin: PixelCoord, width: u32, height: u32, codel_size: u32) -> PietCode {
+    let code = iproduct!(0..height as usize, 0..width as usize)
+        .map(|(y, x)| {
+            let PixelCoord { x, y } = CodelCoord::new(x, y).to_pixel(codel_size, origin);
+            img.view(x, y, codel_size, codel_size)
+                .pixels()
+                .map(|(_, _, px)| px)
+                .get_all_equal()
+                // Mixed codels and off-palette colors load as `Other`, for
+                // execution to make of what its `OtherColorPolicy` says.
+                .map_or(Color::Other, |px| px.into())
+        })
+        .collect();
+    PietCode::new(width as usize, height as usize, code)
+}
+
+/// What codels that aren't Piet colors are saved as: a purple off the palette,
+/// so they load as `Other` again.
+const OTHER_COLOR: Rgb<u8> = Rgb([0x73, 0x26, 0xb1]);
+
+/// `code` as indexed-color PNG data: a palette of the colors it uses, the bit
+/// depth that fits them, and the rows of pixels, packed to that depth.
+fn to_indexed(code: &PietCode, codel_size: u32) -> (Vec<u8>, png::BitDepth, Vec<u8>) {
+    let mut colors = Vec::new();
+    let indices: Vec<_> = code.code.iter()
+        .map(|&color| {
+            let rgb = Rgb::try_from(color).unwrap_or(OTHER_COLOR);
+            match colors.iter().position(|&c| c == rgb) {
+                Some(index) => index as u8,
+                None => {
+                    colors.push(rgb);
+                    (colors.len() - 1) as u8
+                }
+            }
+        })
+        .collect();
+    let (depth, bits) = match colors.len() {
+        0..=2 => (png::BitDepth::One, 1),
+        3..=4 => (png::BitDepth::Two, 2),
+        5..=16 => (png::BitDepth::Four, 4),
+        _ => (png::BitDepth::Eight, 8),
+    };
+    let palette = colors.iter().flat_map(|Rgb(rgb)| *rgb).collect();
+    let width = code.width * codel_size as usize;
+    let row_len = (width * bits).div_ceil(8);
+    let mut data = vec![0; row_len * code.height * codel_size as usize];
+    for (y, row) in data.chunks_mut(row_len.max(1)).enumerate() {
+        let codel_row = y / codel_size as usize * code.width;
+        for x in 0..width {
+            let index = indices[codel_row + x / codel_size as usize];
+            let bit = x * bits;
+            row[bit / 8] |= index << (8 - bits - bit % 8);
+        }
+    }
+    (palette, depth, data)
+}
+
+fn to_image(code: &PietCode, codel_size: u32) -> RgbImage {
+    let PietCode { width, height, .. } = code;
+    let mut img = RgbImage::new(
+        *width as u32 * codel_size,
+        *height as u32 * codel_size,
+    );
+    for (coord, codel) in code.codels() {
+        let PixelCoord { x, y } = coord.to_pixel(codel_size, PixelCoord::default());
+        let color = codel.try_into().unwrap_or(OTHER_COLOR);
+
+        for dx in 0..codel_size {
             for dy in 0..codel_size {
-                img.put_pixel(img_x + dx, img_y + dy, color);
+                img.put_pixel(x + dx, y + dy, color);
+            }
+        }
+    }
+    img
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use assert_matches::assert_matches;
+
+    fn to_stack(nums: &[i32]) -> Vec<Value> {
+        nums.iter().map(|e| (*e).into()).collect()
+    }
+
+    #[test]
+    fn test_roll() {
+        let mut vm = PietVM { stack: to_stack(&[4, 5, 6, 7, 8, 9, 3, 2]), ..Default::default() };
+        vm.run_command(Command::Roll, Value::zero()).unwrap();
+        assert_eq!(vm.stack, to_stack(&[4, 5, 6, 8, 9, 7]));
+    }
+
+    #[test]
+    fn test_div_zero() {
+        let mut vm = PietVM { stack: to_stack(&[4, 0]), ..Default::default() };
+        let result = vm.run_command(Command::Divide, Value::zero());
+        assert!(matches!(result, Err(ExecutionError::DivisionByZero)));
+        assert_eq!(vm.stack, to_stack(&[4, 0]));
+    }
+
+    /// If we're going to divide by zero but have too few arguments on the stack,
+    /// prefer the "too few arguments" message
+    #[test]
+    fn test_div_zero_too_few() {
+        let mut vm = PietVM { stack: to_stack(&[0]), ..Default::default() };
+        let result = vm.run_command(Command::Divide, Value::zero());
+        assert!(matches!(result, Err(ExecutionError::NotEnoughStack(2, 1))));
+        assert_eq!(vm.stack, to_stack(&[0]));
+    }
+
+    #[test]
+    fn test_mod_zero() {
+        let mut vm = PietVM { stack: to_stack(&[4, 0]), ..Default::default() };
+        let result = vm.run_command(Command::Mod, Value::zero());
+        assert!(matches!(result, Err(ExecutionError::DivisionByZero)));
+        assert_eq!(vm.stack, to_stack(&[4, 0]));
+    }
+
+    /// If we're going to modulo by zero but have too few arguments on the stack,
+    /// prefer the "too few arguments" message
+    #[test]
+    fn test_mod_zero_too_few() {
+        let mut vm = PietVM { stack: to_stack(&[0]), ..Default::default() };
+        let result = vm.run_command(Command::Mod, Value::zero());
+        assert!(matches!(result, Err(ExecutionError::NotEnoughStack(2, 1))));
+        assert_eq!(vm.stack, to_stack(&[0]));
+    }
+
+    #[test]
+    fn test_out_char_unicode() {
+        let mut vm = PietVM { stack: to_stack(&[0x1F967, 0xE9, 65]), output: Output::Buffer(Vec::new()), ..Default::default() };
+        for _ in 0..3 {
+            vm.run_command(Command::OutChar, Value::zero()).unwrap();
+        }
+        let Output::Buffer(output) = &vm.output else { panic!() };
+        assert_eq!(String::from_utf8(output.clone()).unwrap(), "Aé🥧");
+        // Surrogates and numbers past the last code point aren't characters.
+        for num in [0xD800, 0x110000, -1] {
+            vm.stack = to_stack(&[num]);
+            let result = vm.run_command(Command::OutChar, Value::zero());
+            assert_matches!(result, Err(ExecutionError::EncodeError(n)) if n == num.into());
+        }
+    }
+
+    #[test]
+    fn test_eof_behavior() {
+        let read_at_eof = |eof| {
+            let mut vm = PietVM { input: Input::Buffer(VecDeque::new()), eof, ..Default::default() };
+            let results = [Command::InNum, Command::InChar].map(|command| {
+                vm.run_command(command, Value::zero()).map_err(|err| err.to_string())
+            });
+            (results, vm.stack)
+        };
+        let eof_error = Err("IO error: unexpected end of file".to_string());
+        assert_eq!(read_at_eof(EofBehavior::Error), ([eof_error.clone(), eof_error], vec![]));
+        assert_eq!(read_at_eof(EofBehavior::PushNegativeOne), ([Ok(()), Ok(())], to_stack(&[-1, -1])));
+        assert_eq!(read_at_eof(EofBehavior::Ignore), ([Ok(()), Ok(())], vec![]));
+    }
+
+    #[test]
+    fn test_value_cap() {
+        let multiply = |policy, stack: &[i32]| {
+            let cap = Some(ValueCap::new(8, policy));
+            let mut vm = PietVM { stack: to_stack(stack), cap, ..Default::default() };
+            let result = vm.run_command(Command::Multiply, Value::zero()).map_err(|err| err.to_string());
+            (result, vm.stack)
+        };
+        assert_eq!(multiply(OverflowPolicy::Error, &[3, 40]), (Ok(()), to_stack(&[120])));
+        // A failed command leaves the stack as it found it.
+        let too_wide = Err("result doesn't fit in the 8-bit limit on values".to_string());
+        assert_eq!(multiply(OverflowPolicy::Error, &[1, 16, 16]), (too_wide, to_stack(&[1, 16, 16])));
+        assert_eq!(multiply(OverflowPolicy::Saturate, &[-16, 16]), (Ok(()), to_stack(&[-128])));
+        assert_eq!(multiply(OverflowPolicy::Wrap, &[16, 16]), (Ok(()), to_stack(&[0])));
+    }
+
+    #[test]
+    fn test_max_stack() {
+        let mut vm = PietVM { stack: to_stack(&[1, 2]), max_stack: Some(3), input: Input::Buffer("7".bytes().collect()), ..Default::default() };
+        vm.run_command(Command::Duplicate, Value::zero()).unwrap();
+        assert_matches!(vm.run_command(Command::Duplicate, Value::zero()), Err(ExecutionError::StackOverflow(3)));
+        assert_matches!(vm.run_command(Command::Push, Value::one()), Err(ExecutionError::StackOverflow(3)));
+        // The input's left unread.
+        assert_matches!(vm.run_command(Command::InChar, Value::zero()), Err(ExecutionError::StackOverflow(3)));
+        vm.run_command(Command::Add, Value::zero()).unwrap();
+        vm.run_command(Command::InNum, Value::zero()).unwrap();
+        assert_eq!(vm.stack, to_stack(&[1, 4, 7]));
+
+        // Pushes forever, unless stopped.
+        let code = asm::load_str(":L\nPUSH 1\nJUMP L\n").unwrap();
+        let mut runner = code.execute().max_stack(100).strict();
+        assert_matches!(runner.run(), ExitReason::Error(ExecutionError::StackOverflow(100)));
+        assert_eq!(runner.stack().len(), 100);
+    }
+
+    #[test]
+    fn test_int_model() {
+        let code = PietCode::new(3, 1, vec![Color::LightRed, Color::DarkRed, Color::Black]);
+        let run = |model, stack: &[Value], command| {
+            let mut runner = code.execute().int_model(model);
+            runner.vm.stack = stack.to_vec();
+            runner.vm.run_command(command, Value::zero()).unwrap();
+            runner.vm.stack.clone()
+        };
+        let max = [Value::from(i32::MAX), Value::one()];
+        assert_eq!(run(IntModel::Unbounded, &max, Command::Add), [Value::from(i64::from(i32::MAX) + 1)]);
+        assert_eq!(run(IntModel::Wrapping(IntWidth::I32), &max, Command::Add), [Value::from(i32::MIN)]);
+        let min = [Value::from(i64::MIN), -Value::one()];
+        assert_eq!(run(IntModel::Wrapping(IntWidth::I64), &min, Command::Divide), [Value::from(i64::MIN)]);
+        assert_eq!(run(IntModel::Wrapping(IntWidth::I64), &min, Command::Multiply), [Value::from(i64::MIN)]);
+    }
+
+    #[test]
+    fn test_edit() {
+        let mut code = PietCode::new(4, 1, vec![Color::Red, Color::Red, Color::Black, Color::Blue]);
+        let start = CodelCoord::new(0, 0);
+        assert!(!code.set(CodelCoord::new(4, 0), Color::Red));
+        assert_eq!(code.fill(start, Color::Red), 0);
+        assert_eq!(code.fill(start, Color::Other), 2);
+        assert_eq!(code.code, [Color::Other, Color::Other, Color::Black, Color::Blue]);
+        // Runs see the change.
+        assert_matches!(code.execute().run(), ExitReason::Error(ExecutionError::InvalidColor(_)));
+        assert_eq!(code.fill(start, Color::Red), 2);
+        assert_matches!(code.execute().run(), ExitReason::Halted);
+        // Filling can join blocks together.
+        assert!(code.set(CodelCoord::new(2, 0), Color::Yellow));
+        assert_eq!(code.fill(CodelCoord::new(2, 0), Color::Red), 1);
+        assert_eq!(code.region_at(start).unwrap().region.len(), 3);
+    }
+
+    #[test]
+    fn test_compile() {
+        let code = PietCode::new(2, 1, vec![Color::Red, Color::Blue]);
+        code.compile();
+        let (red, blue) = (CodelCoord::new(0, 0), CodelCoord::new(1, 0));
+        let right = InstructionPointer::default();
+        assert_matches!(code.move_from(red, right), Some((_, Some((ip, to)))) if ip == right && to == blue);
+        // Blocked by the edge of the image everywhere but to the left.
+        let left = InstructionPointer(Direction::Left, CodelChoice::Left);
+        assert_matches!(code.move_from(blue, right), Some((_, Some((ip, to)))) if ip == left && to == red);
+        assert_matches!(code.move_from(CodelCoord::new(2, 0), right), None);
+
+        let code = PietCode::new(1, 1, vec![Color::Red]);
+        assert_matches!(code.move_from(red, right), Some((_, None)));
+    }
+
+    #[test]
+    fn test_region_cache() {
+        let code = load("test_imgs/test_slide.png", 1).unwrap();
+        // Every codel of a block shares the one cached block.
+        let first = code.region_at(CodelCoord::new(0, 0)).unwrap();
+        for &coord in &first.region {
+            assert!(std::ptr::eq(code.region_at(coord).unwrap(), first));
+        }
+        assert!(code.region_at(CodelCoord::new(code.width, 0)).is_none());
+        let blocks: usize = code.regions().blocks.iter().map(|b| b.region.len()).sum();
+        assert_eq!(blocks, code.width * code.height);
+        // Exits are the same as finding them again from scratch.
+        for (coord, _) in code.codels() {
+            let block = code.region_at(coord).unwrap();
+            for (i, ip) in InstructionPointer::ALL.into_iter().enumerate() {
+                assert_eq!(ip.index(), i);
+                assert_eq!(block.exit_to(ip), find_exit(&block.region, ip));
+            }
+        }
+    }
+
+    #[test]
+    fn test_num_input_mode() {
+        let read_nums = |input: &str, num_input| {
+            let input = Input::Buffer(input.bytes().collect());
+            let mut vm = PietVM { input, num_input, ..Default::default() };
+            (0..3)
+                .map(|_| match vm.read_num() {
+                    Ok(num) => num.to_string(),
+                    Err(err) => err.to_string(),
+                })
+                .collect::<Vec<_>>()
+        };
+        let input = " +12x\n-3 \n4";
+        let scan = NumInputMode::default();
+        assert_eq!(read_nums(input, scan), ["12", "expected a number on input", "expected a number on input"]);
+        let line = NumInputMode { whole_line: true, ..scan };
+        assert_eq!(read_nums(input, line), ["expected a number on input", "-3", "4"]);
+        let strict = NumInputMode { skip_whitespace: false, plus_sign: false, whole_line: true };
+        assert_eq!(read_nums(input, strict), ["expected a number on input", "-3", "4"]);
+        assert_eq!(
+            read_nums("+5\n 6\n", strict),
+            ["expected a number on input", "expected a number on input", "IO error: unexpected end of file"],
+        );
+    }
+
+    #[test]
+    fn test_input_mode() {
+        let input = Input::Pending("ab".bytes().collect());
+        let mut raw = PietVM { input, input_mode: InputMode::Raw, ..Default::default() };
+        assert_eq!(raw.read_byte().unwrap(), b'a');
+        assert_eq!(raw.read_byte().unwrap(), b'b');
+        assert_matches!(raw.read_byte(), Err(ExecutionError::IoError(err)) if err.kind() == std::io::ErrorKind::WouldBlock);
+
+        // Nothing's read until the line's complete, and the rest is kept for `InNum`.
+        let input = Input::Pending("ab".bytes().collect());
+        let mut vm = PietVM { input, input_mode: InputMode::Line, ..Default::default() };
+        assert_matches!(vm.read_byte(), Err(ExecutionError::IoError(err)) if err.kind() == std::io::ErrorKind::WouldBlock);
+        let Input::Pending(input) = &mut vm.input else { unreachable!() };
+        input.extend(b" 12\n3".iter());
+        assert_eq!(vm.read_byte().unwrap(), b'a');
+        assert_eq!(vm.read_byte().unwrap(), b'b');
+        assert_eq!(vm.read_num().unwrap(), Value::from(12));
+        // The newline's still to be read, and then the next line is waited for.
+        assert_eq!(vm.read_byte().unwrap(), b'\n');
+        assert_matches!(vm.read_byte(), Err(ExecutionError::IoError(err)) if err.kind() == std::io::ErrorKind::WouldBlock);
+        let Input::Pending(input) = &mut vm.input else { unreachable!() };
+        vm.input = Input::Buffer(std::mem::take(input));
+        assert_eq!(vm.read_byte().unwrap(), b'3');
+        assert!(vm.peek_byte().unwrap().is_none());
+    }
+
+    #[test]
+    fn test_rescale_round_trip() {
+        let img = image::open("test_imgs/test_slide.png").unwrap();
+        let big = rescale(&img, 1, 3).unwrap();
+        assert_eq!(big.dimensions(), (img.width() * 3, img.height() * 3));
+        let small = rescale(&big.into(), 3, 1).unwrap();
+        assert_eq!(small, img.to_rgb8());
+    }
+
+    #[test]
+    fn test_rescale_not_uniform() {
+        let mut img = RgbImage::from_pixel(4, 4, Rgb([0xFF, 0, 0]));
+        img.put_pixel(3, 2, Rgb([0, 0, 0]));
+        let err = rescale(&img.into(), 2, 1).unwrap_err();
+        assert_eq!(
+            err,
+            "codel (1, 1) isn't uniform: pixel (2, 2) is #ff0000 but pixel (3, 2) is #000000",
+        );
+    }
+
+    #[test]
+    fn test_clean() {
+        // test_slide has some off-palette codels; snap those first.
+        let img = clean(&image::open("test_imgs/test_slide.png").unwrap(), 1).unwrap().image;
+        let expected = rescale(&img.clone().into(), 1, 4).unwrap();
+        // Smear it, and knock the colors off a bit.
+        let mut noisy = image::imageops::resize(&expected, 17 * img.width(), 17 * img.height(), image::imageops::FilterType::Triangle);
+        for px in noisy.pixels_mut() {
+            for c in px.0.iter_mut() {
+                *c = c.saturating_sub(9);
             }
         }
+        let cleaned = clean(&noisy.into(), 17).unwrap();
+        assert_eq!(cleaned.uncertain, Vec::new());
+        assert_eq!(rescale(&cleaned.image.into(), 17, 4).unwrap(), expected);
     }
-    img
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+    /// Frame a 3x scale copy of `test_slide.png` in a grey border.
+    fn framed_slide(left: u32, top: u32, right: u32, bottom: u32) -> (DynamicImage, PietCode) {
+        let img = image::open("test_imgs/test_slide.png").unwrap();
+        let expected = to_codels_with_options(img.clone(), &LoadOptions::new(1)).unwrap();
+        let big = rescale(&img, 1, 3).unwrap();
+        let grey = Rgb([0x80, 0x80, 0x80]);
+        let width = left + big.width() + right;
+        let height = top + big.height() + bottom;
+        let mut framed = RgbImage::from_pixel(width, height, grey);
+        image::imageops::replace(&mut framed, &big, left.into(), top.into());
+        (framed.into(), expected)
+    }
 
-    fn to_stack(nums: &[i32]) -> Vec<BigInt> {
-        nums.into_iter().map(|e| (*e).into()).collect()
+    #[test]
+    fn test_offset() {
+        let (img, expected) = framed_slide(4, 5, 2, 1);
+        let options = LoadOptions::new(3).offset(4, 5);
+        let code = to_codels_with_options(img, &options).unwrap();
+        assert_eq!((code.width, code.height), (expected.width, expected.height));
+        assert!(code.code == expected.code);
     }
 
     #[test]
-    fn test_roll() {
-        let mut vm = PietVM { stack: to_stack(&[4, 5, 6, 7, 8, 9, 3, 2]), ..Default::default() };
-        vm.run_command(Command::Roll, BigInt::zero()).unwrap();
-        assert_eq!(vm.stack, to_stack(&[4, 5, 6, 8, 9, 7]));
+    fn test_detect_margin() {
+        let (img, expected) = framed_slide(7, 5, 10, 4);
+        let options = LoadOptions::new(3).detect_margin();
+        let code = to_codels_with_options(img, &options).unwrap();
+        assert_eq!((code.width, code.height), (expected.width, expected.height));
+        assert!(code.code == expected.code);
     }
 
     #[test]
-    fn test_div_zero() {
-        let mut vm = PietVM { stack: to_stack(&[4, 0]), ..Default::default() };
-        let result = vm.run_command(Command::Divide, BigInt::zero());
-        assert!(matches!(result, Err(ExecutionError::DivisionByZero)));
-        assert_eq!(vm.stack, to_stack(&[4, 0]));
+    fn test_mask() {
+        let red = Rgba([0xff, 0, 0, 0xff]);
+        let clear = Rgba([0xff, 0, 0, 0]);
+        let mut img = image::RgbaImage::from_pixel(6, 4, red);
+        img.put_pixel(3, 0, clear);
+        img.put_pixel(5, 3, clear);
+        let img = DynamicImage::ImageRgba8(img);
+        let expected = [
+            Color::Red, Color::Black, Color::Red,
+            Color::Red, Color::Red, Color::Black,
+        ];
+
+        let code = to_codels_with_options(img.clone(), &LoadOptions::new(2)).unwrap();
+        assert!(code.code == [Color::Red; 6]);
+        let code = to_codels_with_options(img.clone(), &LoadOptions::new(2).mask_alpha()).unwrap();
+        assert!(code.code == expected);
+
+        let mut mask = GrayImage::from_pixel(6, 4, Luma([255]));
+        mask.put_pixel(2, 1, Luma([20]));
+        mask.put_pixel(5, 2, Luma([0]));
+        let filename = std::env::temp_dir().join("piet_tools_test_mask.png");
+        mask.save(&filename).unwrap();
+        let options = LoadOptions::new(2).mask_image(filename.to_str().unwrap());
+        let code = to_codels_with_options(img, &options).unwrap();
+        assert!(code.code == expected);
     }
 
-    /// If we're going to divide by zero but have too few arguments on the stack,
-    /// prefer the "too few arguments" message
     #[test]
-    fn test_div_zero_too_few() {
-        let mut vm = PietVM { stack: to_stack(&[0]), ..Default::default() };
-        let result = vm.run_command(Command::Divide, BigInt::zero());
-        assert!(matches!(result, Err(ExecutionError::NotEnoughStack(2, 1))));
-        assert_eq!(vm.stack, to_stack(&[0]));
+    fn test_color_diagnostics() {
+        // A white border, then red, off-by-one red, and a codel split between the two.
+        let mut img = RgbImage::from_pixel(10, 6, Rgb([0xff, 0xff, 0xff]));
+        for (x, y) in iproduct!(2..8, 2..4) {
+            img.put_pixel(x, y, Rgb([0xff, 0, 0]));
+        }
+        for (x, y) in iproduct!(4..6, 2..4) {
+            img.put_pixel(x, y, Rgb([0xfe, 0, 0]));
+        }
+        img.put_pixel(7, 3, Rgb([0xfe, 0, 0]));
+        let img = DynamicImage::ImageRgb8(img);
+
+        let (code, diagnostics) = to_codels_with_diagnostics(img.clone(), &LoadOptions::new(2)).unwrap();
+        assert_eq!(code.width, 5);
+        let expected = [
+            ColorDiagnostic::OffPalette {
+                codel: CodelCoord::new(2, 1),
+                pixel: PixelCoord::new(4, 2),
+                rgb: [0xfe, 0, 0],
+                nearest: Color::Red,
+                distance_sq: 1,
+            },
+            ColorDiagnostic::Mixed { codel: CodelCoord::new(3, 1), pixel: PixelCoord::new(7, 3) },
+        ];
+        assert_eq!(diagnostics, expected);
+        assert!(diagnostics.iter().all(ColorDiagnostic::is_likely_mistake));
+        assert_eq!(
+            diagnostics[0].to_string(),
+            "codel (2, 1): pixel (4, 2) is #fe0000, not a Piet color; \
+                nearest is Red (#ff0000), at distance 1.0",
+        );
+
+        // Codels are numbered within the trimmed program, but pixels within the image.
+        let options = LoadOptions::new(2).detect_margin();
+        let (code, diagnostics) = to_codels_with_diagnostics(img, &options).unwrap();
+        assert_eq!(code.width, 3);
+        assert_matches!(
+            diagnostics.as_slice(),
+            [
+                ColorDiagnostic::OffPalette { codel: CodelCoord { x: 1, y: 0 }, pixel: PixelCoord { x: 4, y: 2 }, .. },
+                ColorDiagnostic::Mixed { codel: CodelCoord { x: 2, y: 0 }, .. },
+            ]
+        );
     }
 
     #[test]
-    fn test_mod_zero() {
-        let mut vm = PietVM { stack: to_stack(&[4, 0]), ..Default::default() };
-        let result = vm.run_command(Command::Mod, BigInt::zero());
-        assert!(matches!(result, Err(ExecutionError::DivisionByZero)));
-        assert_eq!(vm.stack, to_stack(&[4, 0]));
+    fn test_frames() {
+        use image::codecs::gif::GifEncoder;
+
+        let frame = |rgba| image::Frame::new(image::RgbaImage::from_pixel(4, 2, Rgba(rgba)));
+        let filename = std::env::temp_dir().join("piet_tools_test_frames.gif");
+        let mut encoder = GifEncoder::new(File::create(&filename).unwrap());
+        encoder.encode_frames([
+            frame([0xff, 0, 0, 0xff]),
+            frame([0, 0, 0xc0, 0xff]),
+        ]).unwrap();
+        drop(encoder);
+        let filename = filename.to_str().unwrap();
+
+        let frames = load_frames(filename, &LoadOptions::new(2)).unwrap();
+        assert_eq!(frames.len(), 2);
+        assert!(frames[0].code == [Color::Red; 2]);
+        assert!(frames[1].code == [Color::DarkBlue; 2]);
+        let code = load_with_options(filename, &LoadOptions::new(2).frame(1)).unwrap();
+        assert!(code.code == [Color::DarkBlue; 2]);
+        assert!(load_with_options(filename, &LoadOptions::new(2).frame(2)).is_err());
+
+        // Still images have just the one frame.
+        assert_eq!(load_frames("test_imgs/test_slide.png", &LoadOptions::new(1)).unwrap().len(), 1);
     }
 
-    /// If we're going to modulo by zero but have too few arguments on the stack,
-    /// prefer the "too few arguments" message
     #[test]
-    fn test_mod_zero_too_few() {
-        let mut vm = PietVM { stack: to_stack(&[0]), ..Default::default() };
-        let result = vm.run_command(Command::Mod, BigInt::zero());
-        assert!(matches!(result, Err(ExecutionError::NotEnoughStack(2, 1))));
-        assert_eq!(vm.stack, to_stack(&[0]));
+    fn test_pages() {
+        let pages = ["PUSH 3\n", "PUSH 4\nADD\nOUTNUM\n"].map(|source| asm::load_str(source).unwrap());
+        let mut runner = execute_pages(&pages).capture_io("");
+        let result = runner.run_collect();
+        assert_matches!(result.reason, ExitReason::Halted);
+        assert_eq!(result.stdout, b"7");
+        assert_eq!(runner.page(), 1);
+    }
+
+    #[test]
+    fn test_fingerprint() {
+        let (img, expected) = framed_slide(0, 0, 0, 0);
+        let code = to_codels_with_options(img, &LoadOptions::new(3)).unwrap();
+        assert_eq!(code.fingerprint(), expected.fingerprint());
+        // Pinned, since fingerprints may be stored.
+        assert_eq!(code.fingerprint(), 0x36ff28580365f9b3);
+
+        let transposed = PietCode::new(expected.height, expected.width, expected.code.clone());
+        assert_ne!(transposed.fingerprint(), expected.fingerprint());
+    }
+
+    #[test]
+    fn test_guess_codel_size() {
+        let img = image::open("test_imgs/test_slide.png").unwrap();
+        assert_eq!(guess_codel_size(&img), 1);
+        let big = rescale(&img, 1, 6).unwrap().into();
+        assert_eq!(guess_codel_size(&big), 6);
+    }
+
+    #[test]
+    fn test_display_runner() {
+        let code = load("test_imgs/test_slide.png", 1).unwrap();
+        let mut runner = code.execute();
+        runner.run();
+        runner.vm.stack = to_stack(&[1, 2, 3, 4, 5, 6, 7, 8, 9]);
+        assert_eq!(
+            runner.to_string(),
+            format!(
+                "step {}, at codel {}, DP {:?}, CC {:?}\nstack (9 items): ... 2 3 4 5 6 7 8 9",
+                runner.steps, runner.vm.pos, runner.vm.instruction_pointer.0, runner.vm.instruction_pointer.1,
+            ),
+        );
+    }
+
+    #[test]
+    fn test_tracer() {
+        #[derive(Default)]
+        struct Recorder {
+            steps: Vec<u64>,
+            commands: Vec<Command>,
+            errors: usize,
+        }
+
+        impl Tracer for Recorder {
+            fn on_step(
+                &mut self,
+                step: u64,
+                _pos: CodelCoord,
+                _direction: Direction,
+                _chooser: CodelChoice,
+                _stack: &[Value],
+            ) {
+                self.steps.push(step);
+            }
+
+            fn on_command(
+                &mut self,
+                from: Color,
+                to: Color,
+                _pos: CodelCoord,
+                command: Command,
+                _value: &Value,
+            ) {
+                assert_eq!(from.step_to(to), command);
+                self.commands.push(command);
+            }
+
+            fn on_error(&mut self, _command: Command, _error: &ExecutionError) {
+                self.errors += 1;
+            }
+        }
+
+        let code = load("test_imgs/test_slide.png", 1).unwrap();
+        let mut recorder = Recorder::default();
+        let mut runner = code.execute().tracer(&mut recorder);
+        runner.run();
+        let steps = runner.steps();
+        drop(runner);
+        assert_eq!(recorder.steps, (1..=steps).collect::<Vec<_>>());
+        assert!(recorder.commands.contains(&Command::Push));
+        assert_eq!(recorder.errors, 0);
+
+        // A bare VM reports its commands to the tracer it's stepped with.
+        let commands = recorder.commands;
+        let mut recorder = Recorder::default();
+        let mut vm = PietVM::new();
+        while !matches!(vm.step(&code, &mut recorder), StepResult::Halted) {}
+        assert_eq!(recorder.commands, commands);
+    }
+
+    #[test]
+    fn test_hooks() {
+        let code = asm::load_str("PUSH 72\nOUTCHAR\nPUSH 105\nOUTCHAR\nPOP\n").unwrap();
+        let (mut out_chars, mut depths, mut output, mut errors) = (0, Vec::new(), String::new(), Vec::new());
+        let result = code.execute().capture_io("")
+            .before_command(|command, stack| {
+                if command == Command::OutChar {
+                    assert_eq!(stack.len(), 1);
+                    out_chars += 1;
+                }
+            })
+            .after_command(|_, stack| depths.push(stack.len()))
+            .on_output(|text| output.push_str(text))
+            .on_error(|command, _| errors.push(command))
+            .run_collect();
+        assert_matches!(result.reason, ExitReason::Halted);
+        assert_eq!(out_chars, 2);
+        assert_eq!(depths.last(), Some(&0));
+        assert_eq!(output, "Hi");
+        assert_eq!(errors, [Command::Pop]);
+    }
+
+    #[test]
+    fn test_captured_io() {
+        let code = asm::load_str("PUSH 72\nOUTCHAR\nPUSH 105\nOUTCHAR\n").unwrap();
+        let mut runner = code.execute().capture_io("");
+        runner.run();
+        assert_eq!(runner.output(), Some("Hi".as_bytes()));
+
+        let echo = asm::load_str("INCHAR\nOUTCHAR\n").unwrap();
+        let mut runner = echo.execute().capture_io("hi");
+        runner.run();
+        assert_eq!(runner.output(), Some("h".as_bytes()));
+    }
+
+    #[test]
+    fn test_run_collect() {
+        let code = asm::load_str("PUSH 72\nOUTCHAR\nPUSH 105\nOUTCHAR\nPUSH 3\n").unwrap();
+        let result = code.execute().run_collect();
+        assert_matches!(result.reason, ExitReason::Halted);
+        assert_eq!(result.stdout_lossy(), "Hi");
+        assert_eq!(result.stack, to_stack(&[3]));
+    }
+
+    #[test]
+    fn test_streams() {
+        use std::io::{self, Cursor};
+        use std::sync::Mutex;
+
+        #[derive(Clone, Default)]
+        struct Shared(Arc<Mutex<Vec<u8>>>);
+
+        impl Write for Shared {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                self.0.lock().unwrap().write(buf)
+            }
+
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let echo = asm::load_str("INNUM\nOUTNUM\nINCHAR\nOUTCHAR\n").unwrap();
+        let output = Shared::default();
+        let mut runner = echo.execute().io(Cursor::new("-12!"), output.clone());
+        runner.run();
+        assert_eq!(*output.0.lock().unwrap(), b"-12!");
+        assert_eq!(runner.output(), None);
+    }
+
+    #[test]
+    fn test_in_num() {
+        let adder = asm::load_str("INNUM\nINNUM\nADD\nOUTNUM\nINCHAR\nOUTCHAR\n").unwrap();
+        let mut runner = adder.execute().capture_io(" 12\n\t-5x");
+        runner.run();
+        assert_eq!(runner.output(), Some("7x".as_bytes()));
+
+        let mut runner = adder.execute().strict().capture_io("12 abc");
+        assert_matches!(runner.run(), ExitReason::Error(ExecutionError::ExpectedNumber));
+        let mut runner = adder.execute().strict().capture_io("12  ");
+        assert_matches!(runner.run(), ExitReason::Error(ExecutionError::IoError(_)));
+    }
+
+    #[test]
+    fn test_flush_policy() {
+        use std::sync::Mutex;
+
+        /// Records what's written, with a `|` wherever it's flushed.
+        #[derive(Clone, Default)]
+        struct Flushes(Arc<Mutex<String>>);
+
+        impl Write for Flushes {
+            fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+                self.0.lock().unwrap().push_str(std::str::from_utf8(buf).unwrap());
+                Ok(buf.len())
+            }
+
+            fn flush(&mut self) -> std::io::Result<()> {
+                self.0.lock().unwrap().push('|');
+                Ok(())
+            }
+        }
+
+        let code = asm::load_str("PUSH 1\nOUTNUM\nPUSH 2\nOUTNUM\nINCHAR\nOUTCHAR\nPUSH 3\nOUTNUM\n").unwrap();
+        let flushes = |policy| {
+            let out = Flushes::default();
+            let mut runner = code.execute().io(&b"!"[..], out.clone()).flush_policy(policy);
+            assert_matches!(runner.run(), ExitReason::Halted);
+            let flushed = out.0.lock().unwrap().clone();
+            flushed
+        };
+        assert_eq!(flushes(FlushPolicy::Auto), "12|!3|");
+        assert_eq!(flushes(FlushPolicy::Always), "1|2|!|3||");
+        assert_eq!(flushes(FlushPolicy::Manual), "12!3|");
+    }
+
+    #[test]
+    fn test_trivial_programs() {
+        let canvas = |width, height, color| PietCode::new(
+            width,
+            height,
+            vec![color; width * height],
+        );
+        for color in [Color::Red, Color::White, Color::Black] {
+            for (width, height) in [(1, 1), (3, 2), (0, 0)] {
+                let code = canvas(width, height, color);
+                let result = code.execute().fuel(100).strict().capture_io("").run_collect();
+                assert_matches!(result.reason, ExitReason::Halted, "{color:?} {width}x{height}");
+                assert_eq!(result.steps, 1);
+                assert_eq!(result.stack, []);
+                assert_eq!(result.stdout, []);
+            }
+        }
+    }
+
+    #[test]
+    fn test_exit_reasons() {
+        let code = load("test_imgs/test_slide.png", 1).unwrap();
+        assert_matches!(code.execute().run(), ExitReason::Halted);
+        let mut runner = code.execute().fuel(3);
+        assert_matches!(runner.run(), ExitReason::OutOfFuel);
+        assert_eq!(runner.steps(), 3);
+
+        let reports = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let progress = Progress::new({
+            let reports = reports.clone();
+            move |steps| reports.lock().unwrap().push(steps)
+        });
+        let mut runner = code.execute().progress(2, progress);
+        runner.run();
+        let expected: Vec<_> = (2..=runner.steps()).step_by(2).collect();
+        assert!(!expected.is_empty());
+        assert_eq!(*reports.lock().unwrap(), expected);
+
+        let flag = Arc::new(AtomicBool::new(true));
+        assert_matches!(code.execute().interrupt_on(flag).run(), ExitReason::Interrupted);
+
+        // Pop an empty stack, then halt
+        let code = PietCode::new(
+            4,
+            3,
+            vec![
+                Color::Black, Color::DarkRed, Color::DarkRed, Color::DarkRed,
+                Color::LightRed, Color::DarkRed, Color::DarkRed, Color::DarkRed,
+                Color::Black, Color::DarkRed, Color::DarkRed, Color::DarkRed,
+            ],
+        );
+        let mut runner = code.execute();
+        runner.vm.pos = CodelCoord::new(0, 1);
+        assert_matches!(runner.run(), ExitReason::Halted);
+        let mut runner = code.execute().strict();
+        runner.vm.pos = CodelCoord::new(0, 1);
+        assert_matches!(
+            runner.run(),
+            ExitReason::Error(ExecutionError::NotEnoughStack(1, 0))
+        );
+
+        let mut errors = Vec::new();
+        let mut runner = code.execute().policy(RuntimePolicy::Callback(Box::new(|executed, err| {
+            errors.push((executed.command, err.to_string()));
+            ControlFlow::Continue(())
+        })));
+        runner.vm.pos = CodelCoord::new(0, 1);
+        assert_matches!(runner.run(), ExitReason::Halted);
+        drop(runner);
+        assert_eq!(errors, [(Command::Pop, "insufficient stack length (0); expected at least 1".to_string())]);
+
+        let mut runner = code.execute().policy(RuntimePolicy::Callback(Box::new(|_, _| ControlFlow::Break(()))));
+        runner.vm.pos = CodelCoord::new(0, 1);
+        assert_matches!(runner.run(), ExitReason::Error(_));
+    }
+
+    #[test]
+    fn test_runtime_warnings() {
+        #[derive(Default)]
+        struct Warnings(Vec<(u64, CodelCoord, Command, String)>);
+
+        impl Tracer for Warnings {
+            fn on_warning(&mut self, warning: &RuntimeWarning) {
+                self.0.push((warning.step, warning.pos, warning.command, warning.error.to_string()));
+            }
+        }
+
+        // Pop an empty stack, then halt
+        let code = PietCode::new(
+            4,
+            3,
+            vec![
+                Color::Black, Color::DarkRed, Color::DarkRed, Color::DarkRed,
+                Color::LightRed, Color::DarkRed, Color::DarkRed, Color::DarkRed,
+                Color::Black, Color::DarkRed, Color::DarkRed, Color::DarkRed,
+            ],
+        );
+        let mut warnings = Warnings::default();
+        let mut runner = code.execute().tracer(&mut warnings);
+        runner.vm.pos = CodelCoord::new(0, 1);
+        assert_matches!(runner.run(), ExitReason::Halted);
+        drop(runner);
+        assert_eq!(
+            warnings.0,
+            [(1, CodelCoord::new(0, 1), Command::Pop, "insufficient stack length (0); expected at least 1".to_string())],
+        );
+        let warning = RuntimeWarning {
+            step: 1,
+            pos: CodelCoord::new(0, 0),
+            command: Command::Pop,
+            error: &ExecutionError::NotEnoughStack(1, 0),
+        };
+        assert_eq!(warning.to_string(), "step 1 at (0, 0): skipping Pop: insufficient stack length (0); expected at least 1");
+
+        // Only skipped commands warn, not ones that stop the run.
+        let mut warnings = Warnings::default();
+        let mut runner = code.execute().strict().tracer(&mut warnings);
+        runner.vm.pos = CodelCoord::new(0, 1);
+        assert_matches!(runner.run(), ExitReason::Error(_));
+        drop(runner);
+        assert_eq!(warnings.0, []);
+    }
+
+    #[test]
+    fn test_step_result() {
+        let code = PietCode::new(
+            4,
+            1,
+            vec![Color::White, Color::LightRed, Color::DarkRed, Color::Black],
+        );
+        let mut runner = code.execute();
+        let coord = |x| CodelCoord::new(x, 0);
+        assert_matches!(
+            runner.step(),
+            StepResult::Continue(Executed { command: Command::Noop, value, .. })
+                if value.is_zero()
+        );
+        assert_matches!(
+            runner.step(),
+            StepResult::Error(
+                Executed { command: Command::Pop, from, to, value },
+                ExecutionError::NotEnoughStack(1, 0),
+            ) if from == coord(1) && to == coord(2) && value.is_one()
+        );
+        // Blocked by the black codel, so turn around.
+        assert_matches!(
+            runner.step(),
+            StepResult::Continue(Executed { command: Command::Push, from, to, .. })
+                if from == coord(2) && to == coord(1)
+        );
+
+        let code = PietCode::new(1, 1, vec![Color::Red]);
+        assert_matches!(code.execute().step(), StepResult::Halted);
+    }
+
+    #[test]
+    fn test_run_for() {
+        let code = load("test_imgs/test_slide.png", 1).unwrap();
+        let mut runner = code.execute();
+        assert_matches!(runner.run_for(2), ControlFlow::Continue(()));
+        assert_eq!(runner.steps(), 2);
+        let reason = loop {
+            if let ControlFlow::Break(reason) = runner.run_for(2) {
+                break reason;
+            }
+        };
+        assert_matches!(reason, ExitReason::Halted);
+        assert_eq!(runner.vm.stack, to_stack(&[8]));
+
+        let mut runner = code.execute().fuel(3);
+        assert_matches!(runner.run_for(10), ControlFlow::Break(ExitReason::OutOfFuel));
+        assert_eq!(runner.steps(), 3);
+    }
+
+    #[test]
+    fn test_run_with_limits() {
+        let code = load("test_imgs/test_slide.png", 1).unwrap();
+        let mut runner = code.execute();
+        assert_matches!(runner.run_with_limits(2, Duration::MAX), ExitReason::OutOfFuel);
+        assert_eq!(runner.steps(), 2);
+        // The limit counts from where the run is up to, and is lifted afterwards.
+        assert_matches!(runner.run_with_limits(1, Duration::MAX), ExitReason::OutOfFuel);
+        assert_eq!(runner.steps(), 3);
+        assert_matches!(runner.run(), ExitReason::Halted);
+
+        let mut runner = code.execute().fuel(2);
+        assert_matches!(runner.run_with_limits(10, Duration::MAX), ExitReason::OutOfFuel);
+        assert_eq!(runner.steps(), 2);
+
+        // Loops forever
+        let code = PietCode::new(3, 1, vec![Color::LightRed, Color::DarkRed, Color::Black]);
+        let mut runner = code.execute();
+        let reason = runner.run_with_limits(u64::MAX, Duration::from_millis(20));
+        assert_matches!(reason, ExitReason::TimedOut);
+        assert!(runner.steps() > 0);
+    }
+
+    #[test]
+    fn test_breakpoints() {
+        // Loops forever: push, pop, push, pop, ...
+        let code = PietCode::new(3, 1, vec![Color::LightRed, Color::DarkRed, Color::Black]);
+        let mut runner = code.execute()
+            .breakpoint(Breakpoint::Step(5))
+            .breakpoint(Breakpoint::Command(Command::Push));
+        assert_matches!(runner.run(), ExitReason::Breakpoint(Breakpoint::Command(Command::Push)));
+        assert_eq!(runner.steps(), 2);
+        assert_eq!(runner.stack(), to_stack(&[1]));
+        assert_matches!(runner.run(), ExitReason::Breakpoint(Breakpoint::Command(Command::Push)));
+        assert_eq!(runner.steps(), 4);
+
+        assert!(runner.remove_breakpoint(&Breakpoint::Command(Command::Push)));
+        assert!(!runner.remove_breakpoint(&Breakpoint::Command(Command::Push)));
+        assert_matches!(runner.run(), ExitReason::Breakpoint(Breakpoint::Step(5)));
+        assert_eq!(runner.steps(), 5);
+
+        runner.add_breakpoint(Breakpoint::At(CodelCoord::new(1, 0)));
+        assert_matches!(runner.run(), ExitReason::Breakpoint(Breakpoint::At(_)));
+        assert_eq!(runner.steps(), 7);
+        assert_eq!(runner.vm.pos, CodelCoord::new(1, 0));
+    }
+
+    #[test]
+    fn test_step_back() {
+        let code = asm::load_str("INCHAR\nPUSH 1\nADD\nOUTCHAR\nINCHAR\nOUTCHAR\n").unwrap();
+        let mut runner = code.execute().capture_io("ab").history(1000);
+        let mut states = Vec::new();
+        loop {
+            states.push((runner.vm.pos, runner.vm.stack.clone(), runner.output().unwrap().to_vec()));
+            if matches!(runner.step(), StepResult::Halted) {
+                break;
+            }
+        }
+        assert_eq!(runner.output(), Some(&b"bb"[..]));
+        let steps = runner.steps();
+        for back in [1, 3, 2] {
+            assert_eq!(runner.step_back(back), back);
+            let (pos, stack, output) = &states[runner.steps() as usize];
+            assert_eq!((&runner.vm.pos, &runner.vm.stack, runner.output().unwrap()), (pos, stack, &output[..]));
+        }
+        // Input read by the undone steps is read again.
+        assert_matches!(runner.run(), ExitReason::Halted);
+        assert_eq!(runner.output(), Some(&b"bb"[..]));
+        assert_eq!(runner.steps(), steps);
+        assert_eq!(runner.step_back(steps as usize + 5), steps as usize);
+        assert_matches!(runner.run(), ExitReason::Halted);
+        assert_eq!(runner.output(), Some(&b"bb"[..]));
+
+        // Only so far back.
+        let mut runner = code.execute().capture_io("ab").history(3);
+        runner.run();
+        assert_eq!(runner.step_back(5), 3);
+        assert_eq!(runner.steps(), steps - 3);
+        assert_eq!(code.execute().step_back(1), 0);
+    }
+
+    #[test]
+    fn test_dialect() {
+        // R W W #
+        // # W # #
+        // # G # #
+        let code = PietCode::new(4, 3, vec![
+            Color::Red, Color::White, Color::White, Color::Black,
+            Color::Black, Color::White, Color::Black, Color::Black,
+            Color::Black, Color::Green, Color::Black, Color::Black,
+        ]);
+        // Into the white, then across it.
+        let mut runner = code.execute();
+        runner.step();
+        runner.step();
+        // Sliding right, then down, then left, back to where it came from.
+        assert_eq!(runner.vm.pos, CodelCoord::new(0, 0));
+        assert_eq!(runner.vm.direction(), Direction::Left);
+
+        let mut runner = code.execute().dialect(Dialect::PietDev);
+        runner.step();
+        assert_matches!(runner.step(), StepResult::Continue(Executed { command: Command::Noop, .. }));
+        // Out of the bottom of the white block, choosing its only codel there.
+        assert_eq!(runner.vm.pos, CodelCoord::new(1, 2));
+        assert_eq!(runner.vm.direction(), Direction::Down);
+        assert_eq!(runner.vm.codel_chooser(), CodelChoice::Right);
+    }
+
+    #[test]
+    fn test_detect_loops() {
+        // Loops forever: push, pop, push, pop, ...
+        let code = PietCode::new(3, 1, vec![Color::LightRed, Color::DarkRed, Color::Black]);
+        let mut runner = code.execute().detect_loops(3).fuel(1000);
+        assert_matches!(runner.run(), ExitReason::LoopDetected);
+        assert!(runner.steps() < 20);
+
+        // Output every time round isn't the same state twice, and nor is a deeper stack.
+        for source in [":L\nPUSH 1\nOUTNUM\nJUMP L\n", ":L\nPUSH 1\nJUMP L\n"] {
+            let code = asm::load_str(source).unwrap();
+            let mut runner = code.execute().capture_io("").detect_loops(1).fuel(1000);
+            assert_matches!(runner.run(), ExitReason::OutOfFuel);
+        }
+        let code = asm::load_str("PUSH 3\nDUP\nMUL\nOUTNUM\n").unwrap();
+        assert_matches!(code.execute().capture_io("").detect_loops(1).run(), ExitReason::Halted);
+    }
+
+    #[test]
+    fn test_limits() {
+        let limits = RunLimits::parse("steps=100000 seconds=2.5").unwrap();
+        assert_eq!(limits.steps, Some(100000));
+        assert_eq!(limits.time, Some(Duration::from_millis(2500)));
+        assert_eq!(limits.to_string(), "steps=100000 seconds=2.5");
+        assert_eq!(RunLimits::parse("seconds=1"), Some(RunLimits { steps: None, time: Some(Duration::from_secs(1)) }));
+        assert_eq!(RunLimits::parse(""), None);
+        assert_eq!(RunLimits::parse("steps=1 steps=2"), None);
+        assert_eq!(RunLimits::parse("steps=-1"), None);
+        assert_eq!(RunLimits::parse("fuel=1"), None);
+
+        let code = load("test_imgs/test_slide.png", 1).unwrap();
+        let filename = std::env::temp_dir().join("piet_tools_limits.png");
+        let filename = filename.to_str().unwrap();
+        save_with_limits(&code, filename, 2, &limits).unwrap();
+        assert_eq!(read_limits(filename), Ok(Some(limits)));
+        assert_eq!(load(filename, 2).unwrap().fingerprint(), code.fingerprint());
+        save(&code, filename, 2).unwrap();
+        assert_eq!(read_limits(filename), Ok(None));
+    }
+
+    #[test]
+    fn test_input_schema() {
+        let schema = InputSchema::parse("num  num char*").unwrap();
+        assert_eq!(schema.items, [InputItem::Num, InputItem::Num, InputItem::Char]);
+        assert_eq!(schema.to_string(), "num num char*");
+        assert_eq!(InputSchema::parse(""), None);
+        assert_eq!(InputSchema::parse("char* num"), None);
+        assert_eq!(InputSchema::parse("string"), None);
+
+        assert_eq!(schema.validate(b"3 -4\nhello"), Ok(()));
+        assert_eq!(schema.validate(b" 3\n+4"), Ok(()));
+        assert_eq!(schema.validate(b"3 x"), Err("expected a number at byte 1".to_string()));
+        assert_eq!(schema.validate(b"3"), Err("expected a number, at the end of input".to_string()));
+        let nums = InputSchema::parse("char num*").unwrap();
+        assert_eq!(nums.validate(b"a1 2 3\n"), Ok(()));
+        assert_eq!(nums.validate(b"a"), Ok(()));
+        assert_eq!(nums.validate(b"a1 2 x"), Err("expected a number at byte 4".to_string()));
+        assert_eq!(nums.validate(b""), Err("expected a character, at the end of input".to_string()));
+
+        let code = load("test_imgs/test_slide.png", 1).unwrap();
+        let filename = std::env::temp_dir().join("piet_tools_input_schema.png");
+        let filename = filename.to_str().unwrap();
+        save_with_options(&code, filename, &SaveOptions::new(1).input(Some(schema.clone()))).unwrap();
+        assert_eq!(read_input_schema(filename), Ok(Some(schema)));
+        assert_eq!(read_limits(filename), Ok(None));
+        save(&code, filename, 1).unwrap();
+        assert_eq!(read_input_schema(filename), Ok(None));
+    }
+
+    #[test]
+    fn test_save_compact() {
+        let filename = std::env::temp_dir().join("piet_tools_compact.png");
+        let filename = filename.to_str().unwrap();
+        // Every color, to need a full byte per pixel; then just a few colors,
+        // packed into part of a byte with rows that don't end on a byte boundary.
+        let every_color = PietCode::new(
+            21,
+            1,
+            Color::ALL.into_iter().chain([Color::Other]).collect(),
+        );
+        let few_colors = PietCode::new(
+            3,
+            2,
+            vec![Color::Red, Color::White, Color::Black, Color::Black, Color::Blue, Color::Red],
+        );
+        for code in [every_color, few_colors] {
+            for codel_size in [1, 3] {
+                save_with_options(&code, filename, &SaveOptions::new(codel_size).compact()).unwrap();
+                assert_eq!(load(filename, codel_size).unwrap().fingerprint(), code.fingerprint());
+            }
+        }
+
+        let code = load("test_imgs/test_slide.png", 1).unwrap();
+        let limits = RunLimits { steps: Some(10), time: None };
+        save_with_options(&code, filename, &SaveOptions::new(10).compact().limits(limits)).unwrap();
+        let compact_size = std::fs::metadata(filename).unwrap().len();
+        assert_eq!(read_limits(filename), Ok(Some(limits)));
+        assert_eq!(load(filename, 10).unwrap().fingerprint(), code.fingerprint());
+        save(&code, filename, 10).unwrap();
+        assert!(compact_size < std::fs::metadata(filename).unwrap().len());
+    }
+
+    #[test]
+    fn test_stats() {
+        let code = load("test_imgs/test_slide.png", 1).unwrap();
+        let mut runner = code.execute().collect_stats();
+        runner.run();
+        let stats = runner.stats().unwrap();
+        assert_eq!(stats.steps, runner.steps());
+        assert_eq!(stats.max_stack_depth, 1);
+        assert!(stats.white_slides > 0);
+        assert_eq!(stats.count(Command::Push), 1);
+        let counted: u64 = stats.commands().map(|(_, count)| count).sum();
+        // Every step but the last, which halted, either slid or ran a command.
+        assert_eq!(counted + stats.white_slides, stats.steps - 1);
+        assert!(code.execute().stats().is_none());
+    }
+
+    #[test]
+    fn test_vm_accessors() {
+        let code = PietCode::new(3, 1, vec![Color::LightRed, Color::DarkRed, Color::Black]);
+        let mut runner = code.execute();
+        assert_eq!(runner.vm().position(), CodelCoord::new(0, 0));
+        assert_eq!(runner.vm().direction(), Direction::Right);
+        assert_eq!(runner.vm().codel_chooser(), CodelChoice::Left);
+        runner.step();
+        assert_eq!(runner.vm().position(), CodelCoord::new(1, 0));
+        // Blocked to the right, so it turns back the way it came, pushing.
+        runner.step();
+        assert_eq!(runner.vm().position(), CodelCoord::new(0, 0));
+        assert_eq!(runner.vm().direction(), Direction::Left);
+        assert_eq!(runner.vm().stack(), to_stack(&[1]));
+
+        // The next step pops what's pushed here, rather than the 1.
+        runner.vm_mut().stack_mut().push(Value::from(9));
+        runner.step();
+        assert_eq!(runner.stack(), to_stack(&[1]));
+    }
+
+    #[test]
+    fn test_replay() {
+        let code = load("test_imgs/test_slide.png", 1).unwrap();
+        let mut trace = Trace::new();
+        code.execute().tracer(&mut trace).run();
+        assert_eq!(code.execute().replay(&trace), Ok(()));
+
+        let mut tampered = Trace { steps: trace.steps.clone() };
+        tampered.steps[2].stack.push(Value::from(7));
+        let divergence = code.execute().replay(&tampered).unwrap_err();
+        assert_eq!(divergence.actual.step, 3);
+        assert_eq!(divergence.actual.stack, trace.steps[2].stack);
+        assert!(divergence.to_string().starts_with("step 3: expected stack"));
+
+        // A different program diverges on its first command.
+        let other = PietCode::new(3, 1, vec![Color::LightRed, Color::DarkRed, Color::Black]);
+        let divergence = other.execute().replay(&trace).unwrap_err();
+        assert_eq!(divergence.actual.step, 1);
+    }
+
+    #[test]
+    fn test_shared_between_threads() {
+        let code = Arc::new(load("test_imgs/test_slide.png", 1).unwrap());
+        let handles: Vec<_> = (0..4)
+            .map(|_| {
+                let code = code.clone();
+                std::thread::spawn(move || {
+                    let mut runner = code.execute();
+                    runner.run();
+                    runner.vm.stack
+                })
+            })
+            .collect();
+        for handle in handles {
+            assert_eq!(handle.join().unwrap(), to_stack(&[8]));
+        }
     }
 
     /// Exercises sliding, slide cycle detection, and slide CC maintenance
@@ -770,4 +4001,144 @@ mod tests {
         runner.run();
         assert_eq!(runner.vm.stack, to_stack(&[8]));
     }
+
+    #[test]
+    fn test_invalid_color() {
+        // Red moves right into an invalid codel, with nowhere else to go.
+        let code = PietCode::new(3, 1, vec![Color::Red, Color::Other, Color::Blue]);
+        let mut runner = code.execute();
+        let result = runner.step();
+        let coord = CodelCoord::new(1, 0);
+        assert_matches!(result, StepResult::Error(_, ExecutionError::InvalidColor(c)) if c == coord);
+        assert_eq!(runner.vm.pos, CodelCoord::new(0, 0));
+        // Even a lenient run can't get past it.
+        let mut runner = code.execute();
+        assert_matches!(runner.run(), ExitReason::Error(ExecutionError::InvalidColor(c)) if c == coord);
+        assert_eq!(runner.steps(), 1);
+        // Nor can a slide.
+        let code = PietCode::new(3, 1, vec![Color::White, Color::Other, Color::Blue]);
+        let mut runner = code.execute();
+        assert_matches!(runner.run(), ExitReason::Error(ExecutionError::InvalidColor(c)) if c == coord);
+        assert_eq!(runner.vm.pos, CodelCoord::new(0, 0));
+        // Or starting on one.
+        let code = PietCode::new(3, 1, vec![Color::Other, Color::Blue, Color::Blue]);
+        assert_matches!(code.execute().run(), ExitReason::Error(ExecutionError::InvalidColor(_)));
+        assert_matches!(execute_pages(&[]).run(), ExitReason::Halted);
+    }
+
+    #[test]
+    fn test_other_colors() {
+        // R ? B
+        // # # #
+        let code = PietCode::new(3, 2, vec![
+            Color::Red, Color::Other, Color::Blue,
+            Color::Black, Color::Black, Color::Black,
+        ]);
+        let mut runner = code.execute().other_colors(OtherColorPolicy::White);
+        // Red slides across into blue, running nothing.
+        assert_matches!(runner.step(), StepResult::Continue(Executed { command: Command::Noop, .. }));
+        assert_matches!(runner.step(), StepResult::Continue(Executed { command: Command::Noop, .. }));
+        assert_eq!(runner.vm.pos, CodelCoord::new(2, 0));
+
+        // Red can't get out, so halts.
+        let mut runner = code.execute().other_colors(OtherColorPolicy::Black);
+        assert_matches!(runner.run(), ExitReason::Halted);
+        assert_eq!(runner.steps(), 1);
+        assert_eq!(runner.vm.pos, CodelCoord::new(0, 0));
+
+        let mut runner = code.execute().other_colors(OtherColorPolicy::Black).other_colors(OtherColorPolicy::Error);
+        assert_matches!(runner.run(), ExitReason::Error(ExecutionError::InvalidColor(_)));
+    }
+
+    #[test]
+    fn test_check_standard() {
+        assert_eq!(PietCode::new(2, 1, vec![Color::Red, Color::White]).check_standard(), Ok(()));
+        let code = PietCode::new(3, 2, vec![
+            Color::Red, Color::Other, Color::Blue,
+            Color::Black, Color::Black, Color::Other,
+        ]);
+        assert_eq!(code.check_standard(), Err("codel (1, 0) isn't a Piet color, nor is 1 more".to_string()));
+        assert_eq!(code.replace_other(Color::White).check_standard(), Ok(()));
+        let code = PietCode::new(1, 1, vec![Color::Other]);
+        assert_eq!(code.check_standard(), Err("codel (0, 0) isn't a Piet color".to_string()));
+    }
+
+    /// A small xorshift generator, so the fuzzing below is repeatable.
+    struct Rng(u64);
+
+    impl Rng {
+        fn below(&mut self, n: usize) -> usize {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            (self.0 % n as u64) as usize
+        }
+
+        fn color(&mut self) -> Color {
+            match self.below(8) {
+                0 => Color::Other,
+                1 => Color::White,
+                2 | 3 => Color::Black,
+                _ => Color::ALL[self.below(18)],
+            }
+        }
+
+        fn bytes(&mut self) -> Vec<u8> {
+            (0..self.below(8)).map(|_| b"-0123 9x\n\xff"[self.below(10)]).collect()
+        }
+    }
+
+    /// Throw random images and programs at the public API: anything may fail,
+    /// but nothing may panic.
+    #[test]
+    fn test_no_panics() {
+        // Few enough steps that a program squaring a number over and over finishes.
+        const FUEL: u64 = 50;
+        let mut rng = Rng(0x9E37_79B9_7F4A_7C15);
+        for _ in 0..300 {
+            let (w, h) = (rng.below(7) as u32, rng.below(7) as u32);
+            let mut img = image::RgbaImage::new(w, h);
+            for px in img.pixels_mut() {
+                *px = match rng.below(4) {
+                    0 => Rgba([rng.below(256) as u8, 0, 0, rng.below(2) as u8 * 255]),
+                    _ => image::Pixel::to_rgba(&Rgb::try_from(rng.color()).unwrap_or(OTHER_COLOR)),
+                };
+            }
+            let img = DynamicImage::ImageRgba8(img);
+            let mut options = LoadOptions::new(rng.below(4) as u32);
+            match rng.below(4) {
+                0 => { options = options.offset(rng.below(3) as u32, rng.below(3) as u32); }
+                1 => { options = options.detect_margin(); }
+                2 => { options = options.mask_alpha(); }
+                _ => (),
+            }
+            let _ = to_codels_with_diagnostics(img.clone(), &options);
+            let _ = rescale(&img, rng.below(3) as u32, rng.below(3) as u32);
+            let _ = clean(&img, rng.below(3) as u32);
+            guess_codel_size(&img);
+
+            let (width, height) = (rng.below(6), rng.below(6));
+            let code = PietCode::new(
+                width,
+                height,
+                (0..width * height).map(|_| rng.color()).collect(),
+            );
+            let input = rng.bytes();
+            let mut trace = Trace::new();
+            let mut runner = code.execute().capture_io(input.clone()).fuel(FUEL).tracer(&mut trace);
+            if rng.below(2) == 0 {
+                runner = runner.strict();
+            }
+            runner.run_collect();
+            drop(runner);
+            let _ = code.execute().capture_io(input).replay(&trace);
+            trace.to_json();
+            trace.to_html(&code);
+            code.npiet_trace(&rng.bytes(), FUEL);
+            code.explain();
+            let _ = code.link(&code);
+            let pages = [code, PietCode::new(1, 1, vec![rng.color()])];
+            execute_pages(&pages).capture_io(rng.bytes()).fuel(FUEL).run();
+        }
+    }
 }
@@ -0,0 +1,269 @@
+use crate::{CodelCoord, CodelRegion, Color, Delta, Direction, InstructionPointer, PietCode};
+use std::collections::HashSet;
+
+/// The result of linking two programs with `PietCode::link`.
+pub struct Linked {
+    pub code: PietCode,
+    /// Ways the second program might not behave quite as it did on its own.
+    pub warnings: Vec<String>,
+}
+
+/// Every instruction pointer, in the order a block tries them when leaving
+/// it from the initial one.
+fn instruction_pointers() -> Vec<InstructionPointer> {
+    let mut ip = InstructionPointer::default();
+    let mut ips = Vec::new();
+    for _ in 0..4 {
+        ips.push(ip);
+        ip.flip();
+        ips.push(ip);
+        ip.rotate();
+    }
+    ips
+}
+
+/// Every block of a Piet color in `code`.
+fn colored_blocks(code: &PietCode) -> impl Iterator<Item = &CodelRegion> {
+    code.regions().blocks.iter()
+        .filter(|block| matches!(block.color, Color::Color(..)))
+}
+
+/// The black codels execution would try to move into when leaving `block`.
+/// Leaving into a non-Piet color would crash, so a block that never leaves
+/// into anything else is where the program halts.
+fn blocked_exits(code: &PietCode, block: &CodelRegion) -> Option<HashSet<CodelCoord>> {
+    let mut blocked = HashSet::new();
+    for ip in instruction_pointers() {
+        let Some(exit) = block.exit_to(ip) else { continue; };
+        match code.at(exit) {
+            None => (),
+            Some(Color::Black) => { blocked.insert(exit); }
+            Some(_) => { return None; }
+        }
+    }
+    Some(blocked)
+}
+
+/// The top-left codel of a block, to name it in errors.
+fn corner(region: &CodelRegion) -> CodelCoord {
+    region.region.iter().min_by_key(|c| (c.y, c.x)).copied().unwrap_or_default()
+}
+
+impl PietCode {
+    /// Join two programs into one, which runs this program and then,
+    /// instead of halting, runs `next` from the start.
+    ///
+    /// This program must have exactly one block where it can halt, which is
+    /// checked statically, and that block must touch the right or bottom edge
+    /// of the image, so a path of white can lead out of it. The path winds
+    /// clockwise around to `next`, which is placed below this program, and
+    /// enters `next`'s first block from the left with the direction and codel
+    /// choice a program starts with. Halting by sliding around white forever
+    /// isn't detected.
+    pub fn link(&self, next: &PietCode) -> Result<Linked, String> {
+        let start = CodelCoord::new(0, 0);
+        if self.at(start) == Some(Color::Black) {
+            return Err("the first program starts on black, so it can't run".to_string());
+        }
+        let mut halting = Vec::new();
+        // Black codels other blocks might try to leave into, so can't be opened up.
+        let mut exits = HashSet::new();
+        for block in colored_blocks(self) {
+            match blocked_exits(self, block) {
+                Some(_) => halting.push(block),
+                None => {
+                    exits.extend(instruction_pointers().into_iter()
+                        .filter_map(|ip| block.exit_to(ip))
+                        .filter(|&exit| self.at(exit) == Some(Color::Black)));
+                }
+            }
+        }
+        let block = match halting.as_slice() {
+            [block] => block,
+            [] => {
+                return Err(
+                    "the first program has no block to halt in, so it can't be followed".to_string()
+                );
+            }
+            blocks => {
+                let corners: Vec<_> = blocks.iter().map(|b| corner(b).to_string()).collect();
+                return Err(format!(
+                    "the first program can halt in {} blocks, at {}; it must have exactly one",
+                    blocks.len(),
+                    corners.join(", "),
+                ));
+            }
+        };
+        let (width, height) = (self.width, self.height);
+        let own_exits = blocked_exits(self, block).unwrap_or_default();
+        // Open up a straight line out of the block to the right or bottom edge
+        // of the image, through codels nothing else could move into. Non-Piet
+        // colors are fair game: execution never moves into them without crashing.
+        let line_out = |ip: InstructionPointer| {
+            let InstructionPointer(dp, _) = ip;
+            let exit = block.exit_to(ip)?;
+            let line: Vec<_> = match dp {
+                Direction::Right => (exit.x..width).map(|x| CodelCoord::new(x, exit.y)).collect(),
+                // Leaving downwards the path has to turn left before it can turn back
+                // to the right, so there has to be room to its left.
+                Direction::Down if exit.x > 0 => {
+                    (exit.y..height).map(|y| CodelCoord::new(exit.x, y)).collect()
+                }
+                _ => { return None; }
+            };
+            // The block mustn't be able to move into the line any other way.
+            let other_way = instruction_pointers().into_iter()
+                .any(|other| other.0 != dp && block.exit_to(other).is_some_and(|c| line.contains(&c)));
+            if other_way || line.iter().skip(1).any(|c| own_exits.contains(c)) {
+                return None;
+            }
+            let open = line.iter().all(|&coord| {
+                matches!(self.at(coord), Some(Color::Black | Color::Other))
+                    && !exits.contains(&coord)
+                    && [Delta::UP, Delta::DOWN, Delta::LEFT, Delta::RIGHT].into_iter()
+                        .filter_map(|delta| coord.checked_add(delta))
+                        .all(|next| self.at(next) != Some(Color::White) || line.contains(&next))
+            });
+            open.then_some((line, exit, dp))
+        };
+        let (line, exit, dp) = instruction_pointers().into_iter()
+            .find_map(line_out)
+            .ok_or_else(|| format!(
+                "the first program halts in the block at {}, which has no clear way out to the right or bottom edge of the image",
+                corner(block),
+            ))?;
+
+        let mut warnings = Vec::new();
+        let first = next.region_at(start)
+            .ok_or_else(|| "the second program is empty".to_string())?;
+        // The row of `next`'s first column to enter it at. Entering the first
+        // block on its top or bottom codel in that column would give it an exit
+        // to the left that it didn't have before.
+        let entry_row = match first.color {
+            Color::Black => {
+                return Err("the second program starts on black, so it can't run".to_string());
+            }
+            Color::White => {
+                warnings.push(
+                    "the second program starts on white; any slide left out of its top-left codel will leave it".to_string()
+                );
+                0
+            }
+            _ => {
+                let mut rows: Vec<_> = first.region.iter().filter(|c| c.x == 0).map(|c| c.y).collect();
+                rows.sort();
+                match rows.as_slice() {
+                    [_, middle, .., _] => *middle,
+                    _ => {
+                        warnings.push(
+                            "the second program's first block is too short to enter safely; it leaves to the left of (0, 0) if it ever tries to".to_string()
+                        );
+                        0
+                    }
+                }
+            }
+        };
+
+        // Lay out the canvas: this program at the top left, walled off in black,
+        // with a row below it to collect a path leaving downwards. Below that
+        // is `next`, walled off too, with room at its left for the path in.
+        let collect_row = height + 1;
+        let next_x = 4;
+        let next_y = height + 4;
+        let bottom_row = next_y + next.height + 2;
+        let right_column = (width + 1).max(next_x + next.width + 1);
+        let mut linked = PietCode::new(
+            right_column + 1,
+            bottom_row + 1,
+            vec![Color::Black; (right_column + 1) * (bottom_row + 1)],
+        );
+        let mut paint = |x: usize, y: usize, color: Color| {
+            linked.code[x + y * linked.width] = color;
+        };
+        for (coord, color) in self.codels() {
+            paint(coord.x, coord.y, color);
+        }
+        for (coord, color) in next.codels() {
+            paint(next_x + coord.x, next_y + coord.y, color);
+        }
+
+        // Every turn the path takes is clockwise, where it runs into black or the
+        // edge of the image. Both ways out end up heading down the right column.
+        for coord in line {
+            paint(coord.x, coord.y, Color::White);
+        }
+        let down_from = match dp {
+            Direction::Right => {
+                for x in width..=right_column {
+                    paint(x, exit.y, Color::White);
+                }
+                exit.y
+            }
+            _ => {
+                // Down into the collecting row, left along it, then blocked both
+                // left and up, back to the right along it.
+                paint(exit.x, height, Color::White);
+                for x in 0..=right_column {
+                    paint(x, collect_row, Color::White);
+                }
+                collect_row
+            }
+        };
+        for y in down_from..=bottom_row {
+            paint(right_column, y, Color::White);
+        }
+        // Left along the bottom, then up the left edge to the entry row.
+        let entry_y = next_y + entry_row;
+        for x in 0..=right_column {
+            paint(x, bottom_row, Color::White);
+        }
+        for y in entry_y..=bottom_row {
+            paint(0, y, Color::White);
+        }
+        // Then right into `next`, through a block that sets the codel chooser:
+        // leaving it, the codel chooser's first choice is blocked, and the
+        // other is the way on.
+        paint(1, entry_y, Color::White);
+        paint(2, entry_y, Color::Red);
+        paint(2, entry_y + 1, Color::Red);
+        paint(3, entry_y, Color::White);
+
+        Ok(Linked { code: linked, warnings })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::asm;
+    use crate::{PietCode, Color};
+
+    fn assemble(source: &str) -> PietCode {
+        asm::load_str(source).unwrap()
+    }
+
+    fn output(code: &PietCode) -> String {
+        let mut runner = code.execute().capture_io("");
+        runner.run();
+        String::from_utf8(runner.output().unwrap().to_vec()).unwrap()
+    }
+
+    #[test]
+    fn test_link() {
+        let a = assemble("PUSH 72\nOUTCHAR\nPUSH 105\nOUTCHAR\n");
+        let b = assemble("PUSH 5\n:L\nDUP\nOUTNUM\nSUB 1\nDUP\nJUMPIF L\n");
+        assert_eq!(output(&a), "Hi");
+        assert_eq!(output(&b), "54321");
+        let linked = a.link(&b).unwrap();
+        assert_eq!(output(&linked.code), "Hi54321");
+        let linked = b.link(&a).unwrap();
+        assert_eq!(output(&linked.code), "54321Hi");
+    }
+
+    #[test]
+    fn test_link_needs_one_halt() {
+        // Two separate dead ends.
+        let code = PietCode::new(3, 1, vec![Color::Red, Color::Black, Color::Blue]);
+        let err = code.link(&code).err().unwrap();
+        assert_eq!(err, "the first program can halt in 2 blocks, at (0, 0), (2, 0); it must have exactly one");
+    }
+}
@@ -0,0 +1,128 @@
+use crate::{CodelChoice, Color, Command, Direction, ExecutionError, InstructionPointer, PietCode, StepResult};
+
+/// A color as npiet names it, eg `lR` for light red.
+fn color_name(color: Color) -> &'static str {
+    match color {
+        Color::Color(hue, lightness) => {
+            const NAMES: [[&str; 3]; 6] = [
+                ["lR", "nR", "dR"],
+                ["lY", "nY", "dY"],
+                ["lG", "nG", "dG"],
+                ["lC", "nC", "dC"],
+                ["lB", "nB", "dB"],
+                ["lM", "nM", "dM"],
+            ];
+            NAMES[hue as usize][lightness as usize]
+        }
+        Color::White => "WW",
+        Color::Black => "BB",
+        Color::Other => "??",
+    }
+}
+
+fn action_name(command: Command) -> &'static str {
+    match command {
+        Command::Noop => "none",
+        Command::Push => "push",
+        Command::Pop => "pop",
+        Command::Add => "add",
+        Command::Subtract => "sub",
+        Command::Multiply => "mul",
+        Command::Divide => "div",
+        Command::Mod => "mod",
+        Command::Not => "not",
+        Command::Greater => "greater",
+        Command::Pointer => "pointer",
+        Command::Switch => "switch",
+        Command::Duplicate => "duplicate",
+        Command::Roll => "roll",
+        Command::InNum => "in(number)",
+        Command::InChar => "in(char)",
+        Command::OutNum => "out(number)",
+        Command::OutChar => "out(char)",
+    }
+}
+
+/// A position and instruction pointer as npiet writes them, eg `3,0/r,l`.
+fn state(x: usize, y: usize, ip: InstructionPointer) -> String {
+    let InstructionPointer(dp, cc) = ip;
+    let dp = match dp {
+        Direction::Right => 'r',
+        Direction::Down => 'd',
+        Direction::Left => 'l',
+        Direction::Up => 'u',
+    };
+    let cc = match cc {
+        CodelChoice::Left => 'l',
+        CodelChoice::Right => 'r',
+    };
+    format!("{x},{y}/{dp},{cc}")
+}
+
+impl PietCode {
+    /// Run the program on `input` for up to `max_steps` steps, describing each
+    /// in the vocabulary of npiet's trace (`npiet -t`): the codels moved between
+    /// with their DP, CC, and colors, the command run and the size of the block
+    /// it ran from, and the stack afterwards, top first.
+    /// This is to cross-check a program against npiet, so its output isn't kept.
+    pub fn npiet_trace(&self, input: &[u8], max_steps: u64) -> String {
+        let mut runner = self.execute().capture_io(input);
+        let mut trace = Vec::new();
+        for step in 0..max_steps {
+            let from = runner.vm.pos;
+            let from_ip = runner.vm.instruction_pointer;
+            let from_color = self.at(from).unwrap_or(Color::Black);
+            let executed = match runner.step() {
+                StepResult::Continue(executed) => executed,
+                StepResult::Error(_, ExecutionError::InvalidColor(coord)) => {
+                    trace.push(format!("trace: stuck on invalid color at {},{}", coord.x, coord.y));
+                    break;
+                }
+                StepResult::Error(executed, _) => executed,
+                StepResult::Halted | StepResult::TurnedPage(_) => {
+                    trace.push(format!("trace: program halted after {step} steps"));
+                    break;
+                }
+            };
+            let to = executed.to;
+            let to_color = self.at(to).unwrap_or(Color::Black);
+            trace.push(format!(
+                "trace: step {step}  ({} {} -> {} {}):",
+                state(from.x, from.y, from_ip), color_name(from_color),
+                state(to.x, to.y, runner.vm.instruction_pointer), color_name(to_color),
+            ));
+            if from_color == Color::White {
+                trace.push("trace: white cell(s) crossed - continuing with no command".to_string());
+                continue;
+            }
+            trace.push(match executed.command {
+                Command::Push => format!("action: push, value {}", executed.value),
+                command => format!("action: {}", action_name(command)),
+            });
+            let stack: Vec<_> = runner.vm.stack.iter().rev().map(|n| n.to_string()).collect();
+            let line = format!("trace: stack ({} values): {}", stack.len(), stack.join(" "));
+            trace.push(line.trim_end().to_string());
+        }
+        trace.iter().map(|line| format!("{line}\n")).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::load;
+
+    #[test]
+    fn test_npiet_trace() {
+        let code = load("test_imgs/test_slide.png", 1).unwrap();
+        let trace = code.npiet_trace(b"", 100);
+        let lines: Vec<_> = trace.lines().collect();
+        assert!(lines[0].starts_with("trace: step 0  (0,0/r,l "));
+        assert!(lines.contains(&"action: push, value 8"));
+        assert!(lines.contains(&"trace: stack (1 values): 8"));
+        assert_eq!(lines.last(), Some(&"trace: program halted after 3 steps"));
+        // Running out of steps just stops the trace.
+        let trace = code.npiet_trace(b"", 1);
+        assert!(!trace.contains("step 1"));
+        assert!(!trace.contains("halted"));
+    }
+}
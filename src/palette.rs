@@ -0,0 +1,65 @@
+//! The Piet color table and command matrix, as this crate interprets them.
+
+use crate::{Color, Command, Hue, Lightness};
+
+/// The 20 standard colors, and their RGB values.
+/// Any other pixel value is `Color::Other`.
+pub const COLORS: [(Color, [u8; 3]); 20] = [
+    (Color::Color(Hue::Red, Lightness::Light), [0xFF, 0xC0, 0xC0]),
+    (Color::Color(Hue::Yellow, Lightness::Light), [0xFF, 0xFF, 0xC0]),
+    (Color::Color(Hue::Green, Lightness::Light), [0xC0, 0xFF, 0xC0]),
+    (Color::Color(Hue::Cyan, Lightness::Light), [0xC0, 0xFF, 0xFF]),
+    (Color::Color(Hue::Blue, Lightness::Light), [0xC0, 0xC0, 0xFF]),
+    (Color::Color(Hue::Magenta, Lightness::Light), [0xFF, 0xC0, 0xFF]),
+    (Color::Color(Hue::Red, Lightness::Normal), [0xFF, 0x00, 0x00]),
+    (Color::Color(Hue::Yellow, Lightness::Normal), [0xFF, 0xFF, 0x00]),
+    (Color::Color(Hue::Green, Lightness::Normal), [0x00, 0xFF, 0x00]),
+    (Color::Color(Hue::Cyan, Lightness::Normal), [0x00, 0xFF, 0xFF]),
+    (Color::Color(Hue::Blue, Lightness::Normal), [0x00, 0x00, 0xFF]),
+    (Color::Color(Hue::Magenta, Lightness::Normal), [0xFF, 0x00, 0xFF]),
+    (Color::Color(Hue::Red, Lightness::Dark), [0xC0, 0x00, 0x00]),
+    (Color::Color(Hue::Yellow, Lightness::Dark), [0xC0, 0xC0, 0x00]),
+    (Color::Color(Hue::Green, Lightness::Dark), [0x00, 0xC0, 0x00]),
+    (Color::Color(Hue::Cyan, Lightness::Dark), [0x00, 0xC0, 0xC0]),
+    (Color::Color(Hue::Blue, Lightness::Dark), [0x00, 0x00, 0xC0]),
+    (Color::Color(Hue::Magenta, Lightness::Dark), [0xC0, 0x00, 0xC0]),
+    (Color::White, [0xFF, 0xFF, 0xFF]),
+    (Color::Black, [0x00, 0x00, 0x00]),
+];
+
+/// The command run by moving between two colors, indexed by how many steps
+/// around the hue cycle, then how many steps darker, the second color is.
+pub const COMMANDS: [[Command; 3]; 6] = [
+    [Command::Noop, Command::Push, Command::Pop],
+    [Command::Add, Command::Subtract, Command::Multiply],
+    [Command::Divide, Command::Mod, Command::Not],
+    [Command::Greater, Command::Pointer, Command::Switch],
+    [Command::Duplicate, Command::Roll, Command::InNum],
+    [Command::InChar, Command::OutNum, Command::OutChar],
+];
+
+/// The RGB value of `color`, or None for `Color::Other`.
+pub fn rgb(color: Color) -> Option<[u8; 3]> {
+    COLORS.into_iter()
+        .find(|&(c, _)| c == color)
+        .map(|(_, rgb)| rgb)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tables() {
+        let colors: Vec<_> = COLORS.iter().map(|&(color, _)| color).collect();
+        assert_eq!(colors, Color::ALL);
+        for (hue_step, row) in COMMANDS.iter().enumerate() {
+            for (light_step, &command) in row.iter().enumerate() {
+                assert_eq!(command as usize, hue_step * 3 + light_step);
+                assert_eq!(Color::Red.step_to(Color::Red.next_for_command(command)), command);
+            }
+        }
+        assert_eq!(rgb(Color::DarkBlue), Some([0x00, 0x00, 0xC0]));
+        assert_eq!(rgb(Color::Other), None);
+    }
+}
@@ -0,0 +1,41 @@
+use std::mem;
+
+/// Takes the terminal on stdin out of line-buffered mode, so reads see each
+/// key as it's pressed, and puts it back when dropped. Keys are still echoed,
+/// and Ctrl-C still interrupts. Does nothing if stdin isn't a terminal.
+pub(crate) struct RawTerminal {
+    original: Option<libc::termios>,
+}
+
+impl RawTerminal {
+    pub(crate) fn enable() -> Self {
+        let fd = libc::STDIN_FILENO;
+        // SAFETY: termios is plain data, and is only used once tcgetattr has filled it in.
+        let original = unsafe {
+            let mut termios: libc::termios = mem::zeroed();
+            if libc::isatty(fd) == 0 || libc::tcgetattr(fd, &mut termios) != 0 {
+                None
+            } else {
+                Some(termios)
+            }
+        };
+        if let Some(original) = original {
+            let mut raw = original;
+            raw.c_lflag &= !libc::ICANON;
+            raw.c_cc[libc::VMIN] = 1;
+            raw.c_cc[libc::VTIME] = 0;
+            // SAFETY: raw is a valid termios, copied from the terminal's own.
+            unsafe { libc::tcsetattr(fd, libc::TCSANOW, &raw); }
+        }
+        RawTerminal { original }
+    }
+}
+
+impl Drop for RawTerminal {
+    fn drop(&mut self) {
+        if let Some(original) = &self.original {
+            // SAFETY: original came from tcgetattr on the same fd.
+            unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, original); }
+        }
+    }
+}
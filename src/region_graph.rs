@@ -0,0 +1,104 @@
+use crate::{CodelCoord, Color, Direction, PietCode};
+use std::collections::BTreeSet;
+
+/// The blocks of a program, and which touch which. See `PietCode::region_graph`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RegionGraph {
+    /// Every block of same-colored codels, including white, black, and
+    /// non-Piet colors, in the reading order of their first codels.
+    pub regions: Vec<RegionNode>,
+    /// An index into `regions` for each codel, in reading order.
+    ids: Vec<usize>,
+    width: usize,
+}
+
+/// A block of a program, and the blocks touching it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RegionNode {
+    pub color: Color,
+    /// The block's codels, in reading order.
+    pub codels: Vec<CodelCoord>,
+    /// For each side, by `Direction as usize`, the blocks with a codel
+    /// just past one of this block's on that side.
+    neighbors: [BTreeSet<usize>; 4],
+}
+
+impl RegionGraph {
+    /// The index of the region containing `coord`.
+    pub fn region_at(&self, coord: CodelCoord) -> Option<usize> {
+        if coord.x >= self.width { return None; }
+        self.ids.get(coord.x + coord.y * self.width).copied()
+    }
+}
+
+impl RegionNode {
+    /// The regions touching this one on its `side`, by index into `RegionGraph::regions`.
+    pub fn neighbors(&self, side: Direction) -> &BTreeSet<usize> {
+        &self.neighbors[side as usize]
+    }
+
+    /// The regions touching this one on any side.
+    pub fn all_neighbors(&self) -> BTreeSet<usize> {
+        self.neighbors.iter().flatten().copied().collect()
+    }
+}
+
+impl PietCode {
+    /// Every block of the program, and the blocks touching each on each side,
+    /// eg for tools that analyse a program's layout rather than run it.
+    pub fn region_graph(&self) -> RegionGraph {
+        let regions = self.regions();
+        let mut nodes: Vec<_> = regions.blocks.iter()
+            .map(|block| {
+                let mut codels: Vec<_> = block.region.iter().copied().collect();
+                codels.sort_by_key(|coord| (coord.y, coord.x));
+                RegionNode { color: block.color, codels, neighbors: Default::default() }
+            })
+            .collect();
+        let sides = [Direction::Right, Direction::Down, Direction::Left, Direction::Up];
+        for (coord, _) in self.codels() {
+            let Some(id) = self.block_id(coord) else { continue; };
+            for side in sides {
+                let Some(neighbor) = coord.checked_add(side.to_delta())
+                    .and_then(|next| self.block_id(next))
+                else { continue; };
+                if neighbor != id {
+                    nodes[id].neighbors[side as usize].insert(neighbor);
+                }
+            }
+        }
+        RegionGraph { regions: nodes, ids: regions.ids.clone(), width: self.width }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_region_graph() {
+        // R R B
+        // Y W B
+        let code = PietCode::new(3, 2, vec![
+            Color::Red, Color::Red, Color::Blue,
+            Color::Yellow, Color::White, Color::Blue,
+        ]);
+        let graph = code.region_graph();
+        assert_eq!(graph.regions.len(), 4);
+        let [red, blue, yellow, white] = [(0, 0), (2, 0), (0, 1), (1, 1)]
+            .map(|(x, y)| graph.region_at(CodelCoord::new(x, y)).unwrap());
+        assert_eq!([red, blue, yellow, white], [0, 1, 2, 3]);
+        assert_eq!(graph.region_at(CodelCoord::new(3, 0)), None);
+        assert_eq!(graph.region_at(CodelCoord::new(0, 2)), None);
+
+        let node = &graph.regions[red];
+        assert_eq!(node.color, Color::Red);
+        assert_eq!(node.codels, [CodelCoord::new(0, 0), CodelCoord::new(1, 0)]);
+        assert_eq!(node.neighbors(Direction::Right), &BTreeSet::from([blue]));
+        assert_eq!(node.neighbors(Direction::Down), &BTreeSet::from([yellow, white]));
+        assert!(node.neighbors(Direction::Left).is_empty());
+        assert!(node.neighbors(Direction::Up).is_empty());
+        assert_eq!(graph.regions[white].all_neighbors(), BTreeSet::from([red, blue, yellow]));
+        assert_eq!(graph.regions[blue].neighbors(Direction::Left), &BTreeSet::from([red, white]));
+    }
+}
@@ -0,0 +1,163 @@
+use crate::transpile::{Graph, Move};
+use crate::{Color, Command, PietCode};
+use num_traits::FromPrimitive;
+use std::collections::{BTreeMap, HashSet};
+
+/// Static measurements of a program, without running it, eg for studying a
+/// corpus of programs. See `PietCode::stats` and `asm::stats`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ProgramStats {
+    /// How many times each command appears, indexed by `Command as usize`.
+    commands: [u64; 18],
+    /// How many colored blocks there are of each size, in codels.
+    pub region_sizes: BTreeMap<usize, u64>,
+    /// How many places execution can go more than one way: `Pointer` and
+    /// `Switch` commands in an image, or conditional jumps in PietASM.
+    pub branches: u64,
+    /// How many ways back execution has to somewhere it's already been.
+    pub loops: u64,
+}
+
+impl ProgramStats {
+    pub(crate) fn add(&mut self, command: Command) {
+        self.commands[command as usize] += 1;
+    }
+
+    /// How many times `command` appears.
+    pub fn count(&self, command: Command) -> u64 {
+        self.commands[command as usize]
+    }
+
+    /// Each command that appears, and how many times, in the order of `Command`.
+    pub fn commands(&self) -> impl Iterator<Item = (Command, u64)> + '_ {
+        self.commands.iter().enumerate()
+            .filter(|(_, &count)| count > 0)
+            .filter_map(|(i, &count)| Some((FromPrimitive::from_usize(i)?, count)))
+    }
+
+    /// Serialize the stats as a JSON object, with `commands` and
+    /// `region_sizes` as objects of counts keyed by command name and size.
+    pub fn to_json(&self) -> String {
+        let commands: Vec<_> = self.commands()
+            .map(|(command, count)| format!(r#""{command:?}":{count}"#))
+            .collect();
+        let region_sizes: Vec<_> = self.region_sizes.iter()
+            .map(|(size, count)| format!(r#""{size}":{count}"#))
+            .collect();
+        format!(
+            r#"{{"commands":{{{}}},"region_sizes":{{{}}},"branches":{},"loops":{}}}"#,
+            commands.join(","), region_sizes.join(","), self.branches, self.loops,
+        )
+    }
+}
+
+/// The states a move can leave execution in, each a node of the graph and
+/// the index of the instruction pointer there, as `node * 8 + ip`.
+/// A `Pointer` or `Switch` can leave the instruction pointer any way it can turn.
+fn arrivals(step: &Move) -> Vec<usize> {
+    let &Move::Go(command, _, ip, node) = step else { return Vec::new(); };
+    let ips = match command {
+        Command::Pointer => vec![ip, (ip + 2) % 8, (ip + 4) % 8, (ip + 6) % 8],
+        Command::Switch => vec![ip, ip ^ 1],
+        _ => vec![ip],
+    };
+    ips.into_iter().map(|ip| node * 8 + ip).collect()
+}
+
+impl PietCode {
+    /// Measure the program. Commands and branches are counted once for each
+    /// move between two blocks (or white codels) execution can make from the
+    /// start, and loops for each way back to somewhere it's been, taking each
+    /// `Pointer` and `Switch` to turn every way it can.
+    /// Every colored block counts towards `region_sizes`, reachable or not.
+    pub fn stats(&self) -> ProgramStats {
+        let mut stats = ProgramStats::default();
+        for block in &self.regions().blocks {
+            if let Color::Color(..) = block.color {
+                *stats.region_sizes.entry(block.region.len()).or_default() += 1;
+            }
+        }
+
+        // Walk the states execution can be in, each a node of the graph and the
+        // instruction pointer it arrived with, counting the moves out of each,
+        // and the moves back to a state still being explored in a depth-first
+        // search from the start.
+        let graph = Graph::new(self);
+        let mut on_path = vec![false; graph.moves.len() * 8];
+        let mut seen = vec![false; graph.moves.len() * 8];
+        let mut moves = HashSet::new();
+        let mut path = vec![(None, arrivals(&graph.start).into_iter())];
+        while let Some((state, next)) = path.last_mut() {
+            match next.next() {
+                Some(next) if on_path[next] => { stats.loops += 1; }
+                Some(next) if !seen[next] => {
+                    on_path[next] = true;
+                    seen[next] = true;
+                    let step = &graph.moves[next / 8][next % 8];
+                    // The same move between two blocks, made with a different
+                    // instruction pointer, is still the one command.
+                    match *step {
+                        Move::Go(command, .., to) if moves.insert((next / 8, to)) => {
+                            stats.add(command);
+                            if matches!(command, Command::Pointer | Command::Switch) {
+                                stats.branches += 1;
+                            }
+                        }
+                        _ => (),
+                    }
+                    path.push((Some(next), arrivals(step).into_iter()));
+                }
+                Some(_) => (),
+                None => {
+                    if let Some(state) = *state {
+                        on_path[state] = false;
+                    }
+                    path.pop();
+                }
+            }
+        }
+        stats
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{asm, Command};
+    use std::fs;
+
+
+    #[test]
+    fn test_stats() {
+        let code = asm::load_str("PUSH 3\nDUP\nMUL\nOUTNUM\n").unwrap();
+        let stats = code.stats();
+        assert_eq!(stats.count(Command::Multiply), 1);
+        assert_eq!(stats.count(Command::OutNum), 1);
+        assert_eq!((stats.branches, stats.loops), (0, 0));
+        assert_eq!(stats.region_sizes.values().sum::<u64>(), code.regions().blocks.iter()
+            .filter(|block| matches!(block.color, crate::Color::Color(..)))
+            .count() as u64);
+
+        let code = asm::load_str("PUSH 5\n:L\nDUP\nOUTNUM\nSUB 1\nDUP\nJUMPIF L\n").unwrap();
+        let stats = code.stats();
+        // Taking each `Pointer` and `Switch` every way finds more than the one
+        // way back the program really takes.
+        assert_eq!((stats.branches, stats.loops), (2, 3));
+        assert_eq!(stats.count(Command::OutNum), 1);
+        let json = stats.to_json();
+        assert!(json.starts_with(r#"{"commands":{"#));
+        assert!(json.contains(r#""OutNum":1"#));
+        assert!(json.ends_with(&format!(r#""branches":{},"loops":{}}}"#, stats.branches, stats.loops)));
+    }
+
+    #[test]
+    fn test_asm_stats() {
+        let filename = std::env::temp_dir().join("piet_tools_stats_asm.pasm");
+        fs::write(&filename, "PUSH 5\n:L\nDUP\nOUTNUM\nSUB 1\nDUP\nJUMPIF L\nJUMP END\n:END\n").unwrap();
+        let ir = asm::load_ir(filename.to_str().unwrap(), &Default::default()).unwrap();
+        let stats = asm::stats(&ir);
+        assert_eq!(stats.count(Command::Duplicate), 2);
+        assert_eq!(stats.count(Command::OutNum), 1);
+        assert_eq!((stats.branches, stats.loops), (1, 1));
+        assert!(stats.region_sizes.is_empty());
+    }
+}
@@ -0,0 +1,677 @@
+use crate::{CodelChoice, CodelCoord, Color, Command, Direction, ExecutionError, Value};
+use log::{info, log_enabled, Level};
+use num_bigint::BigInt;
+use num_traits::{FromPrimitive, ToPrimitive};
+use std::fmt;
+
+/// Receives events as a program runs, eg to log or visualize it.
+/// Every method does nothing by default.
+pub trait Tracer {
+    /// Whether there's anything to report to this tracer, eg whether its
+    /// logging is turned on. A runner checks as the tracer's attached, and
+    /// reports nothing at all to one that isn't, so tracing costs nothing.
+    fn enabled(&self) -> bool {
+        true
+    }
+
+    /// About to take step number `step`, from `pos`, with the direction
+    /// pointer and codel chooser pointing so, and with `stack`.
+    fn on_step(
+        &mut self,
+        _step: u64,
+        _pos: CodelCoord,
+        _direction: Direction,
+        _chooser: CodelChoice,
+        _stack: &[Value],
+    ) {}
+
+    /// Moved from a `from` block into a `to` block at `pos`, running `command`.
+    /// Sliding across white runs no command, so isn't reported.
+    fn on_command(
+        &mut self,
+        _from: Color,
+        _to: Color,
+        _pos: CodelCoord,
+        _command: Command,
+        _value: &Value,
+    ) {}
+
+    /// `command` failed, so was skipped (or stopped the run, if strict).
+    fn on_error(&mut self, _command: Command, _error: &ExecutionError) {}
+
+    /// The command just reported to `on_command` wrote `text`.
+    fn on_output(&mut self, _text: &str) {}
+
+    /// A run carried on past a failed command, skipping it.
+    /// Reported after `on_error`, once the runner's policy has decided to skip it.
+    fn on_warning(&mut self, _warning: &RuntimeWarning) {}
+}
+
+/// A failed command a run skipped, eg to count or list a program's mistakes.
+#[derive(Debug)]
+pub struct RuntimeWarning<'a> {
+    /// The step that ran the command.
+    pub step: u64,
+    /// The codel the step moved out of.
+    pub pos: CodelCoord,
+    pub command: Command,
+    pub error: &'a ExecutionError,
+}
+
+impl fmt::Display for RuntimeWarning<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "step {} at {}: skipping {:?}: {}", self.step, self.pos, self.command, self.error)
+    }
+}
+
+impl<T: Tracer + ?Sized> Tracer for &mut T {
+    fn enabled(&self) -> bool {
+        (**self).enabled()
+    }
+
+    fn on_step(
+        &mut self,
+        step: u64,
+        pos: CodelCoord,
+        direction: Direction,
+        chooser: CodelChoice,
+        stack: &[Value],
+    ) {
+        (**self).on_step(step, pos, direction, chooser, stack)
+    }
+
+    fn on_command(
+        &mut self,
+        from: Color,
+        to: Color,
+        pos: CodelCoord,
+        command: Command,
+        value: &Value,
+    ) {
+        (**self).on_command(from, to, pos, command, value)
+    }
+
+    fn on_error(&mut self, command: Command, error: &ExecutionError) {
+        (**self).on_error(command, error)
+    }
+
+    fn on_output(&mut self, text: &str) {
+        (**self).on_output(text)
+    }
+
+    fn on_warning(&mut self, warning: &RuntimeWarning) {
+        (**self).on_warning(warning)
+    }
+}
+
+/// Reports every event to both tracers, eg to log a run while recording it.
+impl<A: Tracer, B: Tracer> Tracer for (A, B) {
+    fn enabled(&self) -> bool {
+        self.0.enabled() || self.1.enabled()
+    }
+
+    fn on_step(
+        &mut self,
+        step: u64,
+        pos: CodelCoord,
+        direction: Direction,
+        chooser: CodelChoice,
+        stack: &[Value],
+    ) {
+        self.0.on_step(step, pos, direction, chooser, stack);
+        self.1.on_step(step, pos, direction, chooser, stack);
+    }
+
+    fn on_command(
+        &mut self,
+        from: Color,
+        to: Color,
+        pos: CodelCoord,
+        command: Command,
+        value: &Value,
+    ) {
+        self.0.on_command(from, to, pos, command, value);
+        self.1.on_command(from, to, pos, command, value);
+    }
+
+    fn on_error(&mut self, command: Command, error: &ExecutionError) {
+        self.0.on_error(command, error);
+        self.1.on_error(command, error);
+    }
+
+    fn on_output(&mut self, text: &str) {
+        self.0.on_output(text);
+        self.1.on_output(text);
+    }
+
+    fn on_warning(&mut self, warning: &RuntimeWarning) {
+        self.0.on_warning(warning);
+        self.1.on_warning(warning);
+    }
+}
+
+/// Ignores every event.
+pub struct NoTracer;
+
+impl Tracer for NoTracer {
+    fn enabled(&self) -> bool {
+        false
+    }
+}
+
+/// Records every step of a run, eg to export it for debugging.
+#[derive(Debug, Default)]
+pub struct Trace {
+    pub steps: Vec<TraceStep>,
+}
+
+/// The state of a run just before a step, and the command the step ran.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TraceStep {
+    pub step: u64,
+    pub pos: CodelCoord,
+    pub direction: Direction,
+    pub chooser: CodelChoice,
+    pub stack: Vec<Value>,
+    /// None if the step ran no command, eg because it slid across white,
+    /// or the program halted.
+    pub command: Option<TracedCommand>,
+}
+
+/// A command run during a trace, and the move that ran it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TracedCommand {
+    pub command: Command,
+    pub from: Color,
+    pub to: Color,
+    /// The codel moved into.
+    pub pos: CodelCoord,
+    /// The size of the block moved out of.
+    pub value: Value,
+}
+
+impl Trace {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Serialize the trace as JSON: an object with a `steps` array.
+    /// Each step has its `step` number, position (`x`, `y`), `direction`,
+    /// `chooser`, `stack` from the bottom up, and `command`: null, or an
+    /// object with the command's `name`, the `from` and `to` colors, the
+    /// position moved to, and the block `value`. Numbers that can grow
+    /// without bound (stack values and the block value) are strings.
+    /// Read it back with `from_json`.
+    pub fn to_json(&self) -> String {
+        let mut json = String::from(r#"{"steps":["#);
+        for (i, step) in self.steps.iter().enumerate() {
+            if i > 0 {
+                json.push(',');
+            }
+            let stack: Vec<_> = step.stack.iter().map(|value| format!(r#""{value}""#)).collect();
+            json.push_str(&format!(
+                r#"{{"step":{},"x":{},"y":{},"direction":"{:?}","chooser":"{:?}","stack":[{}],"command":"#,
+                step.step, step.pos.x, step.pos.y, step.direction, step.chooser, stack.join(","),
+            ));
+            match &step.command {
+                None => json.push_str("null"),
+                Some(TracedCommand { command, from, to, pos, value }) => {
+                    json.push_str(&format!(
+                        r#"{{"name":"{command:?}","from":"{from:?}","to":"{to:?}","x":{},"y":{},"value":"{value}"}}"#,
+                        pos.x, pos.y,
+                    ));
+                }
+            }
+            json.push('}');
+        }
+        json.push_str("]}");
+        json
+    }
+
+    /// Read back a trace serialized with `to_json`, eg to `replay` it.
+    pub fn from_json(json: &str) -> Result<Trace, String> {
+        let err = || "expected a trace, as written by --trace-json".to_string();
+        let mut reader = JsonReader { rest: json };
+        let trace = reader.value().filter(|_| reader.rest.trim().is_empty()).ok_or_else(err)?;
+        let steps = match trace.field("steps") {
+            Some(Json::Array(steps)) => steps,
+            _ => { return Err(err()); }
+        };
+        let steps = steps.iter().map(trace_step).collect::<Option<_>>().ok_or_else(err)?;
+        Ok(Trace { steps })
+    }
+
+    /// The position of each step of a trace serialized with `to_json`,
+    /// eg to profile where a run spent its steps.
+    pub fn positions_from_json(json: &str) -> Result<Vec<CodelCoord>, String> {
+        let trace = Self::from_json(json)?;
+        Ok(trace.steps.iter().map(|step| step.pos).collect())
+    }
+}
+
+/// Just enough JSON to read back what `Trace::to_json` writes.
+enum Json {
+    Null,
+    Number(usize),
+    String(String),
+    Array(Vec<Json>),
+    Object(Vec<(String, Json)>),
+}
+
+impl Json {
+    fn field(&self, name: &str) -> Option<&Json> {
+        let Json::Object(fields) = self else { return None; };
+        fields.iter().find(|(field, _)| field == name).map(|(_, value)| value)
+    }
+
+    fn number(&self, name: &str) -> Option<usize> {
+        match self.field(name)? {
+            Json::Number(n) => Some(*n),
+            _ => None,
+        }
+    }
+
+    fn string(&self, name: &str) -> Option<&str> {
+        match self.field(name)? {
+            Json::String(s) => Some(s),
+            _ => None,
+        }
+    }
+}
+
+struct JsonReader<'a> {
+    rest: &'a str,
+}
+
+impl JsonReader<'_> {
+    /// Consume `token`, after any whitespace, if it's next.
+    fn eat(&mut self, token: &str) -> bool {
+        self.rest = self.rest.trim_start();
+        let Some(rest) = self.rest.strip_prefix(token) else { return false; };
+        self.rest = rest;
+        true
+    }
+
+    fn value(&mut self) -> Option<Json> {
+        if self.eat("null") {
+            Some(Json::Null)
+        } else if self.eat("\"") {
+            let mut string = String::new();
+            let mut chars = self.rest.char_indices();
+            loop {
+                match chars.next()? {
+                    (idx, '"') => {
+                        self.rest = &self.rest[idx + 1..];
+                        return Some(Json::String(string));
+                    }
+                    (_, '\\') => string.push(chars.next()?.1),
+                    (_, chr) => string.push(chr),
+                }
+            }
+        } else if self.eat("[") {
+            let mut items = Vec::new();
+            while !self.eat("]") {
+                if !items.is_empty() && !self.eat(",") {
+                    return None;
+                }
+                items.push(self.value()?);
+            }
+            Some(Json::Array(items))
+        } else if self.eat("{") {
+            let mut fields = Vec::new();
+            while !self.eat("}") {
+                if !fields.is_empty() && !self.eat(",") {
+                    return None;
+                }
+                let Json::String(name) = self.value()? else { return None; };
+                if !self.eat(":") {
+                    return None;
+                }
+                fields.push((name, self.value()?));
+            }
+            Some(Json::Object(fields))
+        } else {
+            let len = self.rest.find(|c: char| !c.is_ascii_digit()).unwrap_or(self.rest.len());
+            let number = self.rest[..len].parse().ok()?;
+            self.rest = &self.rest[len..];
+            Some(Json::Number(number))
+        }
+    }
+}
+
+fn trace_step(json: &Json) -> Option<TraceStep> {
+    let pos = |json: &Json| Some(CodelCoord::new(json.number("x")?, json.number("y")?));
+    let direction = match json.string("direction")? {
+        "Right" => Direction::Right,
+        "Down" => Direction::Down,
+        "Left" => Direction::Left,
+        "Up" => Direction::Up,
+        _ => { return None; }
+    };
+    let chooser = match json.string("chooser")? {
+        "Left" => CodelChoice::Left,
+        "Right" => CodelChoice::Right,
+        _ => { return None; }
+    };
+    let Some(Json::Array(stack)) = json.field("stack") else { return None; };
+    let stack = stack.iter()
+        .map(|value| match value {
+            Json::String(value) => value.parse::<BigInt>().ok().map(Value::from),
+            _ => None,
+        })
+        .collect::<Option<_>>()?;
+    let command = match json.field("command")? {
+        Json::Null => None,
+        command => {
+            let name = |name: &str| command.string(name);
+            let color = |name| Color::ALL.into_iter().find(|color| Some(format!("{color:?}").as_str()) == name);
+            Some(TracedCommand {
+                command: (0..).map_while(Command::from_usize).find(|cmd| Some(format!("{cmd:?}").as_str()) == name("name"))?,
+                from: color(name("from"))?,
+                to: color(name("to"))?,
+                pos: pos(command)?,
+                value: Value::from(name("value")?.parse::<BigInt>().ok()?),
+            })
+        }
+    };
+    Some(TraceStep { step: json.number("step")? as u64, pos: pos(json)?, direction, chooser, stack, command })
+}
+
+/// Where a replayed run first differed from its trace. See `PietRunner::replay`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Divergence {
+    pub expected: TraceStep,
+    pub actual: TraceStep,
+}
+
+fn describe_command(command: &Option<TracedCommand>) -> String {
+    match command {
+        None => "no command".to_string(),
+        Some(TracedCommand { command, from, to, pos, value }) => {
+            format!("{command:?} ({from:?} ({value}) -> {to:?}) into {pos}")
+        }
+    }
+}
+
+impl fmt::Display for Divergence {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let Divergence { expected, actual } = self;
+        write!(f, "step {}: ", expected.step)?;
+        if expected.step != actual.step {
+            write!(f, "expected step {}, but was at step {}", expected.step, actual.step)
+        } else if expected.pos != actual.pos {
+            write!(f, "expected to be at {}, but was at {}", expected.pos, actual.pos)
+        } else if (expected.direction, expected.chooser) != (actual.direction, actual.chooser) {
+            write!(
+                f,
+                "expected to point {:?} (CC {:?}), but pointed {:?} (CC {:?})",
+                expected.direction, expected.chooser, actual.direction, actual.chooser,
+            )
+        } else if expected.stack != actual.stack {
+            write!(f, "expected stack {:?}, but was {:?}", expected.stack, actual.stack)
+        } else {
+            write!(
+                f,
+                "expected {}, but ran {}",
+                describe_command(&expected.command),
+                describe_command(&actual.command),
+            )
+        }
+    }
+}
+
+impl Tracer for Trace {
+    fn on_step(
+        &mut self,
+        step: u64,
+        pos: CodelCoord,
+        direction: Direction,
+        chooser: CodelChoice,
+        stack: &[Value],
+    ) {
+        self.steps.push(TraceStep { step, pos, direction, chooser, stack: stack.to_vec(), command: None });
+    }
+
+    fn on_command(
+        &mut self,
+        from: Color,
+        to: Color,
+        pos: CodelCoord,
+        command: Command,
+        value: &Value,
+    ) {
+        if let Some(step) = self.steps.last_mut() {
+            step.command = Some(TracedCommand { command, from, to, pos, value: value.clone() });
+        }
+    }
+}
+
+/// Logs every event at the `info` level. Each step logs how the stack
+/// changed since the last, rather than the whole stack, which is unreadable
+/// and slow to print once it's deep, unless asked for `full_stack`.
+#[derive(Debug, Default)]
+pub struct LogTracer {
+    full_stack: bool,
+    /// The stack as of the last step logged, to log the next's changes from.
+    last_stack: Vec<Value>,
+    /// The next step, and how many values at the top of `last_stack` it
+    /// might change, so only those need comparing. Nothing's known of any
+    /// other step, eg one stepped back to.
+    next: Option<(u64, usize)>,
+}
+
+impl LogTracer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Log the whole stack at each step.
+    pub fn full_stack(mut self) -> Self {
+        self.full_stack = true;
+        self
+    }
+}
+
+/// How many values at the top of the stack `command` might change, run on `stack`.
+fn touched_by(command: Command, stack: &[Value]) -> usize {
+    match command {
+        Command::Noop | Command::Push | Command::Duplicate | Command::InNum | Command::InChar => 0,
+        Command::Pop | Command::Not | Command::Pointer | Command::Switch | Command::OutNum | Command::OutChar => 1,
+        Command::Add | Command::Subtract | Command::Multiply | Command::Divide | Command::Mod | Command::Greater => 2,
+        // Rolls to the depth second from the top; a depth that isn't one fails, changing nothing.
+        Command::Roll => {
+            let depth = stack.len().checked_sub(2).and_then(|i| stack[i].to_usize()).unwrap_or(0);
+            depth.saturating_add(2)
+        }
+    }
+}
+
+/// How many values at the bottom of the stack are the same in `last` and
+/// `stack`, given only the top `touched` of `last` might have changed.
+fn unchanged_depth(last: &[Value], stack: &[Value], touched: usize) -> usize {
+    let start = last.len().min(stack.len()).saturating_sub(touched);
+    start + last[start..].iter().zip(&stack[start..]).take_while(|(a, b)| a == b).count()
+}
+
+/// How the stack changed from `last` to `stack`: the values popped off the
+/// top, then the values pushed, and the depth it's left at.
+fn describe_stack_change(last: &[Value], stack: &[Value], kept: usize) -> String {
+    let (popped, pushed) = (&last[kept..], &stack[kept..]);
+    match (popped.is_empty(), pushed.is_empty()) {
+        (true, true) => format!("stack unchanged (depth {})", stack.len()),
+        (false, true) => format!("popped {popped:?} (depth {})", stack.len()),
+        (true, false) => format!("pushed {pushed:?} (depth {})", stack.len()),
+        (false, false) => format!("popped {popped:?}, pushed {pushed:?} (depth {})", stack.len()),
+    }
+}
+
+impl Tracer for LogTracer {
+    fn enabled(&self) -> bool {
+        log_enabled!(Level::Info)
+    }
+
+    fn on_step(
+        &mut self,
+        step: u64,
+        pos: CodelCoord,
+        _direction: Direction,
+        _chooser: CodelChoice,
+        stack: &[Value],
+    ) {
+        if !log_enabled!(Level::Info) {
+            return;
+        }
+        if self.full_stack {
+            info!("step {step} at {pos}: {stack:?}");
+            return;
+        }
+        let touched = match self.next {
+            Some((next, touched)) if next == step => touched,
+            _ => usize::MAX,
+        };
+        let kept = unchanged_depth(&self.last_stack, stack, touched);
+        info!("step {step} at {pos}: {}", describe_stack_change(&self.last_stack, stack, kept));
+        self.last_stack.truncate(kept);
+        self.last_stack.extend_from_slice(&stack[kept..]);
+        // Sliding across white changes nothing, unless `on_command` says there's a command.
+        self.next = Some((step + 1, 0));
+    }
+
+    fn on_command(
+        &mut self,
+        from: Color,
+        to: Color,
+        pos: CodelCoord,
+        command: Command,
+        value: &Value,
+    ) {
+        if let Some((_, touched)) = &mut self.next {
+            *touched = touched_by(command, &self.last_stack);
+        }
+        info!("({from:?} ({value}) -> {to:?}) [{pos}] = {command:?}");
+    }
+
+    fn on_warning(&mut self, warning: &RuntimeWarning) {
+        info!("{warning}");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::load;
+
+    #[test]
+    fn test_disabled_tracer() {
+        struct Counter {
+            enabled: bool,
+            steps: u64,
+        }
+
+        impl Tracer for Counter {
+            fn enabled(&self) -> bool {
+                self.enabled
+            }
+
+            fn on_step(
+                &mut self,
+                _step: u64,
+                _pos: CodelCoord,
+                _direction: Direction,
+                _chooser: CodelChoice,
+                _stack: &[Value],
+            ) {
+                self.steps += 1;
+            }
+        }
+
+        let code = load("test_imgs/test_slide.png", 1).unwrap();
+        let mut off = Counter { enabled: false, steps: 0 };
+        code.execute().tracer(&mut off).run();
+        assert_eq!(off.steps, 0);
+        // Either tracer of a pair being enabled reports to both.
+        let mut on = Counter { enabled: true, steps: 0 };
+        let mut runner = code.execute().tracer((&mut off, &mut on));
+        runner.run();
+        let steps = runner.steps();
+        drop(runner);
+        assert_eq!((off.steps, on.steps), (steps, steps));
+        assert!(!(NoTracer, NoTracer).enabled());
+    }
+
+    /// A disabled `LogTracer` shouldn't slow a run down. Timing-dependent, so
+    /// only run when asked: `cargo test --release -- --ignored`.
+    #[test]
+    #[ignore]
+    fn test_disabled_tracer_cost() {
+        use crate::asm;
+        use std::time::{Duration, Instant};
+
+        // Counts down from 200000.
+        let code = asm::load_str("PUSH 200000\n:loop\nPUSH 1\nSUB\nDUP\nJUMPIF loop\n").unwrap();
+        // The fastest of a few runs, to smooth over noise.
+        let fastest = |traced: bool| {
+            (0..5).map(|_| {
+                let start = Instant::now();
+                match traced {
+                    true => code.execute().tracer(LogTracer::new()).run(),
+                    false => code.execute().run(),
+                };
+                start.elapsed()
+            }).min().unwrap_or(Duration::ZERO)
+        };
+        assert!(!LogTracer::new().enabled());
+        let (untraced, traced) = (fastest(false), fastest(true));
+        assert!(traced.as_secs_f64() < untraced.as_secs_f64() * 1.2, "untraced {untraced:?}, traced {traced:?}");
+    }
+
+    #[test]
+    fn test_describe_stack_change() {
+        let change = |last: &[i32], stack: &[i32]| {
+            let [last, stack] = [last, stack].map(|values| values.iter().map(|&v| Value::from(v)).collect::<Vec<_>>());
+            describe_stack_change(&last, &stack, unchanged_depth(&last, &stack, usize::MAX))
+        };
+        assert_eq!(change(&[1, 2], &[1, 2]), "stack unchanged (depth 2)");
+        assert_eq!(change(&[1], &[1, 5]), "pushed [5] (depth 2)");
+        assert_eq!(change(&[1, 2, 3], &[1]), "popped [2, 3] (depth 1)");
+        assert_eq!(change(&[1, 2, 3], &[1, 5]), "popped [2, 3], pushed [5] (depth 2)");
+        // A roll changes everything above its deepest value.
+        assert_eq!(
+            change(&[9, 1, 2, 3], &[9, 3, 1, 2]),
+            "popped [1, 2, 3], pushed [3, 1, 2] (depth 4)",
+        );
+
+        // Only the values a command might have changed are compared.
+        let [last, stack] = [[1, 2, 3], [7, 2, 5]].map(|values| values.map(Value::from));
+        assert_eq!(unchanged_depth(&last, &stack, 1), 2);
+        assert_eq!(unchanged_depth(&last, &stack, usize::MAX), 0);
+        let roll = [9, 1, 2, 3, 3, 1].map(Value::from);
+        assert_eq!(touched_by(Command::Roll, &roll), 5);
+        assert_eq!(touched_by(Command::Add, &roll), 2);
+        assert_eq!(touched_by(Command::Roll, &roll[..1]), 2);
+    }
+
+    #[test]
+    fn test_to_json() {
+        let code = load("test_imgs/test_slide.png", 1).unwrap();
+        let mut trace = Trace::new();
+        code.execute().tracer(&mut trace).run();
+        let json = trace.to_json();
+        assert!(json.starts_with(r#"{"steps":[{"step":1,"x":0,"y":0,"direction":"Right","chooser":"Left","stack":[],"command":"#));
+        assert!(json.ends_with(r#""stack":["8"],"command":null}]}"#));
+        let pushes = trace.steps.iter()
+            .filter(|step| step.command.as_ref().is_some_and(|c| c.command == Command::Push))
+            .count();
+        assert!(pushes > 0);
+        assert_eq!(json.matches(r#""name":"Push""#).count(), pushes);
+
+        let positions: Vec<_> = trace.steps.iter().map(|step| step.pos).collect();
+        assert_eq!(Trace::positions_from_json(&json).unwrap(), positions);
+        assert_eq!(Trace::positions_from_json(r#"{"steps":[]}"#).unwrap(), []);
+        assert!(Trace::positions_from_json("[]").is_err());
+        assert!(Trace::positions_from_json(r#"{"steps":[{"step":1,"x":"#).is_err());
+        // Every step reads back as it was.
+        assert_eq!(Trace::from_json(&json).unwrap().steps, trace.steps);
+        assert!(Trace::from_json(&json.replace(r#""chooser":"Left""#, r#""chooser":"Up""#)).is_err());
+    }
+}
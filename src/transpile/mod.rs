@@ -0,0 +1,720 @@
+use crate::{CodelCoord, Color, Command, ExecutionError, InstructionPointer, PietCode, PietVM};
+use num_bigint::BigInt;
+use num_traits::Zero;
+use std::collections::HashMap;
+
+mod pasm;
+mod wasm;
+pub use pasm::to_pasm;
+pub use wasm::to_wasm;
+
+/// One way execution can go, from the start or from a node of the graph.
+pub(crate) enum Move {
+    /// There's no way out, so the program halts.
+    Halt,
+    /// The way out is into a codel that isn't a Piet color: a fatal error.
+    Stuck(ExecutionError),
+    /// Run a command, with the size of the block left, then arrive at a node
+    /// with the instruction pointer at this index (before the command changes it).
+    Go(Command, BigInt, usize, usize),
+}
+
+/// Every way the program can run: each block execution can reach, and each
+/// white codel it can slide out from, with the move out of it for each
+/// instruction pointer it could arrive with.
+pub(crate) struct Graph {
+    pub(crate) start: Move,
+    pub(crate) moves: Vec<[Move; 8]>,
+}
+
+/// Where a node of the graph is: a colored block, or a white codel.
+/// The block is named by any codel in it.
+#[derive(Clone, Copy)]
+enum Node {
+    Block(CodelCoord),
+    White(CodelCoord),
+}
+
+struct GraphBuilder<'a> {
+    code: &'a PietCode,
+    nodes: Vec<Node>,
+    /// The node for each block, by block id.
+    blocks: HashMap<usize, usize>,
+    /// The node for each white codel.
+    whites: HashMap<CodelCoord, usize>,
+}
+
+impl GraphBuilder<'_> {
+    /// The move to `coord`, a Piet color or white, numbering its node if it's new.
+    fn go(&mut self, command: Command, value: BigInt, ip: InstructionPointer, coord: CodelCoord) -> Move {
+        let (node, index) = match self.code.at(coord) {
+            Some(Color::White) => (Node::White(coord), self.whites.get(&coord).copied()),
+            _ => {
+                let id = self.code.block_id(coord).unwrap_or_default();
+                (Node::Block(coord), self.blocks.get(&id).copied())
+            }
+        };
+        let index = index.unwrap_or_else(|| {
+            let index = self.nodes.len();
+            self.nodes.push(node);
+            match node {
+                Node::White(coord) => { self.whites.insert(coord, index); }
+                Node::Block(coord) => {
+                    self.blocks.insert(self.code.block_id(coord).unwrap_or_default(), index);
+                }
+            }
+            index
+        });
+        Move::Go(command, value, ip.index(), index)
+    }
+
+    /// Where leaving `node` with `ip` leads, as a step of `PietVM::advance` would have it.
+    fn leave(&mut self, node: Node, ip: InstructionPointer) -> Move {
+        match node {
+            Node::White(from) => {
+                let mut vm = PietVM { pos: from, instruction_pointer: ip, ..PietVM::new() };
+                match vm.walk_white(self.code) {
+                    None => Move::Halt,
+                    Some((to, Color::Other)) => Move::Stuck(ExecutionError::InvalidColor(to)),
+                    Some((to, _)) => self.go(Command::Noop, BigInt::zero(), vm.instruction_pointer, to),
+                }
+            }
+            Node::Block(from) => {
+                let Some((region, Some((ip, to)))) = self.code.move_from(from, ip) else {
+                    return Move::Halt;
+                };
+                let (color, value) = (region.color, region.value().to_bigint());
+                match self.code.at(to) {
+                    Some(Color::Other) | None => Move::Stuck(ExecutionError::InvalidColor(to)),
+                    Some(next) => self.go(color.step_to(next), value, ip, to),
+                }
+            }
+        }
+    }
+}
+
+impl Graph {
+    /// Find every node execution can reach from the top-left codel.
+    pub(crate) fn new(code: &PietCode) -> Self {
+        let mut builder = GraphBuilder {
+            code,
+            nodes: Vec::new(),
+            blocks: HashMap::new(),
+            whites: HashMap::new(),
+        };
+        let start = CodelCoord::default();
+        let ip = InstructionPointer::default();
+        let start = match code.at(start) {
+            None | Some(Color::Black) => Move::Halt,
+            Some(Color::Other) => Move::Stuck(ExecutionError::InvalidColor(start)),
+            Some(_) => builder.go(Command::Noop, BigInt::zero(), ip, start),
+        };
+        let mut moves = Vec::new();
+        // Nodes are numbered as they're found, so each is handled after those before it.
+        while let Some(&node) = builder.nodes.get(moves.len()) {
+            let row = InstructionPointer::ALL.map(|ip| builder.leave(node, ip));
+            moves.push(row);
+        }
+        Graph { start, moves }
+    }
+}
+
+impl Move {
+    fn to_rust(&self) -> String {
+        match self {
+            Move::Halt => "Halt".to_string(),
+            Move::Stuck(err) => format!("Stuck({:?})", err.to_string()),
+            Move::Go(command, value, ip, node) => format!("Go({command:?}, {value}, {ip}, {node})"),
+        }
+    }
+
+    fn to_c(&self) -> String {
+        match self {
+            Move::Halt => "{PIET_HALT, PIET_NOOP, 0, 0, 0, 0}".to_string(),
+            Move::Stuck(err) => format!("{{PIET_STUCK, PIET_NOOP, 0, 0, 0, {:?}}}", err.to_string()),
+            Move::Go(command, value, ip, node) => {
+                let command = format!("{command:?}").to_uppercase();
+                format!("{{PIET_GO, PIET_{command}, {value}, {ip}, {node}, 0}}")
+            }
+        }
+    }
+}
+
+/// Everything in a transpiled Rust program but its moves.
+const RUST_RUNTIME: &str = r#"// Transpiled from a Piet program by piet_tools.
+// Build with `rustc --edition 2021 -O`.
+// Numbers are 128-bit: a program whose numbers outgrow that stops with an error.
+#![allow(dead_code)]
+
+use std::io::{self, BufRead, BufWriter, Write};
+use std::process;
+
+#[derive(Clone, Copy)]
+enum Cmd {
+    Noop, Push, Pop, Add, Subtract, Multiply, Divide, Mod, Not, Greater,
+    Pointer, Switch, Duplicate, Roll, InNum, InChar, OutNum, OutChar,
+}
+
+/// The way out of a block, or a slide across white, for one instruction pointer.
+#[derive(Clone, Copy)]
+enum Move {
+    /// There's no way out, so the program halts.
+    Halt,
+    /// The way out is into a codel that isn't a Piet color.
+    Stuck(&'static str),
+    /// Run a command, with the size of the block left, then carry on from a
+    /// node with the instruction pointer at an index (before the command
+    /// changes it): the direction pointer times two, plus the codel chooser.
+    Go(Cmd, i128, usize, usize),
+}
+
+use Cmd::*;
+use Move::*;
+
+struct Vm {
+    stack: Vec<i128>,
+    input: io::StdinLock<'static>,
+    output: BufWriter<io::StdoutLock<'static>>,
+}
+
+impl Vm {
+    fn fail(&mut self, message: &str) -> ! {
+        let _ = self.output.flush();
+        eprintln!("error: {message}");
+        process::exit(2);
+    }
+
+    fn push(&mut self, num: Option<i128>) {
+        match num {
+            Some(num) => self.stack.push(num),
+            None => self.fail("number too large"),
+        }
+    }
+
+    fn pop2(&mut self) -> Option<(i128, i128)> {
+        let [.., a, b] = self.stack[..] else { return None; };
+        self.stack.truncate(self.stack.len() - 2);
+        Some((a, b))
+    }
+
+    fn peek(&mut self) -> Option<u8> {
+        self.input.fill_buf().ok()?.first().copied()
+    }
+
+    fn read_byte(&mut self) -> Option<u8> {
+        let byte = self.peek()?;
+        self.input.consume(1);
+        Some(byte)
+    }
+
+    fn read_num(&mut self) -> Option<i128> {
+        while self.peek().is_some_and(|b| b.is_ascii_whitespace()) {
+            self.input.consume(1);
+        }
+        let mut digits = String::new();
+        if let Some(sign @ (b'-' | b'+')) = self.peek() {
+            digits.push(sign as char);
+            self.input.consume(1);
+        }
+        while let Some(digit) = self.peek().filter(u8::is_ascii_digit) {
+            digits.push(digit as char);
+            self.input.consume(1);
+        }
+        match digits.parse() {
+            Ok(num) => Some(num),
+            Err(_) if digits.bytes().any(|b| b.is_ascii_digit()) => self.fail("number too large"),
+            Err(_) => None,
+        }
+    }
+
+    /// Run a command, which may change the instruction pointer.
+    /// A command that can't run is skipped, as `pieti` does by default.
+    fn run(&mut self, cmd: Cmd, value: i128, ip: &mut usize) -> Option<()> {
+        match cmd {
+            Noop => (),
+            Push => self.stack.push(value),
+            Pop => { self.stack.pop()?; }
+            Add => { let (a, b) = self.pop2()?; self.push(a.checked_add(b)); }
+            Subtract => { let (a, b) = self.pop2()?; self.push(a.checked_sub(b)); }
+            Multiply => { let (a, b) = self.pop2()?; self.push(a.checked_mul(b)); }
+            Divide | Mod => {
+                let [.., _, b] = self.stack[..] else { return None; };
+                if b == 0 { return None; }
+                let (a, b) = self.pop2()?;
+                // Round towards negative infinity.
+                let rem = a.wrapping_rem(b);
+                let floor = rem != 0 && (rem < 0) != (b < 0);
+                match cmd {
+                    Divide => self.push(a.checked_div(b).map(|q| q - floor as i128)),
+                    _ => self.stack.push(if floor { rem + b } else { rem }),
+                }
+            }
+            Not => { let a = self.stack.pop()?; self.stack.push((a == 0) as i128); }
+            Greater => { let (a, b) = self.pop2()?; self.stack.push((a > b) as i128); }
+            Pointer => {
+                let spin = self.stack.pop()?.rem_euclid(4) as usize;
+                *ip = (*ip / 2 + spin) % 4 * 2 + *ip % 2;
+            }
+            Switch => {
+                if self.stack.pop()? % 2 != 0 {
+                    *ip ^= 1;
+                }
+            }
+            Duplicate => { let a = *self.stack.last()?; self.stack.push(a); }
+            Roll => {
+                let [.., dive, roll] = self.stack[..] else { return None; };
+                let len = self.stack.len() - 2;
+                let dive = usize::try_from(dive).ok().filter(|&dive| dive > 0 && dive <= len)?;
+                let roll = roll.rem_euclid(dive as i128) as usize;
+                self.stack.truncate(len);
+                self.stack[len - dive..].rotate_right(roll);
+            }
+            InNum => {
+                let _ = self.output.flush();
+                let num = self.read_num()?;
+                self.stack.push(num);
+            }
+            InChar => {
+                let _ = self.output.flush();
+                let byte = self.read_byte()?;
+                self.stack.push(byte.into());
+            }
+            OutNum => {
+                let num = self.stack.pop()?;
+                let _ = write!(self.output, "{num}");
+            }
+            OutChar => {
+                let num = self.stack.pop()?;
+                let chr = u32::try_from(num).ok().and_then(char::from_u32)?;
+                let _ = write!(self.output, "{chr}");
+            }
+        }
+        Some(())
+    }
+}
+
+fn main() {
+    let mut vm = Vm {
+        stack: Vec::new(),
+        input: io::stdin().lock(),
+        output: BufWriter::new(io::stdout().lock()),
+    };
+    let mut next = START;
+    loop {
+        match next {
+            Halt => break,
+            Stuck(message) => vm.fail(message),
+            Go(cmd, value, mut ip, node) => {
+                vm.run(cmd, value, &mut ip);
+                next = MOVES[node][ip];
+            }
+        }
+    }
+    let _ = vm.output.flush();
+}
+"#;
+
+/// Write a standalone Rust program that runs the same way as `code` does
+/// by default, for building into a fast native binary with just `rustc`
+/// (`rustc --edition 2021 -O program.rs`).
+///
+/// Execution is worked out ahead of time, as a table of every move between
+/// the blocks (and white codels) the program can reach, so the transpiled
+/// program only has to run commands and look up where to go next.
+/// The program reads stdin and writes stdout, skips commands that fail,
+/// and exits with status 2 if it would move into a non-Piet color.
+///
+/// Unlike `PietRunner`, whose numbers grow without bound, its numbers are
+/// `i128`s. A program whose numbers outgrow them stops with
+/// `error: number too large` and exit status 2, where `PietRunner` would
+/// carry on; nothing checks ahead of time whether a program might.
+pub fn to_rust(code: &PietCode) -> String {
+    let graph = Graph::new(code);
+    let mut rust = String::from(RUST_RUNTIME);
+    rust.push_str(&format!("\nconst START: Move = {};\n\n", graph.start.to_rust()));
+    rust.push_str(&format!("static MOVES: [[Move; 8]; {}] = [\n", graph.moves.len()));
+    for row in &graph.moves {
+        let row: Vec<_> = row.iter().map(Move::to_rust).collect();
+        rust.push_str(&format!("    [{}],\n", row.join(", ")));
+    }
+    rust.push_str("];\n");
+    rust
+}
+
+/// The start of a transpiled C program, up to its moves.
+const C_HEADER: &str = r#"/* Transpiled from a Piet program by piet_tools.
+ * Build with `cc -O2`. Numbers are 128-bit: a program whose numbers outgrow
+ * that stops with an error. To embed the program, define PIET_NO_MAIN and call
+ * piet_run(), which returns what main would exit with.
+ */
+#include <setjmp.h>
+#include <stdio.h>
+#include <stdlib.h>
+
+typedef __int128 piet_num;
+
+enum piet_cmd {
+    PIET_NOOP, PIET_PUSH, PIET_POP, PIET_ADD, PIET_SUBTRACT, PIET_MULTIPLY,
+    PIET_DIVIDE, PIET_MOD, PIET_NOT, PIET_GREATER, PIET_POINTER, PIET_SWITCH,
+    PIET_DUPLICATE, PIET_ROLL, PIET_INNUM, PIET_INCHAR, PIET_OUTNUM, PIET_OUTCHAR,
+};
+
+/* The way out of a block, or a slide across white, for one instruction pointer:
+ * halt; fail, moving into a codel that isn't a Piet color; or run a command,
+ * with the size of the block left, then carry on from a node with the
+ * instruction pointer at an index (before the command changes it): the
+ * direction pointer times two, plus the codel chooser.
+ */
+struct piet_move {
+    enum { PIET_HALT, PIET_STUCK, PIET_GO } kind;
+    enum piet_cmd cmd;
+    piet_num value;
+    int ip;
+    int node;
+    const char *message;
+};
+
+"#;
+
+/// The rest of a transpiled C program, after its moves.
+const C_RUNTIME: &str = r#"
+static piet_num *piet_stack;
+static size_t piet_len, piet_cap;
+static jmp_buf piet_failed;
+
+static void piet_fail(const char *message) {
+    fflush(stdout);
+    fprintf(stderr, "error: %s\n", message);
+    longjmp(piet_failed, 1);
+}
+
+static void piet_push(piet_num num) {
+    if (piet_len == piet_cap) {
+        piet_cap = piet_cap ? piet_cap * 2 : 64;
+        piet_stack = realloc(piet_stack, piet_cap * sizeof *piet_stack);
+        if (!piet_stack) piet_fail("out of memory");
+    }
+    piet_stack[piet_len++] = num;
+}
+
+static int piet_peek(void) {
+    int c = getchar();
+    if (c != EOF) ungetc(c, stdin);
+    return c;
+}
+
+static int piet_is_space(int c) {
+    return c == ' ' || c == '\t' || c == '\n' || c == '\f' || c == '\r';
+}
+
+/* Read an integer into *num, returning whether there was one. */
+static int piet_read_num(piet_num *num) {
+    while (piet_is_space(piet_peek())) getchar();
+    int negative = 0, digits = 0;
+    if (piet_peek() == '-' || piet_peek() == '+') negative = getchar() == '-';
+    piet_num n = 0;
+    for (int c; (c = piet_peek()) >= '0' && c <= '9'; digits++) {
+        getchar();
+        piet_num digit = c - '0';
+        if (__builtin_mul_overflow(n, 10, &n)
+            || (negative ? __builtin_sub_overflow(n, digit, &n) : __builtin_add_overflow(n, digit, &n))) {
+            piet_fail("number too large");
+        }
+    }
+    *num = n;
+    return digits > 0;
+}
+
+static void piet_write_num(piet_num num) {
+    char digits[41];
+    int i = sizeof digits;
+    unsigned __int128 n = num < 0 ? -(unsigned __int128)num : (unsigned __int128)num;
+    digits[--i] = '\0';
+    do {
+        digits[--i] = '0' + (int)(n % 10);
+        n /= 10;
+    } while (n);
+    if (num < 0) putchar('-');
+    fputs(digits + i, stdout);
+}
+
+/* Write a Unicode scalar value as UTF-8, returning whether it was one. */
+static int piet_write_char(piet_num c) {
+    if (c < 0 || c > 0x10FFFF || (c >= 0xD800 && c <= 0xDFFF)) return 0;
+    if (c < 0x80) {
+        putchar((int)c);
+    } else if (c < 0x800) {
+        putchar(0xC0 | (int)(c >> 6));
+        putchar(0x80 | (int)(c & 0x3F));
+    } else if (c < 0x10000) {
+        putchar(0xE0 | (int)(c >> 12));
+        putchar(0x80 | (int)((c >> 6) & 0x3F));
+        putchar(0x80 | (int)(c & 0x3F));
+    } else {
+        putchar(0xF0 | (int)(c >> 18));
+        putchar(0x80 | (int)((c >> 12) & 0x3F));
+        putchar(0x80 | (int)((c >> 6) & 0x3F));
+        putchar(0x80 | (int)(c & 0x3F));
+    }
+    return 1;
+}
+
+static void piet_reverse(piet_num *nums, size_t len) {
+    for (size_t i = 0; i < len / 2; i++) {
+        piet_num swap = nums[i];
+        nums[i] = nums[len - 1 - i];
+        nums[len - 1 - i] = swap;
+    }
+}
+
+/* Run a command, which may change the instruction pointer.
+ * A command that can't run is skipped, as `pieti` does by default.
+ */
+static void piet_command(enum piet_cmd cmd, piet_num value, int *ip) {
+    piet_num *top = piet_len ? piet_stack + piet_len - 1 : NULL;
+    piet_num a, b, result;
+    switch (cmd) {
+    case PIET_NOOP:
+        break;
+    case PIET_PUSH:
+        piet_push(value);
+        break;
+    case PIET_POP:
+        if (piet_len >= 1) piet_len--;
+        break;
+    case PIET_ADD: case PIET_SUBTRACT: case PIET_MULTIPLY: case PIET_GREATER: {
+        if (piet_len < 2) break;
+        a = top[-1], b = top[0];
+        piet_len -= 2;
+        int overflowed = 0;
+        switch (cmd) {
+        case PIET_ADD: overflowed = __builtin_add_overflow(a, b, &result); break;
+        case PIET_SUBTRACT: overflowed = __builtin_sub_overflow(a, b, &result); break;
+        case PIET_MULTIPLY: overflowed = __builtin_mul_overflow(a, b, &result); break;
+        default: result = a > b;
+        }
+        if (overflowed) piet_fail("number too large");
+        piet_push(result);
+        break;
+    }
+    case PIET_DIVIDE: case PIET_MOD: {
+        if (piet_len < 2 || top[0] == 0) break;
+        a = top[-1], b = top[0];
+        piet_len -= 2;
+        /* Round towards negative infinity. */
+        piet_num rem = b == -1 ? 0 : a % b;
+        int floor = rem != 0 && (rem < 0) != (b < 0);
+        if (cmd == PIET_MOD) {
+            piet_push(floor ? rem + b : rem);
+        } else {
+            piet_num min = (piet_num)((unsigned __int128)1 << 127);
+            if (b == -1 && a == min) piet_fail("number too large");
+            piet_push(a / b - floor);
+        }
+        break;
+    }
+    case PIET_NOT:
+        if (piet_len >= 1) *top = *top == 0;
+        break;
+    case PIET_POINTER:
+        if (piet_len < 1) break;
+        piet_len--;
+        *ip = (int)((*ip / 2 + (*top % 4 + 4) % 4) % 4 * 2 + *ip % 2);
+        break;
+    case PIET_SWITCH:
+        if (piet_len < 1) break;
+        piet_len--;
+        if (*top % 2 != 0) *ip ^= 1;
+        break;
+    case PIET_DUPLICATE:
+        if (piet_len >= 1) piet_push(*top);
+        break;
+    case PIET_ROLL: {
+        if (piet_len < 2) break;
+        piet_num dive = top[-1], roll = top[0];
+        size_t len = piet_len - 2;
+        if (dive <= 0 || dive > (piet_num)len) break;
+        size_t depth = (size_t)dive;
+        size_t by = (size_t)((roll % dive + dive) % dive);
+        piet_len = len;
+        piet_num *rolled = piet_stack + len - depth;
+        piet_reverse(rolled, depth);
+        piet_reverse(rolled, by);
+        piet_reverse(rolled + by, depth - by);
+        break;
+    }
+    case PIET_INNUM: {
+        fflush(stdout);
+        piet_num num;
+        if (piet_read_num(&num)) piet_push(num);
+        break;
+    }
+    case PIET_INCHAR: {
+        fflush(stdout);
+        int c = getchar();
+        if (c != EOF) piet_push(c);
+        break;
+    }
+    case PIET_OUTNUM:
+        if (piet_len < 1) break;
+        piet_len--;
+        piet_write_num(*top);
+        break;
+    case PIET_OUTCHAR:
+        if (piet_len < 1) break;
+        piet_len--;
+        piet_write_char(*top);
+        break;
+    }
+}
+
+static void piet_walk(void) {
+    const struct piet_move *next = &piet_start;
+    while (next->kind == PIET_GO) {
+        int ip = next->ip;
+        piet_command(next->cmd, next->value, &ip);
+        next = &piet_moves[next->node][ip];
+    }
+    if (next->kind == PIET_STUCK) piet_fail(next->message);
+}
+
+int piet_run(void) {
+    piet_len = 0;
+    if (setjmp(piet_failed)) return 2;
+    piet_walk();
+    fflush(stdout);
+    return 0;
+}
+
+#ifndef PIET_NO_MAIN
+int main(void) {
+    return piet_run();
+}
+#endif
+"#;
+
+/// Write a standalone C program that runs the same way as `code` does
+/// by default, for building with gcc or clang (`cc -O2 program.c`), or
+/// embedding in another program: defining `PIET_NO_MAIN` leaves out `main`,
+/// so the program can be run with `piet_run()` instead.
+///
+/// Like `to_rust`, it's built from a table of every move the program can make.
+///
+/// Its numbers are `__int128`s, which gcc and clang support, rather than
+/// growing without bound as `PietRunner`'s do. A program whose numbers
+/// outgrow them stops with `error: number too large`, and `main` (or
+/// `piet_run()`) returns 2, where `PietRunner` would carry on; nothing checks
+/// ahead of time whether a program might.
+pub fn to_c(code: &PietCode) -> String {
+    let graph = Graph::new(code);
+    let mut c = String::from(C_HEADER);
+    c.push_str(&format!("\nstatic const struct piet_move piet_start = {};\n\n", graph.start.to_c()));
+    // C doesn't allow empty arrays, so a program with no moves gets an unused one.
+    c.push_str(&format!("static const struct piet_move piet_moves[{}][8] = {{\n", graph.moves.len().max(1)));
+    for row in &graph.moves {
+        let row: Vec<_> = row.iter().map(Move::to_c).collect();
+        c.push_str(&format!("    {{{}}},\n", row.join(", ")));
+    }
+    c.push_str("};\n");
+    c.push_str(C_RUNTIME);
+    c
+}
+
+
+#[cfg(test)]
+pub(super) mod tests {
+    use super::*;
+    use std::fs;
+    use std::io::Write;
+    use std::process::{Command as Process, Stdio};
+
+    #[test]
+    fn test_graph() {
+        let code = PietCode::new(4, 1, vec![Color::Red, Color::White, Color::Blue, Color::Black]);
+        let graph = Graph::new(&code);
+        // Red, the white codel it leaves into, and Blue.
+        assert_eq!(graph.moves.len(), 3);
+        assert!(matches!(graph.start, Move::Go(Command::Noop, _, 0, 0)));
+        assert!(matches!(graph.moves[0][0], Move::Go(Command::Noop, _, 0, 1)));
+        assert!(matches!(graph.moves[1][0], Move::Go(Command::Noop, _, 0, 2)));
+
+        let code = PietCode::new(2, 1, vec![Color::Red, Color::Other]);
+        let graph = Graph::new(&code);
+        assert!(matches!(graph.moves[0][0], Move::Stuck(ExecutionError::InvalidColor(_))));
+        let code = PietCode::new(1, 1, vec![Color::Black]);
+        assert!(matches!(Graph::new(&code).start, Move::Halt));
+        assert!(to_rust(&code).contains("[[Move; 8]; 0]"));
+    }
+
+    /// A program exercising every command, with input for it to read.
+    pub(super) const EVERY_COMMAND: &str = "\
+        INNUM\n:L\nDUP\nOUTNUM\nOUTCHAR 32\nSUB 1\nDUP\nJUMPIF L\nPOP\n\
+        INCHAR\nOUTCHAR\nPOP\nPUSH -7 2\nDIV\nOUTNUM\nPUSH -7 2\nMOD\nOUTNUM\n\
+        PUSH 1 2 3 3 1\nROLL\nOUTNUM\nOUTNUM\nOUTNUM\nOUTCHAR 955\n\
+    ";
+    pub(super) const EVERY_COMMAND_INPUT: &str = "3\nab";
+
+    /// A directory of the test's own, so tests running at once, here or in
+    /// another checkout, don't build over each other's files.
+    pub(super) fn test_dir(name: &str) -> std::path::PathBuf {
+        let dir = std::env::temp_dir().join(format!("piet_tools_transpile_{}_{name}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// Whether `tool` can be run, so a test that needs it can be skipped
+    /// where it isn't installed.
+    pub(super) fn installed(tool: &str) -> bool {
+        let found = Process::new(tool).arg("--version").output().is_ok();
+        if !found {
+            eprintln!("skipping: {tool} isn't installed");
+        }
+        found
+    }
+
+    /// Run `program` on `input`, checking it succeeds, for its output.
+    pub(super) fn run_with_input(program: &mut Process, input: &str) -> String {
+        let mut child = program.stdin(Stdio::piped()).stdout(Stdio::piped()).spawn().unwrap();
+        child.stdin.take().unwrap().write_all(input.as_bytes()).unwrap();
+        let output = child.wait_with_output().unwrap();
+        assert!(output.status.success());
+        String::from_utf8(output.stdout).unwrap()
+    }
+
+    /// Build `EVERY_COMMAND`, and a transpiled copy of it, and check the
+    /// two give the same output.
+    fn check_transpiled(name: &str, transpile: fn(&PietCode) -> String, build: &[&str]) {
+        if !installed(build[0]) {
+            return;
+        }
+        let dir = test_dir(name);
+        let code = crate::asm::load_str(EVERY_COMMAND).unwrap();
+        let source = dir.join(name);
+        let binary = dir.join(format!("{name}.out"));
+        fs::write(&source, transpile(&code)).unwrap();
+        let status = Process::new(build[0])
+            .args(&build[1..])
+            .arg("-o").arg(&binary).arg(&source)
+            .status()
+            .unwrap();
+        assert!(status.success());
+
+        let expected = code.execute().capture_io(EVERY_COMMAND_INPUT).run_collect().stdout_lossy().into_owned();
+        assert_eq!(expected, "3 2 1 \n-41213\u{3bb}");
+        assert_eq!(run_with_input(&mut Process::new(&binary), EVERY_COMMAND_INPUT), expected);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_to_rust() {
+        let rustc = std::env::var("RUSTC").unwrap_or("rustc".to_string());
+        check_transpiled("test.rs", to_rust, &[&rustc, "--edition", "2021", "-O"]);
+    }
+
+    #[test]
+    fn test_to_c() {
+        check_transpiled("test.c", to_c, &["cc", "-O2", "-Wall", "-Werror"]);
+        let code = PietCode::new(1, 1, vec![Color::Black]);
+        assert!(to_c(&code).contains("piet_moves[1][8]"));
+    }
+}
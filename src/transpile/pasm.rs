@@ -0,0 +1,543 @@
+use super::{Graph, Move};
+use crate::asm::string_source;
+use crate::{Command, InstructionPointer, PietCode, PietVM, Value};
+use num_bigint::BigInt;
+use num_traits::{ToPrimitive, Zero};
+use std::collections::{HashMap, HashSet, VecDeque};
+
+/// Where execution is: a node of the graph, and the index of the
+/// instruction pointer it arrives with.
+type State = (usize, usize);
+
+/// What the program does from a state, with the states it can go to next
+/// numbered as steps too: one for each value a turn could pop, and
+/// otherwise just the one.
+///
+/// States that always go on to do the same thing are the same step, eg
+/// each time around a loop that's back where it started, but with the
+/// codel chooser the other way.
+enum Step {
+    Halt,
+    Stuck(String),
+    Go(Command, BigInt, Vec<usize>),
+}
+
+/// A piece of the decompiled program, before its labels are named and its
+/// commands are collapsed into PietASM's conveniences.
+enum Piece {
+    /// Where the code for a step starts. Only named if something jumps there.
+    Label(usize),
+    Command(Command, BigInt),
+    Jump(usize),
+    /// Jump if the top of the stack, which is known to be 0 or 1, is 1.
+    JumpIf(usize),
+    /// Go the `n`th way for a top of the stack of `n`, modulo the number of ways.
+    Switch(Vec<usize>),
+    /// Halt, or stop with the reason execution can't go on.
+    Stop(Option<String>),
+}
+
+/// The instruction pointers `command` can leave `ip` as, for each value it
+/// could pop: four for `Pointer`, two for `Switch`, and otherwise just `ip`.
+fn turns(command: Command, ip: usize) -> Vec<usize> {
+    let mut ip = InstructionPointer::ALL[ip];
+    let (ways, turn): (usize, fn(&mut InstructionPointer)) = match command {
+        Command::Pointer => (4, InstructionPointer::rotate),
+        Command::Switch => (2, InstructionPointer::flip),
+        _ => (1, |_| ()),
+    };
+    (0..ways)
+        .map(|_| {
+            let index = ip.index();
+            turn(&mut ip);
+            index
+        })
+        .collect()
+}
+
+/// Where execution can go from `state`: only the first two ways out of
+/// a turn if the state is `binary`.
+fn successors(graph: &Graph, state: State, binary: &HashSet<State>) -> Vec<State> {
+    let Move::Go(command, _, ip, next) = &graph.moves[state.0][state.1] else {
+        return Vec::new();
+    };
+    let mut ways = turns(*command, *ip);
+    if binary.contains(&state) {
+        ways.truncate(2);
+    }
+    ways.into_iter().map(|ip| (*next, ip)).collect()
+}
+
+/// Every state execution can reach from `start`, in the order they're found.
+fn reachable(graph: &Graph, start: State, binary: &HashSet<State>) -> Vec<State> {
+    let mut seen = HashSet::from([start]);
+    let mut states = vec![start];
+    let mut idx = 0;
+    while let Some(&state) = states.get(idx) {
+        for next in successors(graph, state, binary) {
+            if seen.insert(next) {
+                states.push(next);
+            }
+        }
+        idx += 1;
+    }
+    states
+}
+
+/// The states execution only ever reaches with a 0 or 1 on top of the
+/// stack, straight from a `NOT` or `GREATER` or sliding on from a state
+/// that does, so that a turn from them can only go two ways.
+fn binary_states(graph: &Graph, start: State) -> HashSet<State> {
+    // Start by supposing every state is, and rule out any with a way in
+    // that says otherwise, until no more are ruled out. Ways in from a
+    // turn from a state still supposed to be binary don't count.
+    let mut binary: HashSet<_> = reachable(graph, start, &HashSet::new()).into_iter().collect();
+    binary.remove(&start);
+    loop {
+        let states = reachable(graph, start, &binary);
+        let mut still: HashSet<_> = states.iter().copied().filter(|state| binary.contains(state)).collect();
+        for &state in &states {
+            let Move::Go(command, ..) = &graph.moves[state.0][state.1] else { continue; };
+            let keeps = match command {
+                Command::Not | Command::Greater => true,
+                Command::Noop => binary.contains(&state),
+                _ => false,
+            };
+            if !keeps {
+                for next in successors(graph, state, &binary) {
+                    still.remove(&next);
+                }
+            }
+        }
+        if still == binary {
+            return binary;
+        }
+        binary = still;
+    }
+}
+
+/// Number the steps of the program from `start`, which is step 0.
+fn steps(graph: &Graph, start: State) -> Vec<Step> {
+    let binary = binary_states(graph, start);
+    let states = reachable(graph, start, &binary);
+    let nexts: Vec<_> = states.iter().map(|&state| successors(graph, state, &binary)).collect();
+
+    // Split the states into steps by what they do, then by the steps they
+    // go to next, until no step splits further.
+    let mut step_of: HashMap<State, usize> = HashMap::new();
+    let mut ids = HashMap::new();
+    for &state in &states {
+        let key = match &graph.moves[state.0][state.1] {
+            Move::Halt => "halt".to_string(),
+            Move::Stuck(err) => format!("stuck {err}"),
+            Move::Go(command, value, ..) => format!("{command:?} {value}"),
+        };
+        let ways = successors(graph, state, &binary).len();
+        let count = ids.len();
+        step_of.insert(state, *ids.entry((key, ways)).or_insert(count));
+    }
+    loop {
+        let mut ids = HashMap::new();
+        let split: HashMap<State, usize> = states.iter().zip(&nexts)
+            .map(|(&state, nexts)| {
+                let key = (step_of[&state], nexts.iter().map(|next| step_of[next]).collect::<Vec<_>>());
+                let count = ids.len();
+                (state, *ids.entry(key).or_insert(count))
+            })
+            .collect();
+        let done = ids.len() == step_of.values().collect::<HashSet<_>>().len();
+        step_of = split;
+        if done {
+            break;
+        }
+    }
+
+    let mut steps: Vec<Option<Step>> = Vec::new();
+    steps.resize_with(states.len(), || None);
+    for (state, nexts) in states.iter().zip(nexts) {
+        let step = &mut steps[step_of[state]];
+        if step.is_some() {
+            continue;
+        }
+        *step = Some(match &graph.moves[state.0][state.1] {
+            Move::Halt => Step::Halt,
+            Move::Stuck(err) => Step::Stuck(err.to_string()),
+            Move::Go(command, value, ..) => {
+                Step::Go(*command, value.clone(), nexts.iter().map(|next| step_of[next]).collect())
+            }
+        });
+    }
+    steps.into_iter().map_while(|step| step).collect()
+}
+
+/// The first step from `step` that does anything, past any that only
+/// slide on, eg through white.
+fn past_noops(steps: &[Step], mut step: usize) -> usize {
+    for _ in 0..steps.len() {
+        match &steps[step] {
+            Step::Go(Command::Noop, _, ways) => { step = ways[0]; }
+            _ => break,
+        }
+    }
+    step
+}
+
+/// Every step execution can go on to from `step`, including itself.
+fn reaches(steps: &[Step], step: usize) -> HashSet<usize> {
+    let mut seen = HashSet::from([step]);
+    let mut stack = vec![step];
+    while let Some(step) = stack.pop() {
+        if let Step::Go(_, _, ways) = &steps[step] {
+            stack.extend(ways.iter().copied().filter(|&way| seen.insert(way)));
+        }
+    }
+    seen
+}
+
+/// Lay out the code for every step. Each step's code follows on from the
+/// step before it where it can, and jumps to it where it's already been
+/// laid out.
+fn pieces(steps: &[Step]) -> Vec<Piece> {
+    let mut pieces = Vec::new();
+    let mut placed = HashSet::new();
+    let mut queue = VecDeque::from([past_noops(steps, 0)]);
+    while let Some(mut step) = queue.pop_front() {
+        if placed.contains(&step) {
+            continue;
+        }
+        loop {
+            placed.insert(step);
+            pieces.push(Piece::Label(step));
+            let (command, value, ways) = match &steps[step] {
+                Step::Halt => {
+                    pieces.push(Piece::Stop(None));
+                    break;
+                }
+                Step::Stuck(err) => {
+                    pieces.push(Piece::Stop(Some(err.clone())));
+                    break;
+                }
+                Step::Go(command, value, ways) => (*command, value, ways),
+            };
+            let ways: Vec<_> = ways.iter().map(|&way| past_noops(steps, way)).collect();
+            let target = match *ways.as_slice() {
+                [target] => {
+                    pieces.push(Piece::Command(command, value.clone()));
+                    target
+                }
+                // A turn that goes the same way whatever it pops.
+                [target, ..] if ways.iter().all(|&way| way == target) => {
+                    pieces.push(Piece::Command(Command::Pop, BigInt::zero()));
+                    target
+                }
+                // After a `NOT` or `GREATER`, there are only two ways to go.
+                [target, jump] => {
+                    pieces.push(Piece::JumpIf(jump));
+                    queue.push_back(jump);
+                    target
+                }
+                _ => {
+                    queue.extend(ways.iter().copied());
+                    pieces.push(Piece::Switch(ways));
+                    break;
+                }
+            };
+            if placed.contains(&target) {
+                pieces.push(Piece::Jump(target));
+                break;
+            }
+            step = target;
+        }
+    }
+    pieces
+}
+
+/// The PietASM for a command, or `None` for steps that do nothing, and
+/// turns, which are written as jumps instead.
+fn mnemonic(command: Command) -> Option<&'static str> {
+    Some(match command {
+        Command::Noop | Command::Pointer | Command::Switch => return None,
+        Command::Push => "PUSH",
+        Command::Pop => "POP",
+        Command::Add => "ADD",
+        Command::Subtract => "SUB",
+        Command::Multiply => "MUL",
+        Command::Divide => "DIV",
+        Command::Mod => "MOD",
+        Command::Not => "NOT",
+        Command::Greater => "GREATER",
+        Command::Duplicate => "DUP",
+        Command::Roll => "ROLL",
+        Command::InNum => "INNUM",
+        Command::InChar => "INCHAR",
+        Command::OutNum => "OUTNUM",
+        Command::OutChar => "OUTCHAR",
+    })
+}
+
+/// How many of a command's operands can be written as literal arguments.
+fn operands(command: Command) -> usize {
+    match command {
+        Command::Add | Command::Subtract | Command::Multiply | Command::Divide
+            | Command::Mod | Command::Greater | Command::Roll => 2,
+        Command::Not | Command::OutNum | Command::OutChar => 1,
+        _ => 0,
+    }
+}
+
+/// What `command` leaves of constant `operands`, if it's a single value.
+fn fold(command: Command, operands: &[BigInt]) -> Option<BigInt> {
+    let mut vm = PietVM::new();
+    vm.stack = operands.iter().cloned().map(Value::from).collect();
+    vm.run_command(command, Value::zero()).ok()?;
+    match vm.stack.as_slice() {
+        [value] => Some(value.to_bigint()),
+        _ => None,
+    }
+}
+
+/// Fold the arithmetic generated code builds constants with back into
+/// pushes of the constants, eg `PUSH 10, DUP, MUL, PUSH 5, ADD` into `PUSH 105`.
+fn fold_constants(run: &[(Command, BigInt)]) -> Vec<(Command, BigInt)> {
+    let mut folded: Vec<(Command, BigInt)> = Vec::new();
+    for (command, value) in run {
+        // Everything pushed is a constant, so folding needs only as many pushes as operands.
+        let operands = match command {
+            Command::Add | Command::Subtract | Command::Multiply | Command::Divide
+                | Command::Mod | Command::Greater => 2,
+            Command::Not | Command::Duplicate => 1,
+            _ => 0,
+        };
+        let start = folded.len().saturating_sub(operands);
+        let constants: Vec<_> = folded[start..].iter()
+            .map_while(|(command, value)| (*command == Command::Push).then(|| value.clone()))
+            .collect();
+        let result = match command {
+            _ if operands == 0 || constants.len() < operands => None,
+            Command::Duplicate => Some(vec![constants[0].clone(), constants[0].clone()]),
+            _ => fold(*command, &constants).map(|value| vec![value]),
+        };
+        match result {
+            Some(values) => {
+                folded.truncate(start);
+                folded.extend(values.into_iter().map(|value| (Command::Push, value)));
+            }
+            None => { folded.push((*command, value.clone())); }
+        }
+    }
+    folded
+}
+
+/// The text output by the pushes and `OUTCHAR`s starting at `run[start]`,
+/// and how many commands that is, if it's worth an `OUTSTR`.
+fn text_at(run: &[(Command, BigInt)], start: usize) -> Option<(String, usize)> {
+    let text: String = run[start..].chunks_exact(2)
+        .map_while(|pair| match pair {
+            [(Command::Push, value), (Command::OutChar, _)] => value.to_u32()
+                .and_then(char::from_u32)
+                .filter(|&c| !c.is_control() || matches!(c, '\n' | '\t')),
+            _ => None,
+        })
+        .collect();
+    let len = text.chars().count();
+    (len >= 2).then_some((text, len * 2))
+}
+
+/// Write a straight run of commands as PietASM, folding constants,
+/// collapsing runs of constant output into `OUTSTR`s, and pushes into the arguments of the
+/// commands they're operands of.
+fn render_run(run: &[(Command, BigInt)]) -> Vec<String> {
+    let run = fold_constants(run);
+    let run = run.as_slice();
+    let mut lines = Vec::new();
+    let mut i = 0;
+    while i < run.len() {
+        if let Some((text, len)) = text_at(run, i) {
+            lines.push(format!("OUTSTR {}", string_source(&text)));
+            i += len;
+            continue;
+        }
+        let mut pushes = Vec::new();
+        while i < run.len() && run[i].0 == Command::Push && (pushes.is_empty() || text_at(run, i).is_none()) {
+            pushes.push(run[i].1.to_string());
+            i += 1;
+        }
+        let command = match run.get(i) {
+            Some(&(command, _)) if text_at(run, i).is_none() => command,
+            _ => {
+                lines.push(format!("PUSH {}", pushes.join(" ")));
+                continue;
+            }
+        };
+        let args = pushes.split_off(pushes.len().saturating_sub(operands(command)));
+        if !pushes.is_empty() {
+            lines.push(format!("PUSH {}", pushes.join(" ")));
+        }
+        lines.push(std::iter::once(mnemonic(command).unwrap_or_default().to_string()).chain(args).collect::<Vec<_>>().join(" "));
+        i += 1;
+    }
+    lines
+}
+
+/// Decompile `code` into PietASM which runs the same way.
+///
+/// Every way the program can go becomes labelled code, each following on
+/// from the code before it where it can. Each `Pointer` or `Switch` becomes
+/// a `SWITCH` on which way the program turns, or a `JUMPIF` where it can
+/// only go two ways, eg after a `NOT`. Constant output is collapsed into
+/// `OUTSTR`s, pushes into the literal arguments of the commands after them,
+/// and the bodies of loops are indented.
+///
+/// The PietASM lays out differently, so the images needn't match, but the
+/// programs run the same, as long as every command has the stack it needs.
+pub fn to_pasm(code: &PietCode) -> String {
+    let graph = Graph::new(code);
+    let start = match &graph.start {
+        Move::Go(_, _, ip, node) => (*node, *ip),
+        Move::Halt => return "STOP\n".to_string(),
+        Move::Stuck(err) => return format!("# stuck: {err}\nSTOP\n"),
+    };
+    let steps = steps(&graph, start);
+    let pieces = pieces(&steps);
+
+    // A label is a loop's if execution can go from it to a jump back to it.
+    let owners: Vec<_> = pieces.iter()
+        .scan(0, |owner, piece| {
+            if let Piece::Label(step) = piece {
+                *owner = *step;
+            }
+            Some(*owner)
+        })
+        .collect();
+    let mut names = HashMap::new();
+    let (mut loops, mut skips) = (0, 0);
+    for (idx, piece) in pieces.iter().enumerate() {
+        let Piece::Label(step) = piece else { continue; };
+        let jumps: Vec<_> = pieces.iter().enumerate()
+            .filter(|(_, piece)| match piece {
+                Piece::Jump(to) | Piece::JumpIf(to) => to == step,
+                Piece::Switch(ways) => ways.contains(step),
+                _ => false,
+            })
+            .map(|(jump_idx, _)| jump_idx)
+            .collect();
+        if jumps.is_empty() {
+            continue;
+        }
+        let body = reaches(&steps, *step);
+        let back = jumps.iter().copied()
+            .filter(|&jump_idx| jump_idx > idx && body.contains(&owners[jump_idx]))
+            .max();
+        let name = match back {
+            Some(_) => { loops += 1; format!("loop{loops}") }
+            None => { skips += 1; format!("skip{skips}") }
+        };
+        names.insert(*step, (name, idx, back));
+    }
+
+    let mut lines = Vec::new();
+    let mut run = Vec::new();
+    for (idx, piece) in pieces.iter().enumerate() {
+        if let Piece::Command(command, value) = piece {
+            if mnemonic(*command).is_some() {
+                run.push((*command, value.clone()));
+            }
+            continue;
+        }
+        if matches!(piece, Piece::Label(step) if !names.contains_key(step)) {
+            continue;
+        }
+        if let (Piece::JumpIf(_), [.., (Command::Not, _), (Command::Not, _)]) = (piece, run.as_slice()) {
+            // PietASM's `JUMPIF` adds these itself.
+            run.truncate(run.len() - 2);
+        }
+        lines.extend(render_run(&run).into_iter().map(|line| (idx, line)));
+        run.clear();
+        let name = |step: &usize| names[step].0.clone();
+        match piece {
+            Piece::Command(..) => {}
+            Piece::Label(step) => { lines.push((idx, format!(":{}", name(step)))); }
+            Piece::Jump(step) => { lines.push((idx, format!("JUMP {}", name(step)))); }
+            Piece::JumpIf(step) => { lines.push((idx, format!("JUMPIF {}", name(step)))); }
+            Piece::Switch(ways) => {
+                lines.push((idx, format!("MOD {}", ways.len())));
+                lines.push((idx, "SWITCH".to_string()));
+                for (value, way) in ways.iter().enumerate().skip(1) {
+                    lines.push((idx, format!("CASE {value}")));
+                    lines.push((idx, format!("JUMP {}", name(way))));
+                }
+                lines.push((idx, "DEFAULT".to_string()));
+                lines.push((idx, format!("JUMP {}", name(&ways[0]))));
+                lines.push((idx, "ENDSWITCH".to_string()));
+            }
+            Piece::Stop(reason) => {
+                if let Some(reason) = reason {
+                    lines.push((idx, format!("# stuck: {reason}")));
+                }
+                lines.push((idx, "STOP".to_string()));
+            }
+        }
+    }
+
+    // Indent each loop's body, from its label to the last jump back to it.
+    let bodies: Vec<_> = names.values()
+        .filter_map(|&(_, start, end)| Some((start, end?)))
+        .collect();
+    let mut text = String::new();
+    for (idx, line) in lines {
+        let depth = bodies.iter()
+            .filter(|&&(start, end)| start < idx && idx <= end)
+            .count();
+        text.push_str(&"  ".repeat(depth));
+        text.push_str(&line);
+        text.push('\n');
+    }
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm;
+
+    /// Decompile `source` once assembled, and check the decompiled program
+    /// runs just as the original does on each of `inputs`.
+    fn round_trip(source: &str, inputs: &[&str]) -> String {
+        let code = asm::load_str(source).unwrap();
+        let decompiled = to_pasm(&code);
+        let recompiled = asm::load_str(&decompiled).unwrap_or_else(|e| panic!("{e}\n{decompiled}"));
+        for &input in inputs {
+            let expected = code.execute().capture_io(input).fuel(10_000).run_collect();
+            let actual = recompiled.execute().capture_io(input).fuel(10_000).run_collect();
+            assert_eq!((actual.stdout, actual.stack), (expected.stdout, expected.stack), "{decompiled}");
+        }
+        decompiled
+    }
+
+    #[test]
+    fn test_outstr() {
+        let decompiled = round_trip("OUTSTR \"Hi\\n\"\nPUSH 3 4\nADD\nOUTNUM\nPUSH 5 6\n", &[""]);
+        assert_eq!(decompiled, "OUTSTR \"Hi\\n\"\nOUTNUM 7\nPUSH 5 6\nSTOP\n");
+    }
+
+    #[test]
+    fn test_loop() {
+        // The loop goes round twice before it's back where it started, but is only written once.
+        let decompiled = round_trip("PUSH 5\n:L\nDUP\nOUTNUM\nSUB 1\nDUP\nJUMPIF L\n", &[""]);
+        assert_eq!(decompiled, "PUSH 5\n:loop1\n  DUP\n  OUTNUM\n  SUB 1\n  DUP\n  JUMPIF loop1\nSTOP\n");
+    }
+
+    #[test]
+    fn test_branches() {
+        // Echoes numbers until a 0, then prints which of three the next is.
+        let source = "\
+            :L\nINNUM\nDUP\nOUTNUM\nDUP\nJUMPIF L\nPOP\nINNUM\n\
+            SWITCH\nCASE 1\nOUTSTR \"one\"\nCASE 2\nOUTSTR \"two\"\nDEFAULT\nOUTSTR \"other\"\nENDSWITCH\n";
+        let decompiled = round_trip(source, &["3 1 0 1", "0 2", "0 7", "5 0"]);
+        assert!(!decompiled.contains("SWITCH"), "{decompiled}");
+
+        // A turn by a value that needn't be 0 or 1 becomes a `SWITCH`.
+        let decompiled = round_trip("INNUM\n@RAW 2 1\n> r dc >\n@END\nOUTNUM 5\n", &["0", "1", "2", "3", "-1"]);
+        assert!(decompiled.contains("  MOD 4\n  SWITCH\n"), "{decompiled}");
+    }
+}
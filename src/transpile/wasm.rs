@@ -0,0 +1,718 @@
+//! A WebAssembly backend. The module is the same for every program but for
+//! its data: a fixed interpreter loop that walks the program's moves, which
+//! are laid out in memory as a table.
+
+use super::{Graph, Move};
+use crate::PietCode;
+use num_traits::ToPrimitive;
+
+/// Each move in the table: its kind, command, and instruction pointer as
+/// bytes, then the node it goes to as an `i32`, and the value as an `i64`.
+const ENTRY_SIZE: u32 = 16;
+const GO: u8 = 0;
+const HALT: u8 = 1;
+const STUCK: u8 = 2;
+
+/// What `run` returns.
+const HALTED: i32 = 0;
+const INVALID_COLOR: i32 = 1;
+const OVERFLOWED: i32 = 2;
+
+// Functions, imports first.
+const READ: u32 = 0;
+const WRITE: u32 = 1;
+const PEEK: u32 = 2;
+const READ_BYTE: u32 = 3;
+const PUSH: u32 = 4;
+const POP: u32 = 5;
+const PEEK_STACK: u32 = 6;
+const REVERSE: u32 = 7;
+const READ_NUM: u32 = 8;
+const WRITE_NUM: u32 = 9;
+const WRITE_CHAR: u32 = 10;
+const LEN: u32 = 11;
+const RUN: u32 = 12;
+
+// Globals.
+/// The address just past the top of the stack.
+const SP: u32 = 0;
+/// The next byte of input, if it's been read ahead, or `UNREAD`.
+const PEEKED: u32 = 1;
+const UNREAD: i32 = -2;
+
+// Types of functions, by their params and results.
+const I32: u8 = 0x7f;
+const I64: u8 = 0x7e;
+const TYPES: [(&[u8], &[u8]); 6] = [
+    (&[], &[I32]),
+    (&[I32], &[]),
+    (&[I64], &[]),
+    (&[], &[I64]),
+    (&[I32], &[I64]),
+    (&[I32, I32], &[]),
+];
+const TYPE_I32: u32 = 0;
+const TYPE_WRITE: u32 = 1;
+const TYPE_TAKE_I64: u32 = 2;
+const TYPE_I64: u32 = 3;
+const TYPE_PEEK_STACK: u32 = 4;
+const TYPE_REVERSE: u32 = 5;
+
+fn uleb(bytes: &mut Vec<u8>, mut n: u64) {
+    loop {
+        let byte = (n & 0x7f) as u8;
+        n >>= 7;
+        if n == 0 {
+            bytes.push(byte);
+            return;
+        }
+        bytes.push(byte | 0x80);
+    }
+}
+
+fn sleb(bytes: &mut Vec<u8>, mut n: i64) {
+    loop {
+        let byte = (n & 0x7f) as u8;
+        n >>= 7;
+        if (n == 0 && byte & 0x40 == 0) || (n == -1 && byte & 0x40 != 0) {
+            bytes.push(byte);
+            return;
+        }
+        bytes.push(byte | 0x80);
+    }
+}
+
+/// A function body, built up an instruction at a time.
+#[derive(Default)]
+struct Asm(Vec<u8>);
+
+impl Asm {
+    fn op(&mut self, op: u8) -> &mut Self {
+        self.0.push(op);
+        self
+    }
+
+    fn with_index(&mut self, op: u8, index: u32) -> &mut Self {
+        self.0.push(op);
+        uleb(&mut self.0, index.into());
+        self
+    }
+
+    fn i32(&mut self, n: i32) -> &mut Self {
+        self.0.push(0x41);
+        sleb(&mut self.0, n.into());
+        self
+    }
+
+    fn i64(&mut self, n: i64) -> &mut Self {
+        self.0.push(0x42);
+        sleb(&mut self.0, n);
+        self
+    }
+
+    fn get(&mut self, local: u32) -> &mut Self { self.with_index(0x20, local) }
+    fn set(&mut self, local: u32) -> &mut Self { self.with_index(0x21, local) }
+    fn tee(&mut self, local: u32) -> &mut Self { self.with_index(0x22, local) }
+    fn global_get(&mut self, global: u32) -> &mut Self { self.with_index(0x23, global) }
+    fn global_set(&mut self, global: u32) -> &mut Self { self.with_index(0x24, global) }
+    fn call(&mut self, function: u32) -> &mut Self { self.with_index(0x10, function) }
+    fn br(&mut self, depth: u32) -> &mut Self { self.with_index(0x0c, depth) }
+    fn br_if(&mut self, depth: u32) -> &mut Self { self.with_index(0x0d, depth) }
+
+    /// Start a block, loop, or if, with no result.
+    fn block(&mut self) -> &mut Self { self.op(0x02).op(0x40) }
+    fn loop_(&mut self) -> &mut Self { self.op(0x03).op(0x40) }
+    fn if_(&mut self) -> &mut Self { self.op(0x04).op(0x40) }
+    fn else_(&mut self) -> &mut Self { self.op(0x05) }
+    fn end(&mut self) -> &mut Self { self.op(0x0b) }
+    fn ret(&mut self) -> &mut Self { self.op(0x0f) }
+
+    /// Return `status` from `run` (or `read_num`).
+    fn exit(&mut self, status: i32) -> &mut Self {
+        self.i32(status).ret()
+    }
+
+    fn memory(&mut self, op: u8, align: u32, offset: u32) -> &mut Self {
+        self.op(op);
+        uleb(&mut self.0, align.into());
+        uleb(&mut self.0, offset.into());
+        self
+    }
+
+    fn load8(&mut self, offset: u32) -> &mut Self { self.memory(0x2d, 0, offset) }
+    fn load32(&mut self, offset: u32) -> &mut Self { self.memory(0x28, 2, offset) }
+    fn load64(&mut self, offset: u32) -> &mut Self { self.memory(0x29, 3, offset) }
+    fn store64(&mut self) -> &mut Self { self.memory(0x37, 3, 0) }
+
+    /// Run the rest of the body only if the stack has at least `n` items.
+    fn if_len(&mut self, n: i32) -> &mut Self {
+        self.call(LEN).i32(n).op(I32_GE_U).if_()
+    }
+
+    /// Pop the stack's top two items into locals `a` and `b`, top into `b`.
+    fn pop2(&mut self, a: u32, b: u32) -> &mut Self {
+        self.call(POP).set(b).call(POP).set(a)
+    }
+}
+
+const UNREACHABLE: u8 = 0x00;
+const DROP: u8 = 0x1a;
+const SELECT: u8 = 0x1b;
+const MEMORY_SIZE: u8 = 0x3f;
+const MEMORY_GROW: u8 = 0x40;
+const I32_EQZ: u8 = 0x45;
+const I32_EQ: u8 = 0x46;
+const I32_LT_S: u8 = 0x48;
+const I32_LT_U: u8 = 0x49;
+const I32_GT_S: u8 = 0x4a;
+const I32_GT_U: u8 = 0x4b;
+const I32_GE_S: u8 = 0x4e;
+const I32_GE_U: u8 = 0x4f;
+const I64_EQZ: u8 = 0x50;
+const I64_EQ: u8 = 0x51;
+const I64_NE: u8 = 0x52;
+const I64_LT_S: u8 = 0x53;
+const I64_LT_U: u8 = 0x54;
+const I64_GT_S: u8 = 0x55;
+const I64_LE_S: u8 = 0x57;
+const I64_GE_S: u8 = 0x59;
+const I32_ADD: u8 = 0x6a;
+const I32_SUB: u8 = 0x6b;
+const I32_AND: u8 = 0x71;
+const I32_OR: u8 = 0x72;
+const I32_XOR: u8 = 0x73;
+const I32_SHL: u8 = 0x74;
+const I32_SHR_U: u8 = 0x76;
+const I64_ADD: u8 = 0x7c;
+const I64_SUB: u8 = 0x7d;
+const I64_MUL: u8 = 0x7e;
+const I64_DIV_S: u8 = 0x7f;
+const I64_DIV_U: u8 = 0x80;
+const I64_REM_S: u8 = 0x81;
+const I64_REM_U: u8 = 0x82;
+const I64_AND: u8 = 0x83;
+const I64_XOR: u8 = 0x85;
+const I32_WRAP_I64: u8 = 0xa7;
+const I64_EXTEND_I32_U: u8 = 0xad;
+
+/// The next byte of input, or -1 at the end of input, without consuming it.
+fn peek() -> Asm {
+    let mut f = Asm::default();
+    f.global_get(PEEKED).i32(UNREAD).op(I32_EQ).if_()
+        .call(READ).global_set(PEEKED)
+        .end()
+        .global_get(PEEKED);
+    f
+}
+
+fn read_byte() -> Asm {
+    let mut f = Asm::default();
+    f.call(PEEK).i32(UNREAD).global_set(PEEKED);
+    f
+}
+
+/// Push an `i64`, growing memory if the stack has run out of room.
+fn push() -> Asm {
+    let mut f = Asm::default();
+    f.global_get(SP).i32(8).op(I32_ADD)
+        .op(MEMORY_SIZE).op(0).i32(16).op(I32_SHL)
+        .op(I32_GT_U).if_()
+            .i32(1).op(MEMORY_GROW).op(0).i32(-1).op(I32_EQ).if_().op(UNREACHABLE).end()
+        .end()
+        .global_get(SP).get(0).store64()
+        .global_get(SP).i32(8).op(I32_ADD).global_set(SP);
+    f
+}
+
+fn pop() -> Asm {
+    let mut f = Asm::default();
+    f.global_get(SP).i32(8).op(I32_SUB).global_set(SP)
+        .global_get(SP).load64(0);
+    f
+}
+
+/// The item `depth` items down from the top of the stack.
+fn peek_stack() -> Asm {
+    let mut f = Asm::default();
+    f.global_get(SP).get(0).i32(3).op(I32_SHL).op(I32_SUB).i32(8).op(I32_SUB).load64(0);
+    f
+}
+
+/// Reverse `count` items starting at address `start`.
+fn reverse() -> Asm {
+    let (start, count, end, swap) = (0, 1, 2, 3);
+    let mut f = Asm::default();
+    f.get(count).op(I32_EQZ).if_().ret().end()
+        .get(start).get(count).i32(3).op(I32_SHL).op(I32_ADD).i32(8).op(I32_SUB).set(end)
+        .block().loop_()
+            .get(start).get(end).op(I32_GE_U).br_if(1)
+            .get(start).load64(0).set(swap)
+            .get(start).get(end).load64(0).store64()
+            .get(end).get(swap).store64()
+            .get(start).i32(8).op(I32_ADD).set(start)
+            .get(end).i32(8).op(I32_SUB).set(end)
+            .br(0)
+        .end().end();
+    f
+}
+
+/// Read an integer as `pieti` does by default, pushing it if there was one.
+/// Returns 1 if there was, 0 if not, or `OVERFLOWED`.
+fn read_num() -> Asm {
+    let (c, negative, n, digits, digit) = (0, 1, 2, 3, 4);
+    let mut f = Asm::default();
+    f.block().loop_()
+        .call(PEEK).tee(c).i32(b' '.into()).op(I32_EQ);
+    for space in [b'\t', b'\n', 0x0c, b'\r'] {
+        f.get(c).i32(space.into()).op(I32_EQ).op(I32_OR);
+    }
+    f.op(I32_EQZ).br_if(1)
+        .call(READ_BYTE).op(DROP)
+        .br(0)
+        .end().end();
+    f.call(PEEK).tee(c).i32(b'-'.into()).op(I32_EQ).if_()
+            .i32(1).set(negative).call(READ_BYTE).op(DROP)
+        .else_()
+            .get(c).i32(b'+'.into()).op(I32_EQ).if_().call(READ_BYTE).op(DROP).end()
+        .end();
+    f.block().loop_()
+        .call(PEEK).tee(c).i32(b'0'.into()).op(I32_LT_S)
+        .get(c).i32(b'9'.into()).op(I32_GT_S).op(I32_OR).br_if(1)
+        .call(READ_BYTE).op(DROP)
+        .get(c).i32(b'0'.into()).op(I32_SUB).op(I64_EXTEND_I32_U).set(digit)
+        .get(n).i64(i64::MAX / 10).op(I64_GT_S)
+        .get(n).i64(i64::MIN / 10).op(I64_LT_S).op(I32_OR).if_().exit(OVERFLOWED).end()
+        .get(n).i64(10).op(I64_MUL).set(n)
+        .get(negative).if_()
+            .get(n).i64(i64::MIN).get(digit).op(I64_ADD).op(I64_LT_S).if_().exit(OVERFLOWED).end()
+            .get(n).get(digit).op(I64_SUB).set(n)
+        .else_()
+            .get(n).i64(i64::MAX).get(digit).op(I64_SUB).op(I64_GT_S).if_().exit(OVERFLOWED).end()
+            .get(n).get(digit).op(I64_ADD).set(n)
+        .end()
+        .get(digits).i32(1).op(I32_ADD).set(digits)
+        .br(0)
+        .end().end();
+    f.get(digits).op(I32_EQZ).if_().exit(0).end()
+        .get(n).call(PUSH)
+        .i32(1);
+    f
+}
+
+fn write_num() -> Asm {
+    let (n, magnitude, place) = (0, 1, 2);
+    let mut f = Asm::default();
+    f.get(n).i64(0).op(I64_LT_S).if_().i32(b'-'.into()).call(WRITE).end()
+        .i64(0).get(n).op(I64_SUB).get(n).get(n).i64(0).op(I64_LT_S).op(SELECT).set(magnitude)
+        .i64(1).set(place)
+        .block().loop_()
+            .get(magnitude).get(place).op(I64_DIV_U).i64(10).op(I64_LT_U).br_if(1)
+            .get(place).i64(10).op(I64_MUL).set(place)
+            .br(0)
+        .end().end()
+        .loop_()
+            .get(magnitude).get(place).op(I64_DIV_U).op(I32_WRAP_I64).i32(b'0'.into()).op(I32_ADD).call(WRITE)
+            .get(magnitude).get(place).op(I64_REM_U).set(magnitude)
+            .get(place).i64(10).op(I64_DIV_U).tee(place).op(I64_EQZ).op(I32_EQZ).br_if(0)
+        .end();
+    f
+}
+
+/// Write a Unicode scalar value as UTF-8, or nothing if it isn't one.
+fn write_char() -> Asm {
+    let (c, x) = (0, 1);
+    let continuation = |f: &mut Asm, shift: i32| {
+        f.get(x).i32(shift).op(I32_SHR_U).i32(0x3f).op(I32_AND).i32(0x80).op(I32_OR).call(WRITE);
+    };
+    let mut f = Asm::default();
+    f.get(c).i64(0).op(I64_LT_S)
+        .get(c).i64(0x10ffff).op(I64_GT_S).op(I32_OR)
+        .get(c).i64(0xd800).op(I64_GE_S).get(c).i64(0xdfff).op(I64_LE_S).op(I32_AND).op(I32_OR)
+        .if_().ret().end()
+        .get(c).op(I32_WRAP_I64).set(x)
+        .get(x).i32(0x80).op(I32_LT_U).if_().get(x).call(WRITE).ret().end()
+        .get(x).i32(0x800).op(I32_LT_U).if_()
+            .get(x).i32(6).op(I32_SHR_U).i32(0xc0).op(I32_OR).call(WRITE)
+        .else_()
+            .get(x).i32(0x10000).op(I32_LT_U).if_()
+                .get(x).i32(12).op(I32_SHR_U).i32(0xe0).op(I32_OR).call(WRITE)
+            .else_()
+                .get(x).i32(18).op(I32_SHR_U).i32(0xf0).op(I32_OR).call(WRITE);
+    continuation(&mut f, 12);
+    f.end();
+    continuation(&mut f, 6);
+    f.end();
+    continuation(&mut f, 0);
+    f
+}
+
+fn len(stack_base: i32) -> Asm {
+    let mut f = Asm::default();
+    f.global_get(SP).i32(stack_base).op(I32_SUB).i32(3).op(I32_SHR_U);
+    f
+}
+
+/// Walk the moves from the start, running each command, until the program
+/// halts or fails. A command that can't run is skipped, as `pieti` does by default.
+fn run(stack_base: i32) -> Asm {
+    let (entry, ip, node, value, a, b, r, n, depth, rolled) = (0, 1, 2, 3, 4, 5, 6, 7, 8, 9);
+    let mut f = Asm::default();
+    f.i32(stack_base).global_set(SP)
+        .i32(UNREAD).global_set(PEEKED)
+        .i32(0).set(entry)
+        .loop_()
+        .get(entry).load8(0).tee(n).if_()
+            .get(n).i32(HALT.into()).op(I32_EQ).if_().exit(HALTED).end()
+            .exit(INVALID_COLOR)
+        .end()
+        .get(entry).load8(2).set(ip)
+        .get(entry).load32(4).set(node)
+        .get(entry).load64(8).set(value)
+        .block();
+    // A block per command, innermost first, for `br_table` to break out of
+    // into the code for that command.
+    for _ in 0..18 {
+        f.block();
+    }
+    f.get(entry).load8(1).op(0x0e);
+    uleb(&mut f.0, 17);
+    for command in 0..18 {
+        uleb(&mut f.0, command);
+    }
+    for command in 0..18u32 {
+        f.end();
+        match command {
+            // Noop
+            0 => (),
+            // Push
+            1 => { f.get(value).call(PUSH); }
+            // Pop
+            2 => { f.if_len(1).call(POP).op(DROP).end(); }
+            // Add, Subtract, and Multiply, failing if they overflow
+            3 => {
+                f.if_len(2).pop2(a, b)
+                    .get(a).get(b).op(I64_ADD).set(r)
+                    .get(a).get(r).op(I64_XOR).get(b).get(r).op(I64_XOR).op(I64_AND).i64(0).op(I64_LT_S)
+                    .if_().exit(OVERFLOWED).end()
+                    .get(r).call(PUSH)
+                    .end();
+            }
+            4 => {
+                f.if_len(2).pop2(a, b)
+                    .get(a).get(b).op(I64_SUB).set(r)
+                    .get(a).get(b).op(I64_XOR).get(a).get(r).op(I64_XOR).op(I64_AND).i64(0).op(I64_LT_S)
+                    .if_().exit(OVERFLOWED).end()
+                    .get(r).call(PUSH)
+                    .end();
+            }
+            5 => {
+                f.if_len(2).pop2(a, b)
+                    .get(a).get(b).op(I64_MUL).set(r)
+                    .get(a).i64(-1).op(I64_EQ).if_()
+                        .get(b).i64(i64::MIN).op(I64_EQ).if_().exit(OVERFLOWED).end()
+                    .else_()
+                        .get(a).op(I64_EQZ).op(I32_EQZ).if_()
+                            .get(r).get(a).op(I64_DIV_S).get(b).op(I64_NE).if_().exit(OVERFLOWED).end()
+                        .end()
+                    .end()
+                    .get(r).call(PUSH)
+                    .end();
+            }
+            // Divide and Mod, rounding towards negative infinity
+            6 | 7 => {
+                f.if_len(2).i32(0).call(PEEK_STACK).op(I64_EQZ).op(I32_EQZ).if_()
+                    .pop2(a, b)
+                    .get(a).get(b).op(I64_REM_S).set(r)
+                    .get(r).op(I64_EQZ).op(I32_EQZ)
+                    .get(r).get(b).op(I64_XOR).i64(0).op(I64_LT_S).op(I32_AND).set(n);
+                if command == 6 {
+                    f.get(a).i64(i64::MIN).op(I64_EQ).get(b).i64(-1).op(I64_EQ).op(I32_AND)
+                        .if_().exit(OVERFLOWED).end()
+                        .get(a).get(b).op(I64_DIV_S).get(n).op(I64_EXTEND_I32_U).op(I64_SUB);
+                } else {
+                    f.get(r).get(b).i64(0).get(n).op(SELECT).op(I64_ADD);
+                }
+                f.call(PUSH).end().end();
+            }
+            // Not
+            8 => { f.if_len(1).call(POP).op(I64_EQZ).op(I64_EXTEND_I32_U).call(PUSH).end(); }
+            // Greater
+            9 => {
+                f.if_len(2).pop2(a, b)
+                    .get(a).get(b).op(I64_GT_S).op(I64_EXTEND_I32_U).call(PUSH)
+                    .end();
+            }
+            // Pointer: rotate the direction pointer, the high bits of `ip`
+            10 => {
+                f.if_len(1)
+                    .call(POP).i64(4).op(I64_REM_S).i64(4).op(I64_ADD).i64(4).op(I64_REM_S).op(I32_WRAP_I64)
+                    .get(ip).i32(1).op(I32_SHR_U).op(I32_ADD).i32(3).op(I32_AND).i32(1).op(I32_SHL)
+                    .get(ip).i32(1).op(I32_AND).op(I32_OR).set(ip)
+                    .end();
+            }
+            // Switch: flip the codel chooser, the low bit of `ip`
+            11 => {
+                f.if_len(1)
+                    .call(POP).i64(2).op(I64_REM_S).op(I64_EQZ).op(I32_EQZ).if_()
+                        .get(ip).i32(1).op(I32_XOR).set(ip)
+                    .end()
+                    .end();
+            }
+            // Duplicate
+            12 => { f.if_len(1).i32(0).call(PEEK_STACK).call(PUSH).end(); }
+            // Roll, by reversing the rolled items, then each side of where they split
+            13 => {
+                f.if_len(2)
+                    .i32(1).call(PEEK_STACK).set(a)
+                    .i32(0).call(PEEK_STACK).set(b)
+                    .call(LEN).i32(2).op(I32_SUB).set(n)
+                    .get(a).i64(0).op(I64_GT_S).get(a).get(n).op(I64_EXTEND_I32_U).op(I64_LE_S).op(I32_AND)
+                    .if_()
+                        .global_get(SP).i32(16).op(I32_SUB).global_set(SP)
+                        .get(a).op(I32_WRAP_I64).set(depth)
+                        .get(b).get(a).op(I64_REM_S).get(a).op(I64_ADD).get(a).op(I64_REM_S).op(I32_WRAP_I64).set(n)
+                        .global_get(SP).get(depth).i32(3).op(I32_SHL).op(I32_SUB).set(rolled)
+                        .get(rolled).get(depth).call(REVERSE)
+                        .get(rolled).get(n).call(REVERSE)
+                        .get(rolled).get(n).i32(3).op(I32_SHL).op(I32_ADD).get(depth).get(n).op(I32_SUB).call(REVERSE)
+                    .end()
+                    .end();
+            }
+            // InNum
+            14 => { f.call(READ_NUM).i32(OVERFLOWED).op(I32_EQ).if_().exit(OVERFLOWED).end(); }
+            // InChar
+            15 => {
+                f.call(READ_BYTE).tee(n).i32(0).op(I32_GE_S).if_()
+                    .get(n).op(I64_EXTEND_I32_U).call(PUSH)
+                    .end();
+            }
+            // OutNum
+            16 => { f.if_len(1).call(POP).call(WRITE_NUM).end(); }
+            // OutChar
+            _ => { f.if_len(1).call(POP).call(WRITE_CHAR).end(); }
+        }
+        f.br(17 - command);
+    }
+    f.end()
+        .get(node).i32(3).op(I32_SHL).get(ip).op(I32_ADD).i32(4).op(I32_SHL).i32(ENTRY_SIZE as i32).op(I32_ADD).set(entry)
+        .br(0)
+        .end()
+        .op(UNREACHABLE);
+    f
+}
+
+fn section(module: &mut Vec<u8>, id: u8, count: usize, contents: &[u8]) {
+    let mut body = Vec::new();
+    uleb(&mut body, count as u64);
+    body.extend_from_slice(contents);
+    module.push(id);
+    uleb(module, body.len() as u64);
+    module.extend(body);
+}
+
+fn name(bytes: &mut Vec<u8>, name: &str) {
+    uleb(bytes, name.len() as u64);
+    bytes.extend_from_slice(name.as_bytes());
+}
+
+/// The moves as a table: the start, then each node's eight moves.
+fn table(graph: &Graph) -> Vec<u8> {
+    let mut table = Vec::new();
+    for step in std::iter::once(&graph.start).chain(graph.moves.iter().flatten()) {
+        let (kind, command, ip, node, value) = match step {
+            Move::Halt => (HALT, 0, 0, 0, 0),
+            Move::Stuck(_) => (STUCK, 0, 0, 0, 0),
+            Move::Go(command, value, ip, node) => {
+                (GO, *command as u8, *ip as u8, *node as u32, value.to_i64().unwrap_or_default())
+            }
+        };
+        table.extend([kind, command, ip, 0]);
+        table.extend(node.to_le_bytes());
+        table.extend(value.to_le_bytes());
+    }
+    table
+}
+
+/// Compile `code` to a WebAssembly module, which runs the same way as `code`
+/// does by default, without needing an interpreter.
+///
+/// The module imports two functions from `env`: `read`, which returns the
+/// next byte of input, or -1 at the end of input, and `write`, which takes
+/// a byte of output. It exports its `memory`, and `run`, which runs the
+/// program once, returning 0 when it halts, 1 if it would move into a
+/// non-Piet color, or 2 if its numbers grew too large.
+///
+/// Its numbers are `i64`s, rather than growing without bound as
+/// `PietRunner`'s do, so `run` returns 2 for a program whose numbers outgrow
+/// them where `PietRunner` would carry on; nothing checks ahead of time
+/// whether a program might.
+pub fn to_wasm(code: &PietCode) -> Vec<u8> {
+    let graph = Graph::new(code);
+    let table = table(&graph);
+    let stack_base = table.len() as u32;
+
+    let mut module = b"\0asm\x01\0\0\0".to_vec();
+    let mut types = Vec::new();
+    for (params, results) in TYPES {
+        types.push(0x60);
+        uleb(&mut types, params.len() as u64);
+        types.extend_from_slice(params);
+        uleb(&mut types, results.len() as u64);
+        types.extend_from_slice(results);
+    }
+    section(&mut module, 1, TYPES.len(), &types);
+
+    let mut imports = Vec::new();
+    for (field, ty) in [("read", TYPE_I32), ("write", TYPE_WRITE)] {
+        name(&mut imports, "env");
+        name(&mut imports, field);
+        imports.push(0x00);
+        uleb(&mut imports, ty.into());
+    }
+    section(&mut module, 2, 2, &imports);
+
+    // Each function's type, its locals' types, and its body.
+    let base = stack_base as i32;
+    let functions: [(u32, &[u8], Asm); 11] = [
+        (TYPE_I32, &[], peek()),
+        (TYPE_I32, &[], read_byte()),
+        (TYPE_TAKE_I64, &[], push()),
+        (TYPE_I64, &[], pop()),
+        (TYPE_PEEK_STACK, &[], peek_stack()),
+        (TYPE_REVERSE, &[I32, I64], reverse()),
+        (TYPE_I32, &[I32, I32, I64, I32, I64], read_num()),
+        (TYPE_TAKE_I64, &[I64, I64], write_num()),
+        (TYPE_TAKE_I64, &[I32], write_char()),
+        (TYPE_I32, &[], len(base)),
+        (TYPE_I32, &[I32, I32, I32, I64, I64, I64, I64, I32, I32, I32], run(base)),
+    ];
+    let mut declarations = Vec::new();
+    let mut bodies = Vec::new();
+    for (ty, locals, mut asm) in functions {
+        uleb(&mut declarations, ty.into());
+        asm.end();
+        let mut body = Vec::new();
+        uleb(&mut body, locals.len() as u64);
+        for &local in locals {
+            body.extend([1, local]);
+        }
+        body.extend(asm.0);
+        uleb(&mut bodies, body.len() as u64);
+        bodies.extend(body);
+    }
+    section(&mut module, 3, functions_len(), &declarations);
+
+    // Enough memory for the table, and the stack to start out on.
+    let pages = stack_base / 0x10000 + 1;
+    let mut memory = vec![0x00];
+    uleb(&mut memory, pages.into());
+    section(&mut module, 5, 1, &memory);
+
+    let mut globals = Vec::new();
+    for init in [base, UNREAD] {
+        globals.extend([I32, 0x01]);
+        let mut init_expr = Asm::default();
+        init_expr.i32(init).end();
+        globals.extend(init_expr.0);
+    }
+    section(&mut module, 6, 2, &globals);
+
+    let mut exports = Vec::new();
+    name(&mut exports, "run");
+    exports.push(0x00);
+    uleb(&mut exports, RUN.into());
+    name(&mut exports, "memory");
+    exports.extend([0x02, 0x00]);
+    section(&mut module, 7, 2, &exports);
+
+    section(&mut module, 10, functions_len(), &bodies);
+
+    let mut data = vec![0x00];
+    let mut offset = Asm::default();
+    offset.i32(0).end();
+    data.extend(offset.0);
+    uleb(&mut data, table.len() as u64);
+    data.extend(table);
+    section(&mut module, 11, 1, &data);
+    module
+}
+
+/// How many functions the module defines, besides its imports.
+fn functions_len() -> usize {
+    (RUN - WRITE) as usize
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transpile::tests::{installed, run_with_input, test_dir, EVERY_COMMAND, EVERY_COMMAND_INPUT};
+    use crate::Color;
+    use std::fs;
+    use std::process::Command as Process;
+
+    /// Runs the module given as its argument, with `env.read` and `env.write`
+    /// on stdin and stdout, exiting with what `run` returns.
+    const NODE_HOST: &str = "
+        const fs = require('fs');
+        const input = fs.readFileSync(0);
+        const output = [];
+        let pos = 0;
+        const env = {
+            read: () => pos < input.length ? input[pos++] : -1,
+            write: (byte) => { output.push(byte); },
+        };
+        WebAssembly.instantiate(fs.readFileSync(process.argv[1]), { env }).then(({ instance }) => {
+            const status = instance.exports.run();
+            process.stdout.write(Buffer.from(output));
+            process.exitCode = status;
+        });
+    ";
+
+    #[test]
+    fn test_leb128() {
+        let encode = |n: i64| {
+            let mut bytes = Vec::new();
+            sleb(&mut bytes, n);
+            bytes
+        };
+        assert_eq!(encode(0), [0x00]);
+        assert_eq!(encode(-1), [0x7f]);
+        assert_eq!(encode(63), [0x3f]);
+        assert_eq!(encode(64), [0xc0, 0x00]);
+        assert_eq!(encode(-64), [0x40]);
+        assert_eq!(encode(-65), [0xbf, 0x7f]);
+        let mut bytes = Vec::new();
+        uleb(&mut bytes, 624485);
+        assert_eq!(bytes, [0xe5, 0x8e, 0x26]);
+    }
+
+    #[test]
+    fn test_to_wasm() {
+        let code = PietCode::new(3, 1, vec![Color::Red, Color::Red, Color::DarkRed]);
+        let module = to_wasm(&code);
+        assert!(module.starts_with(b"\0asm\x01\0\0\0"));
+        // The table ends the module: the start, then two blocks' moves.
+        let table = table(&Graph::new(&code));
+        assert_eq!(table.len(), ENTRY_SIZE as usize * (1 + 2 * 8));
+        assert!(module.ends_with(&table));
+        // Red pushes its size into dark red.
+        let push = &table[ENTRY_SIZE as usize..][..ENTRY_SIZE as usize];
+        assert_eq!(push[..4], [GO, crate::Command::Push as u8, 0, 0]);
+        assert_eq!(push[8..], 2i64.to_le_bytes());
+    }
+
+    #[test]
+    fn test_run_wasm() {
+        if !installed("node") {
+            return;
+        }
+        let dir = test_dir("wasm");
+        let code = crate::asm::load_str(EVERY_COMMAND).unwrap();
+        let module = dir.join("test.wasm");
+        fs::write(&module, to_wasm(&code)).unwrap();
+        let expected = code.execute().capture_io(EVERY_COMMAND_INPUT).run_collect().stdout_lossy().into_owned();
+        let output = run_with_input(Process::new("node").arg("-e").arg(NODE_HOST).arg(&module), EVERY_COMMAND_INPUT);
+        assert_eq!(output, expected);
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,379 @@
+use num_bigint::BigInt;
+use num_integer::Integer;
+use num_traits::{One, ToPrimitive, Zero};
+use std::cmp::Ordering;
+use std::fmt;
+use std::ops::{Add, Mul, Neg, Sub};
+
+/// A number on the stack.
+///
+/// Almost every number a Piet program works with fits in an `i64`, so those
+/// are kept inline, and only the rest are allocated as a `BigInt`. Arithmetic
+/// that overflows an `i64` moves over to a `BigInt`, and back again once the
+/// result fits.
+#[derive(Clone, PartialEq, Eq, Hash)]
+pub struct Value(Repr);
+
+/// A value is only ever `Big` if it doesn't fit in an `i64`, so each number
+/// has exactly one representation.
+#[derive(Clone, PartialEq, Eq, Hash)]
+enum Repr {
+    Small(i64),
+    Big(BigInt),
+}
+
+impl Value {
+    /// The value as a `BigInt`, allocating one if it's small.
+    pub fn to_bigint(&self) -> BigInt {
+        match &self.0 {
+            Repr::Small(n) => BigInt::from(*n),
+            Repr::Big(n) => n.clone(),
+        }
+    }
+
+    /// Both values as `i64`s, if they both are.
+    fn small(&self, other: &Value) -> Option<(i64, i64)> {
+        match (&self.0, &other.0) {
+            (Repr::Small(a), Repr::Small(b)) => Some((*a, *b)),
+            _ => None,
+        }
+    }
+
+    /// Apply `small` to `i64`s, or `big` if either is a `BigInt` or `small` overflows.
+    fn arithmetic(
+        &self,
+        other: &Value,
+        small: fn(i64, i64) -> Option<i64>,
+        big: fn(BigInt, BigInt) -> BigInt,
+    ) -> Value {
+        match self.small(other).and_then(|(a, b)| small(a, b)) {
+            Some(n) => Value(Repr::Small(n)),
+            None => Value::from(big(self.to_bigint(), other.to_bigint())),
+        }
+    }
+
+    /// Division rounding towards negative infinity, as `Integer::div_floor`.
+    pub fn div_floor(&self, other: &Value) -> Value {
+        self.arithmetic(
+            other,
+            |a, b| (b != -1).then(|| Integer::div_floor(&a, &b)).or_else(|| a.checked_neg()),
+            |a, b| a.div_floor(&b),
+        )
+    }
+
+    /// The remainder of `div_floor`, which takes the sign of `other`.
+    pub fn mod_floor(&self, other: &Value) -> Value {
+        self.arithmetic(
+            other,
+            |a, b| Some(if b == -1 { 0 } else { Integer::mod_floor(&a, &b) }),
+            |a, b| a.mod_floor(&b),
+        )
+    }
+
+    pub fn is_negative(&self) -> bool {
+        match &self.0 {
+            Repr::Small(n) => *n < 0,
+            Repr::Big(n) => n.sign() == num_bigint::Sign::Minus,
+        }
+    }
+
+    pub fn is_odd(&self) -> bool {
+        match &self.0 {
+            Repr::Small(n) => n.is_odd(),
+            Repr::Big(n) => n.is_odd(),
+        }
+    }
+}
+
+/// What arithmetic does with a result too wide for a `ValueCap`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum OverflowPolicy {
+    /// The command fails, so is skipped, leaving its operands on the stack.
+    #[default]
+    Error,
+    /// The result is clamped to the widest value allowed, positive or negative.
+    Saturate,
+    /// The result wraps around, as a two's complement integer of that width would.
+    Wrap,
+}
+
+/// A limit on how wide stack values can grow, eg to sandbox programs that
+/// would otherwise use up memory multiplying a number by itself over and over.
+/// Results of arithmetic must fit in a `bits`-bit two's complement integer.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ValueCap {
+    /// At least 1.
+    pub bits: u64,
+    pub policy: OverflowPolicy,
+}
+
+impl ValueCap {
+    pub fn new(bits: u64, policy: OverflowPolicy) -> Self {
+        ValueCap { bits: bits.max(1), policy }
+    }
+
+    /// Bring `value` within the cap, as the policy has it, or None if it
+    /// doesn't fit and the policy is `Error`.
+    pub(crate) fn apply(&self, value: Value) -> Option<Value> {
+        if value.fits(self.bits) {
+            return Some(value);
+        }
+        let half = BigInt::one() << (self.bits - 1);
+        match self.policy {
+            OverflowPolicy::Error => None,
+            OverflowPolicy::Saturate if value.is_negative() => Some(Value::from(-half)),
+            OverflowPolicy::Saturate => Some(Value::from(half - 1)),
+            OverflowPolicy::Wrap => {
+                let n = value.to_bigint().mod_floor(&(&half << 1));
+                Some(Value::from(if n >= half { n - (half << 1) } else { n }))
+            }
+        }
+    }
+}
+
+/// How wide numbers are. Piet's are unbounded, but some interpreters use
+/// machine integers instead, which programs written for them may rely on
+/// overflowing.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum IntModel {
+    #[default]
+    Unbounded,
+    /// Arithmetic wraps around at this width, as machine integers do.
+    Wrapping(IntWidth),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum IntWidth {
+    I32,
+    I64,
+}
+
+impl IntModel {
+    /// The cap on values that has arithmetic work as the model does.
+    pub fn cap(self) -> Option<ValueCap> {
+        match self {
+            IntModel::Unbounded => None,
+            IntModel::Wrapping(IntWidth::I32) => Some(ValueCap::new(32, OverflowPolicy::Wrap)),
+            IntModel::Wrapping(IntWidth::I64) => Some(ValueCap::new(64, OverflowPolicy::Wrap)),
+        }
+    }
+}
+
+impl Value {
+    /// Whether the value fits in a `bits`-bit two's complement integer.
+    fn fits(&self, bits: u64) -> bool {
+        // A negative number fits if the positive number one closer to zero does.
+        match &self.0 {
+            Repr::Small(n) => {
+                let magnitude = if *n < 0 { !*n } else { *n };
+                u64::from(64 - magnitude.leading_zeros()) < bits
+            }
+            Repr::Big(n) if n.sign() == num_bigint::Sign::Minus => (-n - 1u8).bits() < bits,
+            Repr::Big(n) => n.bits() < bits,
+        }
+    }
+}
+
+impl From<BigInt> for Value {
+    fn from(n: BigInt) -> Self {
+        match n.to_i64() {
+            Some(n) => Value(Repr::Small(n)),
+            None => Value(Repr::Big(n)),
+        }
+    }
+}
+
+impl From<i64> for Value {
+    fn from(n: i64) -> Self {
+        Value(Repr::Small(n))
+    }
+}
+
+impl From<i32> for Value {
+    fn from(n: i32) -> Self {
+        Value(Repr::Small(n.into()))
+    }
+}
+
+impl From<u8> for Value {
+    fn from(n: u8) -> Self {
+        Value(Repr::Small(n.into()))
+    }
+}
+
+impl From<usize> for Value {
+    fn from(n: usize) -> Self {
+        match i64::try_from(n) {
+            Ok(n) => Value(Repr::Small(n)),
+            Err(_) => Value(Repr::Big(n.into())),
+        }
+    }
+}
+
+impl From<Value> for BigInt {
+    fn from(value: Value) -> Self {
+        match value.0 {
+            Repr::Small(n) => BigInt::from(n),
+            Repr::Big(n) => n,
+        }
+    }
+}
+
+impl Ord for Value {
+    fn cmp(&self, other: &Value) -> Ordering {
+        match self.small(other) {
+            Some((a, b)) => a.cmp(&b),
+            None => self.to_bigint().cmp(&other.to_bigint()),
+        }
+    }
+}
+
+impl PartialOrd for Value {
+    fn partial_cmp(&self, other: &Value) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Add for Value {
+    type Output = Value;
+
+    fn add(self, other: Value) -> Value {
+        self.arithmetic(&other, i64::checked_add, |a, b| a + b)
+    }
+}
+
+impl Sub for Value {
+    type Output = Value;
+
+    fn sub(self, other: Value) -> Value {
+        self.arithmetic(&other, i64::checked_sub, |a, b| a - b)
+    }
+}
+
+impl Mul for Value {
+    type Output = Value;
+
+    fn mul(self, other: Value) -> Value {
+        self.arithmetic(&other, i64::checked_mul, |a, b| a * b)
+    }
+}
+
+impl Neg for Value {
+    type Output = Value;
+
+    fn neg(self) -> Value {
+        Value::zero() - self
+    }
+}
+
+impl Zero for Value {
+    fn zero() -> Self {
+        Value(Repr::Small(0))
+    }
+
+    fn is_zero(&self) -> bool {
+        self.0 == Repr::Small(0)
+    }
+}
+
+impl One for Value {
+    fn one() -> Self {
+        Value(Repr::Small(1))
+    }
+}
+
+impl ToPrimitive for Value {
+    fn to_i64(&self) -> Option<i64> {
+        match &self.0 {
+            Repr::Small(n) => Some(*n),
+            Repr::Big(_) => None,
+        }
+    }
+
+    fn to_u64(&self) -> Option<u64> {
+        match &self.0 {
+            Repr::Small(n) => n.to_u64(),
+            Repr::Big(n) => n.to_u64(),
+        }
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.0 {
+            Repr::Small(n) => n.fmt(f),
+            Repr::Big(n) => n.fmt(f),
+        }
+    }
+}
+
+impl fmt::Debug for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn big(n: &str) -> Value {
+        Value::from(n.parse::<BigInt>().unwrap())
+    }
+
+    #[test]
+    fn test_promotion() {
+        let max = Value::from(i64::MAX);
+        let past_max = max.clone() + Value::one();
+        assert_eq!(past_max, big("9223372036854775808"));
+        assert!(matches!(past_max.0, Repr::Big(_)));
+        // And back down again.
+        let back = past_max - Value::one();
+        assert!(matches!(back.0, Repr::Small(_)));
+        assert_eq!(back, max);
+
+        let min = Value::from(i64::MIN);
+        assert_eq!(min.div_floor(&Value::from(-1)), big("9223372036854775808"));
+        assert_eq!(min.mod_floor(&Value::from(-1)), Value::zero());
+        assert_eq!(-min.clone(), big("9223372036854775808"));
+        assert_eq!(min.clone() * Value::from(2), big("-18446744073709551616"));
+        assert!(big("-18446744073709551616") < min);
+        assert!(big("18446744073709551616") > Value::from(i64::MAX));
+    }
+
+    #[test]
+    fn test_cap() {
+        let cap = |bits, policy, n: i64| ValueCap::new(bits, policy).apply(Value::from(n));
+        assert_eq!(cap(8, OverflowPolicy::Error, 127), Some(Value::from(127)));
+        assert_eq!(cap(8, OverflowPolicy::Error, -128), Some(Value::from(-128)));
+        assert_eq!(cap(8, OverflowPolicy::Error, 128), None);
+        assert_eq!(cap(8, OverflowPolicy::Error, -129), None);
+        assert_eq!(cap(8, OverflowPolicy::Saturate, 1000), Some(Value::from(127)));
+        assert_eq!(cap(8, OverflowPolicy::Saturate, -1000), Some(Value::from(-128)));
+        assert_eq!(cap(8, OverflowPolicy::Wrap, 200), Some(Value::from(-56)));
+        assert_eq!(cap(8, OverflowPolicy::Wrap, -129), Some(Value::from(127)));
+        assert_eq!(cap(0, OverflowPolicy::Wrap, 3), Some(Value::from(-1)));
+        assert_eq!(cap(64, OverflowPolicy::Error, i64::MIN), Some(Value::from(i64::MIN)));
+
+        let wide = ValueCap::new(64, OverflowPolicy::Wrap);
+        assert_eq!(wide.apply(big("9223372036854775808")), Some(Value::from(i64::MIN)));
+        assert_eq!(wide.apply(big("-9223372036854775809")), Some(Value::from(i64::MAX)));
+        let saturate = ValueCap::new(100, OverflowPolicy::Saturate);
+        assert_eq!(saturate.apply(big("-1267650600228229401496703205376")), Some(big("-633825300114114700748351602688")));
+    }
+
+    #[test]
+    fn test_floor() {
+        let floor = |a: i64, b: i64| {
+            let (a, b) = (Value::from(a), Value::from(b));
+            (a.div_floor(&b).to_i64().unwrap(), a.mod_floor(&b).to_i64().unwrap())
+        };
+        assert_eq!(floor(7, 2), (3, 1));
+        assert_eq!(floor(-7, 2), (-4, 1));
+        assert_eq!(floor(7, -2), (-4, -1));
+        assert_eq!(floor(-7, -2), (3, -1));
+        let huge = big("100000000000000000000");
+        assert_eq!(huge.div_floor(&Value::from(-3)), big("-33333333333333333334"));
+        assert_eq!(huge.mod_floor(&Value::from(-3)), Value::from(-2));
+    }
+}
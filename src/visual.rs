@@ -0,0 +1,125 @@
+use crate::{palette, CodelChoice, CodelCoord, Direction, PietCode, Tracer, Value};
+use std::fmt::Write as _;
+use std::io::Write;
+use std::thread;
+use std::time::Duration;
+
+/// How many values from the top of the stack each frame shows.
+const STACK_SHOWN: usize = 12;
+
+/// Draws a run live in an ANSI terminal, one frame a step, eg to watch how a
+/// program works: the program's codels in color, with an arrow on the current
+/// codel pointing the way the direction pointer does, then the top of the
+/// stack and the program's output so far.
+/// Each frame is drawn over the last, from the top left of the terminal.
+pub struct Visualizer<'a, W: Write> {
+    code: &'a PietCode,
+    out: W,
+    /// How long each frame is shown for.
+    delay: Duration,
+    step: u64,
+    pos: CodelCoord,
+    stack: Vec<Value>,
+    output: String,
+}
+
+impl<'a, W: Write> Visualizer<'a, W> {
+    /// Draw a run of `code` to `out`, waiting `delay` after each step.
+    pub fn new(code: &'a PietCode, out: W, delay: Duration) -> Self {
+        Visualizer {
+            code,
+            out,
+            delay,
+            step: 0,
+            pos: CodelCoord::new(0, 0),
+            stack: Vec::new(),
+            output: String::new(),
+        }
+    }
+
+    fn frame(&self, direction: Direction, chooser: CodelChoice) -> String {
+        // Home the cursor, rather than clearing the screen, so frames don't flicker.
+        let mut frame = "\x1b[H".to_string();
+        for (pos, color) in self.code.codels() {
+            let [r, g, b] = palette::rgb(color).unwrap_or([0x80, 0x80, 0x80]);
+            let _ = write!(frame, "\x1b[48;2;{r};{g};{b}m");
+            if pos == self.pos {
+                // Black on light colors, white on dark ones.
+                let fg = if r as u32 + g as u32 + b as u32 > 0x180 { 30 } else { 97 };
+                let arrow = match direction {
+                    Direction::Right => '→',
+                    Direction::Down => '↓',
+                    Direction::Left => '←',
+                    Direction::Up => '↑',
+                };
+                let _ = write!(frame, "\x1b[1;{fg}m{arrow} ");
+            } else {
+                frame.push_str("  ");
+            }
+            if pos.x + 1 == self.code.width {
+                frame.push_str("\x1b[0m\x1b[K\n");
+            }
+        }
+        let _ = writeln!(
+            frame,
+            "step {} at {}, pointing {direction:?}, choosing {chooser:?}\x1b[K",
+            self.step, self.pos,
+        );
+        let shown = self.stack.len().min(STACK_SHOWN);
+        let top = &self.stack[self.stack.len() - shown..];
+        let more = if shown < self.stack.len() { "... " } else { "" };
+        let top: Vec<_> = top.iter().map(|value| value.to_string()).collect();
+        let _ = writeln!(frame, "stack: [{more}{}]\x1b[K", top.join(", "));
+        let _ = writeln!(frame, "output: {:?}\x1b[J", self.output);
+        frame
+    }
+}
+
+impl<W: Write> Tracer for Visualizer<'_, W> {
+    fn on_step(
+        &mut self,
+        step: u64,
+        pos: CodelCoord,
+        direction: Direction,
+        chooser: CodelChoice,
+        stack: &[Value],
+    ) {
+        self.step = step;
+        self.pos = pos;
+        self.stack = stack.to_vec();
+        let frame = self.frame(direction, chooser);
+        let _ = self.out.write_all(frame.as_bytes());
+        let _ = self.out.flush();
+        thread::sleep(self.delay);
+    }
+
+    fn on_output(&mut self, text: &str) {
+        self.output.push_str(text);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm;
+
+    #[test]
+    fn test_visualizer() {
+        let code = asm::load_str("PUSH 7\nOUTNUM\nPUSH -1\nOUTCHAR\nPUSH 72\nOUTCHAR\nPUSH 1\nPOP\n").unwrap();
+        let mut frames = Vec::new();
+        let steps = {
+            let visualizer = Visualizer::new(&code, &mut frames, Duration::ZERO);
+            let mut runner = code.execute().tracer(visualizer).capture_io("");
+            runner.run();
+            runner.steps()
+        };
+        let frames = String::from_utf8(frames).unwrap();
+        assert_eq!(frames.matches("\x1b[H").count() as u64, steps);
+        let first = frames.split("\x1b[H").nth(1).unwrap();
+        assert_eq!(first.lines().count(), code.height + 3);
+        assert!(first.contains("step 1 at (0, 0), pointing Right, choosing Left"));
+        assert!(first.contains("→"));
+        // Writing -1 as a character fails, so writes nothing.
+        assert!(frames.ends_with("output: \"7H\"\x1b[J\n"));
+    }
+}